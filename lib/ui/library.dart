@@ -1,3 +1,4 @@
+import 'dart:async';
 import 'dart:io';
 
 import 'package:file_picker/file_picker.dart';
@@ -18,7 +19,75 @@ class Library extends StatefulWidget {
 }
 
 class _LibraryState extends State<Library> {
-  List<BookData> _bookData = [];
+  static const int _pageSize = 60;
+  final Map<int, BookData> _bookData = {};
+  final Set<int> _requestedPages = {};
+  int _total = 0;
+  String _orderingToken = "";
+  int _version = -1;
+  StreamSubscription? _pageSubscription;
+  StreamSubscription? _deltaSubscription;
+
+  @override
+  void initState() {
+    super.initState();
+    _pageSubscription = LibraryPage.rustSignalStream.listen((signal) {
+      final page = signal.message;
+      setState(() {
+        if (page.orderingToken != _orderingToken) {
+          _reset(page.orderingToken, page.total);
+        }
+        _total = page.total;
+        for (var i = 0; i < page.books.length; i++) {
+          _bookData[page.offset + i] = page.books[i];
+        }
+      });
+    });
+    _deltaSubscription = LibraryDelta.rustSignalStream.listen((signal) {
+      final delta = signal.message;
+      if (delta.version != _version + 1) {
+        ResyncLibrary().sendSignalToRust();
+        return;
+      }
+      setState(() {
+        _version = delta.version;
+        if (delta.orderingToken != _orderingToken) {
+          // Positions moved, so refetch the visible rows.
+          _reset(delta.orderingToken, delta.total);
+          return;
+        }
+        // Same order, so nothing was added or removed and the loaded rows stay in place.
+        final updated = {for (final book in delta.updated) book.key: book};
+        _bookData.updateAll((_, book) => updated[book.key] ?? book);
+      });
+    });
+  }
+
+  @override
+  void dispose() {
+    _pageSubscription?.cancel();
+    _deltaSubscription?.cancel();
+    super.dispose();
+  }
+
+  void _reset(String orderingToken, int total) {
+    _orderingToken = orderingToken;
+    _total = total;
+    _bookData.clear();
+    _requestedPages.clear();
+  }
+
+  void _requestPageOf(int index) {
+    final page = index ~/ _pageSize;
+    if (_requestedPages.add(page)) {
+      GetLibraryPage(
+        offset: page * _pageSize,
+        limit: _pageSize,
+        query: null,
+        filter: null,
+      ).sendSignalToRust();
+    }
+  }
 
   @override
   Widget build(BuildContext context) {
@@ -33,7 +102,11 @@ class _LibraryState extends State<Library> {
             state = LibraryState.latestRustSignal!.message;
           }
           if (state is LibraryStateShow) {
-            _bookData = state.value.data;
+            if (state.value.orderingToken != _orderingToken ||
+                state.value.version != _version) {
+              _version = state.value.version;
+              _reset(state.value.orderingToken, state.value.total);
+            }
           } else if (state is LibraryStateRefreshingCache) {
             return Center(
               child: Column(
@@ -70,22 +143,42 @@ class _LibraryState extends State<Library> {
                 ],
               ),
             );
-          } else if (state is LibraryStateShow) {
-            _bookData = state.value.data;
           }
-          return GridView.builder(
+          final offline =
+              state is LibraryStateShow && state.value.offline;
+          final grid = GridView.builder(
             gridDelegate: SliverGridDelegateWithMaxCrossAxisExtent(
               maxCrossAxisExtent: 250,
               childAspectRatio: 0.62,
               mainAxisSpacing: 6,
               crossAxisSpacing: 8,
             ),
-            itemCount: _bookData.length,
+            itemCount: _total,
             cacheExtent: 2000,
             itemBuilder: (context, index) {
-              return LibraryGridTile(bookData: _bookData[index]);
+              final bookData = _bookData[index];
+              if (bookData == null) {
+                _requestPageOf(index);
+                return const Card(elevation: 2);
+              }
+              return LibraryGridTile(bookData: bookData);
             },
           );
+          if (!offline) {
+            return grid;
+          }
+          return Column(
+            children: [
+              ListTile(
+                leading: const Icon(Icons.cloud_off),
+                title: const Text("Library is offline"),
+                subtitle: const Text(
+                  "Showing the last known books until the folder is available again.",
+                ),
+              ),
+              Expanded(child: grid),
+            ],
+          );
         },
       ),
     );
@@ -121,6 +214,8 @@ class LibraryGridTile extends StatelessWidget {
                         fit: BoxFit.cover,
                         cacheHeight: 400,
                         filterQuality: FilterQuality.low,
+                        errorBuilder: (context, error, stackTrace) =>
+                            const Center(child: Icon(Icons.book)),
                       ),
               ),
               Spacer(),
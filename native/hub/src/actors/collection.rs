@@ -0,0 +1,348 @@
+use crate::{
+    signals::collection_signals::{
+        AddToCollection, CollectionData, Collections, CreateCollection, DeleteCollection,
+        EditCollection, GetCollectionPage, GetCollections, RemoveFromCollection, ReorderCollection,
+        RuleData, RuleFieldSetting, RuleOpSetting, SmartRulesData,
+    },
+    utility::{
+        collection::{CollectionContent, Rule, RuleField, RuleOp, SmartRules},
+        state::State,
+    },
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use messages::{
+    actor::Actor,
+    prelude::{Address, Context, Notifiable},
+};
+use rinf::{DartSignal, RustSignal};
+use tokio::{spawn, task::JoinSet};
+
+/// Manual and smart collections of the open library.
+pub struct CollectionActor {
+    _tasks: JoinSet<()>,
+}
+
+impl Actor for CollectionActor {}
+
+impl CollectionActor {
+    pub fn create_and_init(ctx: Context<CollectionActor>) -> Address<Self> {
+        let self_addr = ctx.address();
+        let mut owned_tasks = JoinSet::new();
+        owned_tasks.spawn(Self::listen_create_collection(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_edit_collection(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_delete_collection(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_add_to_collection(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_remove_from_collection(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_reorder_collection(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_get_collections(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_get_collection_page(self_addr.clone()));
+
+        spawn(ctx.run(Self {
+            _tasks: owned_tasks,
+        }));
+        return self_addr;
+    }
+
+    async fn listen_create_collection(mut self_addr: Address<Self>) {
+        let recv = CreateCollection::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_edit_collection(mut self_addr: Address<Self>) {
+        let recv = EditCollection::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_delete_collection(mut self_addr: Address<Self>) {
+        let recv = DeleteCollection::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_add_to_collection(mut self_addr: Address<Self>) {
+        let recv = AddToCollection::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_remove_from_collection(mut self_addr: Address<Self>) {
+        let recv = RemoveFromCollection::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_reorder_collection(mut self_addr: Address<Self>) {
+        let recv = ReorderCollection::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_get_collections(mut self_addr: Address<Self>) {
+        let recv = GetCollections::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_get_collection_page(mut self_addr: Address<Self>) {
+        let recv = GetCollectionPage::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn create_collection(msg: CreateCollection) -> anyhow::Result<()> {
+        let content = match msg.rules {
+            Some(rules) => CollectionContent::Smart(Self::smart_rules(rules)),
+            None => CollectionContent::Manual { books: Vec::new() },
+        };
+        State::get()?
+            .write()
+            .await
+            .create_collection(&msg.name, content)?;
+        return Ok(());
+    }
+
+    async fn edit_collection(msg: EditCollection) -> anyhow::Result<()> {
+        let name = msg.name.trim().to_string();
+        if name.is_empty() {
+            return Err(anyhow!("A collection needs a name."));
+        }
+        let rules = msg.rules.map(Self::smart_rules);
+        if let Some(rules) = &rules {
+            rules.validate()?;
+        }
+        State::get()?
+            .write()
+            .await
+            .edit_collection(&msg.collection_id, |collection| {
+                match (&mut collection.content, rules) {
+                    (CollectionContent::Smart(current), Some(rules)) => *current = rules,
+                    (CollectionContent::Manual { .. }, Some(_)) => {
+                        return Err(anyhow!("{} is not a smart collection", collection.name));
+                    }
+                    (_, None) => {}
+                }
+                collection.name = name;
+                return anyhow::Ok(());
+            })?;
+        return Ok(());
+    }
+
+    async fn delete_collection(msg: DeleteCollection) -> anyhow::Result<()> {
+        State::get()?
+            .write()
+            .await
+            .delete_collection(&msg.collection_id)?;
+        return Ok(());
+    }
+
+    async fn add_to_collection(msg: AddToCollection) -> anyhow::Result<()> {
+        State::get()?
+            .write()
+            .await
+            .edit_collection(&msg.collection_id, |collection| {
+                collection.add(msg.book_keys)
+            })?;
+        return Ok(());
+    }
+
+    async fn remove_from_collection(msg: RemoveFromCollection) -> anyhow::Result<()> {
+        State::get()?
+            .write()
+            .await
+            .edit_collection(&msg.collection_id, |collection| {
+                collection.remove(&msg.book_keys)
+            })?;
+        return Ok(());
+    }
+
+    async fn reorder_collection(msg: ReorderCollection) -> anyhow::Result<()> {
+        State::get()?
+            .write()
+            .await
+            .edit_collection(&msg.collection_id, |collection| {
+                collection.set_books(msg.book_keys)
+            })?;
+        return Ok(());
+    }
+
+    async fn get_collection_page(msg: GetCollectionPage) -> anyhow::Result<()> {
+        let page = State::get()?.read().await.get_collection_page(
+            &msg.collection_id,
+            msg.offset as usize,
+            msg.limit as usize,
+        )?;
+        page.send_signal_to_dart();
+        return Ok(());
+    }
+
+    /// Sends the collections of the open library, with the error of the change that was
+    /// just made, if any.
+    async fn send_collections(error: Option<String>) -> anyhow::Result<()> {
+        let collections = State::get()?.read().await.get_collections()?;
+        Collections {
+            collections: collections
+                .into_iter()
+                .map(|(collection, total)| CollectionData {
+                    id: collection.id,
+                    name: collection.name,
+                    rules: match collection.content {
+                        CollectionContent::Smart(rules) => Some(Self::smart_rules_data(rules)),
+                        CollectionContent::Manual { .. } => None,
+                    },
+                    total: total as u32,
+                })
+                .collect(),
+            error,
+        }
+        .send_signal_to_dart();
+        return Ok(());
+    }
+
+    /// Answers a change to the collections with the resulting `Collections`.
+    async fn answer(result: anyhow::Result<()>, action: &str) {
+        let error = match result {
+            Ok(()) => None,
+            Err(e) => {
+                println!("Failed to {}: {:#}", action, e);
+                Some(format!("{:#}", e))
+            }
+        };
+        if let Err(e) = Self::send_collections(error).await {
+            println!("Failed to send collections: {:#}", e);
+        }
+    }
+
+    fn smart_rules(data: SmartRulesData) -> SmartRules {
+        return SmartRules {
+            rules: data
+                .rules
+                .into_iter()
+                .map(|rule| Rule {
+                    field: match rule.field {
+                        RuleFieldSetting::Title => RuleField::Title,
+                        RuleFieldSetting::Author => RuleField::Author,
+                        RuleFieldSetting::Series => RuleField::Series,
+                        RuleFieldSetting::SeriesIndex => RuleField::SeriesIndex,
+                        RuleFieldSetting::Path => RuleField::Path,
+                        RuleFieldSetting::Tag => RuleField::Tag,
+                        RuleFieldSetting::Status => RuleField::Status,
+                        RuleFieldSetting::Rating => RuleField::Rating,
+                    },
+                    op: match rule.op {
+                        RuleOpSetting::Is => RuleOp::Is,
+                        RuleOpSetting::IsNot => RuleOp::IsNot,
+                        RuleOpSetting::Contains => RuleOp::Contains,
+                        RuleOpSetting::NotContains => RuleOp::NotContains,
+                        RuleOpSetting::AtLeast => RuleOp::AtLeast,
+                        RuleOpSetting::AtMost => RuleOp::AtMost,
+                    },
+                    value: rule.value,
+                })
+                .collect(),
+            match_any: data.match_any,
+        };
+    }
+
+    fn smart_rules_data(rules: SmartRules) -> SmartRulesData {
+        return SmartRulesData {
+            rules: rules
+                .rules
+                .into_iter()
+                .map(|rule| RuleData {
+                    field: match rule.field {
+                        RuleField::Title => RuleFieldSetting::Title,
+                        RuleField::Author => RuleFieldSetting::Author,
+                        RuleField::Series => RuleFieldSetting::Series,
+                        RuleField::SeriesIndex => RuleFieldSetting::SeriesIndex,
+                        RuleField::Path => RuleFieldSetting::Path,
+                        RuleField::Tag => RuleFieldSetting::Tag,
+                        RuleField::Status => RuleFieldSetting::Status,
+                        RuleField::Rating => RuleFieldSetting::Rating,
+                    },
+                    op: match rule.op {
+                        RuleOp::Is => RuleOpSetting::Is,
+                        RuleOp::IsNot => RuleOpSetting::IsNot,
+                        RuleOp::Contains => RuleOpSetting::Contains,
+                        RuleOp::NotContains => RuleOpSetting::NotContains,
+                        RuleOp::AtLeast => RuleOpSetting::AtLeast,
+                        RuleOp::AtMost => RuleOpSetting::AtMost,
+                    },
+                    value: rule.value,
+                })
+                .collect(),
+            match_any: rules.match_any,
+        };
+    }
+}
+
+#[async_trait]
+impl Notifiable<CreateCollection> for CollectionActor {
+    async fn notify(&mut self, msg: CreateCollection, _: &Context<Self>) {
+        Self::answer(Self::create_collection(msg).await, "create collection").await;
+    }
+}
+
+#[async_trait]
+impl Notifiable<EditCollection> for CollectionActor {
+    async fn notify(&mut self, msg: EditCollection, _: &Context<Self>) {
+        Self::answer(Self::edit_collection(msg).await, "edit collection").await;
+    }
+}
+
+#[async_trait]
+impl Notifiable<DeleteCollection> for CollectionActor {
+    async fn notify(&mut self, msg: DeleteCollection, _: &Context<Self>) {
+        Self::answer(Self::delete_collection(msg).await, "delete collection").await;
+    }
+}
+
+#[async_trait]
+impl Notifiable<AddToCollection> for CollectionActor {
+    async fn notify(&mut self, msg: AddToCollection, _: &Context<Self>) {
+        Self::answer(Self::add_to_collection(msg).await, "add to collection").await;
+    }
+}
+
+#[async_trait]
+impl Notifiable<RemoveFromCollection> for CollectionActor {
+    async fn notify(&mut self, msg: RemoveFromCollection, _: &Context<Self>) {
+        let result = Self::remove_from_collection(msg).await;
+        Self::answer(result, "remove from collection").await;
+    }
+}
+
+#[async_trait]
+impl Notifiable<ReorderCollection> for CollectionActor {
+    async fn notify(&mut self, msg: ReorderCollection, _: &Context<Self>) {
+        Self::answer(Self::reorder_collection(msg).await, "reorder collection").await;
+    }
+}
+
+#[async_trait]
+impl Notifiable<GetCollections> for CollectionActor {
+    async fn notify(&mut self, _: GetCollections, _: &Context<Self>) {
+        if let Err(e) = Self::send_collections(None).await {
+            println!("Failed to send collections: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<GetCollectionPage> for CollectionActor {
+    async fn notify(&mut self, msg: GetCollectionPage, _: &Context<Self>) {
+        if let Err(e) = Self::get_collection_page(msg).await {
+            println!("Failed to get collection page: {:#}", e);
+        }
+    }
+}
@@ -1,9 +1,35 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    time::Duration,
+};
 
 use crate::{
-    actors::ADDRESSES,
-    signals::library_signals::{AddToLibrary, DisplayLibrary, LibraryState, UpdateCache},
-    utility::state::STATE,
+    actors::{ADDRESSES, reading::ReadingActor},
+    signals::library_signals::{
+        AddToLibrary, BackupContents, BackupCreated, BackupLibrary, BackupRestored,
+        BookMetadataState, BookOpened, CacheLocationSetting, CatalogExportFormat, CatalogExported,
+        CreateBackup, EditBookMetadata, EpubMetadataData, ExportCatalog, ExportOverlaySidecars,
+        GetBookMetadata, GetLibraryPage, GetMetadataOverlay, IdentifierData, ImportCalibreLibrary,
+        LibraryRelocated, LibraryState, MetadataOverlayData, MetadataOverlayState, OpenBook,
+        OverlaySidecarsExported, ReadBackup, RelocateLibrary, RestoreBackup, RestoreModeSetting,
+        ResyncLibrary, SetCacheLocation, SetIndexConcurrency, SetMetadataOverlay, SetScanRules,
+        UpdateCache,
+    },
+    signals::reading_signals::ReadingDataSynced,
+    utility::{
+        backup::{self, RestoreMode},
+        epub_edit::MetadataEdit,
+        export::ExportFormat,
+        library::CacheLocation,
+        metadata::BookMetadata,
+        opf::OpfMetadata,
+        overlay::MetadataOverlay,
+        scan::ScanRules,
+        state::State,
+        user_data::BookFilter,
+    },
 };
 use async_trait::async_trait;
 use messages::{
@@ -11,7 +37,10 @@ use messages::{
     prelude::{Address, Context, Notifiable},
 };
 use rinf::{DartSignal, RustSignal};
-use tokio::{spawn, task::JoinSet};
+use tokio::{spawn, task::JoinSet, time::sleep};
+
+/// How often an offline library is checked for having come back.
+const OFFLINE_POLL_INTERVAL: Duration = Duration::from_secs(30);
 
 pub struct LibraryActor {
     _tasks: JoinSet<()>,
@@ -20,23 +49,43 @@ pub struct LibraryActor {
 impl Actor for LibraryActor {}
 
 impl LibraryActor {
-    pub async fn create_and_init(ctx: Context<LibraryActor>) -> Address<Self> {
+    pub async fn create_and_init(ctx: Context<LibraryActor>) -> anyhow::Result<Address<Self>> {
         let mut self_addr = ctx.address();
         let mut owned_tasks = JoinSet::new();
         owned_tasks.spawn(Self::listen_add_to_library(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_import_calibre_library(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_index_concurrency(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_cache_location(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_scan_rules(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_relocate_library(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_get_library_page(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_resync_library(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_export_catalog(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_open_book(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_get_book_metadata(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_edit_book_metadata(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_get_metadata_overlay(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_metadata_overlay(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_export_overlay_sidecars(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_create_backup(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_read_backup(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_restore_backup(self_addr.clone()));
+        owned_tasks.spawn(Self::watch_offline_library(self_addr.clone()));
 
-        spawn(ctx.run(Self { _tasks: owned_tasks }));
+        spawn(ctx.run(Self {
+            _tasks: owned_tasks,
+        }));
 
         let has_lib = {
-            let state = STATE.get().unwrap().read().await;
+            let state = State::get()?.read().await;
             state.has_lib()
         };
         if has_lib {
-            self_addr.notify(UpdateCache::Refresh).await.unwrap();
+            self_addr.notify(UpdateCache::Refresh).await?;
         } else {
             LibraryState::NoLibraryAvailable.send_signal_to_dart();
         }
-        return self_addr;
+        return Ok(self_addr);
     }
 
     async fn listen_add_to_library(mut self_addr: Address<Self>) {
@@ -45,53 +94,776 @@ impl LibraryActor {
             let _ = self_addr.notify(signal.message).await;
         }
     }
-}
 
-#[async_trait]
-impl Notifiable<AddToLibrary> for LibraryActor {
-    async fn notify(&mut self, msg: AddToLibrary, _: &Context<Self>) {
+    async fn listen_import_calibre_library(mut self_addr: Address<Self>) {
+        let recv = ImportCalibreLibrary::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_index_concurrency(mut self_addr: Address<Self>) {
+        let recv = SetIndexConcurrency::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_cache_location(mut self_addr: Address<Self>) {
+        let recv = SetCacheLocation::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_scan_rules(mut self_addr: Address<Self>) {
+        let recv = SetScanRules::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_relocate_library(mut self_addr: Address<Self>) {
+        let recv = RelocateLibrary::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_get_library_page(mut self_addr: Address<Self>) {
+        let recv = GetLibraryPage::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_resync_library(mut self_addr: Address<Self>) {
+        let recv = ResyncLibrary::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_open_book(mut self_addr: Address<Self>) {
+        let recv = OpenBook::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_get_book_metadata(mut self_addr: Address<Self>) {
+        let recv = GetBookMetadata::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_edit_book_metadata(mut self_addr: Address<Self>) {
+        let recv = EditBookMetadata::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_get_metadata_overlay(mut self_addr: Address<Self>) {
+        let recv = GetMetadataOverlay::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_metadata_overlay(mut self_addr: Address<Self>) {
+        let recv = SetMetadataOverlay::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_export_overlay_sidecars(mut self_addr: Address<Self>) {
+        let recv = ExportOverlaySidecars::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_export_catalog(mut self_addr: Address<Self>) {
+        let recv = ExportCatalog::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_create_backup(mut self_addr: Address<Self>) {
+        let recv = CreateBackup::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_read_backup(mut self_addr: Address<Self>) {
+        let recv = ReadBackup::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_restore_backup(mut self_addr: Address<Self>) {
+        let recv = RestoreBackup::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    /// Refreshes the open library once its root is reachable again after going offline.
+    async fn watch_offline_library(mut self_addr: Address<Self>) {
+        loop {
+            sleep(OFFLINE_POLL_INTERVAL).await;
+            let reappeared = match State::get() {
+                Ok(state) => state.read().await.library_reappeared(),
+                Err(_) => false,
+            };
+            if reappeared {
+                let _ = self_addr.notify(UpdateCache::Refresh).await;
+            }
+        }
+    }
+
+    async fn add_to_library(msg: AddToLibrary) -> anyhow::Result<()> {
         let lib_path = PathBuf::from(msg.path);
         {
-            let mut state = STATE.get().unwrap().write().await;
-            // TODO: send this to the UI as a pop-up of sorts
-            state.import_lib(lib_path).unwrap();
+            let mut state = State::get()?.write().await;
+            state.import_lib(lib_path)?;
         }
         ADDRESSES
             .get()
-            .unwrap()
+            .ok_or_else(|| anyhow::anyhow!("Actors are not initialized."))?
             .get_library()
             .notify(UpdateCache::Rebuild)
+            .await?;
+        return Ok(());
+    }
+
+    async fn import_calibre_library(msg: ImportCalibreLibrary) -> anyhow::Result<()> {
+        let lib_path = PathBuf::from(msg.path);
+        {
+            let mut state = State::get()?.write().await;
+            state.import_calibre(lib_path)?;
+        }
+        // Picks up the books Calibre does not know about.
+        ADDRESSES
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("Actors are not initialized."))?
+            .get_library()
+            .notify(UpdateCache::Refresh)
+            .await?;
+        return Ok(());
+    }
+
+    async fn set_index_concurrency(msg: SetIndexConcurrency) -> anyhow::Result<()> {
+        let limit = msg.limit.map(|limit| limit as usize);
+        State::get()?.write().await.set_index_concurrency(limit)?;
+        return Ok(());
+    }
+
+    async fn set_cache_location(msg: SetCacheLocation) -> anyhow::Result<()> {
+        let location = match msg.location {
+            CacheLocationSetting::InLibrary => CacheLocation::InLibrary,
+            CacheLocationSetting::AppSupport => CacheLocation::AppSupport,
+            CacheLocationSetting::Custom(path) => CacheLocation::Custom(PathBuf::from(path)),
+        };
+        State::get()?.write().await.set_cache_location(location)?;
+        return Ok(());
+    }
+
+    async fn set_scan_rules(msg: SetScanRules) -> anyhow::Result<()> {
+        let rules = ScanRules {
+            include: msg.include,
+            exclude: msg.exclude,
+            max_depth: msg.max_depth.map(|depth| depth as usize),
+            follow_links: msg.follow_links,
+            include_hidden: msg.include_hidden,
+            skip_archives: msg.skip_archives,
+        };
+        State::get()?.write().await.set_scan_rules(rules)?;
+        ADDRESSES
+            .get()
+            .ok_or_else(|| anyhow::anyhow!("Actors are not initialized."))?
+            .get_library()
+            .notify(UpdateCache::Refresh)
+            .await?;
+        return Ok(());
+    }
+
+    async fn relocate_library(msg: RelocateLibrary) -> anyhow::Result<()> {
+        let result = State::get()?
+            .write()
             .await
-            .unwrap();
+            .relocate_lib(PathBuf::from(&msg.old), PathBuf::from(&msg.new));
+        LibraryRelocated {
+            old: msg.old,
+            new: msg.new,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        if result? {
+            ADDRESSES
+                .get()
+                .ok_or_else(|| anyhow::anyhow!("Actors are not initialized."))?
+                .get_library()
+                .notify(UpdateCache::Refresh)
+                .await?;
+        }
+        return Ok(());
     }
-}
 
-#[async_trait]
-impl Notifiable<UpdateCache> for LibraryActor {
-    async fn notify(&mut self, msg: UpdateCache, _: &Context<Self>) {
-        match msg {
+    async fn get_library_page(msg: GetLibraryPage) -> anyhow::Result<()> {
+        let filter = msg.filter.map(|filter| BookFilter {
+            status: filter.status.map(ReadingActor::reading_status),
+            min_rating: filter.min_rating,
+            tag: filter.tag,
+        });
+        let page = State::get()?.read().await.get_library_page(
+            msg.offset as usize,
+            msg.limit as usize,
+            msg.query.as_deref(),
+            filter.as_ref(),
+        );
+        page.send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn export_catalog(msg: ExportCatalog) -> anyhow::Result<()> {
+        let format = match msg.format {
+            CatalogExportFormat::Csv => ExportFormat::Csv,
+            CatalogExportFormat::Json => ExportFormat::Json,
+            CatalogExportFormat::Html => ExportFormat::Html,
+        };
+        let result = State::get()?
+            .read()
+            .await
+            .export_catalog(format, &PathBuf::from(&msg.path));
+        CatalogExported {
+            path: msg.path,
+            books: *result.as_ref().unwrap_or(&0) as u32,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        result?;
+        return Ok(());
+    }
+
+    async fn open_book(msg: OpenBook) -> anyhow::Result<()> {
+        let result = State::get()?.read().await.open_book(&msg.book_key);
+        BookOpened {
+            book_key: msg.book_key,
+            path: result
+                .as_ref()
+                .ok()
+                .map(|path| path.to_string_lossy().into_owned()),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        result?;
+        return Ok(());
+    }
+
+    async fn get_book_metadata(msg: GetBookMetadata) -> anyhow::Result<()> {
+        let result = State::get()?.read().await.read_book_metadata(&msg.book_key);
+        return Self::send_book_metadata(msg.book_key, result);
+    }
+
+    async fn edit_book_metadata(msg: EditBookMetadata) -> anyhow::Result<()> {
+        let book_key = msg.book_key.clone();
+        let result = {
+            let mut state = State::get()?.write().await;
+            let cover = msg.cover_path.map(fs::read).transpose();
+            let data = msg.metadata;
+            let non_empty =
+                |text: Option<String>| text.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+            let trimmed = |texts: Vec<String>| -> Vec<String> {
+                texts
+                    .into_iter()
+                    .filter_map(|t| non_empty(Some(t)))
+                    .collect()
+            };
+            let edit = MetadataEdit {
+                title: data.title,
+                metadata: BookMetadata {
+                    authors: trimmed(data.authors),
+                    author_sort: non_empty(data.author_sort),
+                    series: non_empty(data.series),
+                    series_index: data.series_index,
+                    tags: trimmed(data.subjects),
+                    rating: None,
+                    identifiers: data
+                        .identifiers
+                        .into_iter()
+                        .map(|id| (id.scheme.trim().to_ascii_lowercase(), id.value))
+                        .filter(|(scheme, _)| !scheme.is_empty())
+                        .collect(),
+                },
+                description: non_empty(data.description),
+                cover: None,
+            };
+            cover
+                .map_err(anyhow::Error::from)
+                .and_then(|cover| {
+                    state.edit_book_metadata(&book_key, MetadataEdit { cover, ..edit })
+                })
+                .and_then(|changes| {
+                    if let Some(delta) = state.get_library_delta(changes) {
+                        delta.send_signal_to_dart();
+                    }
+                    state.read_book_metadata(&book_key)
+                })
+        };
+        return Self::send_book_metadata(book_key, result);
+    }
+
+    /// Answers with the metadata of a book, or the error that kept it from being read or
+    /// written.
+    fn send_book_metadata(
+        book_key: String,
+        result: anyhow::Result<OpfMetadata>,
+    ) -> anyhow::Result<()> {
+        let (metadata, error) = match result {
+            Ok(opf) => (Some(opf), None),
+            Err(e) => (None, Some(e)),
+        };
+        BookMetadataState {
+            book_key,
+            metadata: metadata.map(|opf| EpubMetadataData {
+                title: opf.title.unwrap_or_default(),
+                authors: opf.metadata.authors,
+                author_sort: opf.metadata.author_sort,
+                series: opf.metadata.series,
+                series_index: opf.metadata.series_index,
+                identifiers: opf
+                    .metadata
+                    .identifiers
+                    .into_iter()
+                    .map(|(scheme, value)| IdentifierData { scheme, value })
+                    .collect(),
+                subjects: opf.metadata.tags,
+                description: opf.description,
+            }),
+            error: error.as_ref().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    async fn get_metadata_overlay(msg: GetMetadataOverlay) -> anyhow::Result<()> {
+        let result = State::get()?
+            .read()
+            .await
+            .get_metadata_overlay(&msg.book_key);
+        return Self::send_metadata_overlay(msg.book_key, result);
+    }
+
+    async fn set_metadata_overlay(msg: SetMetadataOverlay) -> anyhow::Result<()> {
+        let data = msg.overlay;
+        let trimmed = |text: Option<String>| text.map(|t| t.trim().to_string());
+        let list = |texts: Option<Vec<String>>| {
+            texts.map(|texts| {
+                texts
+                    .iter()
+                    .map(|t| t.trim().to_string())
+                    .filter(|t| !t.is_empty())
+                    .collect()
+            })
+        };
+        let overlay = MetadataOverlay {
+            title: trimmed(data.title).filter(|t| !t.is_empty()),
+            authors: list(data.authors),
+            author_sort: trimmed(data.author_sort),
+            series: trimmed(data.series),
+            series_index: data.series_index,
+            tags: list(data.tags),
+            identifiers: data.identifiers.map(|ids| {
+                ids.into_iter()
+                    .map(|id| (id.scheme.trim().to_ascii_lowercase(), id.value))
+                    .filter(|(scheme, _)| !scheme.is_empty())
+                    .collect()
+            }),
+        };
+        let result = {
+            let mut state = State::get()?.write().await;
+            state
+                .set_metadata_overlay(&msg.book_key, overlay)
+                .and_then(|changes| {
+                    if let Some(delta) = state.get_library_delta(changes) {
+                        delta.send_signal_to_dart();
+                    }
+                    state.get_metadata_overlay(&msg.book_key)
+                })
+        };
+        return Self::send_metadata_overlay(msg.book_key, result);
+    }
+
+    /// Answers with the overlay of a book, or the error that kept it from being read or set.
+    fn send_metadata_overlay(
+        book_key: String,
+        result: anyhow::Result<(MetadataOverlay, String, BookMetadata)>,
+    ) -> anyhow::Result<()> {
+        let (found, error) = match result {
+            Ok(found) => (Some(found), None),
+            Err(e) => (None, Some(e)),
+        };
+        let identifiers = |ids: BTreeMap<String, String>| -> Vec<IdentifierData> {
+            ids.into_iter()
+                .map(|(scheme, value)| IdentifierData { scheme, value })
+                .collect()
+        };
+        let (overlay, extracted) = match found {
+            Some((overlay, title, metadata)) => (
+                Some(MetadataOverlayData {
+                    title: overlay.title,
+                    authors: overlay.authors,
+                    author_sort: overlay.author_sort,
+                    series: overlay.series,
+                    series_index: overlay.series_index,
+                    tags: overlay.tags,
+                    identifiers: overlay.identifiers.map(identifiers),
+                }),
+                Some(EpubMetadataData {
+                    title,
+                    authors: metadata.authors,
+                    author_sort: metadata.author_sort,
+                    series: metadata.series,
+                    series_index: metadata.series_index,
+                    identifiers: identifiers(metadata.identifiers),
+                    subjects: metadata.tags,
+                    description: None,
+                }),
+            ),
+            None => (None, None),
+        };
+        MetadataOverlayState {
+            book_key,
+            overlay,
+            extracted,
+            error: error.as_ref().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    async fn export_overlay_sidecars(msg: ExportOverlaySidecars) -> anyhow::Result<()> {
+        let result = State::get()?
+            .read()
+            .await
+            .export_overlay_sidecars(&PathBuf::from(&msg.path));
+        OverlaySidecarsExported {
+            path: msg.path,
+            books: *result.as_ref().unwrap_or(&0) as u32,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        result?;
+        return Ok(());
+    }
+
+    async fn create_backup(msg: CreateBackup) -> anyhow::Result<()> {
+        let result = State::get()?
+            .read()
+            .await
+            .create_backup(&PathBuf::from(&msg.path), msg.include_cache);
+        BackupCreated {
+            path: msg.path,
+            libraries: result.as_ref().map_or(0, |m| m.libraries.len() as u32),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        result?;
+        return Ok(());
+    }
+
+    async fn read_backup(msg: ReadBackup) -> anyhow::Result<()> {
+        let result = backup::read_manifest(&PathBuf::from(&msg.path));
+        let contents = match &result {
+            Ok(manifest) => BackupContents {
+                path: msg.path,
+                version: manifest.version,
+                created: manifest.created.clone(),
+                libraries: manifest
+                    .libraries
+                    .iter()
+                    .map(|lib| BackupLibrary {
+                        id: lib.id.clone(),
+                        root: lib.root.to_string_lossy().into_owned(),
+                        exists: lib.root.is_dir(),
+                        has_cache: lib.has_cache,
+                    })
+                    .collect(),
+                error: None,
+            },
+            Err(e) => BackupContents {
+                path: msg.path,
+                version: 0,
+                created: String::new(),
+                libraries: vec![],
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        contents.send_signal_to_dart();
+        result?;
+        return Ok(());
+    }
+
+    async fn restore_backup(msg: RestoreBackup) -> anyhow::Result<()> {
+        let remaps: HashMap<PathBuf, PathBuf> = msg
+            .remaps
+            .into_iter()
+            .map(|remap| (PathBuf::from(remap.from), PathBuf::from(remap.to)))
+            .collect();
+        let mode = match msg.mode {
+            RestoreModeSetting::Merge => RestoreMode::Merge,
+            RestoreModeSetting::Replace => RestoreMode::Replace,
+        };
+        let (result, has_lib) = {
+            let mut state = State::get()?.write().await;
+            let result = state.restore_backup(&PathBuf::from(&msg.path), &remaps, mode);
+            (result, state.has_lib())
+        };
+        BackupRestored {
+            path: msg.path,
+            libraries: *result.as_ref().unwrap_or(&0) as u32,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        result?;
+        if has_lib {
+            ADDRESSES
+                .get()
+                .ok_or_else(|| anyhow::anyhow!("Actors are not initialized."))?
+                .get_library()
+                .notify(UpdateCache::Refresh)
+                .await?;
+        } else {
+            LibraryState::NoLibraryAvailable.send_signal_to_dart();
+        }
+        return Ok(());
+    }
+
+    async fn update_cache(msg: UpdateCache) -> anyhow::Result<()> {
+        // Keys of the books whose KOReader sidecars were imported while scanning.
+        let imported = match msg {
             UpdateCache::Refresh => {
                 LibraryState::RefreshingCache.send_signal_to_dart();
+                let mut state = State::get()?.write().await;
+                let (changes, synced) = state.refresh_cache(false)?;
+                if !changes.is_empty()
+                    && let Some(delta) = state.get_library_delta(changes)
                 {
-                    let mut state = STATE.get().unwrap().write().await;
-                    state.refresh_cache(false).unwrap();
+                    delta.send_signal_to_dart();
                 }
+                synced
             }
             UpdateCache::Rebuild => {
                 LibraryState::RebuildingCache.send_signal_to_dart();
-                {
-                    let mut state = STATE.get().unwrap().write().await;
-                    state.refresh_cache(true).unwrap();
-                }
+                let mut state = State::get()?.write().await;
+                state.refresh_cache(true)?.1
             }
         };
 
-        let books;
+        // Picks up progress and annotations other devices synced into the library folder.
+        let mut book_keys = imported;
+        match State::get()?.write().await.merge_sync() {
+            Ok(merged) => book_keys.extend(merged),
+            Err(e) => println!("Failed to sync reading data: {:#}", e),
+        }
+        book_keys.sort();
+        book_keys.dedup();
+        if !book_keys.is_empty() {
+            ReadingDataSynced { book_keys }.send_signal_to_dart();
+        }
 
-        {
-            books = STATE.get().unwrap().read().await.get_book_data();
+        let summary = State::get()?.read().await.get_library_summary();
+
+        LibraryState::Show(summary).send_signal_to_dart();
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl Notifiable<AddToLibrary> for LibraryActor {
+    async fn notify(&mut self, msg: AddToLibrary, _: &Context<Self>) {
+        // TODO: send this to the UI as a pop-up of sorts
+        if let Err(e) = Self::add_to_library(msg).await {
+            println!("Failed to add library: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<UpdateCache> for LibraryActor {
+    async fn notify(&mut self, msg: UpdateCache, _: &Context<Self>) {
+        if let Err(e) = Self::update_cache(msg).await {
+            println!("Failed to update cache: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<ImportCalibreLibrary> for LibraryActor {
+    async fn notify(&mut self, msg: ImportCalibreLibrary, _: &Context<Self>) {
+        if let Err(e) = Self::import_calibre_library(msg).await {
+            println!("Failed to import Calibre library: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetIndexConcurrency> for LibraryActor {
+    async fn notify(&mut self, msg: SetIndexConcurrency, _: &Context<Self>) {
+        if let Err(e) = Self::set_index_concurrency(msg).await {
+            println!("Failed to set index concurrency: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetCacheLocation> for LibraryActor {
+    async fn notify(&mut self, msg: SetCacheLocation, _: &Context<Self>) {
+        if let Err(e) = Self::set_cache_location(msg).await {
+            println!("Failed to move the library cache: {:#}", e);
         }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetScanRules> for LibraryActor {
+    async fn notify(&mut self, msg: SetScanRules, _: &Context<Self>) {
+        if let Err(e) = Self::set_scan_rules(msg).await {
+            println!("Failed to set scan rules: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<RelocateLibrary> for LibraryActor {
+    async fn notify(&mut self, msg: RelocateLibrary, _: &Context<Self>) {
+        if let Err(e) = Self::relocate_library(msg).await {
+            println!("Failed to relocate library: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<GetLibraryPage> for LibraryActor {
+    async fn notify(&mut self, msg: GetLibraryPage, _: &Context<Self>) {
+        if let Err(e) = Self::get_library_page(msg).await {
+            println!("Failed to get library page: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<ExportCatalog> for LibraryActor {
+    async fn notify(&mut self, msg: ExportCatalog, _: &Context<Self>) {
+        if let Err(e) = Self::export_catalog(msg).await {
+            println!("Failed to export catalog: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<OpenBook> for LibraryActor {
+    async fn notify(&mut self, msg: OpenBook, _: &Context<Self>) {
+        if let Err(e) = Self::open_book(msg).await {
+            println!("Failed to open book: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<GetBookMetadata> for LibraryActor {
+    async fn notify(&mut self, msg: GetBookMetadata, _: &Context<Self>) {
+        if let Err(e) = Self::get_book_metadata(msg).await {
+            println!("Failed to read book metadata: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<EditBookMetadata> for LibraryActor {
+    async fn notify(&mut self, msg: EditBookMetadata, _: &Context<Self>) {
+        if let Err(e) = Self::edit_book_metadata(msg).await {
+            println!("Failed to edit book metadata: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<GetMetadataOverlay> for LibraryActor {
+    async fn notify(&mut self, msg: GetMetadataOverlay, _: &Context<Self>) {
+        if let Err(e) = Self::get_metadata_overlay(msg).await {
+            println!("Failed to get metadata overlay: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetMetadataOverlay> for LibraryActor {
+    async fn notify(&mut self, msg: SetMetadataOverlay, _: &Context<Self>) {
+        if let Err(e) = Self::set_metadata_overlay(msg).await {
+            println!("Failed to set metadata overlay: {:#}", e);
+        }
+    }
+}
 
-        LibraryState::Show(DisplayLibrary { data: books }).send_signal_to_dart();
+#[async_trait]
+impl Notifiable<ExportOverlaySidecars> for LibraryActor {
+    async fn notify(&mut self, msg: ExportOverlaySidecars, _: &Context<Self>) {
+        if let Err(e) = Self::export_overlay_sidecars(msg).await {
+            println!("Failed to export overlay sidecars: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<CreateBackup> for LibraryActor {
+    async fn notify(&mut self, msg: CreateBackup, _: &Context<Self>) {
+        if let Err(e) = Self::create_backup(msg).await {
+            println!("Failed to create backup: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<ReadBackup> for LibraryActor {
+    async fn notify(&mut self, msg: ReadBackup, _: &Context<Self>) {
+        if let Err(e) = Self::read_backup(msg).await {
+            println!("Failed to read backup: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<RestoreBackup> for LibraryActor {
+    async fn notify(&mut self, msg: RestoreBackup, _: &Context<Self>) {
+        if let Err(e) = Self::restore_backup(msg).await {
+            println!("Failed to restore backup: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<ResyncLibrary> for LibraryActor {
+    async fn notify(&mut self, _: ResyncLibrary, _: &Context<Self>) {
+        match State::get() {
+            Ok(state) => {
+                let summary = state.read().await.get_library_summary();
+                LibraryState::Show(summary).send_signal_to_dart();
+            }
+            Err(e) => println!("Failed to resync library: {:#}", e),
+        }
     }
 }
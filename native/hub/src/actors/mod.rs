@@ -2,9 +2,17 @@ use std::sync::OnceLock;
 
 use messages::prelude::{Address, Context};
 
-use crate::actors::library::LibraryActor;
+use crate::actors::{
+    collection::CollectionActor, library::LibraryActor, opds_client::OpdsClientActor,
+    organize::OrganizeActor, reading::ReadingActor, server::ServerActor,
+};
 
+pub mod collection;
 pub mod library;
+pub mod opds_client;
+pub mod organize;
+pub mod reading;
+pub mod server;
 
 pub static ADDRESSES: OnceLock<ActorAddresses> = OnceLock::new();
 
@@ -21,11 +29,21 @@ impl ActorAddresses {
 
 pub async fn create_actors() -> anyhow::Result<()> {
     let library_ctx: Context<LibraryActor> = Context::new();
-    let library_addr = LibraryActor::create_and_init(library_ctx).await;
+    let library_addr = LibraryActor::create_and_init(library_ctx).await?;
+    let server_ctx: Context<ServerActor> = Context::new();
+    ServerActor::create_and_init(server_ctx);
+    let opds_client_ctx: Context<OpdsClientActor> = Context::new();
+    OpdsClientActor::create_and_init(opds_client_ctx);
+    let reading_ctx: Context<ReadingActor> = Context::new();
+    ReadingActor::create_and_init(reading_ctx);
+    let collection_ctx: Context<CollectionActor> = Context::new();
+    CollectionActor::create_and_init(collection_ctx);
+    let organize_ctx: Context<OrganizeActor> = Context::new();
+    OrganizeActor::create_and_init(organize_ctx);
     ADDRESSES
         .set(ActorAddresses {
             lib_actor: library_addr,
         })
-        .expect("Failed to initialize actors.");
+        .map_err(|_| anyhow::anyhow!("Failed to initialize actors."))?;
     return anyhow::Ok(());
 }
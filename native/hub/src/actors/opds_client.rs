@@ -0,0 +1,191 @@
+use std::{fs, path::PathBuf};
+
+use crate::{
+    signals::opds_client_signals::{
+        BrowseOpdsFeed, DownloadOpdsBook, OpdsCredentials, OpdsDownloadProgress, OpdsFeedPage,
+        SearchOpdsFeed,
+    },
+    utility::{opds_client::OpdsClient, scan, server::Credentials, state::State},
+};
+use async_trait::async_trait;
+use messages::{
+    actor::Actor,
+    prelude::{Address, Context, Notifiable},
+};
+use rinf::{DartSignal, RustSignal};
+use tokio::{spawn, task::JoinSet};
+
+pub struct OpdsClientActor {
+    /// Downloads run alongside browsing, so a large book does not hold up the catalog.
+    downloads: JoinSet<()>,
+    _tasks: JoinSet<()>,
+}
+
+impl Actor for OpdsClientActor {}
+
+impl OpdsClientActor {
+    pub fn create_and_init(ctx: Context<OpdsClientActor>) -> Address<Self> {
+        let self_addr = ctx.address();
+        let mut owned_tasks = JoinSet::new();
+        owned_tasks.spawn(Self::listen_browse_opds_feed(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_search_opds_feed(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_download_opds_book(self_addr.clone()));
+
+        spawn(ctx.run(Self {
+            downloads: JoinSet::new(),
+            _tasks: owned_tasks,
+        }));
+        return self_addr;
+    }
+
+    async fn listen_browse_opds_feed(mut self_addr: Address<Self>) {
+        let recv = BrowseOpdsFeed::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_search_opds_feed(mut self_addr: Address<Self>) {
+        let recv = SearchOpdsFeed::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_download_opds_book(mut self_addr: Address<Self>) {
+        let recv = DownloadOpdsBook::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    fn client(credentials: Option<OpdsCredentials>) -> OpdsClient {
+        return OpdsClient::new(credentials.map(|c| Credentials {
+            username: c.username,
+            password: c.password,
+        }));
+    }
+
+    /// Fetches a page off the async runtime, turning failures into an empty page with the error.
+    async fn fetch_page(
+        url: String,
+        fetch: impl FnOnce() -> anyhow::Result<OpdsFeedPage> + Send + 'static,
+    ) -> OpdsFeedPage {
+        let result = tokio::task::spawn_blocking(fetch)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|page| page);
+        return match result {
+            Ok(page) => page,
+            Err(e) => {
+                println!("Failed to fetch OPDS feed: {:#}", e);
+                OpdsFeedPage {
+                    url,
+                    title: String::new(),
+                    entries: vec![],
+                    next_url: None,
+                    previous_url: None,
+                    search_url: None,
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        };
+    }
+
+    async fn download_book(msg: DownloadOpdsBook) -> anyhow::Result<PathBuf> {
+        let library = PathBuf::from(&msg.library);
+        let is_library = State::get()?
+            .read()
+            .await
+            .get_library_ids()
+            .iter()
+            .any(|(_, root)| *root == library);
+        if !is_library {
+            return Err(anyhow::anyhow!("{} is not a library", library.display()));
+        }
+
+        let client = Self::client(msg.credentials);
+        let url = msg.url.clone();
+        let dir = library.clone();
+        let book = tokio::task::spawn_blocking(move || {
+            client.download(&url, &dir, msg.file_name.as_deref(), |received, total| {
+                OpdsDownloadProgress {
+                    url: url.clone(),
+                    received,
+                    total,
+                    done: false,
+                    path: None,
+                    error: None,
+                }
+                .send_signal_to_dart();
+            })
+        })
+        .await??;
+
+        // Other formats are kept but, like during a scan, not indexed.
+        if !scan::is_book(&book) {
+            return Ok(book);
+        }
+        let mut state = State::get()?.write().await;
+        if let Some(changes) = state.index_book(&library, book.clone())?
+            && let Some(delta) = state.get_library_delta(changes)
+        {
+            delta.send_signal_to_dart();
+        }
+        return Ok(book);
+    }
+}
+
+#[async_trait]
+impl Notifiable<BrowseOpdsFeed> for OpdsClientActor {
+    async fn notify(&mut self, msg: BrowseOpdsFeed, _: &Context<Self>) {
+        let client = Self::client(msg.credentials);
+        let url = msg.url.clone();
+        Self::fetch_page(msg.url, move || client.browse(&url))
+            .await
+            .send_signal_to_dart();
+    }
+}
+
+#[async_trait]
+impl Notifiable<SearchOpdsFeed> for OpdsClientActor {
+    async fn notify(&mut self, msg: SearchOpdsFeed, _: &Context<Self>) {
+        let client = Self::client(msg.credentials);
+        let url = msg.search_url.clone();
+        Self::fetch_page(msg.search_url, move || client.search(&url, &msg.query))
+            .await
+            .send_signal_to_dart();
+    }
+}
+
+#[async_trait]
+impl Notifiable<DownloadOpdsBook> for OpdsClientActor {
+    async fn notify(&mut self, msg: DownloadOpdsBook, _: &Context<Self>) {
+        // Forget the downloads that are over.
+        while self.downloads.try_join_next().is_some() {}
+        self.downloads.spawn(async move {
+            let url = msg.url.clone();
+            let result = Self::download_book(msg).await;
+            if let Err(e) = &result {
+                println!("Failed to download {}: {:#}", url, e);
+            }
+            let received = result
+                .as_ref()
+                .ok()
+                .and_then(|book| fs::metadata(book).ok())
+                .map_or(0, |metadata| metadata.len());
+            OpdsDownloadProgress {
+                url,
+                received,
+                total: None,
+                done: true,
+                path: result
+                    .as_ref()
+                    .ok()
+                    .map(|book| book.to_string_lossy().into_owned()),
+                error: result.err().map(|e| format!("{:#}", e)),
+            }
+            .send_signal_to_dart();
+        });
+    }
+}
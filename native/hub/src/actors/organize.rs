@@ -0,0 +1,158 @@
+use crate::{
+    signals::organize_signals::{
+        BookMoveData, LibraryOrganized, OrganizeLibrary, OrganizePreview, PreviewOrganize,
+        SkippedBookData, UndoOrganize,
+    },
+    utility::{
+        cache::CacheChanges,
+        organize::{OrganizePlan, SkippedBook},
+        state::State,
+    },
+};
+use async_trait::async_trait;
+use messages::{
+    actor::Actor,
+    prelude::{Address, Context, Notifiable},
+};
+use rinf::{DartSignal, RustSignal};
+use tokio::{spawn, task::JoinSet};
+
+/// Renames and moves the books of the open library following a template.
+pub struct OrganizeActor {
+    _tasks: JoinSet<()>,
+}
+
+impl Actor for OrganizeActor {}
+
+impl OrganizeActor {
+    pub fn create_and_init(ctx: Context<OrganizeActor>) -> Address<Self> {
+        let self_addr = ctx.address();
+        let mut owned_tasks = JoinSet::new();
+        owned_tasks.spawn(Self::listen_preview_organize(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_organize_library(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_undo_organize(self_addr.clone()));
+
+        spawn(ctx.run(Self {
+            _tasks: owned_tasks,
+        }));
+        return self_addr;
+    }
+
+    async fn listen_preview_organize(mut self_addr: Address<Self>) {
+        let recv = PreviewOrganize::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_organize_library(mut self_addr: Address<Self>) {
+        let recv = OrganizeLibrary::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_undo_organize(mut self_addr: Address<Self>) {
+        let recv = UndoOrganize::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn preview_organize(msg: PreviewOrganize) -> anyhow::Result<()> {
+        let result = State::get()?.read().await.preview_organize(&msg.template);
+        let (plan, error) = match result {
+            Ok(plan) => (plan, None),
+            Err(e) => (OrganizePlan::default(), Some(e)),
+        };
+        OrganizePreview {
+            template: msg.template,
+            moves: plan
+                .moves
+                .into_iter()
+                .map(|book| BookMoveData {
+                    book_key: book.key,
+                    from: book.from,
+                    to: book.to,
+                })
+                .collect(),
+            unchanged: plan.unchanged as u32,
+            skipped: Self::skipped_data(plan.skipped),
+            error: error.as_ref().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    /// Sends the delta of the books moved, then the outcome of the run.
+    async fn answer(
+        run: impl FnOnce(&mut State) -> anyhow::Result<(CacheChanges, Vec<SkippedBook>)>,
+    ) -> anyhow::Result<()> {
+        let result = {
+            let mut state = State::get()?.write().await;
+            run(&mut state).map(|(changes, skipped)| {
+                let moved = changes.added.len() as u32;
+                if let Some(delta) = state.get_library_delta(changes) {
+                    delta.send_signal_to_dart();
+                }
+                (moved, skipped)
+            })
+        };
+        let (moved, skipped, error) = match result {
+            Ok((moved, skipped)) => (moved, skipped, None),
+            Err(e) => (0, Vec::new(), Some(e)),
+        };
+        LibraryOrganized {
+            moved,
+            skipped: Self::skipped_data(skipped),
+            error: error.as_ref().map(|e| format!("{:#}", e)),
+        }
+        .send_signal_to_dart();
+        return match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+    }
+
+    fn skipped_data(skipped: Vec<SkippedBook>) -> Vec<SkippedBookData> {
+        return skipped
+            .into_iter()
+            .map(|book| SkippedBookData {
+                book_key: book.key,
+                path: book.path,
+                reason: book.reason,
+            })
+            .collect();
+    }
+}
+
+#[async_trait]
+impl Notifiable<PreviewOrganize> for OrganizeActor {
+    async fn notify(&mut self, msg: PreviewOrganize, _: &Context<Self>) {
+        if let Err(e) = Self::preview_organize(msg).await {
+            println!("Failed to preview organize: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<OrganizeLibrary> for OrganizeActor {
+    async fn notify(&mut self, msg: OrganizeLibrary, _: &Context<Self>) {
+        let result = Self::answer(|state| state.organize_library(&msg.template)).await;
+        if let Err(e) = result {
+            println!("Failed to organize library: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<UndoOrganize> for OrganizeActor {
+    async fn notify(&mut self, _: UndoOrganize, _: &Context<Self>) {
+        if let Err(e) = Self::answer(State::undo_organize).await {
+            println!("Failed to undo organize: {:#}", e);
+        }
+    }
+}
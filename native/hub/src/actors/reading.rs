@@ -0,0 +1,519 @@
+use crate::{
+    signals::reading_signals::{
+        AnnotationData, AnnotationType, DeleteAnnotation, GetKosyncAccount, GetReadingData,
+        KosyncAccountState, KosyncOutcome, KosyncProgressSynced, LocatorType, ProgressData,
+        ReadingData, ReadingStatusSetting, RemoveKosyncAccount, SaveAnnotation, SetDateFinished,
+        SetKosyncAccount, SetRating, SetReadingProgress, SetReadingStatus, SetUserTags,
+        SyncKosyncProgress,
+    },
+    utility::{
+        kosync::{self, DEVICE_NAME, KosyncAccount, KosyncClient, KosyncProgress},
+        state::State,
+        user_data::{
+            Annotation, AnnotationKind, BookUserData, LocatorKind, Progress, ReadingStatus,
+        },
+    },
+};
+use anyhow::anyhow;
+use async_trait::async_trait;
+use messages::{
+    actor::Actor,
+    prelude::{Address, Context, Notifiable},
+};
+use rinf::{DartSignal, RustSignal};
+use tokio::{spawn, task::JoinSet};
+
+/// Reading progress, annotations, status, ratings and tags, synced between devices through
+/// the library folder.
+pub struct ReadingActor {
+    _tasks: JoinSet<()>,
+}
+
+impl Actor for ReadingActor {}
+
+impl ReadingActor {
+    pub fn create_and_init(ctx: Context<ReadingActor>) -> Address<Self> {
+        let self_addr = ctx.address();
+        let mut owned_tasks = JoinSet::new();
+        owned_tasks.spawn(Self::listen_set_reading_progress(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_save_annotation(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_delete_annotation(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_reading_status(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_rating(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_user_tags(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_date_finished(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_get_reading_data(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_set_kosync_account(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_remove_kosync_account(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_get_kosync_account(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_sync_kosync_progress(self_addr.clone()));
+
+        spawn(ctx.run(Self {
+            _tasks: owned_tasks,
+        }));
+        return self_addr;
+    }
+
+    async fn listen_set_reading_progress(mut self_addr: Address<Self>) {
+        let recv = SetReadingProgress::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_save_annotation(mut self_addr: Address<Self>) {
+        let recv = SaveAnnotation::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_delete_annotation(mut self_addr: Address<Self>) {
+        let recv = DeleteAnnotation::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_reading_status(mut self_addr: Address<Self>) {
+        let recv = SetReadingStatus::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_rating(mut self_addr: Address<Self>) {
+        let recv = SetRating::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_user_tags(mut self_addr: Address<Self>) {
+        let recv = SetUserTags::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_date_finished(mut self_addr: Address<Self>) {
+        let recv = SetDateFinished::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_get_reading_data(mut self_addr: Address<Self>) {
+        let recv = GetReadingData::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_set_kosync_account(mut self_addr: Address<Self>) {
+        let recv = SetKosyncAccount::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_remove_kosync_account(mut self_addr: Address<Self>) {
+        let recv = RemoveKosyncAccount::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_get_kosync_account(mut self_addr: Address<Self>) {
+        let recv = GetKosyncAccount::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_sync_kosync_progress(mut self_addr: Address<Self>) {
+        let recv = SyncKosyncProgress::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn set_reading_progress(msg: SetReadingProgress) -> anyhow::Result<()> {
+        let mut state = State::get()?.write().await;
+        let progress = Progress {
+            locator: msg.locator,
+            locator_kind: LocatorKind::Cfi,
+            percent: msg.percent.clamp(0.0, 1.0),
+        };
+        state.set_progress(&msg.book_key, progress)?;
+        let data = state.get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn save_annotation(msg: SaveAnnotation) -> anyhow::Result<()> {
+        let mut state = State::get()?.write().await;
+        let data = msg.annotation;
+        let annotation = Annotation {
+            id: data.id,
+            kind: match data.kind {
+                AnnotationType::Highlight => AnnotationKind::Highlight,
+                AnnotationType::Note => AnnotationKind::Note,
+                AnnotationType::Bookmark => AnnotationKind::Bookmark,
+            },
+            locator: data.locator,
+            locator_kind: Self::locator_kind(data.locator_type),
+            text: data.text,
+            note: data.note,
+            color: data.color,
+            created: data.created,
+        };
+        state.save_annotation(&msg.book_key, annotation)?;
+        let data = state.get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn delete_annotation(msg: DeleteAnnotation) -> anyhow::Result<()> {
+        let mut state = State::get()?.write().await;
+        state.delete_annotation(&msg.book_key, msg.annotation_id)?;
+        let data = state.get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn set_reading_status(msg: SetReadingStatus) -> anyhow::Result<()> {
+        let mut state = State::get()?.write().await;
+        state.set_status(&msg.book_key, msg.status.map(Self::reading_status))?;
+        let data = state.get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn set_rating(msg: SetRating) -> anyhow::Result<()> {
+        let mut state = State::get()?.write().await;
+        state.set_rating(&msg.book_key, msg.rating)?;
+        let data = state.get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn set_user_tags(msg: SetUserTags) -> anyhow::Result<()> {
+        let mut state = State::get()?.write().await;
+        state.set_tags(&msg.book_key, msg.tags)?;
+        let data = state.get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn set_date_finished(msg: SetDateFinished) -> anyhow::Result<()> {
+        let mut state = State::get()?.write().await;
+        state.set_date_finished(&msg.book_key, msg.date)?;
+        let data = state.get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    async fn get_reading_data(msg: GetReadingData) -> anyhow::Result<()> {
+        let data = State::get()?
+            .write()
+            .await
+            .get_reading_data(&msg.book_key)?;
+        Self::reading_data(msg.book_key, data).send_signal_to_dart();
+        return Ok(());
+    }
+
+    /// Checks the account, creating it first if asked to, then saves it.
+    async fn set_kosync_account(msg: SetKosyncAccount) -> anyhow::Result<()> {
+        if msg.server.trim().is_empty() || msg.username.trim().is_empty() {
+            return Err(anyhow!("A server and a username are required."));
+        }
+        let account = KosyncAccount::new(&msg.server, &msg.username, &msg.password);
+        let client = KosyncClient::new(account.clone());
+        tokio::task::spawn_blocking(move || {
+            if msg.register {
+                client.register()?;
+            }
+            client.authorize()
+        })
+        .await??;
+        State::get()?
+            .write()
+            .await
+            .set_kosync_account(Some(account))?;
+        return Ok(());
+    }
+
+    pub async fn send_kosync_account(error: Option<String>) -> anyhow::Result<()> {
+        let state = State::get()?.read().await;
+        let account = state.kosync_account();
+        KosyncAccountState {
+            server: account.map(|a| a.server.clone()),
+            username: account.map(|a| a.username.clone()),
+            error,
+        }
+        .send_signal_to_dart();
+        return Ok(());
+    }
+
+    /// Pulls the progress of a book from the kosync server if it is newer than the local
+    /// one, pushes the local one otherwise.
+    async fn sync_kosync_progress(book_key: &str) -> anyhow::Result<KosyncOutcome> {
+        let (account, book, device_id, local) = {
+            let mut state = State::get()?.write().await;
+            let account = state
+                .kosync_account()
+                .cloned()
+                .ok_or_else(|| anyhow!("No kosync account is set."))?;
+            let book = state.book_file(book_key)?;
+            let local = state.get_reading_data(book_key)?.progress;
+            (account, book, state.device_id().to_string(), local)
+        };
+        let (outcome, pulled) = tokio::task::spawn_blocking(move || {
+            let client = KosyncClient::new(account);
+            let document = kosync::partial_md5(&book)?;
+            let remote = client.pull(&document)?;
+            if let (Some(remote), Some(local)) = (&remote, &local)
+                && remote.progress == local.value.locator
+            {
+                return anyhow::Ok((KosyncOutcome::UpToDate, None));
+            }
+            // The server stamps progress in seconds, local progress is in milliseconds.
+            let remote_is_newer = match (&remote, &local) {
+                (Some(remote), Some(local)) => {
+                    remote.timestamp.unwrap_or_default() * 1000 > local.time
+                }
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            if remote_is_newer {
+                return anyhow::Ok((KosyncOutcome::Pulled, remote));
+            }
+            let Some(local) = local else {
+                return anyhow::Ok((KosyncOutcome::UpToDate, None));
+            };
+            client.push(&KosyncProgress {
+                document,
+                progress: local.value.locator,
+                percentage: local.value.percent,
+                device: DEVICE_NAME.to_string(),
+                device_id,
+                timestamp: None,
+            })?;
+            return anyhow::Ok((KosyncOutcome::Pushed, None));
+        })
+        .await??;
+
+        if let Some(remote) = pulled {
+            let mut state = State::get()?.write().await;
+            // KOReader, the usual client of kosync servers, syncs XPointers.
+            let progress = Progress {
+                locator: remote.progress,
+                locator_kind: LocatorKind::XPointer,
+                percent: remote.percentage.clamp(0.0, 1.0),
+            };
+            state.set_progress(book_key, progress)?;
+            let data = state.get_reading_data(book_key)?;
+            Self::reading_data(book_key.to_string(), data).send_signal_to_dart();
+        }
+        return Ok(outcome);
+    }
+
+    pub fn reading_status(setting: ReadingStatusSetting) -> ReadingStatus {
+        return match setting {
+            ReadingStatusSetting::ToRead => ReadingStatus::ToRead,
+            ReadingStatusSetting::Reading => ReadingStatus::Reading,
+            ReadingStatusSetting::Finished => ReadingStatus::Finished,
+            ReadingStatusSetting::Abandoned => ReadingStatus::Abandoned,
+        };
+    }
+
+    fn locator_kind(locator_type: LocatorType) -> LocatorKind {
+        return match locator_type {
+            LocatorType::Cfi => LocatorKind::Cfi,
+            LocatorType::XPointer => LocatorKind::XPointer,
+        };
+    }
+
+    fn locator_type(kind: LocatorKind) -> LocatorType {
+        return match kind {
+            LocatorKind::Cfi => LocatorType::Cfi,
+            LocatorKind::XPointer => LocatorType::XPointer,
+        };
+    }
+
+    fn reading_data(book_key: String, data: BookUserData) -> ReadingData {
+        let status = data.status().map(|status| match status {
+            ReadingStatus::ToRead => ReadingStatusSetting::ToRead,
+            ReadingStatus::Reading => ReadingStatusSetting::Reading,
+            ReadingStatus::Finished => ReadingStatusSetting::Finished,
+            ReadingStatus::Abandoned => ReadingStatusSetting::Abandoned,
+        });
+        let rating = data.rating();
+        let tags = data.tags().to_vec();
+        let date_finished = data.finished();
+        return ReadingData {
+            book_key,
+            progress: data.progress.map(|p| ProgressData {
+                locator: p.value.locator,
+                locator_type: Self::locator_type(p.value.locator_kind),
+                percent: p.value.percent,
+                updated: p.time,
+                device: p.device,
+            }),
+            annotations: data
+                .annotations
+                .into_values()
+                .map(|a| AnnotationData {
+                    id: a.value.id,
+                    kind: match a.value.kind {
+                        AnnotationKind::Highlight => AnnotationType::Highlight,
+                        AnnotationKind::Note => AnnotationType::Note,
+                        AnnotationKind::Bookmark => AnnotationType::Bookmark,
+                    },
+                    locator: a.value.locator,
+                    locator_type: Self::locator_type(a.value.locator_kind),
+                    text: a.value.text,
+                    note: a.value.note,
+                    color: a.value.color,
+                    created: a.value.created,
+                    updated: a.time,
+                })
+                .collect(),
+            status,
+            rating,
+            tags,
+            date_finished,
+        };
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetReadingProgress> for ReadingActor {
+    async fn notify(&mut self, msg: SetReadingProgress, _: &Context<Self>) {
+        if let Err(e) = Self::set_reading_progress(msg).await {
+            println!("Failed to save reading progress: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SaveAnnotation> for ReadingActor {
+    async fn notify(&mut self, msg: SaveAnnotation, _: &Context<Self>) {
+        if let Err(e) = Self::save_annotation(msg).await {
+            println!("Failed to save annotation: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<DeleteAnnotation> for ReadingActor {
+    async fn notify(&mut self, msg: DeleteAnnotation, _: &Context<Self>) {
+        if let Err(e) = Self::delete_annotation(msg).await {
+            println!("Failed to delete annotation: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetReadingStatus> for ReadingActor {
+    async fn notify(&mut self, msg: SetReadingStatus, _: &Context<Self>) {
+        if let Err(e) = Self::set_reading_status(msg).await {
+            println!("Failed to set reading status: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetRating> for ReadingActor {
+    async fn notify(&mut self, msg: SetRating, _: &Context<Self>) {
+        if let Err(e) = Self::set_rating(msg).await {
+            println!("Failed to set rating: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetUserTags> for ReadingActor {
+    async fn notify(&mut self, msg: SetUserTags, _: &Context<Self>) {
+        if let Err(e) = Self::set_user_tags(msg).await {
+            println!("Failed to set tags: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetDateFinished> for ReadingActor {
+    async fn notify(&mut self, msg: SetDateFinished, _: &Context<Self>) {
+        if let Err(e) = Self::set_date_finished(msg).await {
+            println!("Failed to set date finished: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<GetReadingData> for ReadingActor {
+    async fn notify(&mut self, msg: GetReadingData, _: &Context<Self>) {
+        if let Err(e) = Self::get_reading_data(msg).await {
+            println!("Failed to get reading data: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SetKosyncAccount> for ReadingActor {
+    async fn notify(&mut self, msg: SetKosyncAccount, _: &Context<Self>) {
+        let error = match Self::set_kosync_account(msg).await {
+            Ok(()) => None,
+            Err(e) => {
+                println!("Failed to set kosync account: {:#}", e);
+                Some(format!("{:#}", e))
+            }
+        };
+        if let Err(e) = Self::send_kosync_account(error).await {
+            println!("Failed to send kosync account: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<RemoveKosyncAccount> for ReadingActor {
+    async fn notify(&mut self, _: RemoveKosyncAccount, _: &Context<Self>) {
+        let result = match State::get() {
+            Ok(state) => state.write().await.set_kosync_account(None),
+            Err(e) => Err(e),
+        };
+        let error = result.err().map(|e| {
+            println!("Failed to remove kosync account: {:#}", e);
+            format!("{:#}", e)
+        });
+        if let Err(e) = Self::send_kosync_account(error).await {
+            println!("Failed to send kosync account: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<GetKosyncAccount> for ReadingActor {
+    async fn notify(&mut self, _: GetKosyncAccount, _: &Context<Self>) {
+        if let Err(e) = Self::send_kosync_account(None).await {
+            println!("Failed to send kosync account: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Notifiable<SyncKosyncProgress> for ReadingActor {
+    async fn notify(&mut self, msg: SyncKosyncProgress, _: &Context<Self>) {
+        let result = Self::sync_kosync_progress(&msg.book_key).await;
+        if let Err(e) = &result {
+            println!("Failed to sync progress with kosync: {:#}", e);
+        }
+        KosyncProgressSynced {
+            book_key: msg.book_key,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            outcome: result.ok(),
+        }
+        .send_signal_to_dart();
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{
+    actors::reading::ReadingActor,
+    signals::server_signals::{
+        CatalogServerState, KosyncServerState, StartCatalogServer, StartKosyncServer,
+        StopCatalogServer, StopKosyncServer,
+    },
+    utility::{
+        kosync_server::{KosyncServer, KosyncServerConfig},
+        server::{CatalogServer, Credentials, ServerConfig},
+        state::State,
+    },
+};
+use async_trait::async_trait;
+use messages::{
+    actor::Actor,
+    prelude::{Address, Context, Notifiable},
+};
+use rinf::{DartSignal, RustSignal};
+use tokio::{spawn, task::JoinSet};
+
+pub struct ServerActor {
+    server: Option<CatalogServer>,
+    kosync: Option<KosyncServer>,
+    _tasks: JoinSet<()>,
+}
+
+impl Actor for ServerActor {}
+
+impl ServerActor {
+    pub fn create_and_init(ctx: Context<ServerActor>) -> Address<Self> {
+        let self_addr = ctx.address();
+        let mut owned_tasks = JoinSet::new();
+        owned_tasks.spawn(Self::listen_start_catalog_server(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_stop_catalog_server(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_start_kosync_server(self_addr.clone()));
+        owned_tasks.spawn(Self::listen_stop_kosync_server(self_addr.clone()));
+
+        spawn(ctx.run(Self {
+            server: None,
+            kosync: None,
+            _tasks: owned_tasks,
+        }));
+        return self_addr;
+    }
+
+    async fn listen_start_catalog_server(mut self_addr: Address<Self>) {
+        let recv = StartCatalogServer::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_stop_catalog_server(mut self_addr: Address<Self>) {
+        let recv = StopCatalogServer::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_start_kosync_server(mut self_addr: Address<Self>) {
+        let recv = StartKosyncServer::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    async fn listen_stop_kosync_server(mut self_addr: Address<Self>) {
+        let recv = StopKosyncServer::get_dart_signal_receiver();
+        while let Some(signal) = recv.recv().await {
+            let _ = self_addr.notify(signal.message).await;
+        }
+    }
+
+    /// Stops the running server, waiting off the async runtime for downloads in progress.
+    async fn stop(&mut self) {
+        if let Some(server) = self.server.take() {
+            let _ = tokio::task::spawn_blocking(move || drop(server)).await;
+        }
+    }
+
+    async fn stop_kosync(&mut self) {
+        if let Some(server) = self.kosync.take() {
+            let _ = tokio::task::spawn_blocking(move || drop(server)).await;
+        }
+    }
+
+    async fn start_kosync(msg: StartKosyncServer) -> anyhow::Result<KosyncServer> {
+        let support_dir = State::get()?.read().await.support_dir().to_path_buf();
+        let config = KosyncServerConfig {
+            port: msg.port,
+            allow_registration: msg.allow_registration,
+        };
+        return KosyncServer::start(config, &support_dir);
+    }
+
+    /// Keeps the kosync account of the app on the server it just started, if it is there.
+    async fn follow_kosync(port: u16) -> anyhow::Result<()> {
+        let moved = State::get()?.write().await.follow_kosync_server(port)?;
+        if moved {
+            ReadingActor::send_kosync_account(None).await?;
+        }
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl Notifiable<StartCatalogServer> for ServerActor {
+    async fn notify(&mut self, msg: StartCatalogServer, _: &Context<Self>) {
+        self.stop().await;
+        let started = Credentials::from_parts(msg.username, msg.password).and_then(|credentials| {
+            return CatalogServer::start(ServerConfig {
+                port: msg.port,
+                credentials,
+                all_libraries: msg.all_libraries,
+            });
+        });
+        let state = match started {
+            Ok(server) => {
+                let port = server.port();
+                self.server = Some(server);
+                CatalogServerState {
+                    running: true,
+                    port,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                println!("Failed to start catalog server: {:#}", e);
+                CatalogServerState {
+                    running: false,
+                    port: msg.port,
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        };
+        state.send_signal_to_dart();
+    }
+}
+
+#[async_trait]
+impl Notifiable<StopCatalogServer> for ServerActor {
+    async fn notify(&mut self, _: StopCatalogServer, _: &Context<Self>) {
+        self.stop().await;
+        CatalogServerState {
+            running: false,
+            port: 0,
+            error: None,
+        }
+        .send_signal_to_dart();
+    }
+}
+
+#[async_trait]
+impl Notifiable<StartKosyncServer> for ServerActor {
+    async fn notify(&mut self, msg: StartKosyncServer, _: &Context<Self>) {
+        self.stop_kosync().await;
+        let port = msg.port;
+        let state = match Self::start_kosync(msg).await {
+            Ok(server) => {
+                let port = server.port();
+                self.kosync = Some(server);
+                if let Err(e) = Self::follow_kosync(port).await {
+                    println!("Failed to point the kosync account at the server: {:#}", e);
+                }
+                KosyncServerState {
+                    running: true,
+                    port,
+                    error: None,
+                }
+            }
+            Err(e) => {
+                println!("Failed to start kosync server: {:#}", e);
+                KosyncServerState {
+                    running: false,
+                    port,
+                    error: Some(format!("{:#}", e)),
+                }
+            }
+        };
+        state.send_signal_to_dart();
+    }
+}
+
+#[async_trait]
+impl Notifiable<StopKosyncServer> for ServerActor {
+    async fn notify(&mut self, _: StopKosyncServer, _: &Context<Self>) {
+        self.stop_kosync().await;
+        KosyncServerState {
+            running: false,
+            port: 0,
+            error: None,
+        }
+        .send_signal_to_dart();
+    }
+}
@@ -8,9 +8,7 @@ mod utility;
 use rinf::{DartSignal, dart_shutdown, write_interface};
 
 use crate::{
-    actors::create_actors,
-    signals::{library_signals::UpdateCache, utility_signals::AppSupportDirectory},
-    utility::state::State,
+    actors::create_actors, signals::utility_signals::AppSupportDirectory, utility::state::State,
 };
 
 write_interface!();
@@ -19,11 +17,17 @@ write_interface!();
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     let receiver = AppSupportDirectory::get_dart_signal_receiver();
-    if let Some(support_dir) = receiver.recv().await {
-        State::initialize(support_dir.message.path).unwrap();
+    if let Some(support_dir) = receiver.recv().await
+        && let Err(e) = State::initialize(support_dir.message.path)
+    {
+        println!("Failed to initialize state: {:#}", e);
+        return;
     }
-    create_actors().await.unwrap();
-    
+    if let Err(e) = create_actors().await {
+        println!("Failed to create actors: {:#}", e);
+        return;
+    }
+
     // Keep the main function running until Dart shutdown.
     dart_shutdown().await;
 }
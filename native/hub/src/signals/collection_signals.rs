@@ -0,0 +1,125 @@
+use rinf::{DartSignal, RustSignal, SignalPiece};
+use serde::{Deserialize, Serialize};
+
+use crate::signals::library_signals::BookData;
+
+/// Adds a collection to the open library: a manual one without `rules`, a smart one
+/// otherwise. Answered with `Collections`.
+#[derive(Deserialize, DartSignal)]
+pub struct CreateCollection {
+    pub name: String,
+    pub rules: Option<SmartRulesData>,
+}
+
+/// Renames a collection, and replaces the rules of a smart one if `rules` is set. Answered
+/// with `Collections`.
+#[derive(Deserialize, DartSignal)]
+pub struct EditCollection {
+    pub collection_id: String,
+    pub name: String,
+    pub rules: Option<SmartRulesData>,
+}
+
+/// Answered with `Collections`.
+#[derive(Deserialize, DartSignal)]
+pub struct DeleteCollection {
+    pub collection_id: String,
+}
+
+/// Adds books at the end of a manual collection, answered with `Collections`.
+#[derive(Deserialize, DartSignal)]
+pub struct AddToCollection {
+    pub collection_id: String,
+    pub book_keys: Vec<String>,
+}
+
+/// Answered with `Collections`.
+#[derive(Deserialize, DartSignal)]
+pub struct RemoveFromCollection {
+    pub collection_id: String,
+    pub book_keys: Vec<String>,
+}
+
+/// Replaces the books of a manual collection with `book_keys`, in that order, e.g. after
+/// they were reordered. Answered with `Collections`.
+#[derive(Deserialize, DartSignal)]
+pub struct ReorderCollection {
+    pub collection_id: String,
+    pub book_keys: Vec<String>,
+}
+
+#[derive(Deserialize, DartSignal)]
+pub struct GetCollections;
+
+/// Asks for a window of the books of a collection, answered with `CollectionPage`.
+#[derive(Deserialize, DartSignal)]
+pub struct GetCollectionPage {
+    pub collection_id: String,
+    pub offset: u32,
+    pub limit: u32,
+}
+
+/// Collections of the open library. Smart collections follow the library, so ask again
+/// after a refresh or a change to the user data of a book.
+#[derive(Serialize, RustSignal)]
+pub struct Collections {
+    pub collections: Vec<CollectionData>,
+    /// Set if the last change was refused.
+    pub error: Option<String>,
+}
+
+/// Answer to `GetCollectionPage`. `total` counts the books of the collection in the library.
+#[derive(Serialize, RustSignal)]
+pub struct CollectionPage {
+    pub collection_id: String,
+    pub offset: u32,
+    pub total: u32,
+    pub books: Vec<BookData>,
+}
+
+#[derive(Serialize, SignalPiece)]
+pub struct CollectionData {
+    pub id: String,
+    pub name: String,
+    /// `None` for manual collections.
+    pub rules: Option<SmartRulesData>,
+    pub total: u32,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub struct SmartRulesData {
+    pub rules: Vec<RuleData>,
+    /// Keep books matching any rule rather than all of them.
+    pub match_any: bool,
+}
+
+/// A condition such as author is X or status is not `finished`. Statuses are written
+/// `to_read`, `reading`, `finished` and `abandoned`, ratings from 1 to 5.
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub struct RuleData {
+    pub field: RuleFieldSetting,
+    pub op: RuleOpSetting,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub enum RuleFieldSetting {
+    Title,
+    Author,
+    Series,
+    SeriesIndex,
+    Path,
+    Tag,
+    Status,
+    Rating,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub enum RuleOpSetting {
+    Is,
+    IsNot,
+    Contains,
+    NotContains,
+    AtLeast,
+    AtMost,
+}
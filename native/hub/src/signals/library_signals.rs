@@ -1,28 +1,296 @@
 use rinf::{DartSignal, RustSignal, SignalPiece};
 use serde::{Deserialize, Serialize};
 
+use crate::signals::reading_signals::ReadingStatusSetting;
+
 #[derive(Deserialize, DartSignal)]
 pub struct AddToLibrary {
     pub path: String,
 }
 
+/// Adds a Calibre library, taking titles, metadata and covers from Calibre.
+#[derive(Deserialize, DartSignal)]
+pub struct ImportCalibreLibrary {
+    pub path: String,
+}
+
 #[derive(Deserialize, DartSignal)]
 pub enum UpdateCache {
     Refresh,
     Rebuild,
 }
 
+/// Limits how many books of the open library are parsed at once while indexing,
+/// `None` uses every core.
+#[derive(Deserialize, DartSignal)]
+pub struct SetIndexConcurrency {
+    pub limit: Option<u32>,
+}
+
+/// Sets which files of the open library are indexed, then refreshes it. Globs are relative
+/// to the library root and `.spectecleignore` files in any folder are honored as well.
+/// Unless `skip_archives` is set, books inside `.zip` archives are indexed too.
+#[derive(Deserialize, DartSignal)]
+pub struct SetScanRules {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub max_depth: Option<u32>,
+    pub follow_links: bool,
+    pub include_hidden: bool,
+    pub skip_archives: bool,
+}
+
+/// Moves the cache of the open library, e.g. out of a read-only or shared folder.
+#[derive(Deserialize, DartSignal)]
+pub struct SetCacheLocation {
+    pub location: CacheLocationSetting,
+}
+
+#[derive(Deserialize, SignalPiece)]
+pub enum CacheLocationSetting {
+    /// Inside the library folder, so the cache travels with the books.
+    InLibrary,
+    /// In the app support directory.
+    AppSupport,
+    /// In a folder of the user's choosing.
+    Custom(String),
+}
+
+/// Points the library at `old` to `new` after its folder was moved, keeping its cache.
+#[derive(Deserialize, DartSignal)]
+pub struct RelocateLibrary {
+    pub old: String,
+    pub new: String,
+}
+
+/// Asks for a window of the open library, in display order. With a `query`, only books
+/// whose title, path, authors, series or tags contain it are counted and returned, and
+/// likewise for the books matching `filter`.
+#[derive(Deserialize, DartSignal)]
+pub struct GetLibraryPage {
+    pub offset: u32,
+    pub limit: u32,
+    pub query: Option<String>,
+    pub filter: Option<BookFilterData>,
+}
+
+/// Keeps the books matching every criterion that is set.
+#[derive(Deserialize, SignalPiece)]
+pub struct BookFilterData {
+    pub status: Option<ReadingStatusSetting>,
+    /// Keeps books rated at least this much.
+    pub min_rating: Option<u8>,
+    /// Keeps books with this tag, in any case.
+    pub tag: Option<String>,
+}
+
+/// Asks for the current `LibraryState::Show` summary, after which every page
+/// should be fetched again.
+#[derive(Deserialize, DartSignal)]
+pub struct ResyncLibrary;
+
+/// Writes the catalog of the open library to `path`, answered with `CatalogExported`.
+/// CSV and JSON exports copy the cover thumbnails to a `<name>_covers` folder next to
+/// `path`; the HTML page embeds them so it can be shared as a single file.
+#[derive(Deserialize, DartSignal)]
+pub struct ExportCatalog {
+    pub format: CatalogExportFormat,
+    pub path: String,
+}
+
+#[derive(Deserialize, SignalPiece)]
+pub enum CatalogExportFormat {
+    Csv,
+    Json,
+    Html,
+}
+
+/// Asks for the metadata stored in the EPUB of a book of the open library, answered with
+/// `BookMetadataState`.
+#[derive(Deserialize, DartSignal)]
+pub struct GetBookMetadata {
+    pub book_key: String,
+}
+
+/// Asks for a file the reader can open for a book of the open library, answered with
+/// `BookOpened`. Books listed inside a zip archive are extracted to the library cache.
+#[derive(Deserialize, DartSignal)]
+pub struct OpenBook {
+    pub book_key: String,
+}
+
+/// Writes metadata into the EPUB of a book of the open library, replacing its cover with the
+/// image at `cover_path` if set. The original file is kept in `.spectecle/originals` the
+/// first time a book is edited. Answered with a `LibraryDelta` for the book, then
+/// `BookMetadataState`.
+#[derive(Deserialize, DartSignal)]
+pub struct EditBookMetadata {
+    pub book_key: String,
+    pub metadata: EpubMetadataData,
+    pub cover_path: Option<String>,
+}
+
+/// Asks for the metadata overlay of a book of the open library, answered with
+/// `MetadataOverlayState`.
+#[derive(Deserialize, DartSignal)]
+pub struct GetMetadataOverlay {
+    pub book_key: String,
+}
+
+/// Replaces the metadata overlay of a book of the open library. The book file is left
+/// alone, so books on read-only shares and offline libraries can be corrected too; an
+/// overlay without any field set is removed. Answered with a `LibraryDelta` for the book,
+/// then `MetadataOverlayState`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetMetadataOverlay {
+    pub book_key: String,
+    pub overlay: MetadataOverlayData,
+}
+
+/// Writes the corrected metadata of every book of the open library with an overlay as an
+/// OPF sidecar under the folder at `path`, at the path of the book with an `.opf`
+/// extension. Answered with `OverlaySidecarsExported`.
+#[derive(Deserialize, DartSignal)]
+pub struct ExportOverlaySidecars {
+    pub path: String,
+}
+
+/// Packs the registered libraries, their settings and user data into a single archive at
+/// `path`, answered with `BackupCreated`. Caches can be rebuilt from the books, so they are
+/// only included with `include_cache`, which also keeps covers and metadata imported from
+/// Calibre.
+#[derive(Deserialize, DartSignal)]
+pub struct CreateBackup {
+    pub path: String,
+    pub include_cache: bool,
+}
+
+/// Lists the libraries of a backup, answered with `BackupContents`, so that the ones whose
+/// folder moved can be remapped before restoring.
+#[derive(Deserialize, DartSignal)]
+pub struct ReadBackup {
+    pub path: String,
+}
+
+/// Restores a backup, answered with `BackupRestored`. Each library goes to the `to` of the
+/// remap whose `from` is its backed up root, or stays at that root.
+#[derive(Deserialize, DartSignal)]
+pub struct RestoreBackup {
+    pub path: String,
+    pub remaps: Vec<LibraryRemap>,
+    pub mode: RestoreModeSetting,
+}
+
+#[derive(Deserialize, SignalPiece)]
+pub struct LibraryRemap {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Deserialize, SignalPiece)]
+pub enum RestoreModeSetting {
+    /// Adds the libraries to the registered ones. A library that is already registered stays
+    /// where it is: its reading data is merged with the backup's, it gains the collections
+    /// and metadata corrections it lacks, and its other settings and files are kept.
+    Merge,
+    /// Replaces the registered libraries and the user data of the restored ones.
+    Replace,
+}
+
 #[derive(Serialize, RustSignal)]
 pub enum LibraryState {
-    Show(DisplayLibrary),
+    Show(LibrarySummary),
     NoLibraryAvailable,
     RefreshingCache,
     RebuildingCache,
 }
 
+/// Outcome of a `RelocateLibrary`, `error` is set if the library was left where it was.
+#[derive(Serialize, RustSignal)]
+pub struct LibraryRelocated {
+    pub old: String,
+    pub new: String,
+    pub error: Option<String>,
+}
+
+/// Outcome of an `ExportCatalog`, `error` is set if the export failed.
+#[derive(Serialize, RustSignal)]
+pub struct CatalogExported {
+    pub path: String,
+    pub books: u32,
+    pub error: Option<String>,
+}
+
+/// Outcome of a `CreateBackup`, `error` is set if no backup was written.
+#[derive(Serialize, RustSignal)]
+pub struct BackupCreated {
+    pub path: String,
+    pub libraries: u32,
+    pub error: Option<String>,
+}
+
+/// Answer to `ReadBackup`, `error` is set if the file is not a backup this version can restore.
+#[derive(Serialize, RustSignal)]
+pub struct BackupContents {
+    pub path: String,
+    pub version: u32,
+    pub created: String,
+    pub libraries: Vec<BackupLibrary>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, SignalPiece)]
+pub struct BackupLibrary {
+    pub id: String,
+    /// Root the library had when it was backed up.
+    pub root: String,
+    /// `root` exists on this device; if not, the library must be remapped.
+    pub exists: bool,
+    pub has_cache: bool,
+}
+
+/// Outcome of a `RestoreBackup`, `error` is set if nothing was restored.
+#[derive(Serialize, RustSignal)]
+pub struct BackupRestored {
+    pub path: String,
+    pub libraries: u32,
+    pub error: Option<String>,
+}
+
+/// Sent once the cache is ready; the books themselves are fetched with `GetLibraryPage`.
 #[derive(Serialize, SignalPiece)]
-pub struct DisplayLibrary {
-    pub data: Vec<BookData>,
+pub struct LibrarySummary {
+    pub total: u32,
+    /// Changes whenever the order of the books changes, so that pages fetched under an
+    /// older token can be discarded.
+    pub ordering_token: String,
+    pub version: u32,
+    /// The library root is unreachable (unmounted SD card, network share ...). The books are
+    /// the last known catalog and covers stored inside the library may be missing.
+    pub offline: bool,
+}
+
+/// Books changed by a cache refresh. Each delta bumps `version` by one; if it is not
+/// exactly one more than the last version seen, a delta was missed and Dart should
+/// send `ResyncLibrary`.
+#[derive(Serialize, RustSignal)]
+pub struct LibraryDelta {
+    pub version: u32,
+    pub added: Vec<BookData>,
+    pub updated: Vec<BookData>,
+    pub removed: Vec<String>,
+    pub total: u32,
+    pub ordering_token: String,
+}
+
+/// Answer to `GetLibraryPage`. `total` counts the books matching the query.
+#[derive(Serialize, RustSignal)]
+pub struct LibraryPage {
+    pub offset: u32,
+    pub total: u32,
+    pub ordering_token: String,
+    pub query: Option<String>,
+    pub books: Vec<BookData>,
 }
 
 #[derive(Serialize, SignalPiece)]
@@ -32,3 +300,71 @@ pub struct BookData {
     pub cover_path: Option<String>,
     pub title: String,
 }
+
+/// File to read a book from, `None` if it could not be opened, see `error`.
+#[derive(Serialize, RustSignal)]
+pub struct BookOpened {
+    pub book_key: String,
+    pub path: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Metadata of the EPUB of a book, `None` if it could not be read or written, see `error`.
+#[derive(Serialize, RustSignal)]
+pub struct BookMetadataState {
+    pub book_key: String,
+    pub metadata: Option<EpubMetadataData>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub struct EpubMetadataData {
+    pub title: String,
+    pub authors: Vec<String>,
+    /// How the authors sort, e.g. `Tolkien, J. R. R.`.
+    pub author_sort: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub identifiers: Vec<IdentifierData>,
+    pub subjects: Vec<String>,
+    pub description: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub struct IdentifierData {
+    /// `isbn`, `uuid`, `amazon` ...
+    pub scheme: String,
+    pub value: String,
+}
+
+/// Overlay of a book, along with the metadata read from the book that it applies to.
+#[derive(Serialize, RustSignal)]
+pub struct MetadataOverlayState {
+    pub book_key: String,
+    pub overlay: Option<MetadataOverlayData>,
+    /// `description` is not known to the library and always `None`.
+    pub extracted: Option<EpubMetadataData>,
+    pub error: Option<String>,
+}
+
+/// Outcome of an `ExportOverlaySidecars`, `error` is set if the export failed.
+#[derive(Serialize, RustSignal)]
+pub struct OverlaySidecarsExported {
+    pub path: String,
+    /// Number of sidecars written.
+    pub books: u32,
+    pub error: Option<String>,
+}
+
+/// Fields left `None` keep the value read from the book. An empty `series` or
+/// `author_sort` hides the one of the book.
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub struct MetadataOverlayData {
+    pub title: Option<String>,
+    pub authors: Option<Vec<String>>,
+    pub author_sort: Option<String>,
+    pub series: Option<String>,
+    pub series_index: Option<f64>,
+    pub tags: Option<Vec<String>>,
+    pub identifiers: Option<Vec<IdentifierData>>,
+}
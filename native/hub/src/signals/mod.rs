@@ -1,2 +1,7 @@
 pub mod utility_signals;
 pub mod library_signals;
+pub mod server_signals;
+pub mod opds_client_signals;
+pub mod reading_signals;
+pub mod collection_signals;
+pub mod organize_signals;
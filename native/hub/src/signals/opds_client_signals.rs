@@ -0,0 +1,82 @@
+use rinf::{DartSignal, RustSignal, SignalPiece};
+use serde::{Deserialize, Serialize};
+
+/// Username and password sent with HTTP Basic authentication to a catalog.
+#[derive(Deserialize, SignalPiece)]
+pub struct OpdsCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Fetches an OPDS 1.2 or 2.0 feed, answered with an `OpdsFeedPage`.
+#[derive(Deserialize, DartSignal)]
+pub struct BrowseOpdsFeed {
+    pub url: String,
+    pub credentials: Option<OpdsCredentials>,
+}
+
+/// Searches a catalog through the `search_url` of one of its pages, answered with an
+/// `OpdsFeedPage`.
+#[derive(Deserialize, DartSignal)]
+pub struct SearchOpdsFeed {
+    pub search_url: String,
+    pub query: String,
+    pub credentials: Option<OpdsCredentials>,
+}
+
+/// Downloads an acquisition link into the root of the registered library at `library`, then
+/// indexes the new book. Progress is reported with `OpdsDownloadProgress`. Without a
+/// `file_name`, the one suggested by the server is used.
+#[derive(Deserialize, DartSignal)]
+pub struct DownloadOpdsBook {
+    pub url: String,
+    pub library: String,
+    pub file_name: Option<String>,
+    pub credentials: Option<OpdsCredentials>,
+}
+
+/// A page of a remote catalog. Every URL is absolute. `error` is set if the feed could not
+/// be fetched or read, in which case the page is empty.
+#[derive(Serialize, RustSignal)]
+pub struct OpdsFeedPage {
+    /// URL that was asked for.
+    pub url: String,
+    pub title: String,
+    pub entries: Vec<OpdsEntry>,
+    pub next_url: Option<String>,
+    pub previous_url: Option<String>,
+    /// Pass to `SearchOpdsFeed` to search the catalog.
+    pub search_url: Option<String>,
+    pub error: Option<String>,
+}
+
+/// A link to another feed (`navigation_url`), a book (`acquisitions`), or both.
+#[derive(Serialize, SignalPiece)]
+pub struct OpdsEntry {
+    pub id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    /// Plain text, markup is stripped.
+    pub summary: Option<String>,
+    pub navigation_url: Option<String>,
+    pub cover_url: Option<String>,
+    pub acquisitions: Vec<OpdsAcquisition>,
+}
+
+#[derive(Serialize, SignalPiece)]
+pub struct OpdsAcquisition {
+    pub url: String,
+    pub mime_type: String,
+}
+
+/// Sent while a `DownloadOpdsBook` runs, then once more with `done` set. On success `path`
+/// is where the book was saved; on failure `error` says why and nothing is left behind.
+#[derive(Serialize, RustSignal)]
+pub struct OpdsDownloadProgress {
+    pub url: String,
+    pub received: u64,
+    pub total: Option<u64>,
+    pub done: bool,
+    pub path: Option<String>,
+    pub error: Option<String>,
+}
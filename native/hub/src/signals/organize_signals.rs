@@ -0,0 +1,61 @@
+use rinf::{DartSignal, RustSignal, SignalPiece};
+use serde::{Deserialize, Serialize};
+
+/// Asks where a template such as `{author_sort}/[{series}/][{series_index} - ]{title}.epub`
+/// would put the books of the open library, without moving anything. Fields are `title`,
+/// `author`, `authors`, `author_sort`, `series` and `series_index`; parts inside `[...]`
+/// are left out when one of their fields is empty, while books missing a field used outside
+/// of them are skipped. Answered with `OrganizePreview`.
+#[derive(Deserialize, DartSignal)]
+pub struct PreviewOrganize {
+    pub template: String,
+}
+
+/// Moves the books of the open library where `template` puts them, see `PreviewOrganize`.
+/// Their progress, annotations, overlays and places in collections follow. Answered with a
+/// `LibraryDelta`, the moved books being removed under their old key and added under the
+/// new one, then `LibraryOrganized`.
+#[derive(Deserialize, DartSignal)]
+pub struct OrganizeLibrary {
+    pub template: String,
+}
+
+/// Puts back the books moved by the last `OrganizeLibrary`. Answered like it.
+#[derive(Deserialize, DartSignal)]
+pub struct UndoOrganize;
+
+/// Answer to `PreviewOrganize`, `error` is set if the template is invalid.
+#[derive(Serialize, RustSignal)]
+pub struct OrganizePreview {
+    pub template: String,
+    /// Books that would move. Names already taken get ` (2)`, ` (3)` ... added.
+    pub moves: Vec<BookMoveData>,
+    /// Number of books already in place.
+    pub unchanged: u32,
+    pub skipped: Vec<SkippedBookData>,
+    pub error: Option<String>,
+}
+
+/// Outcome of an `OrganizeLibrary` or `UndoOrganize`.
+#[derive(Serialize, RustSignal)]
+pub struct LibraryOrganized {
+    pub moved: u32,
+    /// Books left where they were, e.g. inside archives or because their file is in use.
+    pub skipped: Vec<SkippedBookData>,
+    pub error: Option<String>,
+}
+
+/// Paths relative to the library root, with `/` separators.
+#[derive(Serialize, SignalPiece)]
+pub struct BookMoveData {
+    pub book_key: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Serialize, SignalPiece)]
+pub struct SkippedBookData {
+    pub book_key: String,
+    pub path: String,
+    pub reason: String,
+}
@@ -0,0 +1,188 @@
+use rinf::{DartSignal, RustSignal, SignalPiece};
+use serde::{Deserialize, Serialize};
+
+/// Saves where the reader stopped in a book of the open library, answered with `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetReadingProgress {
+    pub book_key: String,
+    /// An EPUB CFI.
+    pub locator: String,
+    /// From 0 to 1.
+    pub percent: f64,
+}
+
+/// Adds an annotation, or replaces the one with the same id. Leave `id` empty for a new
+/// annotation. Answered with `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct SaveAnnotation {
+    pub book_key: String,
+    pub annotation: AnnotationData,
+}
+
+/// Deletes an annotation on every device, answered with `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct DeleteAnnotation {
+    pub book_key: String,
+    pub annotation_id: String,
+}
+
+/// Marks a book as to read, reading, finished or abandoned, or clears its status. Finishing
+/// a book also dates it unless it has a date already. Answered with `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetReadingStatus {
+    pub book_key: String,
+    pub status: Option<ReadingStatusSetting>,
+}
+
+/// Rates a book from 1 to 5, or clears its rating. Answered with `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetRating {
+    pub book_key: String,
+    pub rating: Option<u8>,
+}
+
+/// Replaces the tags of a book, answered with `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetUserTags {
+    pub book_key: String,
+    pub tags: Vec<String>,
+}
+
+/// Sets or clears when a book was finished, answered with `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetDateFinished {
+    pub book_key: String,
+    /// Milliseconds since the Unix epoch.
+    pub date: Option<u64>,
+}
+
+/// Asks for the progress, annotations, status, rating and tags of a book, answered with
+/// `ReadingData`.
+#[derive(Deserialize, DartSignal)]
+pub struct GetReadingData {
+    pub book_key: String,
+}
+
+/// What the reader keeps about a book, merged from every device reading the library.
+#[derive(Serialize, RustSignal)]
+pub struct ReadingData {
+    pub book_key: String,
+    pub progress: Option<ProgressData>,
+    pub annotations: Vec<AnnotationData>,
+    pub status: Option<ReadingStatusSetting>,
+    pub rating: Option<u8>,
+    pub tags: Vec<String>,
+    /// Milliseconds since the Unix epoch.
+    pub date_finished: Option<u64>,
+}
+
+/// Sent after a refresh picked up changes made on other devices. Ask for the `ReadingData`
+/// of the books that are shown.
+#[derive(Serialize, RustSignal)]
+pub struct ReadingDataSynced {
+    pub book_keys: Vec<String>,
+}
+
+#[derive(Serialize, SignalPiece)]
+pub struct ProgressData {
+    pub locator: String,
+    pub locator_type: LocatorType,
+    pub percent: f64,
+    /// Milliseconds since the Unix epoch.
+    pub updated: u64,
+    /// Id of the device the progress was saved on.
+    pub device: String,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub struct AnnotationData {
+    pub id: String,
+    pub kind: AnnotationType,
+    pub locator: String,
+    /// Kept as is when saving, so annotations imported from KOReader stay XPointers.
+    pub locator_type: LocatorType,
+    /// The highlighted text.
+    pub text: Option<String>,
+    pub note: Option<String>,
+    pub color: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub created: u64,
+    /// Milliseconds since the Unix epoch, ignored when saving.
+    pub updated: u64,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub enum AnnotationType {
+    Highlight,
+    Note,
+    Bookmark,
+}
+
+/// What a locator is: an EPUB CFI, as the reader of the app writes them, or an XPointer
+/// from KOReader, which needs converting before the reader can follow it.
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub enum LocatorType {
+    Cfi,
+    XPointer,
+}
+
+#[derive(Serialize, Deserialize, SignalPiece)]
+pub enum ReadingStatusSetting {
+    ToRead,
+    Reading,
+    Finished,
+    Abandoned,
+}
+
+/// Syncs progress with a kosync server for KOReader devices, creating the account first if
+/// `register` is set. The account is checked before it is saved. Answered with
+/// `KosyncAccountState`.
+#[derive(Deserialize, DartSignal)]
+pub struct SetKosyncAccount {
+    /// Base URL of the server, e.g. `https://sync.koreader.rocks`.
+    pub server: String,
+    pub username: String,
+    pub password: String,
+    pub register: bool,
+}
+
+/// Stops syncing with the kosync server, answered with `KosyncAccountState`.
+#[derive(Deserialize, DartSignal)]
+pub struct RemoveKosyncAccount;
+
+#[derive(Deserialize, DartSignal)]
+pub struct GetKosyncAccount;
+
+/// The kosync account in use, if any. `error` is set if the last change was refused, in
+/// which case the previous account is kept.
+#[derive(Serialize, RustSignal)]
+pub struct KosyncAccountState {
+    pub server: Option<String>,
+    pub username: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Syncs the progress of a book with the kosync server: the newer of the local and remote
+/// progress wins. Answered with `KosyncProgressSynced`, then `ReadingData` if the progress
+/// was pulled.
+#[derive(Deserialize, DartSignal)]
+pub struct SyncKosyncProgress {
+    pub book_key: String,
+}
+
+/// `outcome` is `None` if the sync failed, see `error`.
+#[derive(Serialize, RustSignal)]
+pub struct KosyncProgressSynced {
+    pub book_key: String,
+    pub outcome: Option<KosyncOutcome>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, SignalPiece)]
+pub enum KosyncOutcome {
+    /// The server had newer progress, which is now the local one.
+    Pulled,
+    /// The local progress was newer and was sent to the server.
+    Pushed,
+    UpToDate,
+}
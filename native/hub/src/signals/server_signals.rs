@@ -0,0 +1,53 @@
+use rinf::{DartSignal, RustSignal};
+use serde::{Deserialize, Serialize};
+
+/// Starts publishing the libraries as an OPDS catalog on the local network, restarting the
+/// server if it already runs. Clients must authenticate if both `username` and `password`
+/// are set; setting only one of them is an error.
+#[derive(Deserialize, DartSignal)]
+pub struct StartCatalogServer {
+    /// Port to listen on, `0` picks a free one.
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Publish every registered library instead of only the open one.
+    pub all_libraries: bool,
+}
+
+#[derive(Deserialize, DartSignal)]
+pub struct StopCatalogServer;
+
+/// Sent whenever the catalog server starts, stops or fails to start.
+#[derive(Serialize, RustSignal)]
+pub struct CatalogServerState {
+    pub running: bool,
+    pub port: u16,
+    pub error: Option<String>,
+}
+
+/// Starts a kosync server KOReader devices can sync their progress with, restarting it if it
+/// already runs. With `allow_registration` set, devices can create accounts on it.
+///
+/// The server listens on every interface (`0.0.0.0`) so devices on the local network reach
+/// it, over plain HTTP: anyone on the network can reach it too, and only the MD5 of the
+/// passwords is ever sent. It keeps progress apart from the libraries; for this app to
+/// sync with the devices, set the kosync account (`SetKosyncAccount`) to
+/// `http://127.0.0.1:<port>` with a username the devices use. That account follows the
+/// server when it restarts on another port.
+#[derive(Deserialize, DartSignal)]
+pub struct StartKosyncServer {
+    /// Port to listen on, `0` picks a free one.
+    pub port: u16,
+    pub allow_registration: bool,
+}
+
+#[derive(Deserialize, DartSignal)]
+pub struct StopKosyncServer;
+
+/// Sent whenever the kosync server starts, stops or fails to start.
+#[derive(Serialize, RustSignal)]
+pub struct KosyncServerState {
+    pub running: bool,
+    pub port: u16,
+    pub error: Option<String>,
+}
@@ -0,0 +1,170 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Ok, anyhow};
+use zip::ZipArchive;
+
+/// Appended to the name of an archive to form the virtual path of the books inside it,
+/// e.g. `bundle.zip!/book.epub`.
+const MARKER: char = '!';
+
+/// Largest book read out of an archive. Archived books are held in memory while they
+/// are parsed or read, never unpacked to disk.
+const MAX_ENTRY_BYTES: u64 = 512 * 1024 * 1024;
+
+/// Returns `true` if the file is an archive that may hold books.
+pub fn is_archive(path: &Path) -> bool {
+    return path
+        .extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"));
+}
+
+/// Lists the EPUBs inside an archive as virtual paths.
+pub fn list_books(archive: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let zip = ZipArchive::new(BufReader::new(File::open(archive)?))?;
+    let books = zip
+        .file_names()
+        .filter(|name| !name.ends_with('/') && name.to_ascii_lowercase().ends_with(".epub"))
+        .filter_map(|name| virtual_path(archive, name))
+        .collect();
+    return Ok(books);
+}
+
+/// Path of the entry `entry` of `archive`, `None` if the entry would escape the archive.
+pub fn virtual_path(archive: &Path, entry: &str) -> Option<PathBuf> {
+    let mut name = archive.file_name()?.to_os_string();
+    name.push(MARKER.to_string());
+    let mut path = archive.with_file_name(name);
+    for component in Path::new(entry).components() {
+        match component {
+            Component::Normal(segment) => path.push(segment),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    return Some(path);
+}
+
+/// Splits a virtual path into the archive on disk and the name of the entry inside it,
+/// `None` for regular files.
+pub fn split(path: &Path) -> Option<(PathBuf, String)> {
+    for ancestor in path.ancestors() {
+        let Some(name) = ancestor.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let Some(archive_name) = name.strip_suffix(MARKER) else {
+            continue;
+        };
+        let archive = ancestor.with_file_name(archive_name);
+        if !is_archive(&archive) {
+            continue;
+        }
+        let entry: Vec<String> = path
+            .strip_prefix(ancestor)
+            .ok()?
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        return Some((archive, entry.join("/")));
+    }
+    return None;
+}
+
+/// The file on disk holding `path`: its archive for virtual paths, the path itself otherwise.
+pub fn physical_path(path: &Path) -> PathBuf {
+    return match split(path) {
+        Some((archive, _)) => archive,
+        None => path.to_path_buf(),
+    };
+}
+
+/// Reads a book into memory, out of its archive for virtual paths.
+pub fn read_book(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let Some((archive, entry)) = split(path) else {
+        return Ok(fs::read(path)?);
+    };
+    let mut zip = ZipArchive::new(BufReader::new(File::open(&archive)?))?;
+    let file = zip.by_name(&entry)?;
+    if file.size() > MAX_ENTRY_BYTES {
+        return Err(anyhow!(
+            "{} is too large to read from {} ({} bytes)",
+            entry,
+            archive.display(),
+            file.size()
+        ));
+    }
+    let mut data = Vec::with_capacity(file.size() as usize);
+    file.take(MAX_ENTRY_BYTES).read_to_end(&mut data)?;
+    return Ok(data);
+}
+
+/// Makes a book readable from a file of its own: regular books are opened where they are,
+/// books inside an archive are written to `destination` with [`read_book`]. A copy newer
+/// than the archive is reused.
+pub fn extract_book(path: &Path, destination: &Path) -> anyhow::Result<PathBuf> {
+    let Some((archive, _)) = split(path) else {
+        return Ok(path.to_path_buf());
+    };
+    let archive_modified = fs::metadata(&archive)?.modified()?;
+    let extracted = fs::metadata(destination).and_then(|md| md.modified());
+    if extracted.is_ok_and(|modified| modified >= archive_modified) {
+        return Ok(destination.to_path_buf());
+    }
+    let data = read_book(path)?;
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Written aside first, so that an interrupted extraction is never taken for a book.
+    let partial = destination.with_extension("partial");
+    fs::write(&partial, data)?;
+    fs::rename(&partial, destination)?;
+    return Ok(destination.to_path_buf());
+}
+
+/// Size of a book in bytes, uncompressed for books inside an archive.
+pub fn book_size(path: &Path) -> anyhow::Result<u64> {
+    let Some((archive, entry)) = split(path) else {
+        return Ok(fs::metadata(path)?.len());
+    };
+    let mut zip = ZipArchive::new(BufReader::new(File::open(&archive)?))?;
+    return Ok(zip.by_name(&entry)?.size());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    #[test]
+    fn extracts_books_out_of_archives() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("spectecle-archive-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let archive = dir.join("bundle.zip");
+        let mut zip = ZipWriter::new(File::create(&archive)?);
+        zip.start_file("novels/dune.epub", SimpleFileOptions::default())?;
+        zip.write_all(b"dune")?;
+        zip.finish()?;
+
+        let books = list_books(&archive)?;
+        assert_eq!(
+            books,
+            [dir.join("bundle.zip!").join("novels").join("dune.epub")]
+        );
+        let destination = dir.join("opened").join("dune.epub");
+        let opened = extract_book(&books[0], &destination)?;
+        assert_eq!(opened, destination);
+        assert_eq!(fs::read(&opened)?, b"dune");
+        // Regular books are opened in place.
+        assert_eq!(extract_book(&archive, &destination)?, archive);
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+}
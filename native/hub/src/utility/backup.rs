@@ -0,0 +1,756 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::utility::{
+    cache::Cache,
+    epub_edit,
+    library::{CacheLocation, DATA_DIR, Library, LibrarySettings},
+    opds, sync,
+};
+
+const MANIFEST: &str = "manifest.json";
+const FORMAT: &str = "spectecle-backup";
+/// Version of the archive layout, bumped whenever it changes. Backups of any earlier
+/// version can still be restored.
+pub const BACKUP_VERSION: u32 = 1;
+
+/// Describes a backup; stored as `manifest.json` at the root of the archive. The user data of
+/// each library, i.e. its data folder without the cache, is stored under
+/// `libraries/<id>/data/`, the changes waiting to be written to it under
+/// `libraries/<id>/local/` and its cache, if included, under `libraries/<id>/cache/`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub format: String,
+    pub version: u32,
+    pub created: String,
+    /// Id of the library that was open.
+    pub open_lib: Option<String>,
+    pub libraries: Vec<BackedUpLibrary>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackedUpLibrary {
+    pub id: String,
+    pub root: PathBuf,
+    pub settings: LibrarySettings,
+    pub has_cache: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreMode {
+    /// Libraries are added to the registered ones. The sync logs of a library that exists
+    /// already are merged entry by entry and the collections and metadata overlays it lacks
+    /// are added, by id; its other settings and files are kept.
+    Merge,
+    /// The registered libraries and the user data of the restored ones are replaced.
+    Replace,
+}
+
+/// Packs the registered libraries, their settings and user data into a single archive at
+/// `destination`. Caches can be rebuilt from the books, so they are only included with
+/// `include_cache`, which also keeps the covers and metadata imported from Calibre.
+/// Libraries that are offline are registered in the backup without their data.
+pub fn create_backup(
+    support_dir: &Path,
+    library: &Library,
+    destination: &Path,
+    include_cache: bool,
+) -> anyhow::Result<BackupManifest> {
+    let mut partial = destination.as_os_str().to_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let mut zip = ZipWriter::new(File::create(&partial)?);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let result = (|| {
+        let mut libraries = Vec::new();
+        for root in library.get_libraries() {
+            let settings = library.get_settings(root);
+            let prefix = format!("libraries/{}", settings.id);
+            let cache_dir = settings.cache_dir(root, support_dir);
+            let data_dir = root.join(DATA_DIR);
+            if data_dir.is_dir() {
+                // Originals of edited books are as large as the books, which are not backed up.
+                let originals = data_dir.join(epub_edit::ORIGINALS_DIR);
+                add_dir(
+                    &mut zip,
+                    &data_dir,
+                    &format!("{}/data", prefix),
+                    &[&cache_dir, &originals],
+                    options,
+                )?;
+            }
+            let local_dir = settings.sync_dir(support_dir);
+            if local_dir.is_dir() {
+                add_dir(
+                    &mut zip,
+                    &local_dir,
+                    &format!("{}/local", prefix),
+                    &[],
+                    options,
+                )?;
+            }
+            let has_cache = include_cache && cache_dir.join("cache.json").is_file();
+            if has_cache {
+                add_dir(
+                    &mut zip,
+                    &cache_dir,
+                    &format!("{}/cache", prefix),
+                    &[],
+                    options,
+                )?;
+            }
+            libraries.push(BackedUpLibrary {
+                id: settings.id.clone(),
+                root: root.clone(),
+                settings,
+                has_cache,
+            });
+        }
+        let manifest = BackupManifest {
+            format: FORMAT.to_string(),
+            version: BACKUP_VERSION,
+            created: opds::rfc3339(opds::now_millis()),
+            open_lib: library
+                .get_open_lib()
+                .map(|open| library.get_settings(&open).id),
+            libraries,
+        };
+        zip.start_file(MANIFEST, options)?;
+        serde_json::to_writer_pretty(&mut zip, &manifest)?;
+        zip.finish()?;
+        return Ok(manifest);
+    })();
+    match result {
+        anyhow::Result::Ok(manifest) => {
+            fs::rename(&partial, destination)?;
+            return Ok(manifest);
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    }
+}
+
+/// Reads the manifest of the backup at `path`, failing if it is not a backup or was made by
+/// a newer version of the app.
+pub fn read_manifest(path: &Path) -> anyhow::Result<BackupManifest> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    return manifest(&mut zip);
+}
+
+/// Restores the backup at `path` into `library`, which is written back to `support_dir`.
+///
+/// Each library goes to the root `remaps` gives for its backed up root; when merging, a
+/// library that is already registered under the same id stays where it is. Every target
+/// root must exist, otherwise nothing is restored and the error lists the missing ones.
+/// When replacing, the restored folders are swapped in only once the whole backup was
+/// extracted, so a failed restore leaves the files of every library as they were.
+/// Returns the roots of the restored libraries.
+pub fn restore_backup(
+    support_dir: &Path,
+    library: &mut Library,
+    path: &Path,
+    remaps: &HashMap<PathBuf, PathBuf>,
+    mode: RestoreMode,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut zip = ZipArchive::new(BufReader::new(File::open(path)?))?;
+    let manifest = manifest(&mut zip)?;
+
+    let targets: Vec<(&BackedUpLibrary, PathBuf)> = manifest
+        .libraries
+        .iter()
+        .map(|lib| {
+            let root = remaps
+                .get(&lib.root)
+                .cloned()
+                .or_else(|| match mode {
+                    RestoreMode::Merge => library.find_by_id(&lib.id),
+                    RestoreMode::Replace => None,
+                })
+                .unwrap_or_else(|| lib.root.clone());
+            (lib, root)
+        })
+        .collect();
+    let missing: Vec<String> = targets
+        .iter()
+        .filter(|(_, root)| !root.is_dir())
+        .map(|(_, root)| root.display().to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "Library folders not found, remap them to restore: {}",
+            missing.join(", ")
+        ));
+    }
+
+    let previous_open = library.get_open_lib();
+    if mode == RestoreMode::Replace {
+        library.clear();
+    }
+    for (lib, root) in &targets {
+        // A library registered at the target root keeps its settings when merging, gaining
+        // the collections and overlays of the backup it lacks.
+        if library.has_lib_path(root) {
+            merge_settings(library.settings_mut(root.clone()), &lib.settings);
+        } else {
+            let mut settings = lib.settings.clone();
+            // Custom cache folders may not exist on this device.
+            if matches!(settings.cache_location, CacheLocation::Custom(_))
+                && !Cache::is_writable(&settings.cache_dir(root, support_dir))
+            {
+                settings.cache_location = CacheLocation::AppSupport;
+            }
+            // The same library restored to a second root needs an id of its own.
+            if library.find_by_id(&settings.id).is_some() {
+                settings.id.clear();
+            }
+            library.register(root.clone(), settings);
+        }
+    }
+
+    // What is replaced is extracted next to it first and only swapped in once every library
+    // is extracted, so that a corrupt backup or a full disk leaves the current files as they
+    // were.
+    let mut staged: Vec<Staged> = Vec::new();
+    let result = (|| {
+        for (lib, root) in &targets {
+            let settings = library.get_settings(root);
+            let cache_dir = settings.cache_dir(root, support_dir);
+            let data_dir = root.join(DATA_DIR);
+            let local_dir = settings.sync_dir(support_dir);
+            let prefix = PathBuf::from("libraries").join(&lib.id);
+            if lib.has_cache
+                && (mode == RestoreMode::Replace || !cache_dir.join("cache.json").exists())
+            {
+                stage(
+                    &mut zip,
+                    &prefix.join("cache"),
+                    &cache_dir,
+                    vec![],
+                    &mut staged,
+                )?;
+            }
+            match mode {
+                RestoreMode::Replace => {
+                    stage(
+                        &mut zip,
+                        &prefix.join("local"),
+                        &local_dir,
+                        vec![],
+                        &mut staged,
+                    )?;
+                    // Originals of edited books are not in backups, see `backup`.
+                    let keep = vec![cache_dir, data_dir.join(epub_edit::ORIGINALS_DIR)];
+                    stage(&mut zip, &prefix.join("data"), &data_dir, keep, &mut staged)?;
+                }
+                // Merging only adds files and entries to the logs, so it happens in place.
+                RestoreMode::Merge => {
+                    let data_logs = Existing::MergeLogs(Path::new(sync::SYNC_DIR));
+                    extract(&mut zip, &prefix.join("data"), &data_dir, data_logs)?;
+                    let local_logs = Existing::MergeLogs(Path::new(""));
+                    extract(&mut zip, &prefix.join("local"), &local_dir, local_logs)?;
+                }
+            }
+        }
+        // A cache inside the data folder is swapped before the data folder moves it over.
+        for folder in &staged {
+            folder.swap_in()?;
+        }
+        return Ok(());
+    })();
+    if let Err(e) = result {
+        for folder in staged.iter().rev() {
+            if let Err(rollback) = folder.roll_back() {
+                println!(
+                    "Failed to roll back {}: {:#}",
+                    folder.target.display(),
+                    rollback
+                );
+            }
+        }
+        return Err(e);
+    }
+    for folder in &staged {
+        folder.finish();
+    }
+
+    let backup_open = manifest
+        .open_lib
+        .as_ref()
+        .and_then(|id| targets.iter().find(|(lib, _)| lib.id == *id))
+        .or(targets.first())
+        .map(|(_, root)| root.clone());
+    let open = match mode {
+        RestoreMode::Merge => previous_open.or(backup_open),
+        RestoreMode::Replace => backup_open,
+    };
+    library.switch_to(open)?;
+    library.write(support_dir)?;
+    return Ok(targets.into_iter().map(|(_, root)| root).collect());
+}
+
+fn manifest(zip: &mut ZipArchive<BufReader<File>>) -> anyhow::Result<BackupManifest> {
+    let file = zip
+        .by_name(MANIFEST)
+        .map_err(|_| anyhow!("Not a backup, {} is missing", MANIFEST))?;
+    let manifest: BackupManifest = serde_json::from_reader(file)?;
+    if manifest.format != FORMAT {
+        return Err(anyhow!("Not a backup, unknown format {}", manifest.format));
+    }
+    if manifest.version > BACKUP_VERSION {
+        return Err(anyhow!(
+            "The backup was made by a newer version of the app (version {})",
+            manifest.version
+        ));
+    }
+    return Ok(manifest);
+}
+
+/// Adds the files under `dir` to the archive under `prefix`, skipping `exclude`.
+fn add_dir(
+    zip: &mut ZipWriter<File>,
+    dir: &Path,
+    prefix: &str,
+    exclude: &[&Path],
+    options: SimpleFileOptions,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if exclude.contains(&path.as_path()) {
+            continue;
+        }
+        let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            add_dir(zip, &path, &name, exclude, options)?;
+        } else if file_type.is_file() {
+            zip.start_file(name, options)?;
+            io::copy(&mut File::open(&path)?, zip)?;
+        }
+    }
+    return Ok(());
+}
+
+/// What [`extract`] does with the files that exist already.
+#[derive(Debug, Clone, Copy)]
+enum Existing<'a> {
+    Replace,
+    /// Merges the sync logs in this folder of the target with those of the backup, see
+    /// [`sync::merge_log`], and keeps the other files.
+    MergeLogs(&'a Path),
+}
+
+/// Extracts the entries of the archive under `prefix` into `target`.
+fn extract(
+    zip: &mut ZipArchive<BufReader<File>>,
+    prefix: &Path,
+    target: &Path,
+    existing: Existing,
+) -> anyhow::Result<()> {
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        // Entries that would land outside of the target are ignored.
+        let Some(name) = file.enclosed_name() else {
+            continue;
+        };
+        let anyhow::Result::Ok(relative) = name.strip_prefix(prefix) else {
+            continue;
+        };
+        let path = target.join(relative);
+        if file.is_dir() {
+            fs::create_dir_all(&path)?;
+            continue;
+        }
+        if let Existing::MergeLogs(logs) = existing
+            && path.exists()
+        {
+            if relative.parent() == Some(logs) && sync::is_log(&path) {
+                let mut log = String::new();
+                file.read_to_string(&mut log)?;
+                sync::merge_log(&path, &log)?;
+            }
+            continue;
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        io::copy(&mut file, &mut File::create(&path)?)?;
+    }
+    return Ok(());
+}
+
+/// Extracts the entries of the archive under `prefix` into a new [`Staged`] folder for
+/// `target`. It is added to `staged` even if extracting fails, so that it is cleaned up.
+fn stage(
+    zip: &mut ZipArchive<BufReader<File>>,
+    prefix: &Path,
+    target: &Path,
+    keep: Vec<PathBuf>,
+    staged: &mut Vec<Staged>,
+) -> anyhow::Result<()> {
+    let folder = Staged::new(target, keep)?;
+    let extracted = extract(zip, prefix, &folder.staging, Existing::Replace);
+    staged.push(folder);
+    return extracted;
+}
+
+/// A folder restored next to the one it replaces, `<target>.restoring`, then swapped in with
+/// renames. The replaced folder is kept as `<target>.previous` until the restore succeeded.
+struct Staged {
+    target: PathBuf,
+    /// Children of the target that are not in backups and move over to the restored folder.
+    keep: Vec<PathBuf>,
+    staging: PathBuf,
+    previous: PathBuf,
+}
+
+impl Staged {
+    fn new(target: &Path, keep: Vec<PathBuf>) -> anyhow::Result<Self> {
+        let sibling = |suffix: &str| {
+            let mut name = target.as_os_str().to_os_string();
+            name.push(suffix);
+            return PathBuf::from(name);
+        };
+        let staged = Self {
+            target: target.to_path_buf(),
+            keep: keep
+                .into_iter()
+                .filter(|path| path.parent() == Some(target))
+                .collect(),
+            staging: sibling(".restoring"),
+            previous: sibling(".previous"),
+        };
+        // Left over by a restore that was interrupted.
+        for dir in [&staged.staging, &staged.previous] {
+            if dir.exists() {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+        fs::create_dir_all(&staged.staging)?;
+        return Ok(staged);
+    }
+
+    fn swap_in(&self) -> anyhow::Result<()> {
+        for kept in &self.keep {
+            if let Some(name) = kept.file_name()
+                && kept.exists()
+            {
+                fs::rename(kept, self.staging.join(name))?;
+            }
+        }
+        if self.target.exists() {
+            fs::rename(&self.target, &self.previous)?;
+        }
+        fs::rename(&self.staging, &self.target)?;
+        return Ok(());
+    }
+
+    /// Undoes whatever part of [`Staged::swap_in`] happened and drops the restored folder.
+    fn roll_back(&self) -> anyhow::Result<()> {
+        if self.previous.exists() {
+            if !self.staging.exists() && self.target.exists() {
+                fs::rename(&self.target, &self.staging)?;
+            }
+            fs::rename(&self.previous, &self.target)?;
+        }
+        for kept in &self.keep {
+            if let Some(name) = kept.file_name()
+                && self.staging.join(name).exists()
+                && !kept.exists()
+            {
+                fs::rename(self.staging.join(name), kept)?;
+            }
+        }
+        if self.staging.exists() {
+            fs::remove_dir_all(&self.staging)?;
+        }
+        return Ok(());
+    }
+
+    fn finish(&self) {
+        if self.previous.exists()
+            && let Err(e) = fs::remove_dir_all(&self.previous)
+        {
+            println!("Failed to remove {}: {:#}", self.previous.display(), e);
+        }
+    }
+}
+
+/// Adds the collections and metadata overlays of `backup` that `settings` lacks, by id.
+fn merge_settings(settings: &mut LibrarySettings, backup: &LibrarySettings) {
+    for collection in &backup.collections {
+        if !settings.collections.iter().any(|c| c.id == collection.id) {
+            settings.collections.push(collection.clone());
+        }
+    }
+    for (identity, overlay) in &backup.overlays {
+        settings
+            .overlays
+            .entry(identity.clone())
+            .or_insert_with(|| overlay.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::utility::user_data::{Change, ChangeEntry};
+
+    struct Fixture {
+        dir: PathBuf,
+        support_dir: PathBuf,
+        root: PathBuf,
+        library: Library,
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> anyhow::Result<Self> {
+            let dir = std::env::temp_dir().join(format!(
+                "spectecle-backup-{}-{}",
+                name,
+                std::process::id()
+            ));
+            if dir.exists() {
+                fs::remove_dir_all(&dir)?;
+            }
+            let support_dir = dir.join("support");
+            let root = dir.join("Books");
+            fs::create_dir_all(&support_dir)?;
+            fs::create_dir_all(&root)?;
+            let mut library = Library::open(&support_dir)?;
+            library.add_lib_and_switch(root.clone());
+            library.write(&support_dir)?;
+            let fixture = Self {
+                dir,
+                support_dir,
+                root,
+                library,
+            };
+            fixture.write(&fixture.data_dir().join("sync/a.jsonl"), &log(&[1, 2]))?;
+            fixture.write(&fixture.data_dir().join("notes.txt"), "backed up")?;
+            fixture.write(&fixture.data_dir().join("originals/dune.epub"), "original")?;
+            fixture.write(&fixture.cache_dir().join("cache.json"), "cached")?;
+            fixture.write(&fixture.local_dir().join("b.jsonl"), &log(&[1]))?;
+            return Ok(fixture);
+        }
+
+        fn data_dir(&self) -> PathBuf {
+            return self.root.join(DATA_DIR);
+        }
+
+        fn cache_dir(&self) -> PathBuf {
+            return self
+                .library
+                .get_settings(&self.root)
+                .cache_dir(&self.root, &self.support_dir);
+        }
+
+        fn local_dir(&self) -> PathBuf {
+            return self
+                .library
+                .get_settings(&self.root)
+                .sync_dir(&self.support_dir);
+        }
+
+        fn write(&self, path: &Path, content: &str) -> anyhow::Result<()> {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, content)?;
+            return Ok(());
+        }
+
+        fn read(&self, path: &Path) -> String {
+            return fs::read_to_string(path).unwrap_or_default();
+        }
+
+        fn restore(&mut self, backup: &Path, mode: RestoreMode) -> anyhow::Result<()> {
+            restore_backup(
+                &self.support_dir,
+                &mut self.library,
+                backup,
+                &HashMap::new(),
+                mode,
+            )?;
+            return Ok(());
+        }
+
+        /// Nothing staged or replaced is left next to the restored folders.
+        fn assert_clean(&self) {
+            for dir in [self.data_dir(), self.cache_dir(), self.local_dir()] {
+                for suffix in [".restoring", ".previous"] {
+                    let mut name = dir.as_os_str().to_os_string();
+                    name.push(suffix);
+                    assert!(!PathBuf::from(name).exists(), "{}{}", dir.display(), suffix);
+                }
+            }
+        }
+    }
+
+    /// A log with a rating change at each of `times`.
+    fn log(times: &[u64]) -> String {
+        return times
+            .iter()
+            .map(|time| ChangeEntry {
+                time: *time,
+                device: "a".to_string(),
+                book: "uid:urn:uuid:dune".to_string(),
+                path: None,
+                change: Change::Rating { rating: Some(3) },
+            })
+            .filter_map(|entry| serde_json::to_string(&entry).ok())
+            .map(|line| line + "\n")
+            .collect();
+    }
+
+    fn times(log: &str) -> Vec<u64> {
+        return log
+            .lines()
+            .filter_map(|line| serde_json::from_str::<ChangeEntry>(line).ok())
+            .map(|entry| entry.time)
+            .collect();
+    }
+
+    #[test]
+    fn replaces_user_data_with_the_backup() -> anyhow::Result<()> {
+        let mut fixture = Fixture::new("replace")?;
+        let backup = fixture.dir.join("backup.zip");
+        let manifest = create_backup(&fixture.support_dir, &fixture.library, &backup, true)?;
+        assert_eq!(manifest.libraries.len(), 1);
+        assert!(manifest.libraries[0].has_cache);
+        assert_eq!(read_manifest(&backup)?.libraries[0].root, fixture.root);
+
+        fixture.write(&fixture.data_dir().join("sync/a.jsonl"), &log(&[1, 2, 3]))?;
+        fixture.write(&fixture.data_dir().join("later.txt"), "not backed up")?;
+        fixture.write(&fixture.cache_dir().join("cache.json"), "changed")?;
+        fs::remove_dir_all(fixture.local_dir())?;
+
+        fixture.restore(&backup, RestoreMode::Replace)?;
+        let data_dir = fixture.data_dir();
+        assert_eq!(times(&fixture.read(&data_dir.join("sync/a.jsonl"))), [1, 2]);
+        assert_eq!(fixture.read(&data_dir.join("notes.txt")), "backed up");
+        assert!(!data_dir.join("later.txt").exists());
+        // Originals are not backed up and survive the restore.
+        assert_eq!(
+            fixture.read(&data_dir.join("originals/dune.epub")),
+            "original"
+        );
+        assert_eq!(
+            fixture.read(&fixture.cache_dir().join("cache.json")),
+            "cached"
+        );
+        assert_eq!(
+            times(&fixture.read(&fixture.local_dir().join("b.jsonl"))),
+            [1]
+        );
+        assert_eq!(fixture.library.get_open_lib(), Some(fixture.root.clone()));
+        fixture.assert_clean();
+
+        fs::remove_dir_all(&fixture.dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn merges_user_data_with_the_backup() -> anyhow::Result<()> {
+        let mut fixture = Fixture::new("merge")?;
+        let backup = fixture.dir.join("backup.zip");
+        create_backup(&fixture.support_dir, &fixture.library, &backup, true)?;
+
+        fixture.write(&fixture.data_dir().join("sync/a.jsonl"), &log(&[1, 3]))?;
+        fixture.write(&fixture.data_dir().join("later.txt"), "not backed up")?;
+        fixture.write(&fixture.data_dir().join("notes.txt"), "changed")?;
+        fixture.write(&fixture.cache_dir().join("cache.json"), "changed")?;
+        fs::remove_dir_all(fixture.local_dir())?;
+
+        fixture.restore(&backup, RestoreMode::Merge)?;
+        let data_dir = fixture.data_dir();
+        assert_eq!(
+            times(&fixture.read(&data_dir.join("sync/a.jsonl"))),
+            [1, 2, 3]
+        );
+        // Other files that exist are kept.
+        assert_eq!(fixture.read(&data_dir.join("notes.txt")), "changed");
+        assert_eq!(fixture.read(&data_dir.join("later.txt")), "not backed up");
+        assert_eq!(
+            fixture.read(&fixture.cache_dir().join("cache.json")),
+            "changed"
+        );
+        assert_eq!(
+            times(&fixture.read(&fixture.local_dir().join("b.jsonl"))),
+            [1]
+        );
+        assert_eq!(fixture.library.get_libraries(), [fixture.root.clone()]);
+        fixture.assert_clean();
+
+        fs::remove_dir_all(&fixture.dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_user_data_when_the_backup_is_corrupt() -> anyhow::Result<()> {
+        let mut fixture = Fixture::new("corrupt")?;
+        let backup = fixture.dir.join("backup.zip");
+        create_backup(&fixture.support_dir, &fixture.library, &backup, true)?;
+
+        // The same backup with its entries stored, so that one of them can be damaged.
+        let corrupt = fixture.dir.join("corrupt.zip");
+        let mut source = ZipArchive::new(File::open(&backup)?)?;
+        let mut zip = ZipWriter::new(File::create(&corrupt)?);
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        // The damaged entry comes last, once everything before it was extracted.
+        let mut names: Vec<String> = source.file_names().map(String::from).collect();
+        names.sort_by_key(|name| name.ends_with("notes.txt"));
+        for name in names {
+            let mut data = Vec::new();
+            source.by_name(&name)?.read_to_end(&mut data)?;
+            zip.start_file(name, stored)?;
+            zip.write_all(&data)?;
+        }
+        zip.finish()?;
+        let mut bytes = fs::read(&corrupt)?;
+        let at = bytes
+            .windows(9)
+            .position(|w| w == b"backed up")
+            .ok_or_else(|| anyhow!("No entry to damage"))?;
+        bytes[at] = b'X';
+        fs::write(&corrupt, bytes)?;
+
+        fixture.write(&fixture.data_dir().join("sync/a.jsonl"), &log(&[1, 2, 3]))?;
+        fixture.write(&fixture.cache_dir().join("cache.json"), "changed")?;
+        assert!(fixture.restore(&corrupt, RestoreMode::Replace).is_err());
+        let data_dir = fixture.data_dir();
+        assert_eq!(
+            times(&fixture.read(&data_dir.join("sync/a.jsonl"))),
+            [1, 2, 3]
+        );
+        assert_eq!(fixture.read(&data_dir.join("notes.txt")), "backed up");
+        assert_eq!(
+            fixture.read(&data_dir.join("originals/dune.epub")),
+            "original"
+        );
+        assert_eq!(
+            fixture.read(&fixture.cache_dir().join("cache.json")),
+            "changed"
+        );
+        assert_eq!(
+            times(&fixture.read(&fixture.local_dir().join("b.jsonl"))),
+            [1]
+        );
+        fixture.assert_clean();
+
+        fs::remove_dir_all(&fixture.dir)?;
+        return Ok(());
+    }
+}
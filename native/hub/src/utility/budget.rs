@@ -0,0 +1,86 @@
+use std::sync::{Condvar, Mutex};
+
+use anyhow::anyhow;
+
+/// A pool of bytes shared between producers and consumers of large buffers.
+///
+/// Producers [`acquire`](ByteBudget::acquire) the size of a buffer before handing it over
+/// and block while the budget is exhausted; the bytes return to the pool when the
+/// [`BudgetGuard`] is dropped by the consumer. Buffers larger than the whole budget are
+/// refused, so the pool is never exceeded.
+#[derive(Debug)]
+pub struct ByteBudget {
+    total: usize,
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Bytes held from a [`ByteBudget`], given back on drop.
+#[derive(Debug)]
+pub struct BudgetGuard<'a> {
+    budget: &'a ByteBudget,
+    bytes: usize,
+}
+
+impl ByteBudget {
+    pub fn new(total: usize) -> Self {
+        return Self {
+            total,
+            available: Mutex::new(total),
+            released: Condvar::new(),
+        };
+    }
+
+    /// Blocks until `bytes` are available and takes them. Requests larger than the whole
+    /// budget could never be met without going over it and fail right away.
+    pub fn acquire(&self, bytes: usize) -> anyhow::Result<BudgetGuard<'_>> {
+        if bytes > self.total {
+            return Err(anyhow!(
+                "{} bytes exceed the budget of {} bytes",
+                bytes,
+                self.total
+            ));
+        }
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        while *available < bytes {
+            available = self
+                .released
+                .wait(available)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *available -= bytes;
+        return Ok(BudgetGuard {
+            budget: self,
+            bytes,
+        });
+    }
+
+    fn release(&self, bytes: usize) {
+        let mut available = self.available.lock().unwrap_or_else(|e| e.into_inner());
+        *available += bytes;
+        self.released.notify_all();
+    }
+}
+
+impl Drop for BudgetGuard<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_more_than_the_budget() -> anyhow::Result<()> {
+        let budget = ByteBudget::new(100);
+        assert!(budget.acquire(101).is_err());
+        let guard = budget.acquire(100)?;
+        drop(guard);
+        // Everything was given back.
+        let _first = budget.acquire(60)?;
+        let _second = budget.acquire(40)?;
+        return Ok(());
+    }
+}
@@ -1,31 +1,80 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fs::{self, File},
     hash::{DefaultHasher, Hash, Hasher},
     io::{Cursor, Read},
-    path::{Component, PathBuf},
-    time::UNIX_EPOCH,
+    path::{Path, PathBuf},
+    sync::{Mutex, mpsc},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::Ok;
+use anyhow::{Ok, anyhow};
 use fast_image_resize::{IntoImageView, Resizer};
-use image::{DynamicImage, ImageFormat, ImageReader, RgbaImage};
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use regex::Regex;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use rayon::{
+    ThreadPoolBuilder,
+    iter::{IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator},
+};
 use serde::{Deserialize, Serialize};
-use walkdir::{DirEntry, WalkDir};
 
-use crate::signals::library_signals::BookData;
+use crate::{
+    signals::library_signals::{BookData, LibraryDelta, LibraryPage, LibrarySummary},
+    utility::{
+        archive,
+        budget::{BudgetGuard, ByteBudget},
+        calibre::CalibreBook,
+        cover, koreader, library,
+        metadata::BookMetadata,
+        organize::BookMove,
+        overlay::MetadataOverlay,
+        parser::{self, ParserBackend},
+        scan::{self, ScanRules},
+        sync,
+    },
+};
+
+/// Bytes of cover images (encoded and decoded) allowed in flight while indexing. Phones get
+/// a smaller share; a cover costing more than the budget gets no thumbnail.
+const COVER_MEMORY_BUDGET: usize = match cfg!(any(target_os = "android", target_os = "ios")) {
+    true => 32 * 1024 * 1024,
+    false => 128 * 1024 * 1024,
+};
+/// Largest allocation decoding a single cover may make, so that a cover whose header lies
+/// about its size cannot go past [`COVER_MEMORY_BUDGET`] either.
+const COVER_DECODE_LIMIT: usize = if COVER_MEMORY_BUDGET < cover::MAX_DECODED_BYTES {
+    COVER_MEMORY_BUDGET
+} else {
+    cover::MAX_DECODED_BYTES
+};
 
-const BATCH_SIZE: usize = 48;
+/// Number of books looked up when checking the new root of a relocated library.
+const RELOCATE_SAMPLE_SIZE: usize = 20;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CacheItem {
     key: String,
     relative_path: String,
+    /// Identity of the book in the sync logs, see [`sync::book_identity`]. Empty for books
+    /// indexed before identities existed, until the next refresh.
+    #[serde(default)]
+    identity: String,
     last_modified: u128,
     title: String,
     has_cover: bool,
+    /// Backend that managed to parse the book, see [`parser::parse`].
+    #[serde(default)]
+    parser: ParserBackend,
+    #[serde(default)]
+    metadata: BookMetadata,
+    /// Modification time of the KOReader sidecar of the book when it was last imported, see
+    /// [`Cache::sidecars_imported`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sidecar_imported: Option<u128>,
+    /// Title and metadata with the overlay of the book on top, if it has one. Never saved,
+    /// so the cache only ever holds what was read from the books.
+    #[serde(skip)]
+    overlaid: Option<(String, BookMetadata)>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -37,123 +86,646 @@ pub struct CacheData {
 pub struct Cache {
     data: CacheData,
     cache_dir: PathBuf,
-    covers: HashMap<String, Vec<u8>>,
+    /// Keys of `data.items` in display order, see [`Cache::reorder`].
+    order: Vec<String>,
+    /// Hash of `order`, see [`Cache::ordering_token`].
+    order_hash: u64,
+    /// Bumped on every change to the items, so that Dart can tell when it missed a delta.
+    version: u32,
+    /// Metadata overlays of the library by book identity, see [`Cache::set_overlays`].
+    overlays: BTreeMap<String, MetadataOverlay>,
+}
+
+/// Keys of the books touched by a [`Cache::refresh`].
+#[derive(Debug, Default)]
+pub struct CacheChanges {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    /// KOReader sidecars found while scanning that changed since they were last imported.
+    pub sidecars: Vec<ChangedSidecar>,
+}
+
+/// The KOReader sidecar of a book, see [`koreader::find_sidecar`], changed since it was
+/// last imported.
+#[derive(Debug)]
+pub struct ChangedSidecar {
+    pub key: String,
+    pub path: PathBuf,
+    /// Modification time of the sidecar when it was found.
+    pub modified: u128,
+}
+
+impl CacheItem {
+    pub fn key(&self) -> &str {
+        return &self.key;
+    }
+
+    pub fn relative_path(&self) -> &str {
+        return &self.relative_path;
+    }
+
+    /// Identity of the book in the sync logs, empty if it is not known yet.
+    pub fn identity(&self) -> &str {
+        return &self.identity;
+    }
+
+    /// Title of the book, corrected by its overlay if it has one.
+    pub fn title(&self) -> &str {
+        return match &self.overlaid {
+            Some((title, _)) => title,
+            None => &self.title,
+        };
+    }
+
+    /// Modification time of the book file, in milliseconds since the Unix epoch.
+    pub fn last_modified(&self) -> u128 {
+        return self.last_modified;
+    }
+
+    /// Metadata of the book, corrected by its overlay if it has one.
+    pub fn metadata(&self) -> &BookMetadata {
+        return match &self.overlaid {
+            Some((_, metadata)) => metadata,
+            None => &self.metadata,
+        };
+    }
+
+    /// Title and metadata as read from the book or Calibre, without the overlay.
+    pub fn extracted(&self) -> (&str, &BookMetadata) {
+        return (&self.title, &self.metadata);
+    }
+
+    pub fn has_overlay(&self) -> bool {
+        return self.overlaid.is_some();
+    }
+
+    /// Returns `true` if the title, path, authors, series or tags contain `query`,
+    /// which must already be lowercase.
+    pub fn matches(&self, query: &str) -> bool {
+        let contains = |text: &str| text.to_lowercase().contains(query);
+        let metadata = self.metadata();
+        return contains(self.title())
+            || contains(&self.relative_path)
+            || metadata.authors.iter().any(|a| contains(a))
+            || metadata.series.as_deref().is_some_and(contains)
+            || metadata.tags.iter().any(|t| contains(t));
+    }
+
+    fn apply_overlay(&mut self, overlays: &BTreeMap<String, MetadataOverlay>) {
+        self.overlaid = overlays
+            .get(&self.identity)
+            .map(|overlay| overlay.apply(&self.title, &self.metadata));
+    }
+}
+
+impl CacheChanges {
+    pub fn is_empty(&self) -> bool {
+        return self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty();
+    }
 }
 
 impl Cache {
-    pub fn open(open_lib: PathBuf) -> anyhow::Result<Self> {
-        let cache_dir_path = open_lib.join(".spectecle/cache");
-        let cache_file_path = cache_dir_path.join("cache.json");
-        if cache_file_path.exists() {
-            let mut cache_file = File::open(cache_file_path)?;
-            let mut content = String::new();
-            cache_file.read_to_string(&mut content)?;
-            let data: CacheData = serde_json::from_str(&content)?;
-            return Ok(Self {
-                data,
-                cache_dir: cache_dir_path,
-                covers: HashMap::new(),
-            });
+    /// Opens the cache stored in `cache_dir_path`, creating an empty one if needed.
+    pub fn open(cache_dir_path: PathBuf) -> anyhow::Result<Self> {
+        let cache = Self::open_read_only(cache_dir_path)?;
+        if !cache.cache_dir.join("cache.json").exists() {
+            fs::create_dir_all(&cache.cache_dir)?;
+            cache.write_cache_file()?;
         }
-        std::fs::create_dir_all(&cache_dir_path)?;
-        let cache = Self {
-            data: CacheData {
+        return Ok(cache);
+    }
+
+    /// Opens the cache stored in `cache_dir_path` without touching the disk, as an empty
+    /// cache if there is none. Used for offline libraries, whose cache may be unreachable too.
+    pub fn open_read_only(cache_dir_path: PathBuf) -> anyhow::Result<Self> {
+        let cache_file_path = cache_dir_path.join("cache.json");
+        let data = match cache_file_path.exists() {
+            true => {
+                let mut cache_file = File::open(cache_file_path)?;
+                let mut content = String::new();
+                cache_file.read_to_string(&mut content)?;
+                serde_json::from_str(&content)?
+            }
+            false => CacheData {
                 items: HashMap::new(),
             },
+        };
+        let mut cache = Self {
+            data,
             cache_dir: cache_dir_path,
-            covers: HashMap::new(),
+            order: Vec::new(),
+            order_hash: 0,
+            version: 0,
+            overlays: BTreeMap::new(),
         };
-        let cache_json_string = serde_json::to_string_pretty(&cache.data)?;
-        std::fs::write(cache_file_path, cache_json_string)?;
+        cache.reorder();
         return Ok(cache);
     }
 
-    pub fn refresh(&mut self, open_lib: PathBuf) -> anyhow::Result<()> {
-        let epup_entries = Self::get_epubs(&open_lib)?;
+    /// When the cache stored in `cache_dir_path` was last saved, `None` if it never was.
+    pub fn saved_at(cache_dir_path: &Path) -> Option<SystemTime> {
+        return fs::metadata(cache_dir_path.join("cache.json"))
+            .and_then(|md| md.modified())
+            .ok();
+    }
+
+    /// Returns `false` if the root of the library marked with `marker` cannot be read, see
+    /// [`library::is_reachable`].
+    pub fn is_reachable(&self, open_lib: &Path, marker: &str) -> bool {
+        return library::is_reachable(open_lib, marker, !self.data.items.is_empty());
+    }
+
+    /// Brings the cache in line with the library folder, re-indexing only new or modified
+    /// books. Books are found following `rules` and at most `concurrency` of them are parsed
+    /// at once (`0` uses every core).
+    /// Returns the keys that changed; the version is bumped if there are any.
+    pub fn refresh(
+        &mut self,
+        open_lib: PathBuf,
+        marker: &str,
+        concurrency: usize,
+        rules: &ScanRules,
+    ) -> anyhow::Result<CacheChanges> {
+        let epup_entries = scan::find_books(&open_lib, rules)?;
+        // Entries that failed to read are skipped by the walk, so a root that went away while
+        // walking looks like a library whose books were all deleted.
+        if !self.is_reachable(&open_lib, marker) {
+            return Err(anyhow!("Library {} is unreachable", open_lib.display()));
+        }
         let mut keys: HashSet<String> = self.data.items.keys().cloned().collect();
-        for entry in epup_entries {
-            let file_path = entry.path().to_path_buf();
+        let mut jobs = Vec::new();
+        let mut sidecars = Vec::new();
+        for file_path in epup_entries {
             let rel_path = file_path.strip_prefix(&open_lib)?.to_path_buf();
             let hash = Self::hash_relative_path(&rel_path);
-            if !keys.contains(&hash) {
-                if let anyhow::Result::Ok(cache_item) = self.cache_file(file_path, rel_path) {
-                    self.data.items.insert(cache_item.key.clone(), cache_item);
-                }
-                continue;
+            if let Some(sidecar) = koreader::find_sidecar(&file_path) {
+                sidecars.push((hash.clone(), sidecar));
             }
-            let last_mod_cache = self.data.items.get(&hash).unwrap().last_modified;
+            let Some(last_mod_cache) = self.data.items.get(&hash).map(|i| i.last_modified) else {
+                jobs.push((file_path, rel_path));
+                continue;
+            };
             let last_mod_file = Self::last_modified(&file_path)?;
             if last_mod_cache != last_mod_file {
                 self.delete_cover_cache(&hash)?;
-                if let anyhow::Result::Ok(cache_item) = self.cache_file(file_path, rel_path) {
-                    self.data.items.insert(cache_item.key.clone(), cache_item);
-                }
+                jobs.push((file_path, rel_path));
             }
             keys.remove(&hash);
         }
 
+        let mut changes = CacheChanges::default();
         for key in keys {
             self.delete_cover_cache(&key)?;
             self.data.items.remove(&key);
+            changes.removed.push(key);
         }
 
+        let previous_keys: HashSet<String> = self.data.items.keys().cloned().collect();
+        for key in self.index_files(jobs, concurrency)? {
+            match previous_keys.contains(&key) {
+                true => changes.updated.push(key),
+                false => changes.added.push(key),
+            }
+        }
+        self.identify(&open_lib, concurrency)?;
+        self.reorder();
         self.write_cache_file()?;
-        self.write_covers_par(true)?;
+        if !changes.is_empty() {
+            self.version += 1;
+        }
+        changes.sidecars = self.changed_sidecars(sidecars);
 
-        return Ok(());
+        return Ok(changes);
     }
 
-    pub fn rebuild(&mut self, open_lib: PathBuf) -> anyhow::Result<()> {
+    /// Clears the cache and indexes the whole library again. Every book counts as added.
+    pub fn rebuild(
+        &mut self,
+        open_lib: PathBuf,
+        marker: &str,
+        concurrency: usize,
+        rules: &ScanRules,
+    ) -> anyhow::Result<CacheChanges> {
+        if !self.is_reachable(&open_lib, marker) {
+            return Err(anyhow!("Library {} is unreachable", open_lib.display()));
+        }
         self.clean_cache()?;
-        let epup_entries = Self::get_epubs(&open_lib)?;
-        for entry in epup_entries {
-            let file_path = entry.path().to_path_buf();
+        let epup_entries = scan::find_books(&open_lib, rules)?;
+        let mut jobs = Vec::new();
+        let mut sidecars = Vec::new();
+        for file_path in epup_entries {
             let rel_path = file_path.strip_prefix(&open_lib)?.to_path_buf();
-            if let anyhow::Result::Ok(cache_item) = self.cache_file(file_path, rel_path) {
-                println!("{}", cache_item.relative_path);
-                self.data.items.insert(cache_item.key.clone(), cache_item);
+            if let Some(sidecar) = koreader::find_sidecar(&file_path) {
+                sidecars.push((Self::hash_relative_path(&rel_path), sidecar));
             }
+            jobs.push((file_path, rel_path));
         }
+        let added = self.index_files(jobs, concurrency)?;
+        self.reorder();
         self.write_cache_file()?;
-        self.write_covers_par(true)?;
+        self.version += 1;
+        return Ok(CacheChanges {
+            added,
+            sidecars: self.changed_sidecars(sidecars),
+            ..Default::default()
+        });
+    }
+
+    /// Indexes a single book of the library, e.g. one just downloaded, without walking the
+    /// rest of the library.
+    pub fn index_book(
+        &mut self,
+        open_lib: &Path,
+        file_path: PathBuf,
+    ) -> anyhow::Result<CacheChanges> {
+        let rel_path = file_path.strip_prefix(open_lib)?.to_path_buf();
+        let key = Self::hash_relative_path(&rel_path);
+        let existed = self.data.items.contains_key(&key);
+        if existed {
+            self.delete_cover_cache(&key)?;
+        }
+        if self
+            .index_files(vec![(file_path.clone(), rel_path)], 1)?
+            .is_empty()
+        {
+            return Err(anyhow!("Failed to index {}", file_path.display()));
+        }
+        let mut changes = CacheChanges::default();
+        match existed {
+            true => changes.updated.push(key),
+            false => changes.added.push(key),
+        }
+        self.reorder();
+        self.write_cache_file()?;
+        self.version += 1;
+        return Ok(changes);
+    }
+
+    /// Replaces the metadata of a book, e.g. once it was written into the book file. Kept in
+    /// memory until the next change to the cache is saved.
+    pub fn set_metadata(&mut self, key: &str, metadata: BookMetadata) -> anyhow::Result<()> {
+        let item = self
+            .data
+            .items
+            .get_mut(key)
+            .ok_or_else(|| anyhow!("No book with key {}", key))?;
+        item.metadata = metadata;
+        item.apply_overlay(&self.overlays);
         return Ok(());
     }
 
-    pub fn get_book_data(&self, open_lib: PathBuf) -> Vec<BookData> {
-        let mut book_data: Vec<BookData> = self
+    /// Sets the metadata overlays of the library, by book identity, on top of what was read from
+    /// the books. They are kept apart from the items, so a rebuild applies them again.
+    pub fn set_overlays(&mut self, overlays: BTreeMap<String, MetadataOverlay>) {
+        self.overlays = overlays;
+        for item in self.data.items.values_mut() {
+            item.apply_overlay(&self.overlays);
+        }
+        self.reorder();
+    }
+
+    /// Replaces the overlay of one book, removing it if `overlay` is empty.
+    pub fn set_overlay(
+        &mut self,
+        key: &str,
+        overlay: MetadataOverlay,
+    ) -> anyhow::Result<CacheChanges> {
+        let item = self
             .data
             .items
+            .get_mut(key)
+            .ok_or_else(|| anyhow!("No book with key {}", key))?;
+        if item.identity.is_empty() {
+            return Err(anyhow!("{} is not identified yet", item.relative_path));
+        }
+        match overlay.is_empty() {
+            true => self.overlays.remove(&item.identity),
+            false => self.overlays.insert(item.identity.clone(), overlay),
+        };
+        item.apply_overlay(&self.overlays);
+        self.reorder();
+        self.version += 1;
+        return Ok(CacheChanges {
+            updated: vec![key.to_string()],
+            ..Default::default()
+        });
+    }
+
+    /// Remembers that the given sidecars were imported, so they are only reported again by
+    /// [`Cache::refresh`] once they change.
+    pub fn sidecars_imported(&mut self, sidecars: &[ChangedSidecar]) -> anyhow::Result<()> {
+        if sidecars.is_empty() {
+            return Ok(());
+        }
+        for sidecar in sidecars {
+            if let Some(item) = self.data.items.get_mut(&sidecar.key) {
+                item.sidecar_imported = Some(sidecar.modified);
+            }
+        }
+        return self.write_cache_file();
+    }
+
+    /// Follows books moved within the library: each item takes the path and key of its new
+    /// place, along with its cover. Returns the changes, the old keys being removed and the
+    /// new ones added, and the new key of each old one.
+    pub fn move_books(
+        &mut self,
+        moves: &[BookMove],
+    ) -> anyhow::Result<(CacheChanges, HashMap<String, String>)> {
+        let mut changes = CacheChanges::default();
+        let mut keys = HashMap::new();
+        for book in moves {
+            let Some(mut item) = self.data.items.remove(&book.key) else {
+                continue;
+            };
+            let rel_path: PathBuf = book.to.split('/').collect();
+            let key = Self::hash_relative_path(&rel_path);
+            if item.has_cover {
+                item.has_cover =
+                    fs::rename(self.cover_path(&book.key), self.cover_path(&key)).is_ok();
+            }
+            item.key = key.clone();
+            item.relative_path = rel_path.to_string_lossy().into_owned();
+            self.data.items.insert(key.clone(), item);
+            changes.removed.push(book.key.clone());
+            changes.added.push(key.clone());
+            keys.insert(book.key.clone(), key);
+        }
+        self.reorder();
+        self.write_cache_file()?;
+        if !changes.is_empty() {
+            self.version += 1;
+        }
+        return Ok((changes, keys));
+    }
+
+    /// Moves the cache files to `new_dir`. A rename is tried first; when it fails, e.g. across
+    /// devices, the files are copied and the old directory removed once the copy succeeded.
+    /// Fails if `new_dir` already holds a cache.
+    pub fn migrate(&mut self, new_dir: PathBuf) -> anyhow::Result<()> {
+        if new_dir == self.cache_dir {
+            return Ok(());
+        }
+        if new_dir.join("cache.json").exists() {
+            return Err(anyhow!("{} already holds a cache", new_dir.display()));
+        }
+        // The target is derived from the library id, so anything else there is what is left
+        // of an interrupted copy.
+        if new_dir.exists() {
+            fs::remove_dir_all(&new_dir)?;
+        }
+        if let Some(parent) = new_dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if fs::rename(&self.cache_dir, &new_dir).is_err() {
+            Self::copy_dir(&self.cache_dir, &new_dir)?;
+            fs::remove_dir_all(&self.cache_dir)?;
+        }
+        self.cache_dir = new_dir;
+        return Ok(());
+    }
+
+    /// Checks that `root` holds the books of this cache by looking for a sample of them,
+    /// spread over the whole catalog. An empty cache has no books to look for, so any
+    /// readable root passes.
+    pub fn verify_root(&self, root: &Path) -> anyhow::Result<()> {
+        if self.order.is_empty() {
+            fs::read_dir(root)?;
+            return Ok(());
+        }
+        let step = (self.order.len() / RELOCATE_SAMPLE_SIZE).max(1);
+        let missing: Vec<&str> = self
+            .order
             .iter()
-            .map(|entry| {
-                let entry = entry.1;
-                let key = entry.key.clone();
-                let book_path = open_lib
-                    .join(&entry.relative_path)
-                    .to_string_lossy()
-                    .into_owned();
-                let cover_path = match entry.has_cover {
-                    true => {
-                        let cover_path = self.cache_dir.join(format!("covers/{}", &key));
-                        let cover_path = cover_path.to_string_lossy().into_owned();
-                        Some(cover_path)
+            .step_by(step)
+            .take(RELOCATE_SAMPLE_SIZE)
+            .filter_map(|key| self.data.items.get(key))
+            .filter(|item| !archive::physical_path(&root.join(&item.relative_path)).is_file())
+            .map(|item| item.relative_path.as_str())
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "{} does not contain the books of this library, missing: {}",
+                root.display(),
+                missing.join(", ")
+            ));
+        }
+        return Ok(());
+    }
+
+    /// Returns `true` if a cache can be written to `dir`, creating it if needed.
+    pub fn is_writable(dir: &Path) -> bool {
+        let probe = dir.join(".write-test");
+        let writable = fs::create_dir_all(dir).is_ok() && fs::write(&probe, []).is_ok();
+        let _ = fs::remove_file(probe);
+        return writable;
+    }
+
+    /// Adds books already described by a Calibre library without parsing them: titles and
+    /// metadata come from Calibre and covers from its `cover.jpg` files. Books whose file is
+    /// missing are skipped; the next refresh indexes any book Calibre does not know about.
+    pub fn import_calibre(
+        &mut self,
+        open_lib: &Path,
+        books: Vec<CalibreBook>,
+        concurrency: usize,
+    ) -> anyhow::Result<CacheChanges> {
+        let (imported, failed_covers) = self.with_cover_writers(&books, concurrency, |book| {
+            let file_path = open_lib.join(&book.relative_path);
+            let identified = Self::last_modified(&file_path)
+                .and_then(|last_modified| Ok((last_modified, sync::book_identity(&file_path)?)));
+            let anyhow::Result::Ok((last_modified, identity)) = identified else {
+                println!(
+                    "Skipping missing Calibre book {}",
+                    book.relative_path.display()
+                );
+                return Ok((None, None));
+            };
+            let key = Self::hash_relative_path(&book.relative_path);
+            let cover = match &book.cover {
+                Some(cover) => match fs::read(cover) {
+                    anyhow::Result::Ok(data) => Some((key.clone(), data)),
+                    Err(e) => {
+                        println!("Failed to read cover {}: {:#}", cover.display(), e);
+                        None
                     }
-                    false => None,
-                };
-                let title = entry.title.clone();
-                return BookData {
-                    key,
-                    book_path,
-                    cover_path,
-                    title,
-                };
-            })
+                },
+                None => None,
+            };
+            let item = CacheItem {
+                key,
+                relative_path: book.relative_path.to_string_lossy().into_owned(),
+                identity,
+                last_modified,
+                title: book.title.clone(),
+                has_cover: cover.is_some(),
+                parser: ParserBackend::default(),
+                metadata: book.metadata.clone(),
+                sidecar_imported: None,
+                overlaid: None,
+            };
+            return Ok((Some(item), cover));
+        })?;
+        let items = imported
+            .into_iter()
+            .filter_map(|result| result.ok().flatten())
+            .map(|mut item| {
+                if failed_covers.contains(&item.key) {
+                    item.has_cover = false;
+                }
+                item
+            });
+
+        let mut changes = CacheChanges::default();
+        for mut item in items {
+            item.apply_overlay(&self.overlays);
+            let key = item.key.clone();
+            match self.data.items.insert(key.clone(), item) {
+                Some(_) => changes.updated.push(key),
+                None => changes.added.push(key),
+            }
+        }
+        self.reorder();
+        self.write_cache_file()?;
+        if !changes.is_empty() {
+            self.version += 1;
+        }
+        return Ok(changes);
+    }
+
+    /// Number of books in the cache and the token of their current ordering.
+    pub fn get_summary(&self) -> LibrarySummary {
+        return LibrarySummary {
+            total: self.order.len() as u32,
+            ordering_token: self.ordering_token(),
+            version: self.version,
+            offline: false,
+        };
+    }
+
+    /// Books in display order.
+    pub fn items(&self) -> impl Iterator<Item = &CacheItem> {
+        return self.order.iter().filter_map(|key| self.data.items.get(key));
+    }
+
+    pub fn item(&self, key: &str) -> Option<&CacheItem> {
+        return self.data.items.get(key);
+    }
+
+    /// Cached cover of a book, if it has one.
+    pub fn cover_file(&self, item: &CacheItem) -> Option<PathBuf> {
+        return item.has_cover.then(|| self.cover_path(&item.key));
+    }
+
+    /// Builds the delta signal for the changes of the last refresh.
+    pub fn get_delta(&self, open_lib: PathBuf, changes: CacheChanges) -> LibraryDelta {
+        let book_data = |keys: &[String]| -> Vec<BookData> {
+            keys.iter()
+                .filter_map(|key| self.data.items.get(key))
+                .map(|item| self.book_data(&open_lib, item))
+                .collect()
+        };
+        return LibraryDelta {
+            version: self.version,
+            added: book_data(&changes.added),
+            updated: book_data(&changes.updated),
+            removed: changes.removed,
+            total: self.order.len() as u32,
+            ordering_token: self.ordering_token(),
+        };
+    }
+
+    /// Returns up to `limit` books starting at `offset`, in title order, optionally keeping
+    /// only the books whose title, path, authors, series or tags contain `query`
+    /// (case-insensitive, see [`CacheItem::matches`]). Books `keep` rejects are left out as
+    /// well.
+    pub fn get_book_page(
+        &self,
+        open_lib: PathBuf,
+        offset: usize,
+        limit: usize,
+        query: Option<&str>,
+        keep: impl Fn(&CacheItem) -> bool,
+    ) -> LibraryPage {
+        let query = query
+            .map(|q| q.trim().to_lowercase())
+            .filter(|q| !q.is_empty());
+        let matching: Vec<&CacheItem> = self
+            .order
+            .iter()
+            .filter_map(|key| self.data.items.get(key))
+            .filter(|item| query.as_deref().is_none_or(|query| item.matches(query)))
+            .filter(|item| keep(item))
+            .collect();
+        let books = matching
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|item| self.book_data(&open_lib, item))
             .collect();
-        book_data.sort_by(|a, b| a.title.cmp(&b.title));
-        return book_data;
+        return LibraryPage {
+            offset: offset as u32,
+            total: matching.len() as u32,
+            ordering_token: self.ordering_token(),
+            query,
+            books,
+        };
+    }
+
+    pub fn book_data(&self, open_lib: &Path, entry: &CacheItem) -> BookData {
+        let key = entry.key.clone();
+        let book_path = open_lib
+            .join(&entry.relative_path)
+            .to_string_lossy()
+            .into_owned();
+        let cover_path = match entry.has_cover {
+            true => {
+                let cover_path = self.cover_path(&key);
+                let cover_path = cover_path.to_string_lossy().into_owned();
+                Some(cover_path)
+            }
+            false => None,
+        };
+        let title = entry.title().to_string();
+        return BookData {
+            key,
+            book_path,
+            cover_path,
+            title,
+        };
+    }
+
+    /// Sorts the books by title (then key, so equal titles keep a stable position) and
+    /// hashes the result for the ordering token. Must run after every change to the items.
+    fn reorder(&mut self) {
+        let mut order: Vec<&CacheItem> = self.data.items.values().collect();
+        order.sort_by(|a, b| a.title().cmp(b.title()).then_with(|| a.key.cmp(&b.key)));
+        let order: Vec<String> = order.into_iter().map(|item| item.key.clone()).collect();
+        let mut hasher = DefaultHasher::new();
+        order.hash(&mut hasher);
+        self.order_hash = hasher.finish();
+        self.order = order;
+    }
+
+    /// Token of the current pages, which only changes with the order of the books. Changes
+    /// to books that keep their position come with `version` and the `updated` keys of the
+    /// delta instead, so that Dart can merge them into the pages it holds.
+    fn ordering_token(&self) -> String {
+        return format!("{:x}", self.order_hash);
+    }
+
+    fn cover_path(&self, key: &str) -> PathBuf {
+        return self.cache_dir.join(format!("covers/{}", key));
+    }
+
+    /// Where a book inside an archive is extracted to be read, see [`archive::extract_book`].
+    pub fn extracted_path(&self, key: &str) -> PathBuf {
+        return self.cache_dir.join(format!("extracted/{}.epub", key));
     }
 
     fn delete_cover_cache(&self, rel_path_hash: &str) -> anyhow::Result<()> {
-        let cover_file = self.cache_dir.join(format!("cover/{}", rel_path_hash));
+        let cover_file = self.cover_path(rel_path_hash);
         if cover_file.exists() {
             fs::remove_file(cover_file)?;
         }
@@ -176,229 +748,454 @@ impl Cache {
         if covers.exists() {
             fs::remove_dir_all(covers)?;
         }
+        let extracted = self.cache_dir.join("extracted");
+        if extracted.exists() {
+            fs::remove_dir_all(extracted)?;
+        }
         self.data.items.clear();
         return Ok(());
     }
 
-    fn hash_relative_path(rel_path: &PathBuf) -> String {
+    /// Key of the book at `rel_path`, relative to the library root.
+    pub fn hash_relative_path(rel_path: &Path) -> String {
         let mut hasher = DefaultHasher::new();
         rel_path.hash(&mut hasher);
         let hash = format!("{:x}", hasher.finish());
         return hash;
     }
 
-    fn last_modified(file_path: &PathBuf) -> anyhow::Result<u128> {
-        let md = fs::metadata(&file_path)?;
+    fn last_modified(file_path: &Path) -> anyhow::Result<u128> {
+        let md = fs::metadata(archive::physical_path(file_path))?;
         let last_modified = md.modified()?;
         let duration = last_modified.duration_since(UNIX_EPOCH)?;
         let last_modified: u128 = duration.as_millis();
         return Ok(last_modified);
     }
 
-    fn cache_file(&mut self, file_path: PathBuf, rel_path: PathBuf) -> anyhow::Result<CacheItem> {
-        let hash = Self::hash_relative_path(&rel_path);
-        let last_modified = Self::last_modified(&file_path)?;
-        let mut has_cover = false;
-        let mut book = epub::doc::EpubDoc::new(&file_path)?;
-        if let Some((cover_data, _)) = book.get_cover() {
-            self.push_cover_for_writing(&hash, cover_data);
-            has_cover = true;
-        }
-        if !has_cover {
-            let mut cover_spine = None;
-            for spine in &book.spine {
-                if spine.idref.contains("cover") && !spine.idref.contains("back") {
-                    cover_spine = Some(spine.clone());
-                }
-            }
-            let mut img_path = None;
-            match &cover_spine {
-                Some(cover_spine) => {
-                    if let Some((cover_page_data, _)) = book.get_resource(&cover_spine.idref) {
-                        let cover_page = String::from_utf8_lossy(&cover_page_data).to_string();
-                        let re = Regex::new(
-                            r#"(?i)<(?:img|image)[^>]+(?:src|href)\s*=\s*["']([^"']+)["']"#,
-                        )
-                        .unwrap();
-                        if let Some(img_path_matches) = re.captures(&cover_page) {
-                            if let Some(img_path_match) = img_path_matches.get(1) {
-                                img_path = Some(img_path_match.as_str().to_string());
-                            }
-                        }
-                    }
-                }
-                None => {}
-            }
-            if let Some(mut img_path) = img_path {
-                if img_path.starts_with("../") {
-                    let cover_page_path = book
-                        .resources
-                        .get(&cover_spine.unwrap().idref)
-                        .unwrap()
-                        .path
-                        .clone();
-                    let abs_path = cover_page_path.parent().unwrap();
-                    let img_path =
-                        Self::normalise_img_path(abs_path.to_path_buf(), PathBuf::from(img_path));
-
-                    if let Some(img_path) = img_path {
-                        if let Some(cover_data) = book.get_resource_by_path(&img_path) {
-                            self.push_cover_for_writing(&hash, cover_data);
-                            has_cover = true;
-                        }
-                    }
-                } else {
-                    img_path = String::from("OEBPS/") + &img_path;
-                    if let Some(cover_data) = book.get_resource_by_path(&img_path) {
-                        self.push_cover_for_writing(&hash, cover_data);
-                        has_cover = true;
+    /// Parses the given `(file_path, rel_path)` pairs on a pool of `concurrency` threads and
+    /// merges the results into the cache, returning the keys of the books indexed.
+    fn index_files(
+        &mut self,
+        jobs: Vec<(PathBuf, PathBuf)>,
+        concurrency: usize,
+    ) -> anyhow::Result<Vec<String>> {
+        let (parsed, failed_covers) =
+            self.with_cover_writers(&jobs, concurrency, |(file_path, rel_path)| {
+                let (cache_item, cover) = Self::cache_file(file_path, rel_path)?;
+                let cover = cover.map(|cover| (cache_item.key.clone(), cover));
+                return Ok((cache_item, cover));
+            })?;
+
+        let mut indexed = Vec::new();
+        for (result, (file_path, _)) in parsed.into_iter().zip(&jobs) {
+            match result {
+                anyhow::Result::Ok(mut cache_item) => {
+                    if failed_covers.contains(&cache_item.key) {
+                        cache_item.has_cover = false;
                     }
-                }
-            }
-        }
-        if !has_cover {
-            let mut cover_id = None;
-            for (key, item) in &book.resources {
-                if key.to_ascii_lowercase().contains("cover")
-                    && !key.to_ascii_lowercase().contains("back")
-                    && item.mime.starts_with("image/")
-                    && !item.mime.contains("html")
-                {
-                    cover_id = Some(key.clone());
-                }
-            }
-            match cover_id {
-                Some(id) => {
-                    let cover = book.get_resource(&id);
-                    if cover.is_some() {
-                        let (cover_data, _) = cover.unwrap();
-                        self.push_cover_for_writing(&hash, cover_data);
-                        has_cover = true;
+                    // Metadata is read back from the book, so edits written into it survive a
+                    // rebuild. EPUBs seldom hold a rating, keep the one Calibre gave.
+                    if cache_item.metadata.rating.is_none()
+                        && let Some(previous) = self.data.items.get(&cache_item.key)
+                    {
+                        cache_item.metadata.rating = previous.metadata.rating;
                     }
+                    cache_item.apply_overlay(&self.overlays);
+                    indexed.push(cache_item.key.clone());
+                    self.data.items.insert(cache_item.key.clone(), cache_item);
                 }
-                None => {}
+                Err(e) => println!("Failed to index {}: {:#}", file_path.display(), e),
             }
         }
-        if !has_cover {
-            let mut cover = None;
-            for md in &book.metadata {
-                if md.property == "cover" || md.property == "cover-image" {
-                    cover = Some(md.value.clone());
-                }
-            }
-            if let Some(cover_path_internal) = cover {
-                match book.get_resource_by_path(&cover_path_internal) {
-                    Some(cover_data) => {
-                        self.push_cover_for_writing(&hash, cover_data);
-                        has_cover = true;
+        return Ok(indexed);
+    }
+
+    /// The sidecars of `found`, by book key, that changed since they were last imported.
+    /// Those of books not identified yet wait for the refresh that identifies them.
+    fn changed_sidecars(&self, found: Vec<(String, PathBuf)>) -> Vec<ChangedSidecar> {
+        return found
+            .into_iter()
+            .filter_map(|(key, path)| {
+                let item = self.data.items.get(&key)?;
+                let modified = Self::last_modified(&path).ok()?;
+                let changed = !item.identity.is_empty() && item.sidecar_imported != Some(modified);
+                return changed.then_some(ChangedSidecar {
+                    key,
+                    path,
+                    modified,
+                });
+            })
+            .collect();
+    }
+
+    /// Finds the identity of the books indexed before identities existed, on a pool of
+    /// `concurrency` threads, then applies their overlays. Books that cannot be read are left
+    /// for the next refresh.
+    fn identify(&mut self, open_lib: &Path, concurrency: usize) -> anyhow::Result<()> {
+        let pool = ThreadPoolBuilder::new().num_threads(concurrency).build()?;
+        let overlays = &self.overlays;
+        pool.install(|| {
+            self.data
+                .items
+                .par_iter_mut()
+                .filter(|(_, item)| item.identity.is_empty())
+                .for_each(|(_, item)| {
+                    match sync::book_identity(&open_lib.join(&item.relative_path)) {
+                        anyhow::Result::Ok(identity) => {
+                            item.identity = identity;
+                            item.apply_overlay(overlays);
+                        }
+                        Err(e) => println!("Failed to identify {}: {:#}", item.relative_path, e),
                     }
-                    None => {}
-                }
-            }
-        }
-        let title = book
-            .get_title()
-            .filter(|s| !s.trim().is_empty())
-            .unwrap_or_else(|| {
-                rel_path
-                    .file_name()
-                    .map(|f| f.to_string_lossy().into_owned())
-                    .unwrap()
-            });
-        self.write_covers_par(false)?;
-        return Ok(CacheItem {
-            key: hash,
-            relative_path: rel_path.to_string_lossy().into_owned(),
-            last_modified: last_modified,
-            title,
-            has_cover,
+                });
         });
+        return Ok(());
     }
 
-    fn push_cover_for_writing(&mut self, hash: &String, cover_data: Vec<u8>) {
-        self.covers.insert(hash.clone(), cover_data);
-    }
+    /// Runs `produce` over `jobs` on a pool of `concurrency` threads (`0` uses every core),
+    /// writing the covers it extracts to the cache. Returns its results, in the order of
+    /// `jobs`, and the keys of the covers that could not be written.
+    ///
+    /// Covers stream from the producing threads to as many writer threads, with at most
+    /// `COVER_MEMORY_BUDGET` bytes of covers in flight. Producers only extract the encoded
+    /// cover; they reserve its [`cover::memory_cost`], read from the image header, before
+    /// handing it over, and block while the writers catch up. Writers decode under that
+    /// reservation, never allocating more than [`COVER_DECODE_LIMIT`], and covers costing more
+    /// than the whole budget are skipped, so memory stays bounded regardless of library size
+    /// or cover dimensions, beyond one encoded cover per producing thread.
+    fn with_cover_writers<J: Sync, T: Send>(
+        &self,
+        jobs: &[J],
+        concurrency: usize,
+        produce: impl Fn(&J) -> anyhow::Result<(T, Option<(String, Vec<u8>)>)> + Sync,
+    ) -> anyhow::Result<(Vec<anyhow::Result<T>>, HashSet<String>)> {
+        let pool = ThreadPoolBuilder::new().num_threads(concurrency).build()?;
+        let budget = ByteBudget::new(COVER_MEMORY_BUDGET);
+        let (cover_tx, cover_rx) = mpsc::channel::<(String, Vec<u8>, BudgetGuard)>();
+        let cover_rx = Mutex::new(cover_rx);
+        let covers_dir = self.cache_dir.join("covers");
+        fs::create_dir_all(&covers_dir)?;
+        let refused = Mutex::new(Vec::new());
 
-    fn write_covers_par(&mut self, force: bool) -> anyhow::Result<()> {
-        if force || self.covers.len() >= BATCH_SIZE {
-            let errors: Vec<anyhow::Result<()>> = self
-                .covers
-                .par_iter()
-                .map(|(hash, data)| -> anyhow::Result<()> { self.write_cover_file(hash, data) })
+        let result = thread::scope(|scope| {
+            let writers: Vec<_> = (0..pool.current_num_threads())
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut failed = Vec::new();
+                        loop {
+                            let next = cover_rx.lock().map(|rx| rx.recv());
+                            let Result::Ok(Result::Ok((key, data, _guard))) = next else {
+                                break;
+                            };
+                            let cover_file = covers_dir.join(&key);
+                            if let Err(e) =
+                                Self::write_cover_file(&cover_file, &data, COVER_DECODE_LIMIT)
+                            {
+                                println!("Failed to write cover {}: {:#}", key, e);
+                                failed.push(key);
+                            }
+                        }
+                        failed
+                    })
+                })
                 .collect();
-            self.covers.clear();
-            errors.into_iter().for_each(|e| e.unwrap());
-        }
-        Ok(())
+
+            let produced: Vec<anyhow::Result<T>> = pool.install(|| {
+                jobs.par_iter()
+                    .map_with(cover_tx, |cover_tx, job| {
+                        let (output, cover) = produce(job)?;
+                        if let Some((key, cover_data)) = cover {
+                            let cost = cover::memory_cost(&cover_data, COVER_DECODE_LIMIT);
+                            match budget.acquire(cost) {
+                                anyhow::Result::Ok(guard) => cover_tx
+                                    .send((key, cover_data, guard))
+                                    .map_err(|_| anyhow!("Cover writers stopped unexpectedly"))?,
+                                Err(e) => {
+                                    println!("Skipping cover {}: {:#}", key, e);
+                                    if let Result::Ok(mut refused) = refused.lock() {
+                                        refused.push(key);
+                                    }
+                                }
+                            }
+                        }
+                        return Ok(output);
+                    })
+                    .collect()
+            });
+
+            let failed_covers: HashSet<String> = writers
+                .into_iter()
+                .flat_map(|writer| writer.join().unwrap_or_default())
+                .chain(refused.into_inner().unwrap_or_default())
+                .collect();
+            (produced, failed_covers)
+        });
+        return Ok(result);
     }
 
-    fn write_cover_file(&self, hash: &str, data: &Vec<u8>) -> anyhow::Result<()> {
-        let cover_file = self.cache_dir.join(format!("covers/{}", &hash));
-        if let Some(cover_dir) = cover_file.parent() {
-            if !cover_dir.exists() {
-                fs::create_dir_all(cover_dir)?;
-            }
-        }
-        let src_img = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
-        let img = src_img.decode()?.to_rgba8();
+    fn cache_file(
+        file_path: &Path,
+        rel_path: &Path,
+    ) -> anyhow::Result<(CacheItem, Option<Vec<u8>>)> {
+        let hash = Self::hash_relative_path(rel_path);
+        let last_modified = Self::last_modified(file_path)?;
+        let book = parser::parse(file_path)?;
+        let identity = sync::book_identity(file_path)?;
+        let has_cover = book.cover.is_some();
+        let title = book.title.unwrap_or_else(|| {
+            rel_path
+                .file_name()
+                .map(|f| f.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        });
+        let cache_item = CacheItem {
+            key: hash,
+            relative_path: rel_path.to_string_lossy().into_owned(),
+            identity,
+            last_modified,
+            title,
+            has_cover,
+            parser: book.backend,
+            metadata: book.metadata,
+            sidecar_imported: None,
+            overlaid: None,
+        };
+        return Ok((cache_item, book.cover));
+    }
+
+    fn write_cover_file(cover_file: &Path, data: &[u8], max_bytes: usize) -> anyhow::Result<()> {
+        let decoded = cover::decode(data, max_bytes)?;
+        let img = decoded.image;
         let width = img.width();
         let height = img.height();
         let target_height = 300;
         if height <= target_height {
-            fs::write(cover_file, data)?;
+            if decoded.passthrough {
+                fs::write(cover_file, data)?;
+            } else {
+                let mut result_buf = Vec::new();
+                img.write_to(&mut Cursor::new(&mut result_buf), ImageFormat::Jpeg)?;
+                fs::write(cover_file, result_buf)?;
+            }
             return Ok(());
         }
+        let img = img.to_rgba8();
         let aspect_ratio = width as f32 / height as f32;
-        let target_width = (target_height as f32 * aspect_ratio).round() as u32;
-        let mut target_img = fast_image_resize::images::Image::new(
-            target_width,
-            target_height,
-            img.pixel_type().unwrap(),
-        );
+        let target_width = (target_height as f32 * aspect_ratio).round().max(1.0) as u32;
+        let pixel_type = img
+            .pixel_type()
+            .ok_or_else(|| anyhow!("Unsupported cover pixel type"))?;
+        let mut target_img =
+            fast_image_resize::images::Image::new(target_width, target_height, pixel_type);
 
         let mut resizer = Resizer::new();
         resizer.resize(&img, &mut target_img, None)?;
         let target_img = target_img.into_vec();
-        let rgba_img = RgbaImage::from_raw(target_width, target_height, target_img);
+        let rgba_img = RgbaImage::from_raw(target_width, target_height, target_img)
+            .ok_or_else(|| anyhow!("Failed to build resized cover"))?;
+        let final_img = DynamicImage::ImageRgba8(rgba_img);
         let mut result_buf = Vec::new();
-        match rgba_img {
-            Some(img_buff) => {
-                let final_img = DynamicImage::ImageRgba8(img_buff);
-                final_img.write_to(&mut Cursor::new(&mut result_buf), ImageFormat::Jpeg)?;
-                fs::write(cover_file, result_buf)?;
-                return Ok(());
-            }
-            None => {
-                fs::write(cover_file, data)?;
-                return Ok(());
-            }
-        };
+        final_img.write_to(&mut Cursor::new(&mut result_buf), ImageFormat::Jpeg)?;
+        fs::write(cover_file, result_buf)?;
+        return Ok(());
     }
 
-    fn normalise_img_path(abs_dir_path: PathBuf, rel_path: PathBuf) -> Option<PathBuf> {
-        let mut normalised: Vec<Component> = abs_dir_path.components().collect();
-        let components = rel_path.components();
-        for comp in components {
-            if comp.as_os_str() == ".." && normalised.is_empty() {
-                return None;
-            } else if comp.as_os_str() == ".." {
-                normalised.pop();
+    fn copy_dir(from: &Path, to: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            let target = to.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                Self::copy_dir(&entry.path(), &target)?;
             } else {
-                normalised.push(comp);
+                fs::copy(entry.path(), target)?;
             }
         }
-        let final_path: PathBuf = normalised.iter().collect();
-        return Some(final_path);
+        return Ok(());
     }
+}
 
-    fn get_epubs(open_lib: &PathBuf) -> anyhow::Result<Vec<DirEntry>> {
-        let epub_entries: Vec<DirEntry> = WalkDir::new(open_lib)
-            .follow_links(false)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|entry| entry.path().extension().and_then(|s| s.to_str()) == Some("epub"))
-            .collect();
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-        Ok(epub_entries)
+    use super::*;
+    use crate::utility::{
+        epub_edit::{self, MetadataEdit},
+        fixtures::{self, write_book},
+    };
+
+    #[test]
+    fn indexes_metadata_from_the_package_document() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("spectecle-cache-{}", std::process::id()));
+        fs::create_dir_all(&root)?;
+        let book = root.join("messiah.epub");
+        write_book(&book, &fixtures::messiah())?;
+
+        let mut cache = Cache::open(root.join("cache"))?;
+        let changes = cache.index_book(&root, book)?;
+        let key = &changes.added[0];
+        let item = cache
+            .item(key)
+            .ok_or_else(|| anyhow!("The book was not indexed"))?;
+        assert_eq!(item.title(), "Dune Messiah");
+        assert_eq!(item.metadata().authors, ["Frank Herbert"]);
+        assert_eq!(
+            item.metadata().author_sort.as_deref(),
+            Some("Herbert, Frank")
+        );
+        assert_eq!(item.metadata().series.as_deref(), Some("Dune"));
+        assert_eq!(item.metadata().series_index, Some(2.0));
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_metadata_edits_across_a_rebuild() -> anyhow::Result<()> {
+        let root =
+            std::env::temp_dir().join(format!("spectecle-cache-edit-{}", std::process::id()));
+        fs::create_dir_all(&root)?;
+        let book = root.join("messiah.epub");
+        write_book(&book, &fixtures::messiah())?;
+        let mut cache = Cache::open(root.join("cache"))?;
+        let key = cache.index_book(&root, book.clone())?.added[0].clone();
+
+        let edit = MetadataEdit {
+            title: "Children of Dune".to_string(),
+            metadata: BookMetadata {
+                authors: vec!["F. Herbert".to_string()],
+                series: Some("Dune Chronicles".to_string()),
+                series_index: Some(3.0),
+                ..Default::default()
+            },
+            description: None,
+            cover: None,
+        };
+        epub_edit::write_metadata(&book, &edit, &root.join("original.epub"))?;
+        // Indexed again over what was known of the book, then into a cleared cache that only
+        // has the book to go by.
+        for clear in [false, true] {
+            if clear {
+                cache.clean_cache()?;
+            }
+            cache.index_book(&root, book.clone())?;
+            let item = cache
+                .item(&key)
+                .ok_or_else(|| anyhow!("The book was not indexed"))?;
+            assert_eq!(item.title(), "Children of Dune");
+            assert_eq!(item.metadata().authors, ["F. Herbert"]);
+            assert_eq!(item.metadata().series.as_deref(), Some("Dune Chronicles"));
+            assert_eq!(item.metadata().series_index, Some(3.0));
+        }
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn refreshes_added_modified_and_deleted_books() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("spectecle-cache-refresh-{}", std::process::id()));
+        let root = dir.join("Books");
+        fs::create_dir_all(&root)?;
+        library::mark_root(&root, "library")?;
+        let rules = ScanRules::default();
+        let book = |name: &str| root.join(name);
+        write_book(&book("alpha.epub"), &fixtures::package("Alpha", "", ""))?;
+        write_book(&book("beta.epub"), &fixtures::package("Beta", "", ""))?;
+        let key = |name: &str| Cache::hash_relative_path(Path::new(name));
+
+        let mut cache = Cache::open(dir.join("cache"))?;
+        let changes = cache.refresh(root.clone(), "library", 1, &rules)?;
+        let mut added = changes.added.clone();
+        added.sort();
+        let mut expected = vec![key("alpha.epub"), key("beta.epub")];
+        expected.sort();
+        assert_eq!(added, expected);
+        assert!(changes.updated.is_empty() && changes.removed.is_empty());
+        let summary = cache.get_summary();
+        assert_eq!(summary.total, 2);
+
+        // Nothing changed on disk.
+        assert!(
+            cache
+                .refresh(root.clone(), "library", 1, &rules)?
+                .is_empty()
+        );
+        assert_eq!(cache.get_summary().version, summary.version);
+
+        // A new title for a book that keeps its place only updates it.
+        write_book(&book("beta.epub"), &fixtures::package("Bravo", "", ""))?;
+        File::options()
+            .write(true)
+            .open(book("beta.epub"))?
+            .set_modified(SystemTime::now() + Duration::from_secs(10))?;
+        let changes = cache.refresh(root.clone(), "library", 1, &rules)?;
+        assert_eq!(changes.updated, [key("beta.epub")]);
+        assert!(changes.added.is_empty() && changes.removed.is_empty());
+        let delta = cache.get_delta(root.clone(), changes);
+        assert_eq!(delta.version, summary.version + 1);
+        assert_eq!(delta.ordering_token, summary.ordering_token);
+        assert_eq!(delta.updated[0].title, "Bravo");
+
+        fs::remove_file(book("alpha.epub"))?;
+        write_book(&book("gamma.epub"), &fixtures::package("Gamma", "", ""))?;
+        let changes = cache.refresh(root.clone(), "library", 1, &rules)?;
+        assert_eq!(changes.added, [key("gamma.epub")]);
+        assert_eq!(changes.removed, [key("alpha.epub")]);
+        assert!(changes.updated.is_empty());
+        let delta = cache.get_delta(root.clone(), changes);
+        assert_eq!(delta.version, summary.version + 2);
+        assert_eq!(delta.total, 2);
+        assert_ne!(delta.ordering_token, summary.ordering_token);
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_the_books_of_an_unreachable_root() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("spectecle-cache-offline-{}", std::process::id()));
+        let root = dir.join("Books");
+        fs::create_dir_all(&root)?;
+        library::mark_root(&root, "library")?;
+        write_book(&root.join("messiah.epub"), &fixtures::messiah())?;
+        let rules = ScanRules::default();
+        let mut cache = Cache::open(dir.join("cache"))?;
+        cache.refresh(root.clone(), "library", 1, &rules)?;
+        assert_eq!(cache.items().count(), 1);
+
+        // The drive is unmounted: first the root is gone, then an empty mount point is left.
+        let unmounted = dir.join("unmounted");
+        fs::rename(&root, &unmounted)?;
+        for present in [false, true] {
+            if present {
+                fs::create_dir_all(&root)?;
+            }
+            assert!(!cache.is_reachable(&root, "library"));
+            assert!(cache.refresh(root.clone(), "library", 1, &rules).is_err());
+            assert!(cache.rebuild(root.clone(), "library", 1, &rules).is_err());
+            assert_eq!(cache.items().count(), 1);
+            assert_eq!(Cache::open(dir.join("cache"))?.items().count(), 1);
+        }
+
+        // Back with its marker, books that really went away are removed.
+        fs::remove_dir_all(&root)?;
+        fs::rename(&unmounted, &root)?;
+        fs::remove_file(root.join("messiah.epub"))?;
+        assert!(cache.is_reachable(&root, "library"));
+        assert_eq!(
+            cache
+                .refresh(root.clone(), "library", 1, &rules)?
+                .removed
+                .len(),
+            1
+        );
+        assert_eq!(cache.items().count(), 0);
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
     }
 }
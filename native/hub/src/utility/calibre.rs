@@ -0,0 +1,329 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Ok, anyhow};
+use rusqlite::{Connection, OpenFlags, params};
+
+use crate::utility::{metadata::BookMetadata, opf};
+
+/// Calibre's database, at the root of every Calibre library.
+const DATABASE: &str = "metadata.db";
+/// Sidecar Calibre writes next to each book, mirroring the database.
+const SIDECAR: &str = "metadata.opf";
+/// Custom cover Calibre keeps next to each book.
+const COVER: &str = "cover.jpg";
+
+/// An EPUB of a Calibre library, with what Calibre knows about it.
+#[derive(Debug)]
+pub struct CalibreBook {
+    /// Path of the EPUB relative to the library root.
+    pub relative_path: PathBuf,
+    pub title: String,
+    pub metadata: BookMetadata,
+    pub cover: Option<PathBuf>,
+}
+
+impl CalibreBook {
+    /// The Calibre rating as a user rating, out of 5 stars. Calibre rates in half stars,
+    /// which are rounded up.
+    pub fn stars(&self) -> Option<u8> {
+        let rating = self.metadata.rating.filter(|rating| *rating > 0)?;
+        return Some(rating.min(10).div_ceil(2));
+    }
+}
+
+/// Returns `true` if `root` looks like a Calibre library.
+pub fn is_calibre_library(root: &Path) -> bool {
+    return root.join(DATABASE).is_file();
+}
+
+/// Reads the EPUBs of the Calibre library at `root` from its `metadata.db`, or from the
+/// `metadata.opf` sidecars of each book if the database is missing.
+pub fn read_library(root: &Path) -> anyhow::Result<Vec<CalibreBook>> {
+    if is_calibre_library(root) {
+        return read_database(root);
+    }
+    let books = read_sidecars(root)?;
+    if books.is_empty() {
+        return Err(anyhow!("{} is not a Calibre library", root.display()));
+    }
+    return Ok(books);
+}
+
+fn read_database(root: &Path) -> anyhow::Result<Vec<CalibreBook>> {
+    // Read-only, so a running Calibre keeps its lock and nothing is ever written back.
+    let db = Connection::open_with_flags(
+        root.join(DATABASE),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    let mut books_query = db.prepare(
+        "SELECT books.id, books.title, books.path, books.has_cover, books.series_index, data.name,
+                books.author_sort
+         FROM books JOIN data ON data.book = books.id
+         WHERE data.format = 'EPUB'",
+    )?;
+    let rows = books_query.query_map([], |row| {
+        return rusqlite::Result::Ok((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, bool>(3)?,
+            row.get::<_, f64>(4)?,
+            row.get::<_, String>(5)?,
+            row.get::<_, Option<String>>(6)?,
+        ));
+    })?;
+
+    let mut books = Vec::new();
+    for row in rows {
+        let (id, title, path, has_cover, series_index, name, author_sort) = row?;
+        let book_dir = Path::new(&path);
+        let mut metadata = read_book_metadata(&db, id)?;
+        metadata.author_sort = author_sort.filter(|sort| !sort.is_empty());
+        if metadata.series.is_some() {
+            metadata.series_index = Some(series_index);
+        }
+        let cover = root.join(book_dir).join(COVER);
+        books.push(CalibreBook {
+            relative_path: book_dir.join(format!("{}.epub", name)),
+            title,
+            metadata,
+            cover: (has_cover && cover.is_file()).then_some(cover),
+        });
+    }
+    return Ok(books);
+}
+
+fn read_book_metadata(db: &Connection, id: i64) -> anyhow::Result<BookMetadata> {
+    let names = |sql: &str| -> anyhow::Result<Vec<String>> {
+        let mut query = db.prepare_cached(sql)?;
+        let names = query
+            .query_map(params![id], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        return Ok(names);
+    };
+
+    let authors = names(
+        "SELECT authors.name FROM authors
+         JOIN books_authors_link link ON link.author = authors.id
+         WHERE link.book = ?1 ORDER BY link.id",
+    )?;
+    let series = names(
+        "SELECT series.name FROM series
+         JOIN books_series_link link ON link.series = series.id
+         WHERE link.book = ?1",
+    )?;
+    let tags = names(
+        "SELECT tags.name FROM tags
+         JOIN books_tags_link link ON link.tag = tags.id
+         WHERE link.book = ?1 ORDER BY tags.name",
+    )?;
+
+    let mut rating_query = db.prepare_cached(
+        "SELECT ratings.rating FROM ratings
+         JOIN books_ratings_link link ON link.rating = ratings.id
+         WHERE link.book = ?1",
+    )?;
+    let rating = rating_query
+        .query_map(params![id], |row| row.get::<_, Option<i64>>(0))?
+        .filter_map(|rating| rating.ok().flatten())
+        .find(|rating| *rating > 0)
+        .map(|rating| rating.clamp(0, 10) as u8);
+
+    let mut identifiers_query =
+        db.prepare_cached("SELECT type, val FROM identifiers WHERE book = ?1")?;
+    let identifiers = identifiers_query
+        .query_map(params![id], |row| {
+            return rusqlite::Result::Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?));
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    return Ok(BookMetadata {
+        authors,
+        author_sort: None,
+        series: series.into_iter().next(),
+        series_index: None,
+        tags,
+        rating,
+        identifiers,
+    });
+}
+
+/// Calibre stores books as `<author>/<title> (<id>)/`, so sidecars are two folders deep.
+fn read_sidecars(root: &Path) -> anyhow::Result<Vec<CalibreBook>> {
+    let mut books = Vec::new();
+    for author_dir in fs::read_dir(root)?.filter_map(|e| e.ok()) {
+        let Result::Ok(book_dirs) = fs::read_dir(author_dir.path()) else {
+            continue;
+        };
+        for book_dir in book_dirs.filter_map(|e| e.ok()) {
+            match read_sidecar(root, &book_dir.path()) {
+                anyhow::Result::Ok(Some(book)) => books.push(book),
+                anyhow::Result::Ok(None) => {}
+                Err(e) => println!("Failed to read {}: {:#}", book_dir.path().display(), e),
+            }
+        }
+    }
+    return Ok(books);
+}
+
+fn read_sidecar(root: &Path, book_dir: &Path) -> anyhow::Result<Option<CalibreBook>> {
+    let sidecar = book_dir.join(SIDECAR);
+    if !sidecar.is_file() {
+        return Ok(None);
+    }
+    let Some(epub) = fs::read_dir(book_dir)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|path| {
+            path.extension()
+                .and_then(|s| s.to_str())
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"))
+        })
+    else {
+        return Ok(None);
+    };
+    let opf = opf::read_metadata(&fs::read_to_string(&sidecar)?)?;
+    let title = opf.title.unwrap_or_else(|| {
+        epub.file_stem()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    let cover = book_dir.join(COVER);
+    return Ok(Some(CalibreBook {
+        relative_path: epub.strip_prefix(root)?.to_path_buf(),
+        title,
+        metadata: opf.metadata,
+        cover: cover.is_file().then_some(cover),
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::fixtures::{self, write_book};
+
+    fn temp_dir(name: &str) -> anyhow::Result<PathBuf> {
+        let dir =
+            std::env::temp_dir().join(format!("spectecle-calibre-{}-{}", name, std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        return Ok(dir);
+    }
+
+    /// The tables of a Calibre `metadata.db` that are read, holding Dune Messiah.
+    fn write_database(root: &Path) -> anyhow::Result<()> {
+        let db = Connection::open(root.join(DATABASE))?;
+        db.execute_batch(
+            "CREATE TABLE books (id INTEGER PRIMARY KEY, title TEXT, path TEXT, has_cover BOOL,
+                                 series_index REAL, author_sort TEXT);
+             CREATE TABLE data (id INTEGER PRIMARY KEY, book INTEGER, format TEXT, name TEXT);
+             CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE TABLE books_authors_link (id INTEGER PRIMARY KEY, book INTEGER, author INTEGER);
+             CREATE TABLE series (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE TABLE books_series_link (id INTEGER PRIMARY KEY, book INTEGER, series INTEGER);
+             CREATE TABLE tags (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE TABLE books_tags_link (id INTEGER PRIMARY KEY, book INTEGER, tag INTEGER);
+             CREATE TABLE ratings (id INTEGER PRIMARY KEY, rating INTEGER);
+             CREATE TABLE books_ratings_link (id INTEGER PRIMARY KEY, book INTEGER, rating INTEGER);
+             CREATE TABLE identifiers (id INTEGER PRIMARY KEY, book INTEGER, type TEXT, val TEXT);
+
+             INSERT INTO books VALUES
+                 (1, 'Dune Messiah', 'Frank Herbert/Dune Messiah (1)', 1, 2.0, 'Herbert, Frank'),
+                 (2, 'Notes', 'Frank Herbert/Notes (2)', 0, 1.0, '');
+             INSERT INTO data VALUES
+                 (1, 1, 'EPUB', 'Dune Messiah - Frank Herbert'),
+                 (2, 2, 'PDF', 'Notes - Frank Herbert');
+             INSERT INTO authors VALUES (1, 'Frank Herbert'), (2, 'Brian Herbert');
+             INSERT INTO books_authors_link VALUES (1, 1, 1), (2, 1, 2);
+             INSERT INTO series VALUES (1, 'Dune');
+             INSERT INTO books_series_link VALUES (1, 1, 1);
+             INSERT INTO tags VALUES (1, 'Science Fiction'), (2, 'Classics');
+             INSERT INTO books_tags_link VALUES (1, 1, 1), (2, 1, 2);
+             INSERT INTO ratings VALUES (1, 7);
+             INSERT INTO books_ratings_link VALUES (1, 1, 1);
+             INSERT INTO identifiers VALUES (1, 1, 'isbn', '9780441172696'), (2, 1, 'goodreads', '106');",
+        )?;
+        return Ok(());
+    }
+
+    #[test]
+    fn reads_the_database() -> anyhow::Result<()> {
+        let root = temp_dir("database")?;
+        write_database(&root)?;
+        let book_dir = root.join("Frank Herbert/Dune Messiah (1)");
+        fs::create_dir_all(&book_dir)?;
+        fs::write(book_dir.join(COVER), b"cover")?;
+
+        let books = read_library(&root)?;
+        // Only EPUBs are imported.
+        assert_eq!(books.len(), 1);
+        let book = &books[0];
+        assert_eq!(
+            book.relative_path,
+            Path::new("Frank Herbert/Dune Messiah (1)/Dune Messiah - Frank Herbert.epub")
+        );
+        assert_eq!(book.title, "Dune Messiah");
+        assert_eq!(book.cover, Some(book_dir.join(COVER)));
+        let metadata = &book.metadata;
+        assert_eq!(metadata.authors, vec!["Frank Herbert", "Brian Herbert"]);
+        assert_eq!(metadata.author_sort.as_deref(), Some("Herbert, Frank"));
+        assert_eq!(metadata.series.as_deref(), Some("Dune"));
+        assert_eq!(metadata.series_index, Some(2.0));
+        assert_eq!(metadata.tags, vec!["Classics", "Science Fiction"]);
+        assert_eq!(
+            metadata.identifiers.get("isbn").map(String::as_str),
+            Some("9780441172696")
+        );
+        assert_eq!(metadata.identifiers.len(), 2);
+        // 7 half stars.
+        assert_eq!(metadata.rating, Some(7));
+        assert_eq!(book.stars(), Some(4));
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn falls_back_to_sidecars() -> anyhow::Result<()> {
+        let root = temp_dir("sidecars")?;
+        assert!(read_library(&root).is_err());
+
+        let book_dir = root.join("Frank Herbert/Dune Messiah (1)");
+        fs::create_dir_all(&book_dir)?;
+        write_book(&book_dir.join("Dune Messiah.epub"), &fixtures::messiah())?;
+        let sidecar = fixtures::messiah().replace(
+            "<dc:creator",
+            r#"<meta name="calibre:rating" content="10"/>
+    <dc:subject>Science Fiction</dc:subject>
+    <dc:creator"#,
+        );
+        fs::write(book_dir.join(SIDECAR), sidecar)?;
+        // Folders without a sidecar are not Calibre books.
+        let stray_dir = root.join("Frank Herbert/Stray");
+        fs::create_dir_all(&stray_dir)?;
+        write_book(&stray_dir.join("Stray.epub"), &fixtures::messiah())?;
+
+        let books = read_library(&root)?;
+        assert_eq!(books.len(), 1);
+        let book = &books[0];
+        assert_eq!(
+            book.relative_path,
+            Path::new("Frank Herbert/Dune Messiah (1)/Dune Messiah.epub")
+        );
+        assert_eq!(book.title, "Dune Messiah");
+        assert_eq!(book.cover, None);
+        assert_eq!(book.metadata.authors, vec!["Frank Herbert"]);
+        assert_eq!(book.metadata.series.as_deref(), Some("Dune"));
+        assert_eq!(book.metadata.series_index, Some(2.0));
+        assert_eq!(book.metadata.tags, vec!["Science Fiction"]);
+        assert_eq!(book.stars(), Some(5));
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+}
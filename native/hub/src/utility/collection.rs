@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    time::SystemTime,
+};
+
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::utility::{
+    cache::{Cache, CacheItem},
+    sync::SyncLog,
+    user_data::BookUserData,
+};
+
+/// A named reading list of books of a library, kept in the library settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: String,
+    pub name: String,
+    pub content: CollectionContent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CollectionContent {
+    /// Books picked by hand, by key, in the order they should be read. Keys of books no
+    /// longer in the library are kept, so the books come back if their files do.
+    Manual { books: Vec<String> },
+    /// Every book matching the rules, in library order.
+    Smart(SmartRules),
+}
+
+/// Rules picking the books of a smart collection.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SmartRules {
+    pub rules: Vec<Rule>,
+    /// Keep books matching any rule rather than all of them.
+    #[serde(default)]
+    pub match_any: bool,
+}
+
+/// A condition on one field of a book, e.g. author is X or status is not finished.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub field: RuleField,
+    pub op: RuleOp,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleField {
+    Title,
+    Author,
+    Series,
+    SeriesIndex,
+    /// Path relative to the library root.
+    Path,
+    /// Tags of the book file as well as the ones the reader added.
+    Tag,
+    /// Reading status, by its export name such as `finished`.
+    Status,
+    /// Rating the reader gave, from 1 to 5.
+    Rating,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleOp {
+    Is,
+    IsNot,
+    Contains,
+    NotContains,
+    /// Numeric fields only.
+    AtLeast,
+    /// Numeric fields only.
+    AtMost,
+}
+
+impl Collection {
+    pub fn new(name: &str, content: CollectionContent) -> anyhow::Result<Self> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(anyhow!("A collection needs a name."));
+        }
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        return Ok(Self {
+            id: format!("{:x}", hasher.finish()),
+            name: name.to_string(),
+            content,
+        });
+    }
+
+    /// Books of the collection found in `cache`, in collection order. Smart rules read the
+    /// user data of the books from `user_data`, if the library has any.
+    pub fn books<'a>(&self, cache: &'a Cache, user_data: Option<&SyncLog>) -> Vec<&'a CacheItem> {
+        return match &self.content {
+            CollectionContent::Manual { books } => {
+                books.iter().filter_map(|key| cache.item(key)).collect()
+            }
+            CollectionContent::Smart(rules) => cache
+                .items()
+                .filter(|item| {
+                    let data =
+                        user_data.and_then(|log| log.book(item.identity(), item.relative_path()));
+                    rules.matches(item, data)
+                })
+                .collect(),
+        };
+    }
+
+    /// Adds books at the end of a manual collection, skipping those already in it.
+    pub fn add(&mut self, keys: Vec<String>) -> anyhow::Result<()> {
+        let books = self.manual_books()?;
+        for key in keys {
+            if !books.contains(&key) {
+                books.push(key);
+            }
+        }
+        return Ok(());
+    }
+
+    pub fn remove(&mut self, keys: &[String]) -> anyhow::Result<()> {
+        self.manual_books()?.retain(|key| !keys.contains(key));
+        return Ok(());
+    }
+
+    /// Replaces the books of a manual collection, e.g. to reorder them.
+    pub fn set_books(&mut self, mut keys: Vec<String>) -> anyhow::Result<()> {
+        let mut seen = Vec::with_capacity(keys.len());
+        keys.retain(|key| {
+            let new = !seen.contains(key);
+            seen.push(key.clone());
+            new
+        });
+        *self.manual_books()? = keys;
+        return Ok(());
+    }
+
+    /// Follows books whose key changed, e.g. because they were moved, from old to new key.
+    pub fn rekey(&mut self, keys: &HashMap<String, String>) {
+        if let CollectionContent::Manual { books } = &mut self.content {
+            for key in books.iter_mut() {
+                if let Some(new_key) = keys.get(key) {
+                    *key = new_key.clone();
+                }
+            }
+        }
+    }
+
+    fn manual_books(&mut self) -> anyhow::Result<&mut Vec<String>> {
+        return match &mut self.content {
+            CollectionContent::Manual { books } => Ok(books),
+            CollectionContent::Smart(_) => Err(anyhow!(
+                "The books of smart collection {} come from its rules.",
+                self.name
+            )),
+        };
+    }
+}
+
+impl SmartRules {
+    /// Checks that numeric comparisons are made against numbers.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for rule in &self.rules {
+            if matches!(rule.op, RuleOp::AtLeast | RuleOp::AtMost)
+                && rule.value.trim().parse::<f64>().is_err()
+            {
+                return Err(anyhow!("{} is not a number", rule.value));
+            }
+        }
+        return Ok(());
+    }
+
+    /// An empty set of rules matches every book.
+    pub fn matches(&self, item: &CacheItem, data: Option<&BookUserData>) -> bool {
+        if self.rules.is_empty() {
+            return true;
+        }
+        let mut results = self.rules.iter().map(|rule| rule.matches(item, data));
+        return match self.match_any {
+            true => results.any(|matched| matched),
+            false => results.all(|matched| matched),
+        };
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, item: &CacheItem, data: Option<&BookUserData>) -> bool {
+        let values = self.values(item, data);
+        let value = self.value.trim().to_lowercase();
+        let number = value.parse::<f64>().ok();
+        let numbers = || values.iter().filter_map(|v| v.parse::<f64>().ok());
+        return match self.op {
+            RuleOp::Is => values.contains(&value),
+            RuleOp::IsNot => !values.contains(&value),
+            RuleOp::Contains => values.iter().any(|v| v.contains(&value)),
+            RuleOp::NotContains => !values.iter().any(|v| v.contains(&value)),
+            RuleOp::AtLeast => number.is_some_and(|n| numbers().any(|v| v >= n)),
+            RuleOp::AtMost => number.is_some_and(|n| numbers().any(|v| v <= n)),
+        };
+    }
+
+    /// Values of the field for a book, lowercase. Fields with several values (authors,
+    /// tags) match if any of them does.
+    fn values(&self, item: &CacheItem, data: Option<&BookUserData>) -> Vec<String> {
+        let metadata = item.metadata();
+        let values: Vec<String> = match self.field {
+            RuleField::Title => vec![item.title().to_string()],
+            RuleField::Author => metadata.authors.clone(),
+            RuleField::Series => metadata.series.iter().cloned().collect(),
+            RuleField::SeriesIndex => metadata.series_index.iter().map(f64::to_string).collect(),
+            RuleField::Path => vec![item.relative_path().replace('\\', "/")],
+            RuleField::Tag => metadata
+                .tags
+                .iter()
+                .chain(data.map_or(&[][..], BookUserData::tags))
+                .cloned()
+                .collect(),
+            RuleField::Status => data
+                .and_then(BookUserData::status)
+                .map(|status| status.name().to_string())
+                .into_iter()
+                .collect(),
+            RuleField::Rating => data
+                .and_then(BookUserData::rating)
+                .map(|rating| rating.to_string())
+                .into_iter()
+                .collect(),
+        };
+        return values.into_iter().map(|v| v.to_lowercase()).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::utility::user_data::{ReadingStatus, Stamped};
+
+    fn item(path: &str, title: &str, metadata: Value) -> anyhow::Result<CacheItem> {
+        return Ok(serde_json::from_value(json!({
+            "key": Cache::hash_relative_path(Path::new(path)),
+            "relative_path": path,
+            "last_modified": 0,
+            "title": title,
+            "has_cover": false,
+            "metadata": metadata,
+        }))?);
+    }
+
+    fn rule(field: RuleField, op: RuleOp, value: &str) -> Rule {
+        return Rule {
+            field,
+            op,
+            value: value.to_string(),
+        };
+    }
+
+    fn stamped<T>(value: T) -> Option<Stamped<T>> {
+        return Some(Stamped {
+            value,
+            time: 0,
+            device: "device".to_string(),
+        });
+    }
+
+    fn dune() -> anyhow::Result<CacheItem> {
+        return item(
+            "Herbert/Dune Messiah.epub",
+            "Dune Messiah",
+            json!({
+                "authors": ["Frank Herbert", "Brian Herbert"],
+                "series": "Dune",
+                "series_index": 2.0,
+                "tags": ["Science Fiction"],
+            }),
+        );
+    }
+
+    #[test]
+    fn matches_fields_in_any_case() -> anyhow::Result<()> {
+        let dune = dune()?;
+        for (rule, expected) in [
+            (rule(RuleField::Author, RuleOp::Is, " frank HERBERT "), true),
+            (rule(RuleField::Author, RuleOp::Is, "Frank"), false),
+            (rule(RuleField::Author, RuleOp::Contains, "brian"), true),
+            (
+                rule(RuleField::Author, RuleOp::IsNot, "Brian Herbert"),
+                false,
+            ),
+            (rule(RuleField::Series, RuleOp::Is, "DUNE"), true),
+            (rule(RuleField::SeriesIndex, RuleOp::Is, "2"), true),
+            (rule(RuleField::SeriesIndex, RuleOp::AtLeast, "1.5"), true),
+            (rule(RuleField::SeriesIndex, RuleOp::AtMost, "1.5"), false),
+            (
+                rule(RuleField::Title, RuleOp::NotContains, "messiah"),
+                false,
+            ),
+            (rule(RuleField::Path, RuleOp::Contains, "herbert/"), true),
+            (rule(RuleField::Tag, RuleOp::Is, "science fiction"), true),
+        ] {
+            assert_eq!(rule.matches(&dune, None), expected, "{:?}", rule);
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn missing_fields_only_match_negations() -> anyhow::Result<()> {
+        let emma = item("emma.epub", "Emma", json!({}))?;
+        for (rule, expected) in [
+            (rule(RuleField::Author, RuleOp::Contains, ""), false),
+            (rule(RuleField::Series, RuleOp::Is, "Dune"), false),
+            (rule(RuleField::Series, RuleOp::IsNot, "Dune"), true),
+            (rule(RuleField::SeriesIndex, RuleOp::AtMost, "100"), false),
+            (rule(RuleField::Status, RuleOp::Is, "finished"), false),
+            (rule(RuleField::Status, RuleOp::IsNot, "finished"), true),
+            (rule(RuleField::Rating, RuleOp::AtLeast, "1"), false),
+            (rule(RuleField::Tag, RuleOp::NotContains, "fiction"), true),
+        ] {
+            assert_eq!(rule.matches(&emma, None), expected, "{:?}", rule);
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn reads_user_data() -> anyhow::Result<()> {
+        let dune = dune()?;
+        let data = BookUserData {
+            status: stamped(Some(ReadingStatus::Finished)),
+            rating: stamped(Some(4)),
+            tags: stamped(vec!["Re-read".to_string()]),
+            ..Default::default()
+        };
+        for (rule, expected) in [
+            (rule(RuleField::Status, RuleOp::Is, "Finished"), true),
+            (rule(RuleField::Rating, RuleOp::AtLeast, "4"), true),
+            (rule(RuleField::Rating, RuleOp::AtMost, "3"), false),
+            // Tags of the file and of the reader alike.
+            (rule(RuleField::Tag, RuleOp::Is, "re-read"), true),
+            (rule(RuleField::Tag, RuleOp::Is, "science fiction"), true),
+        ] {
+            assert_eq!(rule.matches(&dune, Some(&data)), expected, "{:?}", rule);
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn combines_rules() -> anyhow::Result<()> {
+        let dune = dune()?;
+        let by_herbert = rule(RuleField::Author, RuleOp::Is, "Frank Herbert");
+        let by_austen = rule(RuleField::Author, RuleOp::Is, "Jane Austen");
+        let rules = |rules: Vec<Rule>, match_any| SmartRules { rules, match_any };
+
+        assert!(rules(vec![], false).matches(&dune, None));
+        assert!(rules(vec![by_herbert.clone()], false).matches(&dune, None));
+        assert!(!rules(vec![by_herbert.clone(), by_austen.clone()], false).matches(&dune, None));
+        assert!(rules(vec![by_herbert, by_austen.clone()], true).matches(&dune, None));
+        assert!(!rules(vec![by_austen], true).matches(&dune, None));
+        return Ok(());
+    }
+
+    #[test]
+    fn validates_numeric_rules() {
+        let rules = |value: &str| SmartRules {
+            rules: vec![rule(RuleField::Rating, RuleOp::AtLeast, value)],
+            match_any: false,
+        };
+        assert!(rules(" 3 ").validate().is_ok());
+        assert!(rules("three").validate().is_err());
+    }
+}
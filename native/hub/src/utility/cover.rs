@@ -0,0 +1,223 @@
+use std::io::Cursor;
+
+use anyhow::{Ok, anyhow};
+use image::{
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits, RgbImage, metadata::Orientation,
+};
+use regex::Regex;
+use resvg::{tiny_skia, usvg};
+use zune_jpeg::{
+    JpegDecoder,
+    zune_core::{colorspace::ColorSpace, options::DecoderOptions},
+};
+
+/// Height (in pixels) that SVG covers are rasterized at.
+const SVG_RASTER_HEIGHT: f32 = 600.0;
+/// Widest SVG cover rasterized, relative to [`SVG_RASTER_HEIGHT`], so that the memory it
+/// takes is known before the SVG is parsed. Wider covers are scaled down to fit.
+const SVG_MAX_ASPECT: f32 = 2.0;
+
+/// Largest allocation a single cover may make while decoding, whatever memory the caller
+/// allows. Anything bigger is rejected instead of risking running out of memory.
+pub const MAX_DECODED_BYTES: usize = 128 * 1024 * 1024;
+
+/// A cover image decoded into pixels, ready to be resized and written to the cache.
+pub struct DecodedCover {
+    pub image: DynamicImage,
+    /// `true` if the original bytes can be stored as-is when no resize is needed,
+    /// i.e. they are a plain JPEG/PNG that needed no color or orientation fix-up.
+    pub passthrough: bool,
+}
+
+/// Decodes the raw bytes of a cover image, whatever the format.
+///
+/// SVG covers are rasterized onto a white background, CMYK/YCCK JPEGs are converted
+/// to RGB and EXIF orientation is applied, so the result always displays upright. Raster
+/// images needing more than `max_bytes` (at most [`MAX_DECODED_BYTES`]) are rejected.
+pub fn decode(data: &[u8], max_bytes: usize) -> anyhow::Result<DecodedCover> {
+    let max_bytes = max_bytes.min(MAX_DECODED_BYTES);
+    if is_svg(data) {
+        return Ok(DecodedCover {
+            image: rasterize_svg(data)?,
+            passthrough: false,
+        });
+    }
+
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_alloc = Some(max_bytes as u64);
+    reader.limits(limits);
+    let format = reader.format();
+    if format == Some(ImageFormat::Jpeg) && jpeg_component_count(data) == Some(4) {
+        return Ok(DecodedCover {
+            image: decode_cmyk_jpeg(data, max_bytes)?,
+            passthrough: false,
+        });
+    }
+
+    let mut decoder = reader.into_decoder()?;
+    // Not every decoder honours `max_alloc`, the size of the pixels is checked up front too.
+    if decoder.total_bytes() > max_bytes as u64 {
+        return Err(anyhow!(
+            "Cover is too large to decode ({} bytes)",
+            decoder.total_bytes()
+        ));
+    }
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    let passthrough = orientation == Orientation::NoTransforms
+        && matches!(format, Some(ImageFormat::Jpeg) | Some(ImageFormat::Png));
+    return Ok(DecodedCover { image, passthrough });
+}
+
+/// Estimates the peak memory needed to decode and resize a cover: the encoded bytes, the
+/// decoded pixels and the RGBA copy made for resizing. Only the image header is read, so
+/// this can be reserved before anything is decoded. Images whose size cannot be read from
+/// their header may allocate up to the `max_bytes` given to [`decode`] and are counted as such.
+pub fn memory_cost(data: &[u8], max_bytes: usize) -> usize {
+    let pixels = if is_svg(data) {
+        (SVG_RASTER_HEIGHT * SVG_RASTER_HEIGHT * SVG_MAX_ASPECT) as usize
+    } else {
+        let dimensions = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok());
+        match dimensions {
+            Some((width, height)) => width as usize * height as usize,
+            None => return data.len() + max_bytes.min(MAX_DECODED_BYTES),
+        }
+    };
+    return data.len() + pixels * 4 * 2;
+}
+
+/// Returns `true` if the data looks like an SVG document rather than a raster image.
+pub fn is_svg(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(1024)];
+    let head = String::from_utf8_lossy(head);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    return head.starts_with('<') && head.contains("<svg");
+}
+
+/// Returns the target of the first `<image>` element of an SVG wrapper page,
+/// unless it is an inline `data:` URI which the rasterizer handles by itself.
+pub fn svg_image_href(data: &[u8]) -> Option<String> {
+    let svg = String::from_utf8_lossy(data);
+    let re = Regex::new(r#"(?i)<image[^>]+href\s*=\s*["']([^"']+)["']"#).ok()?;
+    let href = re.captures(&svg)?.get(1)?.as_str();
+    if href.starts_with("data:") {
+        return None;
+    }
+    return Some(href.to_string());
+}
+
+fn rasterize_svg(data: &[u8]) -> anyhow::Result<DynamicImage> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())?;
+    let size = tree.size();
+    let scale =
+        (SVG_RASTER_HEIGHT / size.height()).min(SVG_RASTER_HEIGHT * SVG_MAX_ASPECT / size.width());
+    let width = (size.width() * scale).round().max(1.0) as u32;
+    let height = (size.height() * scale).round().max(1.0) as u32;
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)
+        .ok_or_else(|| anyhow!("Invalid SVG dimensions {}x{}", width, height))?;
+    pixmap.fill(tiny_skia::Color::WHITE);
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+    // The background is opaque, so the premultiplied pixels are plain RGBA.
+    let rgb: Vec<u8> = pixmap
+        .data()
+        .chunks_exact(4)
+        .flat_map(|px| [px[0], px[1], px[2]])
+        .collect();
+    let img = RgbImage::from_raw(width, height, rgb)
+        .ok_or_else(|| anyhow!("Failed to build rasterized SVG buffer"))?;
+    return Ok(DynamicImage::ImageRgb8(img));
+}
+
+fn decode_cmyk_jpeg(data: &[u8], max_bytes: usize) -> anyhow::Result<DynamicImage> {
+    let options = DecoderOptions::default().jpeg_set_out_colorspace(ColorSpace::RGB);
+    let mut decoder = JpegDecoder::new_with_options(data, options);
+    decoder
+        .decode_headers()
+        .map_err(|e| anyhow!("Failed to read CMYK JPEG header: {:?}", e))?;
+    let size = decoder.output_buffer_size().unwrap_or(usize::MAX);
+    if size > max_bytes {
+        return Err(anyhow!("CMYK JPEG is too large to decode ({} bytes)", size));
+    }
+    let pixels = decoder
+        .decode()
+        .map_err(|e| anyhow!("Failed to decode CMYK JPEG: {:?}", e))?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| anyhow!("CMYK JPEG has no header information"))?;
+    let img = RgbImage::from_raw(info.width as u32, info.height as u32, pixels)
+        .ok_or_else(|| anyhow!("Failed to build CMYK JPEG buffer"))?;
+    let mut img = DynamicImage::ImageRgb8(img);
+    if let Some(orientation) = decoder.exif().and_then(|e| Orientation::from_exif_chunk(e)) {
+        img.apply_orientation(orientation);
+    }
+    return Ok(img);
+}
+
+/// Reads the number of color components from the first SOF segment of a JPEG.
+fn jpeg_component_count(data: &[u8]) -> Option<u8> {
+    let mut i = 2;
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        if marker == 0xFF {
+            i += 1;
+            continue;
+        }
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        let is_sof = (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker);
+        if is_sof {
+            return data.get(i + 9).copied();
+        }
+        if marker == 0xDA {
+            return None;
+        }
+        i += 2 + len;
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+    use crate::utility::budget::ByteBudget;
+
+    #[test]
+    fn oversized_covers_stay_within_the_budget() -> anyhow::Result<()> {
+        const BUDGET: usize = 1024 * 1024;
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(1000, 1000))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        let budget = ByteBudget::new(BUDGET);
+
+        // 3 MB of pixels can neither be reserved nor decoded under a 1 MB budget.
+        assert!(memory_cost(&png, BUDGET) > BUDGET);
+        assert!(budget.acquire(memory_cost(&png, BUDGET)).is_err());
+        assert!(decode(&png, BUDGET).is_err());
+        // Nor can an image whose size cannot be read before decoding it.
+        assert!(
+            budget
+                .acquire(memory_cost(b"not an image", BUDGET))
+                .is_err()
+        );
+
+        let mut small = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(100, 100))
+            .write_to(&mut Cursor::new(&mut small), ImageFormat::Png)?;
+        let _guard = budget.acquire(memory_cost(&small, BUDGET))?;
+        assert_eq!(decode(&small, BUDGET)?.image.width(), 100);
+        return Ok(());
+    }
+}
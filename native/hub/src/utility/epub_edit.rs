@@ -0,0 +1,322 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, Cursor, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Ok, anyhow};
+use image::{DynamicImage, ImageFormat};
+use quick_xml::{Reader, events::Event};
+use zip::{CompressionMethod, ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::utility::{
+    archive, href,
+    library::DATA_DIR,
+    metadata::BookMetadata,
+    opds,
+    opf::{self, ManifestItem, OpfEdit, OpfMetadata},
+};
+
+/// Folder of the library data dir keeping each edited EPUB as it was before its first edit.
+pub const ORIGINALS_DIR: &str = "originals";
+const CONTAINER: &str = "META-INF/container.xml";
+/// OCF requires this entry to come first in the zip, stored uncompressed.
+const MIMETYPE: &str = "mimetype";
+const EPUB_MEDIA_TYPE: &str = "application/epub+zip";
+/// Manifest id and file name of a cover added to a book that had none.
+const NEW_COVER_ID: &str = "spectecle-cover";
+
+/// Container path and content of a cover image to write.
+type CoverImage = (String, Vec<u8>);
+
+/// Metadata written into an EPUB by [`write_metadata`].
+#[derive(Debug, Clone)]
+pub struct MetadataEdit {
+    pub title: String,
+    /// Everything but the rating, which EPUBs do not hold, replaces the book's metadata.
+    pub metadata: BookMetadata,
+    pub description: Option<String>,
+    /// A new cover image, `None` keeps the current one.
+    pub cover: Option<Vec<u8>>,
+}
+
+/// Where the original of the book at `rel_path` is kept once it was edited.
+pub fn original_path(root: &Path, rel_path: &str) -> PathBuf {
+    return root.join(DATA_DIR).join(ORIGINALS_DIR).join(rel_path);
+}
+
+/// Reads the metadata of the package document of an EPUB.
+pub fn read_metadata(book: &Path) -> anyhow::Result<OpfMetadata> {
+    let data = archive::read_book(book)?;
+    return package_metadata(&mut ZipArchive::new(Cursor::new(data))?);
+}
+
+/// Reads the metadata of the package document of an EPUB already opened as a zip.
+pub fn package_metadata<R: Read + Seek>(zip: &mut ZipArchive<R>) -> anyhow::Result<OpfMetadata> {
+    return opf::read_metadata(&package(zip)?);
+}
+
+/// Value of the unique identifier of an EPUB, see [`opf::unique_identifier`]. Only the
+/// container and the package document are read.
+pub fn unique_identifier(book: &Path) -> anyhow::Result<Option<String>> {
+    let opf = match archive::split(book) {
+        Some(_) => {
+            let data = archive::read_book(book)?;
+            package(&mut ZipArchive::new(Cursor::new(data))?)?
+        }
+        None => package(&mut ZipArchive::new(BufReader::new(File::open(book)?))?)?,
+    };
+    return opf::unique_identifier(&opf);
+}
+
+/// Rewrites the package document of an EPUB with `edit`, replacing the cover image if one
+/// is given. The book is copied to `original` first, unless an original is kept there
+/// already, then rewritten aside and moved over the book, so a failure leaves it untouched.
+///
+/// Every other entry is copied as is, with `mimetype` first and stored as OCF requires.
+pub fn write_metadata(book: &Path, edit: &MetadataEdit, original: &Path) -> anyhow::Result<()> {
+    if edit.title.trim().is_empty() {
+        return Err(anyhow!("A book needs a title."));
+    }
+    if archive::split(book).is_some() {
+        return Err(anyhow!("Books inside archives cannot be edited."));
+    }
+    let mut zip = ZipArchive::new(BufReader::new(File::open(book)?))?;
+    let opf_path = package_path(&mut zip)?;
+    let opf = read_entry(&mut zip, &opf_path)?;
+    let (new_cover, cover_entry) = match &edit.cover {
+        Some(data) => cover_entry(&opf, &opf_path, data)?,
+        None => (None, None),
+    };
+    let modified = opds::rfc3339(opds::now_millis());
+    let package = opf::write_metadata(
+        &opf,
+        &OpfEdit {
+            title: edit.title.trim(),
+            metadata: &edit.metadata,
+            description: edit.description.as_deref(),
+            new_cover: new_cover.as_ref(),
+            modified: &modified,
+        },
+    )?;
+
+    if !original.exists() {
+        if let Some(parent) = original.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(book, original)?;
+    }
+    let mut partial = book.as_os_str().to_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let result = (|| {
+        let mut out = ZipWriter::new(File::create(&partial)?);
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        out.start_file(MIMETYPE, stored)?;
+        out.write_all(EPUB_MEDIA_TYPE.as_bytes())?;
+        for i in 0..zip.len() {
+            let file = zip.by_index_raw(i)?;
+            let name = file.name();
+            if name == MIMETYPE
+                || name == opf_path
+                || cover_entry.as_ref().is_some_and(|(path, _)| path == name)
+            {
+                continue;
+            }
+            out.raw_copy_file(file)?;
+        }
+        out.start_file(opf_path.as_str(), deflated)?;
+        out.write_all(package.as_bytes())?;
+        // Images are compressed already.
+        if let Some((path, data)) = &cover_entry {
+            out.start_file(path.as_str(), stored)?;
+            out.write_all(data)?;
+        }
+        out.finish()?;
+        return Ok(());
+    })();
+    match result {
+        anyhow::Result::Ok(()) => {
+            fs::rename(&partial, book)?;
+            return Ok(());
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    }
+}
+
+/// Container path of the package document, from `META-INF/container.xml`.
+pub fn package_path<R: Read + Seek>(zip: &mut ZipArchive<R>) -> anyhow::Result<String> {
+    let container = read_entry(zip, CONTAINER)?;
+    let mut reader = Reader::from_str(&container);
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"rootfile" => {
+                if let Some(path) = opf::attribute(&e, b"full-path")? {
+                    return Ok(path);
+                }
+            }
+            Event::Eof => return Err(anyhow!("The EPUB names no package document.")),
+            _ => {}
+        }
+    }
+}
+
+/// Content of the package document.
+fn package<R: Read + Seek>(zip: &mut ZipArchive<R>) -> anyhow::Result<String> {
+    let opf_path = package_path(zip)?;
+    return read_entry(zip, &opf_path);
+}
+
+fn read_entry<R: Read + Seek>(zip: &mut ZipArchive<R>, name: &str) -> anyhow::Result<String> {
+    let mut text = String::new();
+    zip.by_name(name)?.read_to_string(&mut text)?;
+    return Ok(text);
+}
+
+/// Where the new cover goes: over the image of the current cover, converted to its format,
+/// or into a new manifest item for books without a cover.
+fn cover_entry(
+    opf: &str,
+    opf_path: &str,
+    data: &[u8],
+) -> anyhow::Result<(Option<ManifestItem>, Option<CoverImage>)> {
+    let format = image::guess_format(data)?;
+    if let Some(item) = opf::cover_item(opf)? {
+        let path = href::resolve(opf_path, &item.href)
+            .ok_or_else(|| anyhow!("The cover of the book is outside of it: {}", item.href))?;
+        let target = ImageFormat::from_mime_type(&item.media_type)
+            .ok_or_else(|| anyhow!("Cannot replace a cover of type {}", item.media_type))?;
+        if target == format {
+            return Ok((None, Some((path, data.to_vec()))));
+        }
+        let mut image = image::load_from_memory(data)?;
+        if target == ImageFormat::Jpeg {
+            image = DynamicImage::ImageRgb8(image.to_rgb8());
+        }
+        let mut converted = Vec::new();
+        image.write_to(&mut Cursor::new(&mut converted), target)?;
+        return Ok((None, Some((path, converted))));
+    }
+    let extension = format
+        .extensions_str()
+        .first()
+        .ok_or_else(|| anyhow!("Unsupported cover format {:?}", format))?;
+    let item = ManifestItem {
+        id: NEW_COVER_ID.to_string(),
+        href: format!("{}.{}", NEW_COVER_ID, extension),
+        media_type: format.to_mime_type().to_string(),
+    };
+    let path = href::resolve(opf_path, &item.href)
+        .ok_or_else(|| anyhow!("Cannot place a cover next to {}", opf_path))?;
+    return Ok((Some(item), Some((path, data.to_vec()))));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use image::RgbImage;
+
+    use super::*;
+    use crate::utility::fixtures::{self, TEXT};
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), format)?;
+        return Ok(data);
+    }
+
+    /// An EPUB whose `mimetype` is neither first nor stored, as some tools write them.
+    fn write_book(path: &Path, cover: &[u8]) -> anyhow::Result<()> {
+        let opf = fixtures::package(
+            "Dune",
+            r#"<dc:creator opf:role="aut">F. Herbert</dc:creator>
+    <meta name="cover" content="cover"/>"#,
+            r#"<item id="cover" href="images/cover.png" media-type="image/png"/>"#,
+        );
+        return fixtures::write_zip(
+            path,
+            &[
+                (CONTAINER, fixtures::CONTAINER.as_bytes()),
+                (MIMETYPE, EPUB_MEDIA_TYPE.as_bytes()),
+                ("OEBPS/content.opf", opf.as_bytes()),
+                ("OEBPS/images/cover.png", cover),
+                ("OEBPS/text.xhtml", TEXT.as_bytes()),
+            ],
+        );
+    }
+
+    #[test]
+    fn writes_metadata_and_cover() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("spectecle-epub-edit-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let book = dir.join("dune.epub");
+        let original = dir.join("originals").join("dune.epub");
+        write_book(&book, &encode(2, 2, ImageFormat::Png)?)?;
+        let before = fs::read(&book)?;
+
+        let metadata = BookMetadata {
+            authors: vec!["Frank Herbert".to_string()],
+            author_sort: Some("Herbert, Frank".to_string()),
+            series: Some("Dune".to_string()),
+            series_index: Some(2.0),
+            tags: vec!["Science fiction".to_string()],
+            rating: None,
+            identifiers: BTreeMap::from([
+                ("isbn".to_string(), "9780441172696".to_string()),
+                ("uuid".to_string(), "0b3c5e1a".to_string()),
+            ]),
+        };
+        let edit = MetadataEdit {
+            title: "Dune Messiah".to_string(),
+            metadata: metadata.clone(),
+            description: Some("The sequel.".to_string()),
+            cover: Some(encode(3, 4, ImageFormat::Jpeg)?),
+        };
+        write_metadata(&book, &edit, &original)?;
+
+        // The original is kept as it was.
+        assert_eq!(fs::read(&original)?, before);
+
+        let read = read_metadata(&book)?;
+        assert_eq!(read.title.as_deref(), Some("Dune Messiah"));
+        assert_eq!(read.description.as_deref(), Some("The sequel."));
+        assert_eq!(read.metadata.authors, metadata.authors);
+        assert_eq!(read.metadata.author_sort, metadata.author_sort);
+        assert_eq!(read.metadata.series, metadata.series);
+        assert_eq!(read.metadata.series_index, metadata.series_index);
+        assert_eq!(read.metadata.tags, metadata.tags);
+        assert_eq!(read.metadata.identifiers, metadata.identifiers);
+        assert_eq!(
+            unique_identifier(&book)?.as_deref(),
+            Some("urn:uuid:0b3c5e1a")
+        );
+
+        let mut zip = ZipArchive::new(File::open(&book)?)?;
+        {
+            let mimetype = zip.by_index(0)?;
+            assert_eq!(mimetype.name(), MIMETYPE);
+            assert_eq!(mimetype.compression(), CompressionMethod::Stored);
+        }
+        assert_eq!(read_entry(&mut zip, MIMETYPE)?, EPUB_MEDIA_TYPE);
+        assert_eq!(read_entry(&mut zip, "OEBPS/text.xhtml")?, TEXT);
+        assert_eq!(zip.len(), 5);
+
+        // The JPEG cover is converted to the PNG the book already had.
+        let mut cover = Vec::new();
+        zip.by_name("OEBPS/images/cover.png")?
+            .read_to_end(&mut cover)?;
+        assert_eq!(image::guess_format(&cover)?, ImageFormat::Png);
+        let cover = image::load_from_memory(&cover)?;
+        assert_eq!((cover.width(), cover.height()), (3, 4));
+        assert!(opf::cover_item(&package(&mut zip)?)?.is_some_and(|item| item.id == "cover"));
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Ok, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use image::ImageFormat;
+use quick_xml::escape::escape;
+use serde::Serialize;
+
+use crate::utility::{
+    archive,
+    cache::{Cache, CacheItem},
+    opds,
+    sync::SyncLog,
+    user_data::{BookUserData, ReadingStatus},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    /// A single page with a cover grid, covers embedded so it can be shared as one file.
+    Html,
+}
+
+/// A book as written to an export.
+#[derive(Serialize)]
+struct ExportedBook<'a> {
+    title: &'a str,
+    authors: &'a [String],
+    series: Option<&'a str>,
+    series_index: Option<f64>,
+    /// Relative to the library root.
+    path: &'a str,
+    /// `None` if the file could not be read, e.g. while the library is offline.
+    size: Option<u64>,
+    read_status: Option<&'static str>,
+    /// From 1 to 5.
+    rating: Option<u8>,
+    tags: &'a [String],
+    /// Path of the cover thumbnail relative to the export, CSV and JSON only.
+    cover: Option<String>,
+    /// Cover thumbnail in the cache, embedded by the HTML page.
+    #[serde(skip)]
+    thumbnail: Option<PathBuf>,
+}
+
+#[derive(Serialize)]
+struct JsonCatalog<'a> {
+    library: &'a str,
+    exported: String,
+    books: Vec<ExportedBook<'a>>,
+}
+
+/// Writes the catalog of the library at `root` to `destination` and returns the number of
+/// books exported. CSV and JSON exports copy the cover thumbnails into a `<name>_covers`
+/// folder next to `destination`, while the HTML page embeds them. Reading status, rating and
+/// tags come from `user_data`, when the library has any.
+pub fn export_catalog(
+    root: &Path,
+    cache: &Cache,
+    user_data: Option<&SyncLog>,
+    format: ExportFormat,
+    destination: &Path,
+) -> anyhow::Result<usize> {
+    let name = root
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| root.to_string_lossy().into_owned());
+    let items: Vec<&CacheItem> = cache.items().collect();
+    let mut out = BufWriter::new(File::create(destination)?);
+    match format {
+        ExportFormat::Csv => {
+            let books = exported_books(root, cache, user_data, &items, Some(destination))?;
+            write_csv(&mut out, &books)?;
+        }
+        ExportFormat::Json => {
+            let catalog = JsonCatalog {
+                library: &name,
+                exported: opds::rfc3339(opds::now_millis()),
+                books: exported_books(root, cache, user_data, &items, Some(destination))?,
+            };
+            serde_json::to_writer_pretty(&mut out, &catalog)?;
+        }
+        ExportFormat::Html => {
+            let books = exported_books(root, cache, user_data, &items, None)?;
+            write_html(&mut out, &name, &books)?;
+        }
+    }
+    out.flush()?;
+    return Ok(items.len());
+}
+
+/// Describes `items`, copying their covers next to `destination` if one is given.
+fn exported_books<'a>(
+    root: &Path,
+    cache: &Cache,
+    user_data: Option<&'a SyncLog>,
+    items: &[&'a CacheItem],
+    destination: Option<&Path>,
+) -> anyhow::Result<Vec<ExportedBook<'a>>> {
+    let covers_dir = destination.map(covers_dir).transpose()?;
+    if let Some((dir, _)) = &covers_dir {
+        fs::create_dir_all(dir)?;
+    }
+    let mut books = Vec::with_capacity(items.len());
+    for item in items {
+        let metadata = item.metadata();
+        let data = user_data.and_then(|log| log.book(item.identity(), item.relative_path()));
+        let cover = match (&covers_dir, cache.cover_file(item)) {
+            (Some((dir, relative)), Some(cover)) => {
+                copy_cover(&cover, dir, item.key())?.map(|file| format!("{}/{}", relative, file))
+            }
+            _ => None,
+        };
+        books.push(ExportedBook {
+            title: item.title(),
+            authors: &metadata.authors,
+            series: metadata.series.as_deref(),
+            series_index: metadata.series_index,
+            path: item.relative_path(),
+            size: archive::book_size(&root.join(item.relative_path())).ok(),
+            read_status: data.and_then(BookUserData::status).map(ReadingStatus::name),
+            rating: data.and_then(BookUserData::rating),
+            tags: data.map_or(&[], BookUserData::tags),
+            cover,
+            thumbnail: cache.cover_file(item),
+        });
+    }
+    return Ok(books);
+}
+
+/// The folder cover thumbnails are copied to for `destination`, and its name.
+fn covers_dir(destination: &Path) -> anyhow::Result<(PathBuf, String)> {
+    let stem = destination
+        .file_stem()
+        .ok_or_else(|| anyhow!("{} is not a file path", destination.display()))?;
+    let name = format!("{}_covers", stem.to_string_lossy());
+    return Ok((destination.with_file_name(&name), name));
+}
+
+/// Copies a cover thumbnail with the extension of its format, returning the new file name.
+/// Missing covers, e.g. of an offline library, are skipped.
+fn copy_cover(cover: &Path, dir: &Path, key: &str) -> anyhow::Result<Option<String>> {
+    let anyhow::Result::Ok(data) = fs::read(cover) else {
+        return Ok(None);
+    };
+    let extension = image::guess_format(&data)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("jpg");
+    let file = format!("{}.{}", key, extension);
+    fs::write(dir.join(&file), data)?;
+    return Ok(Some(file));
+}
+
+fn write_csv(out: &mut impl Write, books: &[ExportedBook]) -> anyhow::Result<()> {
+    writeln!(
+        out,
+        "title,authors,series,series_index,path,size,read_status,rating,tags,cover"
+    )?;
+    for book in books {
+        let fields = [
+            book.title.to_string(),
+            book.authors.join(" & "),
+            book.series.unwrap_or_default().to_string(),
+            book.series_index.map(|i| i.to_string()).unwrap_or_default(),
+            book.path.to_string(),
+            book.size.map(|s| s.to_string()).unwrap_or_default(),
+            book.read_status.unwrap_or_default().to_string(),
+            book.rating.map(|r| r.to_string()).unwrap_or_default(),
+            book.tags.join("; "),
+            book.cover.clone().unwrap_or_default(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    return Ok(());
+}
+
+/// Quotes a field as RFC 4180 requires.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    return field.to_string();
+}
+
+const HTML_STYLE: &str = "\
+body{font-family:system-ui,sans-serif;margin:2rem;background:#fafafa;color:#222}\
+main{display:grid;grid-template-columns:repeat(auto-fill,minmax(140px,1fr));gap:1.5rem}\
+figure{margin:0}\
+img,.no-cover{width:100%;aspect-ratio:2/3;object-fit:cover;border-radius:4px;\
+box-shadow:0 1px 4px rgba(0,0,0,.25);background:#ddd}\
+.no-cover{display:flex;align-items:center;justify-content:center;padding:.5rem;\
+box-sizing:border-box;text-align:center;font-size:.9rem}\
+figcaption{margin-top:.5rem;font-size:.85rem;display:flex;flex-direction:column;gap:.15rem}\
+figcaption span,dl{color:#666}\
+dl{margin:.25rem 0 0;display:grid;grid-template-columns:auto 1fr;gap:0 .5rem;font-size:.75rem}\
+dd{margin:0;overflow-wrap:anywhere}";
+
+/// Writes a cover grid captioned with the same fields as [`write_csv`].
+fn write_html(out: &mut impl Write, name: &str, books: &[ExportedBook]) -> anyhow::Result<()> {
+    let name = escape(name);
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html><head><meta charset=\"utf-8\">")?;
+    writeln!(
+        out,
+        "<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">"
+    )?;
+    writeln!(
+        out,
+        "<title>{}</title><style>{}</style></head><body>",
+        name, HTML_STYLE
+    )?;
+    writeln!(out, "<h1>{}</h1>", name)?;
+    writeln!(
+        out,
+        "<p>{} books, exported {}</p><main>",
+        books.len(),
+        opds::rfc3339(opds::now_millis())
+    )?;
+    for book in books {
+        let title = escape(book.title);
+        writeln!(out, "<figure>")?;
+        // Covers are embedded one at a time, so a large library is never held in memory.
+        match book
+            .thumbnail
+            .as_ref()
+            .and_then(|cover| fs::read(cover).ok())
+        {
+            Some(data) => {
+                let mime = image::guess_format(&data)
+                    .unwrap_or(ImageFormat::Jpeg)
+                    .to_mime_type();
+                writeln!(
+                    out,
+                    "<img src=\"data:{};base64,{}\" alt=\"\" loading=\"lazy\">",
+                    mime,
+                    STANDARD.encode(&data)
+                )?;
+            }
+            None => writeln!(out, "<div class=\"no-cover\">{}</div>", title)?,
+        }
+        writeln!(out, "<figcaption><strong>{}</strong>", title)?;
+        if !book.authors.is_empty() {
+            writeln!(out, "<span>{}</span>", escape(book.authors.join(", ")))?;
+        }
+        if let Some(series) = book.series {
+            let index = book
+                .series_index
+                .map(|i| format!(" #{}", i))
+                .unwrap_or_default();
+            writeln!(out, "<span>{}{}</span>", escape(series), index)?;
+        }
+        let fields = [
+            ("Path", Some(book.path.to_string())),
+            ("Size", book.size.map(human_size)),
+            ("Status", book.read_status.map(str::to_string)),
+            ("Rating", book.rating.map(|r| format!("{}/5", r))),
+            (
+                "Tags",
+                (!book.tags.is_empty()).then(|| book.tags.join(", ")),
+            ),
+        ];
+        writeln!(out, "<dl>")?;
+        for (label, value) in fields {
+            if let Some(value) = value {
+                writeln!(out, "<dt>{}</dt><dd>{}</dd>", label, escape(value))?;
+            }
+        }
+        writeln!(out, "</dl></figcaption></figure>")?;
+    }
+    writeln!(out, "</main></body></html>")?;
+    return Ok(());
+}
+
+/// Size in bytes, kilobytes or megabytes, e.g. `1.4 MB`.
+fn human_size(bytes: u64) -> String {
+    return match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_has_the_csv_columns() -> anyhow::Result<()> {
+        let authors = ["Frank Herbert".to_string()];
+        let tags = ["Science fiction".to_string(), "Re-read".to_string()];
+        let book = ExportedBook {
+            title: "Dune Messiah",
+            authors: &authors,
+            series: Some("Dune"),
+            series_index: Some(2.0),
+            path: "Herbert/Dune Messiah.epub",
+            size: Some(1_572_864),
+            read_status: Some(ReadingStatus::Finished.name()),
+            rating: Some(4),
+            tags: &tags,
+            cover: None,
+            thumbnail: None,
+        };
+        let mut html = Vec::new();
+        write_html(&mut html, "Shelf", &[book])?;
+        let html = String::from_utf8(html)?;
+        for expected in [
+            "<strong>Dune Messiah</strong>",
+            "Frank Herbert",
+            "Dune #2",
+            "<dt>Path</dt><dd>Herbert/Dune Messiah.epub</dd>",
+            "<dt>Size</dt><dd>1.5 MB</dd>",
+            "<dt>Status</dt><dd>finished</dd>",
+            "<dt>Rating</dt><dd>4/5</dd>",
+            "<dt>Tags</dt><dd>Science fiction, Re-read</dd>",
+        ] {
+            assert!(html.contains(expected), "{} is missing", expected);
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,72 @@
+//! EPUBs written by the tests.
+
+use std::{fs::File, io::Write, path::Path};
+
+use anyhow::Ok;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+/// `META-INF/container.xml` pointing at `OEBPS/content.opf`.
+pub const CONTAINER: &str = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+/// The single page of the books, `OEBPS/text.xhtml`.
+pub const TEXT: &str = "<html><body><p>Hello</p></body></html>";
+
+/// Package document of a book with the text page. `metadata` and `manifest` are added to
+/// the elements of the same name, e.g. creators or a cover.
+pub fn package(title: &str, metadata: &str, manifest: &str) -> String {
+    return format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title>{}</dc:title>
+    <dc:identifier id="uid">urn:uuid:0b3c5e1a</dc:identifier>
+    {}
+  </metadata>
+  <manifest>
+    <item id="text" href="text.xhtml" media-type="application/xhtml+xml"/>
+    {}
+  </manifest>
+  <spine><itemref idref="text"/></spine>
+</package>
+"#,
+        title, metadata, manifest
+    );
+}
+
+/// Package document of Dune Messiah, by Frank Herbert and second of the Dune series.
+pub fn messiah() -> String {
+    return package(
+        "Dune Messiah",
+        r#"<dc:creator opf:role="aut" opf:file-as="Herbert, Frank">Frank Herbert</dc:creator>
+    <meta name="calibre:series" content="Dune"/>
+    <meta name="calibre:series_index" content="2"/>"#,
+        "",
+    );
+}
+
+/// Writes a zip of `entries`, deflated and in the given order.
+pub fn write_zip(path: &Path, entries: &[(&str, &[u8])]) -> anyhow::Result<()> {
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, data) in entries {
+        zip.start_file(*name, deflated)?;
+        zip.write_all(data)?;
+    }
+    zip.finish()?;
+    return Ok(());
+}
+
+/// Writes an EPUB of the text page described by the package document `opf`.
+pub fn write_book(path: &Path, opf: &str) -> anyhow::Result<()> {
+    return write_zip(
+        path,
+        &[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER.as_bytes()),
+            ("OEBPS/content.opf", opf.as_bytes()),
+            ("OEBPS/text.xhtml", TEXT.as_bytes()),
+        ],
+    );
+}
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use percent_encoding::percent_decode_str;
+
+/// Converts a path as stored by `epub::doc::EpubDoc` (the OPF directory joined with a
+/// manifest href) into a normalised container path with `/` separators.
+pub fn container_path(path: &Path) -> Option<String> {
+    let path = path.to_string_lossy().replace('\\', "/");
+    return normalise(&path);
+}
+
+/// Resolves an `href` found in the document at container path `base` (e.g. the OPF or an
+/// XHTML page) into the container path of the referenced resource, as described by the OCF
+/// spec: hrefs are URL-encoded, relative to the directory of the referencing document unless
+/// they start with `/` (the container root), and may carry a query or fragment.
+///
+/// Returns `None` for references outside the container (`http:`, `data:` ...) or ones that
+/// climb above the container root.
+pub fn resolve(base: &str, href: &str) -> Option<String> {
+    let href = href.trim();
+    let href = href.split(['#', '?']).next().unwrap_or_default();
+    if href.is_empty() || has_scheme(href) {
+        return None;
+    }
+    let href = percent_decode_str(href).decode_utf8().ok()?;
+    if let Some(absolute) = href.strip_prefix('/') {
+        return normalise(absolute);
+    }
+    let dir = parent_dir(base);
+    if dir.is_empty() {
+        return normalise(&href);
+    }
+    return normalise(&format!("{}/{}", dir, href));
+}
+
+/// Directory part of a container path, `""` for files at the container root.
+pub fn parent_dir(path: &str) -> &str {
+    return match path.rfind('/') {
+        Some(i) => &path[..i],
+        None => "",
+    };
+}
+
+fn has_scheme(href: &str) -> bool {
+    let Some(colon) = href.find(':') else {
+        return false;
+    };
+    let scheme = &href[..colon];
+    return !scheme.is_empty()
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '-' || c == '.');
+}
+
+fn normalise(path: &str) -> Option<String> {
+    let mut normalised: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                normalised.pop()?;
+            }
+            segment => normalised.push(segment),
+        }
+    }
+    return Some(normalised.join("/"));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn resolves_hrefs() {
+        let cases = [
+            // Relative to the directory of the referencing document.
+            (
+                "OEBPS/content.opf",
+                "images/cover.jpg",
+                Some("OEBPS/images/cover.jpg"),
+            ),
+            (
+                "OEBPS/text/cover.xhtml",
+                "../images/cover.jpg",
+                Some("OEBPS/images/cover.jpg"),
+            ),
+            (
+                "OEBPS/text/cover.xhtml",
+                "./cover.jpg",
+                Some("OEBPS/text/cover.jpg"),
+            ),
+            ("content.opf", "cover.jpg", Some("cover.jpg")),
+            // Absolute hrefs start at the container root.
+            (
+                "OEBPS/text/cover.xhtml",
+                "/images/cover.jpg",
+                Some("images/cover.jpg"),
+            ),
+            // Percent-encoding, fragments and queries.
+            (
+                "OEBPS/content.opf",
+                "images/my%20cover.jpg",
+                Some("OEBPS/images/my cover.jpg"),
+            ),
+            ("OEBPS/content.opf", "caf%C3%A9.jpg", Some("OEBPS/café.jpg")),
+            (
+                "OEBPS/content.opf",
+                "text/ch1.xhtml#start",
+                Some("OEBPS/text/ch1.xhtml"),
+            ),
+            (
+                "OEBPS/content.opf",
+                "cover.jpg?v=2",
+                Some("OEBPS/cover.jpg"),
+            ),
+            (
+                "OEBPS/content.opf",
+                "  cover.jpg  ",
+                Some("OEBPS/cover.jpg"),
+            ),
+            // Nothing in the container to point at.
+            ("OEBPS/content.opf", "#start", None),
+            ("OEBPS/content.opf", "", None),
+            ("OEBPS/content.opf", "http://example.com/cover.jpg", None),
+            ("OEBPS/content.opf", "data:image/png;base64,AAAA", None),
+            ("OEBPS/content.opf", "../../cover.jpg", None),
+            ("OEBPS/content.opf", "%FF.jpg", None),
+        ];
+        for (base, href, expected) in cases {
+            assert_eq!(
+                resolve(base, href).as_deref(),
+                expected,
+                "{} from {}",
+                href,
+                base
+            );
+        }
+    }
+
+    #[test]
+    fn normalises_container_paths() {
+        assert_eq!(
+            container_path(&PathBuf::from("OEBPS\\text/../images/cover.jpg")).as_deref(),
+            Some("OEBPS/images/cover.jpg")
+        );
+        assert_eq!(parent_dir("OEBPS/text/ch1.xhtml"), "OEBPS/text");
+        assert_eq!(parent_dir("content.opf"), "");
+    }
+}
@@ -0,0 +1,643 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{Ok, anyhow};
+
+use crate::utility::{
+    archive,
+    user_data::{Annotation, AnnotationKind, LocatorKind, ReadingStatus},
+};
+
+/// Deepest nesting of tables read from a sidecar. KOReader writes a few levels; a sidecar
+/// nesting thousands would overflow the stack of the parser.
+const MAX_DEPTH: usize = 64;
+
+/// What KOReader keeps about a book in its sidecar, `<book>.sdr/metadata.<ext>.lua`.
+#[derive(Debug, Default)]
+pub struct Sidecar {
+    /// Modification time of the sidecar, in milliseconds since the Unix epoch.
+    pub modified: u64,
+    /// XPointer of the last position read, see [`LocatorKind::XPointer`].
+    pub last_xpointer: Option<String>,
+    /// From 0 to 1.
+    pub percent_finished: Option<f64>,
+    pub status: Option<ReadingStatus>,
+    /// From 1 to 5.
+    pub rating: Option<u8>,
+    pub annotations: Vec<Annotation>,
+}
+
+/// Finds the KOReader sidecar of `book`: KOReader names the folder after the book without
+/// its last extension and the file after that extension, so `Dune.kepub.epub` has
+/// `Dune.kepub.sdr/metadata.epub.lua`. Books inside archives have none.
+pub fn find_sidecar(book: &Path) -> Option<PathBuf> {
+    if archive::split(book).is_some() {
+        return None;
+    }
+    let dir = book.with_extension("sdr");
+    let extension = book.extension()?.to_string_lossy();
+    let file = dir.join(format!("metadata.{}.lua", extension));
+    if file.is_file() {
+        return Some(file);
+    }
+    // Some versions wrote the extension in lower case.
+    let file = dir.join(format!("metadata.{}.lua", extension.to_lowercase()));
+    return file.is_file().then_some(file);
+}
+
+/// Reads a sidecar. Both the `annotations` list of current KOReader versions and the
+/// `highlight` and `bookmarks` tables of older ones are understood.
+pub fn read_sidecar(path: &Path) -> anyhow::Result<Sidecar> {
+    let modified = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_millis() as u64;
+    let content = fs::read_to_string(path)?;
+    let root = LuaParser::new(&content).parse_document()?;
+
+    let mut sidecar = Sidecar {
+        modified,
+        last_xpointer: root.get("last_xpointer").and_then(Lua::as_str),
+        percent_finished: root.get("percent_finished").and_then(Lua::as_f64),
+        ..Default::default()
+    };
+    let summary = root.get("summary");
+    sidecar.status = summary
+        .and_then(|summary| summary.get("status"))
+        .and_then(|status| match status.as_str()?.as_str() {
+            "reading" => Some(ReadingStatus::Reading),
+            "complete" => Some(ReadingStatus::Finished),
+            "abandoned" => Some(ReadingStatus::Abandoned),
+            _ => None,
+        });
+    sidecar.rating = summary
+        .and_then(|summary| summary.get("rating")?.as_f64())
+        .filter(|rating| (1.0..=5.0).contains(rating))
+        .map(|rating| rating.round() as u8);
+
+    match root.get("annotations") {
+        Some(annotations) => {
+            for (_, item) in annotations.entries() {
+                sidecar.annotations.extend(annotation(item, "note"));
+            }
+        }
+        None => {
+            // Older versions keep highlights by page and bookmarks apart, with the note of a
+            // highlight on its bookmark.
+            let bookmarks: Vec<&Lua> = root
+                .get("bookmarks")
+                .map(|b| b.entries().map(|(_, v)| v).collect())
+                .unwrap_or_default();
+            for (_, page) in root
+                .get("highlight")
+                .map(Lua::entries)
+                .into_iter()
+                .flatten()
+            {
+                for (_, item) in page.entries() {
+                    let Some(mut highlight) = annotation(item, "note") else {
+                        continue;
+                    };
+                    let bookmark = bookmarks.iter().find(|b| {
+                        b.get("pos0").and_then(Lua::as_str)
+                            == item.get("pos0").and_then(Lua::as_str)
+                    });
+                    // Bookmarks of highlights repeat the highlighted text as their note.
+                    if let Some(note) = bookmark.and_then(|b| b.get("notes")?.as_str())
+                        && Some(&note) != highlight.text.as_ref()
+                    {
+                        highlight.kind = AnnotationKind::Note;
+                        highlight.note = Some(note);
+                    }
+                    sidecar.annotations.push(highlight);
+                }
+            }
+            for bookmark in bookmarks {
+                if bookmark.get("pos0").is_none() {
+                    sidecar.annotations.extend(annotation(bookmark, "notes"));
+                }
+            }
+        }
+    }
+    return Ok(sidecar);
+}
+
+/// Reads a highlight (it has `pos0` and `pos1`) or a bookmark (only a `page`). The id is
+/// derived from the position and creation time, so importing the same sidecar again
+/// yields the same annotations.
+fn annotation(item: &Lua, note_field: &str) -> Option<Annotation> {
+    let text = |field: &str| item.get(field).and_then(Lua::as_str);
+    let datetime = text("datetime").unwrap_or_default();
+    let (kind, locator) = match (text("pos0"), text("pos1")) {
+        (Some(pos0), Some(_)) => (AnnotationKind::Highlight, pos0),
+        _ => (AnnotationKind::Bookmark, text("page")?),
+    };
+    let note = text(note_field).filter(|n| !n.trim().is_empty());
+    let kind = match (kind, &note) {
+        (AnnotationKind::Highlight, Some(_)) => AnnotationKind::Note,
+        (kind, _) => kind,
+    };
+    let id = format!(
+        "koreader-{:x}",
+        md5::compute(format!(
+            "{}|{}|{}",
+            locator,
+            text("pos1").unwrap_or_default(),
+            datetime
+        ))
+    );
+    return Some(Annotation {
+        id,
+        kind,
+        locator,
+        locator_kind: LocatorKind::XPointer,
+        text: text("text").filter(|t| kind != AnnotationKind::Bookmark && !t.is_empty()),
+        note: match kind {
+            AnnotationKind::Bookmark => None,
+            _ => note,
+        },
+        color: text("color"),
+        created: parse_datetime(&datetime).unwrap_or_default(),
+    });
+}
+
+/// Parses the `YYYY-MM-DD HH:MM:SS` local times of KOReader as if they were UTC, which is
+/// close enough to order annotations.
+fn parse_datetime(datetime: &str) -> Option<u64> {
+    let mut numbers = datetime
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<i64>().ok());
+    let mut next = || numbers.next().flatten();
+    let (year, month, day) = (next()?, next()?, next()?);
+    let (hour, minute, second) = (
+        next().unwrap_or(0),
+        next().unwrap_or(0),
+        next().unwrap_or(0),
+    );
+    // Days since 1970-01-01 from a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second;
+    return u64::try_from(secs * 1000).ok();
+}
+
+/// A value of the Lua subset KOReader writes its settings in.
+#[derive(Debug, Clone, PartialEq)]
+enum Lua {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Table(Vec<(Lua, Lua)>),
+}
+
+impl Lua {
+    fn get(&self, key: &str) -> Option<&Lua> {
+        let Lua::Table(entries) = self else {
+            return None;
+        };
+        return entries
+            .iter()
+            .find(|(k, _)| matches!(k, Lua::String(s) if s == key))
+            .map(|(_, v)| v);
+    }
+
+    /// Entries of a table, with list items in the order of their index.
+    fn entries(&self) -> impl Iterator<Item = (&Lua, &Lua)> {
+        let mut entries: Vec<(&Lua, &Lua)> = match self {
+            Lua::Table(entries) => entries.iter().map(|(k, v)| (k, v)).collect(),
+            _ => vec![],
+        };
+        entries.sort_by(|(a, _), (b, _)| match (a, b) {
+            (Lua::Number(a), Lua::Number(b)) => a.total_cmp(b),
+            _ => std::cmp::Ordering::Equal,
+        });
+        return entries.into_iter();
+    }
+
+    fn as_str(&self) -> Option<String> {
+        return match self {
+            Lua::String(s) => Some(s.clone()),
+            _ => None,
+        };
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        return match self {
+            Lua::Number(n) => Some(*n),
+            _ => None,
+        };
+    }
+}
+
+/// Parses `return { ... }` with nested tables, strings, numbers and booleans, which is all
+/// KOReader's settings serializer writes. Anything else, like function calls, is an error.
+struct LuaParser<'a> {
+    input: &'a [u8],
+    pos: usize,
+    /// Tables being read, see [`MAX_DEPTH`].
+    depth: usize,
+}
+
+impl<'a> LuaParser<'a> {
+    fn new(input: &'a str) -> Self {
+        return Self {
+            input: input.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+    }
+
+    fn parse_document(&mut self) -> anyhow::Result<Lua> {
+        self.skip_blank();
+        if self.input[self.pos..].starts_with(b"return") {
+            self.pos += "return".len();
+        }
+        let value = self.parse_value()?;
+        self.skip_blank();
+        return Ok(value);
+    }
+
+    fn parse_value(&mut self) -> anyhow::Result<Lua> {
+        self.skip_blank();
+        let rest = &self.input[self.pos..];
+        return match rest.first() {
+            Some(b'{') => self.parse_table(),
+            Some(b'"' | b'\'') => Ok(Lua::String(self.parse_string()?)),
+            Some(b'[') if matches!(rest.get(1), Some(b'[' | b'=')) => {
+                Ok(Lua::String(self.parse_long_string()?))
+            }
+            Some(c) if c.is_ascii_digit() || *c == b'-' || *c == b'.' => self.parse_number(),
+            Some(_) => match self.parse_name().as_str() {
+                "true" => Ok(Lua::Bool(true)),
+                "false" => Ok(Lua::Bool(false)),
+                "nil" => Ok(Lua::Nil),
+                other => Err(self.error(&format!("unexpected `{}`", other))),
+            },
+            None => Err(self.error("unexpected end")),
+        };
+    }
+
+    fn parse_table(&mut self) -> anyhow::Result<Lua> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("tables nested too deeply"));
+        }
+        self.depth += 1;
+        let table = self.parse_entries();
+        self.depth -= 1;
+        return table;
+    }
+
+    fn parse_entries(&mut self) -> anyhow::Result<Lua> {
+        self.expect(b'{')?;
+        let mut entries = Vec::new();
+        let mut index = 1.0;
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Lua::Table(entries));
+                }
+                Some(b'[') if !matches!(self.input.get(self.pos + 1), Some(b'[' | b'=')) => {
+                    self.pos += 1;
+                    let key = self.parse_value()?;
+                    self.skip_blank();
+                    self.expect(b']')?;
+                    self.skip_blank();
+                    self.expect(b'=')?;
+                    entries.push((key, self.parse_value()?));
+                }
+                Some(c) if c.is_ascii_alphabetic() || c == b'_' => {
+                    let start = self.pos;
+                    let name = self.parse_name();
+                    self.skip_blank();
+                    if self.peek() == Some(b'=') {
+                        self.pos += 1;
+                        entries.push((Lua::String(name), self.parse_value()?));
+                    } else {
+                        // `true`, `false` or `nil` as a list item.
+                        self.pos = start;
+                        entries.push((Lua::Number(index), self.parse_value()?));
+                        index += 1.0;
+                    }
+                }
+                Some(_) => {
+                    entries.push((Lua::Number(index), self.parse_value()?));
+                    index += 1.0;
+                }
+                None => return Err(self.error("unterminated table")),
+            }
+            self.skip_blank();
+            if matches!(self.peek(), Some(b',' | b';')) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> anyhow::Result<String> {
+        let quote = self.peek().ok_or_else(|| self.error("expected a string"))?;
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let c = self
+                .next()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            match c {
+                c if c == quote => break,
+                b'\\' => {
+                    let escaped = self
+                        .next()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    match escaped {
+                        b'n' => bytes.push(b'\n'),
+                        b't' => bytes.push(b'\t'),
+                        b'r' => bytes.push(b'\r'),
+                        b'a' => bytes.push(0x07),
+                        b'b' => bytes.push(0x08),
+                        b'f' => bytes.push(0x0c),
+                        b'v' => bytes.push(0x0b),
+                        // A backslash before a line break continues the string on the next line.
+                        b'\n' => bytes.push(b'\n'),
+                        b'0'..=b'9' => {
+                            let mut value = (escaped - b'0') as u32;
+                            for _ in 0..2 {
+                                match self.peek() {
+                                    Some(d @ b'0'..=b'9') => {
+                                        value = value * 10 + (d - b'0') as u32;
+                                        self.pos += 1;
+                                    }
+                                    _ => break,
+                                }
+                            }
+                            bytes.push(value as u8);
+                        }
+                        other => bytes.push(other),
+                    }
+                }
+                c => bytes.push(c),
+            }
+        }
+        return Ok(String::from_utf8_lossy(&bytes).into_owned());
+    }
+
+    /// Parses `[[...]]`, `[==[...]==]` and so on.
+    fn parse_long_string(&mut self) -> anyhow::Result<String> {
+        self.expect(b'[')?;
+        let mut level = 0;
+        while self.peek() == Some(b'=') {
+            level += 1;
+            self.pos += 1;
+        }
+        self.expect(b'[')?;
+        let close = format!("]{}]", "=".repeat(level));
+        let rest = &self.input[self.pos..];
+        let end = rest
+            .windows(close.len())
+            .position(|w| w == close.as_bytes())
+            .ok_or_else(|| self.error("unterminated long string"))?;
+        let mut content = &rest[..end];
+        // A line break right after the opening bracket is not part of the string.
+        if content.first() == Some(&b'\n') {
+            content = &content[1..];
+        }
+        self.pos += end + close.len();
+        return Ok(String::from_utf8_lossy(content).into_owned());
+    }
+
+    fn parse_number(&mut self) -> anyhow::Result<Lua> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || matches!(c, b'.' | b'-' | b'+') {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(&self.input[start..self.pos]).into_owned();
+        let number = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => i64::from_str_radix(hex, 16).map(|n| n as f64).ok(),
+            None => text.parse::<f64>().ok(),
+        };
+        return number
+            .map(Lua::Number)
+            .ok_or_else(|| self.error(&format!("invalid number `{}`", text)));
+    }
+
+    fn parse_name(&mut self) -> String {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_ascii_alphanumeric() || c == b'_' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        return String::from_utf8_lossy(&self.input[start..self.pos]).into_owned();
+    }
+
+    /// Skips white space and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                self.pos += 1;
+            }
+            if !self.input[self.pos..].starts_with(b"--") {
+                return;
+            }
+            self.pos += 2;
+            let start = self.pos;
+            if self.peek() == Some(b'[') && self.parse_long_string().is_ok() {
+                continue;
+            }
+            self.pos = start;
+            while self.next().is_some_and(|c| c != b'\n') {}
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> anyhow::Result<()> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("expected `{}`", c as char)));
+        }
+        self.pos += 1;
+        return Ok(());
+    }
+
+    fn peek(&self) -> Option<u8> {
+        return self.input.get(self.pos).copied();
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        return c;
+    }
+
+    fn error(&self, message: &str) -> anyhow::Error {
+        return anyhow!("Invalid KOReader sidecar at byte {}: {}", self.pos, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::user_data::Progress;
+
+    fn parse(input: &str) -> anyhow::Result<Lua> {
+        return LuaParser::new(input).parse_document();
+    }
+
+    fn text(value: &str) -> Lua {
+        return Lua::String(value.to_string());
+    }
+
+    #[test]
+    fn parses_nested_tables() -> anyhow::Result<()> {
+        let root = parse(
+            r#"-- ./Dune.sdr/metadata.epub.lua
+return {
+    ["summary"] = {
+        ["status"] = "complete", -- finished
+        rating = 4,
+    },
+    ["stats"] = { pages = 0x1F, ["percent"] = 1.5e-1, [3] = true; nil },
+    list = { "a", 'b', [[c]], false },
+}
+"#,
+        )?;
+        let summary = root.get("summary");
+        assert_eq!(
+            summary.and_then(|s| s.get("status")),
+            Some(&text("complete"))
+        );
+        assert_eq!(
+            summary.and_then(|s| s.get("rating")),
+            Some(&Lua::Number(4.0))
+        );
+        let stats = root.get("stats");
+        assert_eq!(stats.and_then(|s| s.get("pages")), Some(&Lua::Number(31.0)));
+        assert_eq!(stats.and_then(|s| s.get("percent")?.as_f64()), Some(0.15));
+        let list: Vec<&Lua> = root
+            .get("list")
+            .map(|l| l.entries().map(|(_, v)| v).collect())
+            .unwrap_or_default();
+        assert_eq!(
+            list,
+            [&text("a"), &text("b"), &text("c"), &Lua::Bool(false)]
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn parses_escaped_strings() -> anyhow::Result<()> {
+        for (input, expected) in [
+            (r#""say \"hi\"""#, "say \"hi\""),
+            (r#"'it\'s'"#, "it's"),
+            (r#""tab\tnew\nline""#, "tab\tnew\nline"),
+            (r#""back\\slash""#, "back\\slash"),
+            (r#""\65\066\0671""#, "ABC1"),
+            (r#""caf\195\169""#, "café"),
+            ("\"two\\\nlines\"", "two\nlines"),
+            ("[==[\nlong ]] string]==]", "long ]] string"),
+        ] {
+            assert_eq!(parse(input)?, text(expected), "{}", input);
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn rejects_malformed_sidecars() {
+        for input in [
+            "",
+            "return",
+            "return {",
+            "return { a = }",
+            "return { [\"a\" = 1 }",
+            "return { a = \"unterminated }",
+            "return { a = [[unterminated }",
+            "return { a = os.exit() }",
+            "return { a = 1.2.3 }",
+            "return { a = - }",
+            "return ]]",
+            "\u{feff}\u{0}\u{ff}",
+        ] {
+            assert!(parse(input).is_err(), "{:?} parsed", input);
+        }
+        // Deep nesting fails rather than overflow the stack.
+        let deep = format!("return {}{}", "{".repeat(100_000), "}".repeat(100_000));
+        assert!(parse(&deep).is_err());
+        let shallow = format!("return {}{}", "{".repeat(MAX_DEPTH), "}".repeat(MAX_DEPTH));
+        assert!(parse(&shallow).is_ok());
+    }
+
+    #[test]
+    fn reads_sidecars_as_xpointers() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("spectecle-koreader-{}", std::process::id()));
+        let sdr = dir.join("Dune.sdr");
+        fs::create_dir_all(&sdr)?;
+        let book = dir.join("Dune.epub");
+        fs::write(&book, "")?;
+        fs::write(
+            sdr.join("metadata.epub.lua"),
+            r#"return {
+    ["last_xpointer"] = "/body/DocFragment[3]/body/p[2]/text().0",
+    ["percent_finished"] = 0.25,
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2024-01-02 03:04:05",
+            ["pos0"] = "/body/DocFragment[3]/body/p[1]/text().0",
+            ["pos1"] = "/body/DocFragment[3]/body/p[1]/text().11",
+            ["text"] = "A beginning",
+            ["note"] = "is a very delicate time",
+        },
+        [2] = { ["page"] = "/body/DocFragment[4]/body/p[1]/text().0" },
+    },
+}"#,
+        )?;
+
+        let path = find_sidecar(&book).ok_or_else(|| anyhow!("No sidecar found"))?;
+        let sidecar = read_sidecar(&path)?;
+        assert_eq!(
+            sidecar.last_xpointer.as_deref(),
+            Some("/body/DocFragment[3]/body/p[2]/text().0")
+        );
+        assert_eq!(sidecar.percent_finished, Some(0.25));
+        let kinds: Vec<AnnotationKind> = sidecar.annotations.iter().map(|a| a.kind).collect();
+        assert_eq!(kinds, [AnnotationKind::Note, AnnotationKind::Bookmark]);
+        assert!(
+            sidecar
+                .annotations
+                .iter()
+                .all(|a| a.locator_kind == LocatorKind::XPointer)
+        );
+        assert_eq!(sidecar.annotations[0].created, 1_704_164_645_000);
+        // Importing the same sidecar again gives the same ids.
+        assert_eq!(read_sidecar(&path)?.annotations, sidecar.annotations);
+
+        // A broken sidecar is an error for its book, not a panic.
+        fs::write(&path, "return { [\"annotations\"] = {")?;
+        assert!(read_sidecar(&path).is_err());
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn locators_of_older_logs_are_cfis() -> anyhow::Result<()> {
+        let progress: Progress =
+            serde_json::from_str(r#"{ "locator": "epubcfi(/6/4!/4/2/1:0)", "percent": 0.5 }"#)?;
+        assert_eq!(progress.locator_kind, LocatorKind::Cfi);
+        return Ok(());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fs::{self, File},
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use ureq::{
+    Agent, Body, RequestBuilder,
+    http::{Response, Uri},
+    typestate::WithoutBody,
+};
+
+use crate::utility::archive;
+
+/// Media type kosync servers answer with, and KOReader asks for.
+pub const KOSYNC_MEDIA_TYPE: &str = "application/vnd.koreader.v1+json";
+/// Name this app reports as its device, where KOReader reports the model of the reader.
+pub const DEVICE_NAME: &str = "Spectecle";
+const ACCOUNT_FILE: &str = "kosync.json";
+const TIMEOUT: Duration = Duration::from_secs(15);
+/// KOReader hashes samples of 1 KiB taken at growing offsets, see [`partial_md5`].
+const SAMPLE_SIZE: u64 = 1024;
+/// Hosts an account on the kosync server of this device uses, see [`KosyncAccount::local_port`].
+const LOOPBACK_HOSTS: [&str; 4] = ["localhost", "127.0.0.1", "::1", "[::1]"];
+
+/// Hashes a book the way KOReader names documents on a kosync server: the MD5 of 1 KiB
+/// samples at offsets 0, 1 KiB, 4 KiB, 16 KiB ... up to 1 GiB, stopping at the end of the
+/// file. Books inside archives are hashed as if they were extracted.
+pub fn partial_md5(path: &Path) -> anyhow::Result<String> {
+    return match archive::split(path) {
+        Some(_) => partial_md5_of(&mut Cursor::new(archive::read_book(path)?)),
+        None => partial_md5_of(&mut File::open(path)?),
+    };
+}
+
+fn partial_md5_of(reader: &mut (impl Read + Seek)) -> anyhow::Result<String> {
+    let mut context = md5::Context::new();
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE as usize);
+    for i in -1..=10 {
+        // KOReader computes `lshift(1024, 2 * i)`; LuaJIT masks the shift count, so the
+        // first offset, `1024 << -2`, wraps to 0.
+        let offset = match i {
+            -1 => 0,
+            i => SAMPLE_SIZE << (2 * i),
+        };
+        reader.seek(SeekFrom::Start(offset))?;
+        sample.clear();
+        reader.by_ref().take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+        if sample.is_empty() {
+            break;
+        }
+        context.consume(&sample);
+    }
+    return Ok(format!("{:x}", context.finalize()));
+}
+
+/// MD5 of a password, which is all a kosync server ever sees of it.
+pub fn userkey(password: &str) -> String {
+    return format!("{:x}", md5::compute(password));
+}
+
+/// Position in a document as stored on a kosync server.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KosyncProgress {
+    /// Hash of the document, see [`partial_md5`].
+    pub document: String,
+    /// An XPointer for reflowable documents in KOReader, passed through as is.
+    pub progress: String,
+    /// From 0 to 1.
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: String,
+    /// Seconds since the Unix epoch, set by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+/// Account on a kosync server, kept in the app support directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KosyncAccount {
+    /// Base URL of the server, e.g. `https://sync.koreader.rocks`.
+    pub server: String,
+    pub username: String,
+    /// See [`userkey`].
+    pub userkey: String,
+}
+
+impl KosyncAccount {
+    pub fn new(server: &str, username: &str, password: &str) -> Self {
+        return Self {
+            server: server.trim().trim_end_matches('/').to_string(),
+            username: username.trim().to_string(),
+            userkey: userkey(password),
+        };
+    }
+
+    /// Port of the server if the account is on this device, i.e. on the kosync server of the
+    /// app, see [`crate::utility::kosync_server::KosyncServer`].
+    pub fn local_port(&self) -> Option<u16> {
+        let uri: Uri = self.server.parse().ok()?;
+        if !LOOPBACK_HOSTS.contains(&uri.host()?) {
+            return None;
+        }
+        return Some(uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") => 443,
+            _ => 80,
+        }));
+    }
+
+    /// The account moved to the kosync server of this device listening on `port`.
+    pub fn with_local_port(&self, port: u16) -> Self {
+        return Self {
+            server: format!("http://127.0.0.1:{}", port),
+            ..self.clone()
+        };
+    }
+
+    /// Reads the saved account, if any.
+    pub fn read(support_dir: &Path) -> anyhow::Result<Option<Self>> {
+        let file = support_dir.join(ACCOUNT_FILE);
+        if !file.exists() {
+            return Ok(None);
+        }
+        return Ok(Some(serde_json::from_str(&fs::read_to_string(file)?)?));
+    }
+
+    /// Saves `account`, or forgets the saved one for `None`.
+    pub fn write(account: Option<&Self>, support_dir: &Path) -> anyhow::Result<()> {
+        let file = support_dir.join(ACCOUNT_FILE);
+        match account {
+            Some(account) => fs::write(file, serde_json::to_string_pretty(account)?)?,
+            None if file.exists() => fs::remove_file(file)?,
+            None => {}
+        }
+        return Ok(());
+    }
+}
+
+/// Client of the kosync protocol KOReader uses to sync reading progress, as spoken by
+/// koreader-sync-server and compatible servers.
+///
+/// Every call blocks, run them off the async runtime.
+pub struct KosyncClient {
+    agent: Agent,
+    account: KosyncAccount,
+}
+
+impl KosyncClient {
+    pub fn new(account: KosyncAccount) -> Self {
+        let agent = Agent::config_builder()
+            .timeout_global(Some(TIMEOUT))
+            // Error statuses come with a message worth showing.
+            .http_status_as_error(false)
+            .build()
+            .into();
+        return Self { agent, account };
+    }
+
+    /// Creates the account on the server.
+    pub fn register(&self) -> anyhow::Result<()> {
+        let body = json!({
+            "username": self.account.username,
+            "password": self.account.userkey,
+        });
+        let response = self
+            .agent
+            .post(self.url("/users/create"))
+            .header("Accept", KOSYNC_MEDIA_TYPE)
+            .header("Content-Type", "application/json")
+            .send(body.to_string())
+            .map_err(|e| anyhow!("Failed to reach {}: {}", self.account.server, e))?;
+        check(response)?;
+        return Ok(());
+    }
+
+    /// Checks the username and password.
+    pub fn authorize(&self) -> anyhow::Result<()> {
+        let request = self.authenticated(self.agent.get(self.url("/users/auth")));
+        check(self.call(request)?)?;
+        return Ok(());
+    }
+
+    /// Latest progress saved for `document` by any device, `None` if there is none.
+    pub fn pull(&self, document: &str) -> anyhow::Result<Option<KosyncProgress>> {
+        let url = self.url(&format!("/syncs/progress/{}", document));
+        let request = self.authenticated(self.agent.get(url));
+        let mut response = check(self.call(request)?)?;
+        let body: Value = serde_json::from_str(&response.body_mut().read_to_string()?)?;
+        // Documents never synced are answered with an empty object.
+        if body.get("progress").is_none_or(Value::is_null) {
+            return Ok(None);
+        }
+        return Ok(Some(serde_json::from_value(body)?));
+    }
+
+    pub fn push(&self, progress: &KosyncProgress) -> anyhow::Result<()> {
+        let response = self
+            .authenticated(self.agent.put(self.url("/syncs/progress")))
+            .header("Content-Type", "application/json")
+            .send(serde_json::to_string(progress)?)
+            .map_err(|e| anyhow!("Failed to reach {}: {}", self.account.server, e))?;
+        check(response)?;
+        return Ok(());
+    }
+
+    fn url(&self, path: &str) -> String {
+        return format!("{}{}", self.account.server, path);
+    }
+
+    fn authenticated<B>(&self, request: RequestBuilder<B>) -> RequestBuilder<B> {
+        return request
+            .header("Accept", KOSYNC_MEDIA_TYPE)
+            .header("x-auth-user", &self.account.username)
+            .header("x-auth-key", &self.account.userkey);
+    }
+
+    fn call(&self, request: RequestBuilder<WithoutBody>) -> anyhow::Result<Response<Body>> {
+        return request
+            .call()
+            .map_err(|e| anyhow!("Failed to reach {}: {}", self.account.server, e));
+    }
+}
+
+/// Turns error statuses into errors with the message of the server.
+fn check(mut response: Response<Body>) -> anyhow::Result<Response<Body>> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let message = response
+        .body_mut()
+        .read_to_string()
+        .ok()
+        .and_then(|body| serde_json::from_str::<Value>(&body).ok())
+        .and_then(|body| body.get("message")?.as_str().map(str::to_string))
+        .unwrap_or_else(|| status.to_string());
+    return Err(anyhow!("The sync server refused the request: {}", message));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_like_koreader() -> anyhow::Result<()> {
+        // Hashed with KOReader's `util.partialMD5`: samples at 0, 1 KiB, 4 KiB, 16 KiB,
+        // 64 KiB and 256 KiB, the last one cut short by the end of the file.
+        let book: Vec<u8> = (0..262_644).map(|i| (i % 253) as u8).collect();
+        assert_eq!(
+            partial_md5_of(&mut Cursor::new(book))?,
+            "3990ff65dbf6429c21989b6cd670a0a7"
+        );
+        assert_eq!(
+            partial_md5_of(&mut Cursor::new(Vec::new()))?,
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        return Ok(());
+    }
+
+    #[test]
+    fn finds_accounts_on_this_device() {
+        let account = |server: &str| KosyncAccount::new(server, "reader", "secret");
+        assert_eq!(account("http://127.0.0.1:7200").local_port(), Some(7200));
+        assert_eq!(account("http://localhost/").local_port(), Some(80));
+        assert_eq!(account("http://[::1]:7200").local_port(), Some(7200));
+        assert_eq!(account("https://sync.koreader.rocks").local_port(), None);
+        assert_eq!(
+            account("http://localhost:7200")
+                .with_local_port(7300)
+                .local_port(),
+            Some(7300)
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tiny_http::{Method, Request, Response, ResponseBox, Server, StatusCode};
+
+use crate::utility::{
+    kosync::{KOSYNC_MEDIA_TYPE, KosyncProgress},
+    server::header,
+};
+
+/// Progress updates are tiny, two threads keep up with a household of readers.
+const WORKER_THREADS: usize = 2;
+const STORE_FILE: &str = "kosync_server.json";
+/// Largest request body read, progress updates are well under a kilobyte.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct KosyncServerConfig {
+    /// Port to listen on, `0` picks a free one.
+    pub port: u16,
+    /// Let KOReader create accounts; otherwise only existing ones can sync.
+    pub allow_registration: bool,
+}
+
+/// Accounts and the latest progress of each of their documents.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Store {
+    /// Userkey of each username.
+    users: HashMap<String, String>,
+    /// Progress by username, then document.
+    progress: HashMap<String, HashMap<String, KosyncProgress>>,
+}
+
+struct Shared {
+    config: KosyncServerConfig,
+    store: Mutex<Store>,
+    store_file: PathBuf,
+}
+
+/// A kosync server compatible with koreader-sync-server, so KOReader devices can sync their
+/// progress without a server of their own. It listens on every interface (`0.0.0.0`) so that
+/// devices on the local network reach it. Accounts and progress are kept in
+/// `kosync_server.json` in the app support directory, apart from the sync logs of the
+/// libraries: the app syncs with it as a client, see [`KosyncAccount::local_port`].
+///
+/// The server runs on its own threads until it is dropped.
+pub struct KosyncServer {
+    server: Arc<Server>,
+    workers: Vec<JoinHandle<()>>,
+    port: u16,
+}
+
+impl KosyncServer {
+    pub fn start(config: KosyncServerConfig, support_dir: &Path) -> anyhow::Result<Self> {
+        let store_file = support_dir.join(STORE_FILE);
+        let store = match store_file.exists() {
+            true => serde_json::from_str(&fs::read_to_string(&store_file)?)?,
+            false => Store::default(),
+        };
+        let server = Server::http(("0.0.0.0", config.port))
+            .map_err(|e| anyhow!("Failed to listen on port {}: {}", config.port, e))?;
+        let port = server
+            .server_addr()
+            .to_ip()
+            .map(|addr| addr.port())
+            .unwrap_or(config.port);
+        let server = Arc::new(server);
+        let shared = Arc::new(Shared {
+            config,
+            store: Mutex::new(store),
+            store_file,
+        });
+        let workers = (0..WORKER_THREADS)
+            .map(|_| {
+                let server = server.clone();
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    for request in server.incoming_requests() {
+                        handle(request, &shared);
+                    }
+                })
+            })
+            .collect();
+        return Ok(Self {
+            server,
+            workers,
+            port,
+        });
+    }
+
+    pub fn port(&self) -> u16 {
+        return self.port;
+    }
+}
+
+impl Drop for KosyncServer {
+    fn drop(&mut self) {
+        // Each unblock wakes a single worker.
+        for _ in &self.workers {
+            self.server.unblock();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Errors of koreader-sync-server, which KOReader tells apart by their code.
+#[derive(Debug, Clone, Copy)]
+enum KosyncError {
+    Unauthorized,
+    UserExists,
+    InvalidRequest,
+    NoDocument,
+    RegistrationDisabled,
+}
+
+impl KosyncError {
+    fn response(self) -> ResponseBox {
+        let (status, code, message) = match self {
+            Self::Unauthorized => (401, 2001, "Unauthorized"),
+            Self::UserExists => (402, 2002, "Username is already registered."),
+            Self::InvalidRequest => (403, 2003, "Invalid request"),
+            Self::NoDocument => (403, 2004, "Field 'document' not provided."),
+            Self::RegistrationDisabled => (403, 2005, "User registration is disabled."),
+        };
+        return json_response(status, json!({ "code": code, "message": message }));
+    }
+}
+
+fn handle(mut request: Request, shared: &Shared) {
+    let response = route(&mut request, shared).unwrap_or_else(|e| {
+        json_response(500, json!({ "code": 2000, "message": format!("{:#}", e) }))
+    });
+    let _ = request.respond(response);
+}
+
+fn route(request: &mut Request, shared: &Shared) -> anyhow::Result<ResponseBox> {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .to_string();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let method = request.method().clone();
+    match (&method, segments.as_slice()) {
+        (Method::Get, ["healthcheck"]) => return Ok(json_response(200, json!({ "state": "OK" }))),
+        (Method::Post, ["users", "create"]) => return create_user(request, shared),
+        _ => {}
+    }
+
+    let Some(username) = authorized_user(request, shared)? else {
+        return Ok(KosyncError::Unauthorized.response());
+    };
+    return match (&method, segments.as_slice()) {
+        (Method::Get, ["users", "auth"]) => Ok(json_response(200, json!({ "authorized": "OK" }))),
+        (Method::Put, ["syncs", "progress"]) => update_progress(request, shared, &username),
+        (Method::Get, ["syncs", "progress", document]) => {
+            let store = lock(shared)?;
+            let progress = store
+                .progress
+                .get(&username)
+                .and_then(|documents| documents.get(*document));
+            Ok(match progress {
+                Some(progress) => json_response(200, serde_json::to_value(progress)?),
+                None => json_response(200, json!({})),
+            })
+        }
+        _ => Ok(json_response(404, json!({ "message": "Not found" }))),
+    };
+}
+
+fn create_user(request: &mut Request, shared: &Shared) -> anyhow::Result<ResponseBox> {
+    if !shared.config.allow_registration {
+        return Ok(KosyncError::RegistrationDisabled.response());
+    }
+    let body = read_json(request)?;
+    let field = |name: &str| {
+        body.get(name)
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+    };
+    let (Some(username), Some(userkey)) = (field("username"), field("password")) else {
+        return Ok(KosyncError::InvalidRequest.response());
+    };
+    let mut store = lock(shared)?;
+    if store.users.contains_key(&username) {
+        return Ok(KosyncError::UserExists.response());
+    }
+    store.users.insert(username.clone(), userkey);
+    write_store(&store, &shared.store_file)?;
+    return Ok(json_response(201, json!({ "username": username })));
+}
+
+fn update_progress(
+    request: &mut Request,
+    shared: &Shared,
+    username: &str,
+) -> anyhow::Result<ResponseBox> {
+    let body = read_json(request)?;
+    let Some(document) = body
+        .get("document")
+        .and_then(Value::as_str)
+        .filter(|d| !d.is_empty())
+        .map(str::to_string)
+    else {
+        return Ok(KosyncError::NoDocument.response());
+    };
+    let text = |name: &str| {
+        body.get(name)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let Some(percentage) = body.get("percentage").and_then(Value::as_f64) else {
+        return Ok(KosyncError::InvalidRequest.response());
+    };
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let progress = KosyncProgress {
+        document: document.clone(),
+        progress: text("progress"),
+        percentage,
+        device: text("device"),
+        device_id: text("device_id"),
+        timestamp: Some(timestamp),
+    };
+    let mut store = lock(shared)?;
+    store
+        .progress
+        .entry(username.to_string())
+        .or_default()
+        .insert(document.clone(), progress);
+    write_store(&store, &shared.store_file)?;
+    return Ok(json_response(
+        200,
+        json!({ "document": document, "timestamp": timestamp }),
+    ));
+}
+
+/// The user the `x-auth-user` and `x-auth-key` headers authenticate, if they match.
+fn authorized_user(request: &Request, shared: &Shared) -> anyhow::Result<Option<String>> {
+    let value = |field: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(field))
+            .map(|h| h.value.as_str().to_string())
+    };
+    let (Some(username), Some(userkey)) = (value("x-auth-user"), value("x-auth-key")) else {
+        return Ok(None);
+    };
+    let store = lock(shared)?;
+    return Ok((store.users.get(&username) == Some(&userkey)).then_some(username));
+}
+
+fn read_json(request: &mut Request) -> anyhow::Result<Value> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_BYTES)
+        .read_to_string(&mut body)?;
+    return Ok(serde_json::from_str(&body).unwrap_or(Value::Null));
+}
+
+fn lock(shared: &Shared) -> anyhow::Result<std::sync::MutexGuard<'_, Store>> {
+    return shared
+        .store
+        .lock()
+        .map_err(|_| anyhow!("The kosync store is poisoned."));
+}
+
+/// Writes the store aside then renames it, so a crash never leaves it half written.
+fn write_store(store: &Store, file: &Path) -> anyhow::Result<()> {
+    let partial = file.with_extension("json.part");
+    fs::write(&partial, serde_json::to_string_pretty(store)?)?;
+    fs::rename(&partial, file)?;
+    return Ok(());
+}
+
+fn json_response(status: u16, body: Value) -> ResponseBox {
+    let response = Response::from_string(body.to_string()).with_status_code(StatusCode(status));
+    return match header("Content-Type", KOSYNC_MEDIA_TYPE) {
+        anyhow::Result::Ok(header) => response.with_header(header).boxed(),
+        Err(_) => response.boxed(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utility::kosync::{KosyncAccount, KosyncClient};
+
+    #[test]
+    fn syncs_progress_between_clients() -> anyhow::Result<()> {
+        let support_dir =
+            std::env::temp_dir().join(format!("spectecle-kosync-{}", std::process::id()));
+        if support_dir.exists() {
+            fs::remove_dir_all(&support_dir)?;
+        }
+        fs::create_dir_all(&support_dir)?;
+        let config = KosyncServerConfig {
+            port: 0,
+            allow_registration: true,
+        };
+        let server = KosyncServer::start(config, &support_dir)?;
+        let url = format!("http://127.0.0.1:{}", server.port());
+        let client = KosyncClient::new(KosyncAccount::new(&url, "reader", "secret"));
+
+        client.register()?;
+        assert!(client.register().is_err());
+        client.authorize()?;
+        let intruder = KosyncClient::new(KosyncAccount::new(&url, "reader", "guess"));
+        assert!(intruder.authorize().is_err());
+        assert_eq!(client.pull("0123456789abcdef")?, None);
+
+        let progress = KosyncProgress {
+            document: "0123456789abcdef".to_string(),
+            progress: "/body/DocFragment[7]/body/p[4]/text().0".to_string(),
+            percentage: 0.42,
+            device: "Kobo".to_string(),
+            device_id: "kobo-1".to_string(),
+            timestamp: None,
+        };
+        client.push(&progress)?;
+        let pulled = client.pull(&progress.document)?;
+        assert!(pulled.as_ref().is_some_and(|p| p.timestamp.is_some()));
+        assert_eq!(
+            pulled.map(|p| KosyncProgress {
+                timestamp: None,
+                ..p
+            }),
+            Some(progress)
+        );
+
+        // Accounts and progress outlive the server.
+        drop(server);
+        let server = KosyncServer::start(
+            KosyncServerConfig {
+                port: 0,
+                allow_registration: false,
+            },
+            &support_dir,
+        )?;
+        let url = format!("http://127.0.0.1:{}", server.port());
+        let client = KosyncClient::new(KosyncAccount::new(&url, "reader", "secret"));
+        assert!(client.pull("0123456789abcdef")?.is_some());
+        let newcomer = KosyncClient::new(KosyncAccount::new(&url, "newcomer", "secret"));
+        assert!(newcomer.register().is_err());
+        drop(server);
+        fs::remove_dir_all(support_dir)?;
+        return Ok(());
+    }
+}
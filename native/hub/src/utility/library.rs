@@ -1,38 +1,124 @@
 use std::{
+    collections::{BTreeMap, HashMap},
     fs,
+    hash::{DefaultHasher, Hash, Hasher},
     io::Read,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::SystemTime,
 };
 
-use anyhow::Ok;
+use anyhow::{Ok, anyhow};
 use serde::{Deserialize, Serialize};
 
+use crate::utility::{collection::Collection, overlay::MetadataOverlay, scan::ScanRules};
+
+/// Folder inside each library root holding what the app keeps about it.
+pub const DATA_DIR: &str = ".spectecle";
+/// File in the data folder of a library holding its id, see [`mark_root`].
+const ID_FILE: &str = "library-id";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Library {
     open_lib: Option<PathBuf>,
     libraries: Vec<PathBuf>,
+    #[serde(default)]
+    settings: HashMap<PathBuf, LibrarySettings>,
+}
+
+/// Per library preferences, keyed by the library root.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LibrarySettings {
+    /// Stable identifier of the library, independent of where its root lives.
+    #[serde(default)]
+    pub id: String,
+    /// Maximum number of books parsed at once while indexing, `None` uses every core.
+    /// Lower values help on slow storage such as SD cards.
+    #[serde(default)]
+    pub index_concurrency: Option<usize>,
+    #[serde(default)]
+    pub cache_location: CacheLocation,
+    #[serde(default)]
+    pub scan: ScanRules,
+    /// Reading lists, in the order they are shown.
+    #[serde(default)]
+    pub collections: Vec<Collection>,
+    /// Metadata corrections by book identity, see [`MetadataOverlay`] and
+    /// [`crate::utility::sync::book_identity`].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub overlays: BTreeMap<String, MetadataOverlay>,
+    /// Id the root is marked with when it differs from `id`, see [`mark_root`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_marker: Option<String>,
+}
+
+/// Where the cache of a library is stored.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CacheLocation {
+    /// `<library>/.spectecle/cache`, travels with the books.
+    #[default]
+    InLibrary,
+    /// `<support_dir>/caches/<library id>`, for read-only or shared library folders.
+    AppSupport,
+    /// `<path>/<library id>`, so one custom folder can hold the caches of several libraries.
+    Custom(PathBuf),
+}
+
+impl LibrarySettings {
+    pub fn cache_dir(&self, lib_path: &Path, support_dir: &Path) -> PathBuf {
+        return match &self.cache_location {
+            CacheLocation::InLibrary => lib_path.join(DATA_DIR).join("cache"),
+            CacheLocation::AppSupport => support_dir.join("caches").join(&self.id),
+            CacheLocation::Custom(path) => path.join(&self.id),
+        };
+    }
+
+    /// Id the root of the library is recognised by, see [`is_reachable`]. Devices sharing the
+    /// library folder give it ids of their own but agree on the marker of the first one.
+    pub fn root_marker(&self) -> &str {
+        return self.root_marker.as_deref().unwrap_or(&self.id);
+    }
+
+    pub fn set_root_marker(&mut self, marker: String) {
+        self.root_marker = (marker != self.id).then_some(marker);
+    }
+
+    /// `<support_dir>/sync/<library id>`, where the user data of the library waits while the
+    /// library cannot be written, see [`crate::utility::sync::SyncLog::open`].
+    pub fn sync_dir(&self, support_dir: &Path) -> PathBuf {
+        return support_dir.join("sync").join(&self.id);
+    }
 }
 
 impl Library {
-    pub fn open(support_dir: &PathBuf) -> anyhow::Result<Library> {
+    pub fn open(support_dir: &Path) -> anyhow::Result<Library> {
         let lib_file = support_dir.join("lib.json");
         if lib_file.exists() {
             let mut file = fs::File::open(&lib_file)?;
             let mut contents = String::new();
             file.read_to_string(&mut contents)?;
-            let data: Library = serde_json::from_str(&contents)?;
+            let mut data: Library = serde_json::from_str(&contents)?;
+            // Libraries imported before ids existed get one now.
+            let libraries = data.libraries.clone();
+            let missing_ids = libraries
+                .into_iter()
+                .filter(|lib_path| data.ensure_id(lib_path.clone()))
+                .count();
+            if missing_ids > 0 {
+                data.write(support_dir)?;
+            }
             return Ok(data);
         }
         let data = Library {
             open_lib: None,
             libraries: Vec::new(),
+            settings: HashMap::new(),
         };
         let lib_json_string = serde_json::to_string_pretty(&data)?;
         std::fs::write(lib_file, lib_json_string)?;
         return Ok(data);
     }
 
-    pub fn write(&self, support_dir: &PathBuf) -> anyhow::Result<()> {
+    pub fn write(&self, support_dir: &Path) -> anyhow::Result<()> {
         let content = serde_json::to_string_pretty(self)?;
         let lib = support_dir.join("lib.json");
         std::fs::write(lib, content)?;
@@ -43,16 +129,206 @@ impl Library {
         return self.open_lib.clone();
     }
 
+    pub fn get_libraries(&self) -> &[PathBuf] {
+        return &self.libraries;
+    }
+
     pub fn has_lib(&self) -> bool {
         return self.open_lib.is_some();
     }
 
+    pub fn has_lib_path(&self, lib_path: &Path) -> bool {
+        return self.libraries.iter().any(|lib| lib == lib_path);
+    }
+
+    pub fn get_settings(&self, lib_path: &Path) -> LibrarySettings {
+        return self.settings.get(lib_path).cloned().unwrap_or_default();
+    }
+
+    /// Settings of a registered library, borrowed.
+    pub fn settings(&self, lib_path: &Path) -> Option<&LibrarySettings> {
+        return self.settings.get(lib_path);
+    }
+
+    pub fn settings_mut(&mut self, lib_path: PathBuf) -> &mut LibrarySettings {
+        return self.settings.entry(lib_path).or_default();
+    }
+
+    /// Gives the library an id if it does not have one yet, returning `true` if it was missing.
+    fn ensure_id(&mut self, lib_path: PathBuf) -> bool {
+        let settings = self.settings_mut(lib_path.clone());
+        if !settings.id.is_empty() {
+            return false;
+        }
+        let mut hasher = DefaultHasher::new();
+        lib_path.hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        settings.id = format!("{:x}", hasher.finish());
+        return true;
+    }
+
     fn add_lib(&mut self, lib_path: PathBuf) {
-        self.libraries.push(lib_path);
+        if !self.libraries.contains(&lib_path) {
+            self.libraries.push(lib_path.clone());
+        }
+        self.ensure_id(lib_path);
     }
 
     pub fn add_lib_and_switch(&mut self, lib_path: PathBuf) {
         self.add_lib(lib_path.clone());
         self.open_lib = Some(lib_path);
     }
+
+    /// Returns the root of the library with the given id.
+    pub fn find_by_id(&self, id: &str) -> Option<PathBuf> {
+        return self
+            .libraries
+            .iter()
+            .find(|lib| self.settings.get(*lib).is_some_and(|s| s.id == id))
+            .cloned();
+    }
+
+    /// Registers a library with the given settings, e.g. restored from a backup, without
+    /// opening it.
+    pub fn register(&mut self, lib_path: PathBuf, settings: LibrarySettings) {
+        if !self.libraries.contains(&lib_path) {
+            self.libraries.push(lib_path.clone());
+        }
+        self.settings.insert(lib_path.clone(), settings);
+        self.ensure_id(lib_path);
+    }
+
+    /// Opens a registered library, or none.
+    pub fn switch_to(&mut self, lib_path: Option<PathBuf>) -> anyhow::Result<()> {
+        if let Some(lib) = &lib_path
+            && !self.has_lib_path(lib)
+        {
+            return Err(anyhow!("{} is not a library", lib.display()));
+        }
+        self.open_lib = lib_path;
+        return Ok(());
+    }
+
+    /// Forgets every library. Their folders are left as they are.
+    pub fn clear(&mut self) {
+        self.open_lib = None;
+        self.libraries.clear();
+        self.settings.clear();
+    }
+
+    /// Points the library at `old` to its new root `new`, keeping its id and settings.
+    pub fn relocate(&mut self, old: &Path, new: PathBuf) -> anyhow::Result<()> {
+        let index = self
+            .libraries
+            .iter()
+            .position(|lib| lib == old)
+            .ok_or_else(|| anyhow!("{} is not a library", old.display()))?;
+        if self.has_lib_path(&new) {
+            return Err(anyhow!("{} is already a library", new.display()));
+        }
+        self.libraries[index] = new.clone();
+        if let Some(settings) = self.settings.remove(old) {
+            self.settings.insert(new.clone(), settings);
+        }
+        if self.open_lib.as_deref() == Some(old) {
+            self.open_lib = Some(new);
+        }
+        return Ok(());
+    }
+}
+
+/// Leaves the id of the library in its root, so that a root holding no books can be told
+/// apart from the empty mount point an unmounted drive leaves behind, see [`is_reachable`].
+/// A root that is marked already keeps its marker, so that devices sharing the folder do
+/// not keep overwriting each other's. Returns the id the root is marked with. Fails on
+/// read-only roots that are not marked yet.
+pub fn mark_root(lib_path: &Path, id: &str) -> anyhow::Result<String> {
+    let dir = lib_path.join(DATA_DIR);
+    if let anyhow::Result::Ok(marked) = fs::read_to_string(dir.join(ID_FILE))
+        && !marked.trim().is_empty()
+    {
+        return Ok(marked.trim().to_string());
+    }
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(ID_FILE), id)?;
+    return Ok(id.to_string());
+}
+
+/// Returns `false` if the root of a library cannot be read. A root marked with `marker`, see
+/// [`LibrarySettings::root_marker`], is reachable even without any book; an unmarked one,
+/// e.g. read-only, is only trusted when it is not empty or the library is not known to hold
+/// books (`has_books`), as unmounted SD cards and network shares often leave their mount
+/// point behind as an empty directory.
+pub fn is_reachable(lib_path: &Path, marker: &str, has_books: bool) -> bool {
+    let anyhow::Result::Ok(mut entries) = fs::read_dir(lib_path) else {
+        return false;
+    };
+    let marked = fs::read_to_string(lib_path.join(DATA_DIR).join(ID_FILE))
+        .is_ok_and(|marked| !marker.is_empty() && marked.trim() == marker);
+    return marked || !has_books || entries.next().is_some();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> anyhow::Result<PathBuf> {
+        let dir =
+            std::env::temp_dir().join(format!("spectecle-library-{}-{}", name, std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        return Ok(dir);
+    }
+
+    #[test]
+    fn marks_a_root_only_once() -> anyhow::Result<()> {
+        let root = temp_dir("mark")?;
+        assert_eq!(mark_root(&root, "phone")?, "phone");
+        // A second device sharing the folder adopts the marker instead of replacing it.
+        assert_eq!(mark_root(&root, "tablet")?, "phone");
+        assert_eq!(
+            fs::read_to_string(root.join(DATA_DIR).join(ID_FILE))?,
+            "phone"
+        );
+
+        let mut settings = LibrarySettings {
+            id: "tablet".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(settings.root_marker(), "tablet");
+        settings.set_root_marker("phone".to_string());
+        assert_eq!(settings.root_marker(), "phone");
+        assert!(is_reachable(&root, settings.root_marker(), true));
+        settings.set_root_marker("tablet".to_string());
+        assert_eq!(settings.root_marker, None);
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn tells_an_unmounted_root_from_an_empty_library() -> anyhow::Result<()> {
+        let dir = temp_dir("reachable")?;
+        let root = dir.join("Books");
+        assert!(!is_reachable(&root, "phone", false));
+
+        // An empty mount point is only trusted for a library without books.
+        fs::create_dir_all(&root)?;
+        assert!(is_reachable(&root, "phone", false));
+        assert!(!is_reachable(&root, "phone", true));
+
+        mark_root(&root, "phone")?;
+        assert!(is_reachable(&root, "phone", true));
+
+        // Unmarked, e.g. read-only, roots are recognised by what they hold.
+        fs::remove_dir_all(root.join(DATA_DIR))?;
+        assert!(!is_reachable(&root, "phone", true));
+        fs::write(root.join("dune.epub"), "")?;
+        assert!(is_reachable(&root, "phone", true));
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
 }
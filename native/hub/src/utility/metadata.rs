@@ -0,0 +1,26 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Descriptive metadata of a book besides its title.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookMetadata {
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// How the authors sort, e.g. `Tolkien, J. R. R.`.
+    #[serde(default)]
+    pub author_sort: Option<String>,
+    #[serde(default)]
+    pub series: Option<String>,
+    /// Position in `series`, fractional for novellas between two books.
+    #[serde(default)]
+    pub series_index: Option<f64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Out of 10, i.e. in half stars, like Calibre stores it.
+    #[serde(default)]
+    pub rating: Option<u8>,
+    /// Identifier scheme (`isbn`, `uuid`, `amazon` ...) to value.
+    #[serde(default)]
+    pub identifiers: BTreeMap<String, String>,
+}
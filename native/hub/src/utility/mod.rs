@@ -1,3 +1,28 @@
+pub mod archive;
+pub mod backup;
+pub mod budget;
 pub mod cache;
+pub mod calibre;
+pub mod collection;
+pub mod cover;
+pub mod epub_edit;
+pub mod export;
+#[cfg(test)]
+pub mod fixtures;
+pub mod href;
+pub mod koreader;
+pub mod kosync;
+pub mod kosync_server;
 pub mod library;
+pub mod metadata;
+pub mod opds;
+pub mod opds_client;
+pub mod opf;
+pub mod organize;
+pub mod overlay;
+pub mod parser;
+pub mod scan;
+pub mod server;
 pub mod state;
+pub mod sync;
+pub mod user_data;
@@ -0,0 +1,499 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use quick_xml::escape::escape;
+use serde_json::{Value, json};
+
+use crate::utility::cache::{Cache, CacheItem};
+
+/// Books per page of an acquisition feed.
+pub const PAGE_SIZE: usize = 50;
+
+pub const ATOM_NAVIGATION: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+pub const ATOM_ACQUISITION: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+pub const OPDS2: &str = "application/opds+json";
+pub const OPENSEARCH: &str = "application/opensearchdescription+xml";
+const EPUB: &str = "application/epub+zip";
+const JPEG: &str = "image/jpeg";
+
+/// A catalog feed, rendered as OPDS 1.2 ([`Feed::to_atom`]) or OPDS 2.0 ([`Feed::to_json`]).
+///
+/// Hrefs starting with `/` are used as is, others are relative to the catalog root of the
+/// format (`/opds/` or `/opds2/`) so the same feed serves both.
+#[derive(Debug)]
+pub struct Feed {
+    pub id: String,
+    pub title: String,
+    pub self_href: String,
+    /// Library root the search link of the feed points at, if any.
+    pub search_href: Option<String>,
+    pub next_href: Option<String>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+pub enum Entry {
+    /// A link to another feed.
+    Navigation {
+        id: String,
+        title: String,
+        href: String,
+        content: Option<String>,
+        /// The target lists books rather than more links.
+        acquisition: bool,
+    },
+    Book(BookEntry),
+}
+
+#[derive(Debug)]
+pub struct BookEntry {
+    pub id: String,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub tags: Vec<String>,
+    pub series: Option<(String, Option<f64>)>,
+    pub identifiers: BTreeMap<String, String>,
+    pub updated: String,
+    pub cover_href: Option<String>,
+    pub download_href: String,
+}
+
+impl BookEntry {
+    /// Entry of a cached book of the library with the given id.
+    pub fn new(library_id: &str, cache: &Cache, item: &CacheItem) -> Self {
+        let metadata = item.metadata();
+        let key = encode(item.key());
+        return BookEntry {
+            id: format!("urn:spectecle:{}:{}", library_id, item.key()),
+            title: item.title().to_string(),
+            authors: metadata.authors.clone(),
+            tags: metadata.tags.clone(),
+            series: metadata
+                .series
+                .clone()
+                .map(|series| (series, metadata.series_index)),
+            identifiers: metadata.identifiers.clone(),
+            updated: rfc3339(item.last_modified()),
+            cover_href: cache
+                .cover_file(item)
+                .map(|_| format!("/covers/{}/{}", library_id, key)),
+            download_href: format!("/books/{}/{}", library_id, key),
+        };
+    }
+}
+
+impl Feed {
+    fn is_acquisition(&self) -> bool {
+        return self.entries.iter().any(|e| matches!(e, Entry::Book(_)));
+    }
+
+    pub fn to_atom(&self) -> String {
+        let href = |href: &str| escape(resolve(href, "/opds/")).into_owned();
+        let kind = match self.is_acquisition() {
+            true => ATOM_ACQUISITION,
+            false => ATOM_NAVIGATION,
+        };
+        let now = rfc3339(now_millis());
+        let mut xml = String::from(concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/""#,
+            r#" xmlns:opds="http://opds-spec.org/2010/catalog">"#,
+            "\n"
+        ));
+        xml += &format!("<id>{}</id>\n", escape(&self.id));
+        xml += &format!("<title>{}</title>\n", escape(&self.title));
+        xml += &format!("<updated>{}</updated>\n", now);
+        xml += &link("self", &href(&self.self_href), kind);
+        xml += &link("start", &href(""), ATOM_NAVIGATION);
+        if let Some(search) = &self.search_href {
+            xml += &link(
+                "search",
+                &href(&format!("{}/opensearch.xml", search)),
+                OPENSEARCH,
+            );
+        }
+        if let Some(next) = &self.next_href {
+            xml += &link("next", &href(next), kind);
+        }
+        for entry in &self.entries {
+            xml += "<entry>\n";
+            match entry {
+                Entry::Navigation {
+                    id,
+                    title,
+                    href: target,
+                    content,
+                    acquisition,
+                } => {
+                    xml += &format!("<id>{}</id>\n", escape(id));
+                    xml += &format!("<title>{}</title>\n", escape(title));
+                    xml += &format!("<updated>{}</updated>\n", now);
+                    if let Some(content) = content {
+                        xml += &format!("<content type=\"text\">{}</content>\n", escape(content));
+                    }
+                    let kind = match acquisition {
+                        true => ATOM_ACQUISITION,
+                        false => ATOM_NAVIGATION,
+                    };
+                    xml += &link("subsection", &href(target), kind);
+                }
+                Entry::Book(book) => {
+                    xml += &format!("<id>{}</id>\n", escape(&book.id));
+                    xml += &format!("<title>{}</title>\n", escape(&book.title));
+                    xml += &format!("<updated>{}</updated>\n", book.updated);
+                    for author in &book.authors {
+                        xml += &format!("<author><name>{}</name></author>\n", escape(author));
+                    }
+                    for (scheme, value) in &book.identifiers {
+                        let urn = format!("urn:{}:{}", scheme, value);
+                        xml += &format!("<dc:identifier>{}</dc:identifier>\n", escape(&urn));
+                    }
+                    for tag in &book.tags {
+                        let tag = escape(tag);
+                        xml += &format!("<category term=\"{}\" label=\"{}\"/>\n", tag, tag);
+                    }
+                    if let Some((series, index)) = &book.series {
+                        let content = match index {
+                            Some(index) => format!("{} #{}", series, index),
+                            None => series.clone(),
+                        };
+                        xml += &format!("<content type=\"text\">{}</content>\n", escape(&content));
+                    }
+                    if let Some(cover) = &book.cover_href {
+                        xml += &link("http://opds-spec.org/image", &href(cover), JPEG);
+                        xml += &link("http://opds-spec.org/image/thumbnail", &href(cover), JPEG);
+                    }
+                    xml += &link(
+                        "http://opds-spec.org/acquisition",
+                        &href(&book.download_href),
+                        EPUB,
+                    );
+                }
+            }
+            xml += "</entry>\n";
+        }
+        xml += "</feed>\n";
+        return xml;
+    }
+
+    pub fn to_json(&self) -> String {
+        let href = |href: &str| resolve(href, "/opds2/");
+        let mut links = vec![
+            json!({"rel": "self", "href": href(&self.self_href), "type": OPDS2}),
+            json!({"rel": "start", "href": href(""), "type": OPDS2}),
+        ];
+        if let Some(search) = &self.search_href {
+            links.push(json!({
+                "rel": "search",
+                "href": format!("{}{{?query}}", href(&format!("{}/search", search))),
+                "type": OPDS2,
+                "templated": true,
+            }));
+        }
+        if let Some(next) = &self.next_href {
+            links.push(json!({"rel": "next", "href": href(next), "type": OPDS2}));
+        }
+        let mut navigation = Vec::new();
+        let mut publications = Vec::new();
+        for entry in &self.entries {
+            match entry {
+                Entry::Navigation {
+                    title,
+                    href: target,
+                    ..
+                } => navigation.push(json!({
+                    "title": title,
+                    "href": href(target),
+                    "type": OPDS2,
+                    "rel": "subsection",
+                })),
+                Entry::Book(book) => publications.push(publication(book, &href)),
+            }
+        }
+        let mut feed = json!({
+            "metadata": {"title": self.title},
+            "links": links,
+        });
+        if !navigation.is_empty() {
+            feed["navigation"] = Value::Array(navigation);
+        }
+        if !publications.is_empty() || self.is_acquisition() {
+            feed["publications"] = Value::Array(publications);
+        }
+        return feed.to_string();
+    }
+}
+
+/// Which books of a library an acquisition feed lists.
+#[derive(Debug)]
+pub enum Selection {
+    /// Every book, by title.
+    All,
+    /// Every book, most recently modified first.
+    Recent,
+    Author(String),
+    /// Books of a series, by position in the series.
+    Series(String),
+    Search(String),
+}
+
+impl Selection {
+    fn href(&self, library_id: &str) -> String {
+        let base = format!("libraries/{}", library_id);
+        return match self {
+            Selection::All => format!("{}/all", base),
+            Selection::Recent => format!("{}/recent", base),
+            Selection::Author(author) => format!("{}/authors/{}", base, encode(author)),
+            Selection::Series(series) => format!("{}/series/{}", base, encode(series)),
+            Selection::Search(query) => format!("{}/search?q={}", base, encode(query)),
+        };
+    }
+
+    fn title(&self) -> String {
+        return match self {
+            Selection::All => "All books".to_string(),
+            Selection::Recent => "Recently added".to_string(),
+            Selection::Author(author) => author.clone(),
+            Selection::Series(series) => series.clone(),
+            Selection::Search(query) => format!("Search: {}", query),
+        };
+    }
+}
+
+/// Lists the libraries, as `(id, name)` pairs.
+pub fn root_feed(libraries: &[(String, String)]) -> Feed {
+    let entries = libraries
+        .iter()
+        .map(|(id, name)| Entry::Navigation {
+            id: format!("urn:spectecle:{}", id),
+            title: name.clone(),
+            href: format!("libraries/{}", id),
+            content: None,
+            acquisition: false,
+        })
+        .collect();
+    return Feed {
+        id: "urn:spectecle:root".to_string(),
+        title: "Spectecle".to_string(),
+        self_href: String::new(),
+        search_href: None,
+        next_href: None,
+        entries,
+    };
+}
+
+/// The ways to browse one library.
+pub fn library_feed(library_id: &str, name: &str) -> Feed {
+    let base = format!("libraries/{}", library_id);
+    let navigation = |section: &str, title: &str, acquisition: bool| Entry::Navigation {
+        id: format!("urn:spectecle:{}:{}", library_id, section),
+        title: title.to_string(),
+        href: format!("{}/{}", base, section),
+        content: None,
+        acquisition,
+    };
+    return Feed {
+        id: format!("urn:spectecle:{}", library_id),
+        title: name.to_string(),
+        self_href: base.clone(),
+        search_href: Some(base.clone()),
+        next_href: None,
+        entries: vec![
+            navigation("all", "All books", true),
+            navigation("recent", "Recently added", true),
+            navigation("authors", "By author", false),
+            navigation("series", "By series", false),
+        ],
+    };
+}
+
+/// One page (counted from 1) of the books of a library matching `selection`.
+pub fn books_feed(library_id: &str, cache: &Cache, selection: &Selection, page: usize) -> Feed {
+    let mut books: Vec<&CacheItem> = match selection {
+        Selection::All | Selection::Recent => cache.items().collect(),
+        Selection::Author(author) => cache
+            .items()
+            .filter(|item| item.metadata().authors.contains(author))
+            .collect(),
+        Selection::Series(series) => cache
+            .items()
+            .filter(|item| item.metadata().series.as_ref() == Some(series))
+            .collect(),
+        Selection::Search(query) => {
+            let query = query.trim().to_lowercase();
+            cache.items().filter(|item| item.matches(&query)).collect()
+        }
+    };
+    match selection {
+        Selection::Recent => books.sort_by_key(|item| std::cmp::Reverse(item.last_modified())),
+        Selection::Series(_) => books.sort_by(|a, b| {
+            let index = |item: &CacheItem| item.metadata().series_index.unwrap_or(f64::MAX);
+            index(a).total_cmp(&index(b))
+        }),
+        _ => {}
+    }
+
+    let page = page.max(1);
+    let href = selection.href(library_id);
+    let separator = if href.contains('?') { '&' } else { '?' };
+    let has_next = books.len() > page.saturating_mul(PAGE_SIZE);
+    let entries = books
+        .into_iter()
+        .skip((page - 1).saturating_mul(PAGE_SIZE))
+        .take(PAGE_SIZE)
+        .map(|item| Entry::Book(BookEntry::new(library_id, cache, item)))
+        .collect();
+    return Feed {
+        id: format!("urn:spectecle:{}:{}", library_id, href),
+        title: selection.title(),
+        self_href: format!("{}{}page={}", href, separator, page),
+        search_href: Some(format!("libraries/{}", library_id)),
+        next_href: has_next.then(|| format!("{}{}page={}", href, separator, page + 1)),
+        entries,
+    };
+}
+
+/// Navigation over the authors or series of a library, each linking to its books.
+pub fn grouped_feed(library_id: &str, cache: &Cache, by_series: bool) -> Feed {
+    let mut groups: BTreeMap<String, usize> = BTreeMap::new();
+    for item in cache.items() {
+        let metadata = item.metadata();
+        let names: Vec<&String> = match by_series {
+            true => metadata.series.iter().collect(),
+            false => metadata.authors.iter().collect(),
+        };
+        for name in names {
+            *groups.entry(name.clone()).or_default() += 1;
+        }
+    }
+    let section = if by_series { "series" } else { "authors" };
+    let entries = groups
+        .into_iter()
+        .map(|(name, count)| {
+            let selection = match by_series {
+                true => Selection::Series(name.clone()),
+                false => Selection::Author(name.clone()),
+            };
+            Entry::Navigation {
+                id: format!("urn:spectecle:{}:{}:{}", library_id, section, encode(&name)),
+                href: selection.href(library_id),
+                title: name,
+                content: Some(format!("{} books", count)),
+                acquisition: true,
+            }
+        })
+        .collect();
+    return Feed {
+        id: format!("urn:spectecle:{}:{}", library_id, section),
+        title: if by_series { "By series" } else { "By author" }.to_string(),
+        self_href: format!("libraries/{}/{}", library_id, section),
+        search_href: Some(format!("libraries/{}", library_id)),
+        next_href: None,
+        entries,
+    };
+}
+
+fn publication(book: &BookEntry, href: &impl Fn(&str) -> String) -> Value {
+    let mut metadata = json!({
+        "@type": "http://schema.org/Book",
+        "identifier": book.id,
+        "title": book.title,
+        "author": book.authors,
+        "subject": book.tags,
+        "modified": book.updated,
+    });
+    if let Some((series, index)) = &book.series {
+        metadata["belongsTo"] = json!({"series": {"name": series, "position": index}});
+    }
+    let mut publication = json!({
+        "metadata": metadata,
+        "links": [{
+            "rel": "http://opds-spec.org/acquisition",
+            "href": href(&book.download_href),
+            "type": EPUB,
+        }],
+    });
+    if let Some(cover) = &book.cover_href {
+        publication["images"] = json!([{"href": href(cover), "type": JPEG}]);
+    }
+    return publication;
+}
+
+/// OpenSearch description pointing at the search feed of a library, for OPDS 1.2 clients.
+pub fn opensearch(title: &str, search_href: &str) -> String {
+    let template = format!(
+        "{}/search?q={{searchTerms}}",
+        resolve(search_href, "/opds/")
+    );
+    return format!(
+        concat!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+            "\n",
+            r#"<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">"#,
+            "<ShortName>{}</ShortName><Description>Search {}</Description>",
+            r#"<Url type="{}" template="{}"/>"#,
+            "</OpenSearchDescription>\n"
+        ),
+        escape(title),
+        escape(title),
+        ATOM_ACQUISITION,
+        escape(&template),
+    );
+}
+
+/// Percent-encodes a path segment, such as an author name.
+pub fn encode(segment: &str) -> String {
+    return utf8_percent_encode(segment, NON_ALPHANUMERIC).to_string();
+}
+
+fn resolve(href: &str, root: &str) -> String {
+    return match href.starts_with('/') {
+        true => href.to_string(),
+        false => format!("{}{}", root, href),
+    };
+}
+
+fn link(rel: &str, href: &str, kind: &str) -> String {
+    return format!(
+        "<link rel=\"{}\" href=\"{}\" type=\"{}\"/>\n",
+        rel,
+        href,
+        escape(kind)
+    );
+}
+
+pub fn now_millis() -> u128 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+}
+
+/// Formats milliseconds since the Unix epoch as an RFC 3339 UTC timestamp.
+pub fn rfc3339(millis: u128) -> String {
+    let secs = (millis / 1000) as i64;
+    let (days, rem) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    return format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    );
+}
@@ -0,0 +1,852 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::Duration,
+};
+
+use anyhow::{Ok, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use percent_encoding::{NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+use quick_xml::{
+    Reader,
+    escape::{resolve_xml_entity, unescape},
+    events::{BytesStart, Event},
+};
+use regex::Regex;
+use serde_json::Value;
+use ureq::{Agent, Body, ResponseExt, http::Response};
+
+use crate::{
+    signals::opds_client_signals::{OpdsAcquisition, OpdsEntry, OpdsFeedPage},
+    utility::{opds, opf::attribute, server::Credentials},
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// Time allowed for a server to start answering. Reading the body is not limited, so large
+/// downloads on slow connections still go through.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Bytes downloaded between two progress reports.
+const PROGRESS_STEP: u64 = 256 * 1024;
+const FEED_ACCEPT: &str =
+    "application/atom+xml, application/opds+json, application/json;q=0.9, */*;q=0.8";
+const ACQUISITION_REL: &str = "http://opds-spec.org/acquisition";
+const IMAGE_REL: &str = "http://opds-spec.org/image";
+const THUMBNAIL_REL: &str = "http://opds-spec.org/image/thumbnail";
+
+static MARKUP: LazyLock<Option<Regex>> = LazyLock::new(|| Regex::new(r"<[^>]*>").ok());
+
+/// Browses remote OPDS catalogs (Calibre content server, Kavita, Standard Ebooks ...) and
+/// downloads their books. Both OPDS 1.2 (Atom) and OPDS 2.0 (JSON) feeds are understood.
+///
+/// Every call blocks, run them off the async runtime.
+pub struct OpdsClient {
+    agent: Agent,
+    credentials: Option<Credentials>,
+}
+
+impl OpdsClient {
+    pub fn new(credentials: Option<Credentials>) -> Self {
+        let agent = Agent::config_builder()
+            .timeout_connect(Some(CONNECT_TIMEOUT))
+            .timeout_recv_response(Some(RESPONSE_TIMEOUT))
+            .build()
+            .into();
+        return Self { agent, credentials };
+    }
+
+    /// Fetches the feed at `url`. Relative links are resolved against the URL the feed was
+    /// finally served from, after redirects.
+    pub fn browse(&self, url: &str) -> anyhow::Result<OpdsFeedPage> {
+        let mut response = self.get(url, FEED_ACCEPT)?;
+        let base = response.get_uri().to_string();
+        let is_json = content_type(&response).contains("json");
+        let body = response.body_mut().read_to_string()?;
+        let mut page = match is_json || body.trim_start().starts_with('{') {
+            true => read_json_feed(&body, &base)?,
+            false => read_atom_feed(&body, &base)?,
+        };
+        page.url = url.to_string();
+        return Ok(page);
+    }
+
+    /// Searches for `query` through the `search_url` of a feed, either a URL template or an
+    /// OpenSearch description whose template is looked up first.
+    pub fn search(&self, search_url: &str, query: &str) -> anyhow::Result<OpdsFeedPage> {
+        let template = match is_template(search_url) {
+            true => search_url.to_string(),
+            false => self.opensearch_template(search_url)?,
+        };
+        return self.browse(&expand_template(&template, query));
+    }
+
+    /// Downloads `url` into `dir` as `file_name`, or the name suggested by the server, and
+    /// returns the path of the new file. An existing file is never overwritten, a number is
+    /// added to the name instead. The download goes to a hidden partial file first, so the
+    /// library never sees a truncated book.
+    ///
+    /// `progress` is called with the bytes received so far and the expected total, if known.
+    pub fn download(
+        &self,
+        url: &str,
+        dir: &Path,
+        file_name: Option<&str>,
+        mut progress: impl FnMut(u64, Option<u64>),
+    ) -> anyhow::Result<PathBuf> {
+        let mut response = self.get(url, "*/*")?;
+        let suggested = file_name
+            .map(str::to_string)
+            .or_else(|| attachment_name(&response))
+            .or_else(|| {
+                let path = response.get_uri().path();
+                let last = path.rsplit('/').next().unwrap_or_default();
+                Some(percent_decode_str(last).decode_utf8_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        let mut name = sanitize_file_name(&suggested)
+            .ok_or_else(|| anyhow!("No usable file name for {}", url))?;
+        if content_type(&response).starts_with("application/epub+zip")
+            && !name.to_ascii_lowercase().ends_with(".epub")
+        {
+            name.push_str(".epub");
+        }
+        let target = unique_path(dir, &name);
+        let partial = dir.join(format!(".{}.part", name));
+
+        let total = response.body().content_length();
+        let result = (|| {
+            let mut reader = response.body_mut().as_reader();
+            let mut file = File::create(&partial)?;
+            let mut buffer = vec![0; 64 * 1024];
+            let mut received = 0;
+            let mut reported = 0;
+            progress(0, total);
+            loop {
+                let read = reader.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                file.write_all(&buffer[..read])?;
+                received += read as u64;
+                if received - reported >= PROGRESS_STEP {
+                    reported = received;
+                    progress(received, total);
+                }
+            }
+            file.sync_all()?;
+            if let Some(total) = total
+                && received != total
+            {
+                return Err(anyhow!("Received {} of {} bytes", received, total));
+            }
+            progress(received, total);
+            return Ok(());
+        })();
+        if let Err(e) = result {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        fs::rename(&partial, &target)?;
+        return Ok(target);
+    }
+
+    fn get(&self, url: &str, accept: &str) -> anyhow::Result<Response<Body>> {
+        let mut request = self.agent.get(url).header("Accept", accept);
+        if let Some(credentials) = &self.credentials {
+            let encoded =
+                STANDARD.encode(format!("{}:{}", credentials.username, credentials.password));
+            request = request.header("Authorization", format!("Basic {}", encoded));
+        }
+        return request
+            .call()
+            .map_err(|e| anyhow!("Failed to fetch {}: {}", url, e));
+    }
+
+    /// Reads the Atom URL template out of an OpenSearch description.
+    fn opensearch_template(&self, url: &str) -> anyhow::Result<String> {
+        let mut response = self.get(url, opds::OPENSEARCH)?;
+        let base = response.get_uri().to_string();
+        let body = response.body_mut().read_to_string()?;
+        let mut reader = Reader::from_str(&body);
+        let mut templates = Vec::new();
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"Url" => {
+                    if let Some(template) = attribute(&e, b"template")? {
+                        let kind = attribute(&e, b"type")?.unwrap_or_default();
+                        templates.push((kind, template));
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        // Prefer results as an OPDS feed over the HTML search page some servers also list.
+        templates.sort_by_key(|(kind, _)| !kind.contains("atom") && !kind.contains("opds"));
+        let (_, template) = templates
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("{} has no search template", url))?;
+        return Ok(resolve_url(&base, &template));
+    }
+}
+
+/// The part of an Atom feed or entry being read.
+#[derive(Clone, Copy)]
+enum Field {
+    FeedTitle,
+    Id,
+    Title,
+    Author,
+    Summary,
+    Content,
+}
+
+fn read_atom_feed(xml: &str, base: &str) -> anyhow::Result<OpdsFeedPage> {
+    let mut reader = Reader::from_str(xml);
+    let mut page = empty_page();
+    let mut stack: Vec<Vec<u8>> = Vec::new();
+    let mut entry: Option<OpdsEntry> = None;
+    // Field being read and the depth of its element.
+    let mut capture: Option<(Field, usize)> = None;
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                if capture.is_none() {
+                    let parent = stack.last().map(Vec::as_slice);
+                    capture = atom_field(&name, parent, entry.is_some()).map(|f| (f, stack.len()));
+                    text.clear();
+                }
+                match name.as_slice() {
+                    b"entry" => entry = Some(empty_entry()),
+                    b"link" => read_atom_link(&e, base, &mut page, entry.as_mut())?,
+                    _ => {}
+                }
+                stack.push(name);
+            }
+            Event::Empty(e) if e.local_name().as_ref() == b"link" => {
+                read_atom_link(&e, base, &mut page, entry.as_mut())?;
+            }
+            Event::Text(t) if capture.is_some() => text.push_str(&t.decode()?),
+            Event::CData(t) if capture.is_some() => text.push_str(&t.decode()?),
+            Event::GeneralRef(r) if capture.is_some() => {
+                if let Some(c) = r.resolve_char_ref()? {
+                    text.push(c);
+                } else if let Some(entity) = resolve_xml_entity(&r.decode()?) {
+                    text.push_str(entity);
+                }
+            }
+            Event::End(_) => {
+                let name = stack.pop().unwrap_or_default();
+                if let Some((field, depth)) = capture
+                    && depth == stack.len()
+                {
+                    capture = None;
+                    let value = text.trim().to_string();
+                    match (field, entry.as_mut()) {
+                        (Field::FeedTitle, _) => page.title = value,
+                        (Field::Id, Some(entry)) => entry.id = value,
+                        (Field::Title, Some(entry)) => entry.title = value,
+                        (Field::Author, Some(entry)) if !value.is_empty() => {
+                            entry.authors.push(value);
+                        }
+                        (Field::Summary, Some(entry)) => entry.summary = plain_text(&value),
+                        (Field::Content, Some(entry)) if entry.summary.is_none() => {
+                            entry.summary = plain_text(&value);
+                        }
+                        _ => {}
+                    }
+                } else if name == b"entry"
+                    && let Some(entry) = entry.take()
+                {
+                    page.entries.push(entry);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    return Ok(page);
+}
+
+fn atom_field(name: &[u8], parent: Option<&[u8]>, in_entry: bool) -> Option<Field> {
+    return match (name, parent) {
+        (b"title", Some(b"feed")) => Some(Field::FeedTitle),
+        (b"id", Some(b"entry")) => Some(Field::Id),
+        (b"title", Some(b"entry")) => Some(Field::Title),
+        (b"name", Some(b"author")) if in_entry => Some(Field::Author),
+        (b"summary", Some(b"entry")) => Some(Field::Summary),
+        (b"content", Some(b"entry")) => Some(Field::Content),
+        _ => None,
+    };
+}
+
+fn read_atom_link(
+    e: &BytesStart,
+    base: &str,
+    page: &mut OpdsFeedPage,
+    entry: Option<&mut OpdsEntry>,
+) -> anyhow::Result<()> {
+    let Some(href) = attribute(e, b"href")? else {
+        return Ok(());
+    };
+    let href = resolve_url(base, &href);
+    let rel = attribute(e, b"rel")?.unwrap_or_default();
+    let kind = attribute(e, b"type")?.unwrap_or_default();
+    match entry {
+        Some(entry) => {
+            if rel.starts_with(ACQUISITION_REL) {
+                entry.acquisitions.push(OpdsAcquisition {
+                    url: href,
+                    mime_type: kind,
+                });
+            } else if rel == THUMBNAIL_REL || (rel == IMAGE_REL && entry.cover_url.is_none()) {
+                entry.cover_url = Some(href);
+            } else if kind.contains("profile=opds-catalog") {
+                entry.navigation_url = Some(href);
+            }
+        }
+        None => match rel.as_str() {
+            "next" => page.next_url = Some(href),
+            "previous" | "prev" => page.previous_url = Some(href),
+            "search" if page.search_url.is_none() || kind.contains("atom") => {
+                page.search_url = Some(href);
+            }
+            _ => {}
+        },
+    }
+    return Ok(());
+}
+
+fn read_json_feed(json: &str, base: &str) -> anyhow::Result<OpdsFeedPage> {
+    let feed: Value = serde_json::from_str(json)?;
+    let mut page = empty_page();
+    page.title = feed["metadata"]["title"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    for link in as_array(&feed["links"]) {
+        let Some(href) = link["href"].as_str() else {
+            continue;
+        };
+        let href = resolve_url(base, href);
+        if has_rel(link, "next") {
+            page.next_url = Some(href);
+        } else if has_rel(link, "previous") || has_rel(link, "prev") {
+            page.previous_url = Some(href);
+        } else if has_rel(link, "search") {
+            page.search_url = Some(href);
+        }
+    }
+
+    // Groups are flattened into the page, in order.
+    let groups = std::iter::once(&feed).chain(as_array(&feed["groups"]));
+    for group in groups {
+        for link in as_array(&group["navigation"]) {
+            let Some(href) = link["href"].as_str() else {
+                continue;
+            };
+            let href = resolve_url(base, href);
+            let mut entry = empty_entry();
+            entry.id = href.clone();
+            entry.title = link["title"].as_str().unwrap_or_default().to_string();
+            entry.navigation_url = Some(href);
+            page.entries.push(entry);
+        }
+        for publication in as_array(&group["publications"]) {
+            page.entries.push(read_publication(publication, base));
+        }
+    }
+    return Ok(page);
+}
+
+fn read_publication(publication: &Value, base: &str) -> OpdsEntry {
+    let metadata = &publication["metadata"];
+    let mut entry = empty_entry();
+    entry.id = metadata["identifier"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    entry.title = metadata["title"].as_str().unwrap_or_default().to_string();
+    entry.summary = metadata["description"].as_str().and_then(plain_text);
+    // Contributors are a name, an object with a name, or an array of either.
+    let authors = match &metadata["author"] {
+        Value::Array(authors) => authors.iter().collect(),
+        author => vec![author],
+    };
+    entry.authors = authors
+        .into_iter()
+        .filter_map(|author| author.as_str().or_else(|| author["name"].as_str()))
+        .map(str::to_string)
+        .collect();
+    for link in as_array(&publication["links"]) {
+        let Some(href) = link["href"].as_str() else {
+            continue;
+        };
+        let rels = rels(link);
+        if rels.iter().any(|rel| rel.starts_with(ACQUISITION_REL)) {
+            entry.acquisitions.push(OpdsAcquisition {
+                url: resolve_url(base, href),
+                mime_type: link["type"].as_str().unwrap_or_default().to_string(),
+            });
+        } else if rels.contains(&"self") && entry.navigation_url.is_none() {
+            entry.navigation_url = Some(resolve_url(base, href));
+        }
+    }
+    entry.cover_url = as_array(&publication["images"])
+        .filter_map(|image| image["href"].as_str())
+        .next()
+        .map(|href| resolve_url(base, href));
+    if entry.id.is_empty() {
+        entry.id = entry.title.clone();
+    }
+    return entry;
+}
+
+fn as_array(value: &Value) -> impl Iterator<Item = &Value> {
+    return value.as_array().into_iter().flatten();
+}
+
+/// The `rel` of an OPDS 2.0 link, a string or an array of strings.
+fn rels(link: &Value) -> Vec<&str> {
+    return match &link["rel"] {
+        Value::Array(rels) => rels.iter().filter_map(Value::as_str).collect(),
+        rel => rel.as_str().into_iter().collect(),
+    };
+}
+
+fn has_rel(link: &Value, rel: &str) -> bool {
+    return rels(link).contains(&rel);
+}
+
+fn empty_page() -> OpdsFeedPage {
+    return OpdsFeedPage {
+        url: String::new(),
+        title: String::new(),
+        entries: vec![],
+        next_url: None,
+        previous_url: None,
+        search_url: None,
+        error: None,
+    };
+}
+
+fn empty_entry() -> OpdsEntry {
+    return OpdsEntry {
+        id: String::new(),
+        title: String::new(),
+        authors: vec![],
+        summary: None,
+        navigation_url: None,
+        cover_url: None,
+        acquisitions: vec![],
+    };
+}
+
+/// Strips the markup of HTML summaries and decodes their entities.
+fn plain_text(html: &str) -> Option<String> {
+    let text = match MARKUP.as_ref() {
+        Some(markup) => markup.replace_all(html, " ").into_owned(),
+        None => html.to_string(),
+    };
+    // HTML summaries escape their own entities, which a stray `&` in plain text would not.
+    let text = unescape(&text).map_or_else(|_| text.clone(), |text| text.into_owned());
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    return (!text.is_empty()).then_some(text);
+}
+
+fn is_template(url: &str) -> bool {
+    return url.contains("{searchTerms}") || url.contains("{?query");
+}
+
+/// Fills an OpenSearch (`{searchTerms}`) or OPDS 2.0 (`{?query}`) template with `query`,
+/// dropping the optional parameters it does not know.
+fn expand_template(template: &str, query: &str) -> String {
+    let encoded = utf8_percent_encode(query, NON_ALPHANUMERIC).to_string();
+    let mut url = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+            break;
+        };
+        url.push_str(&rest[..start]);
+        match &rest[start + 1..end] {
+            "searchTerms" => url.push_str(&encoded),
+            expression if expression.starts_with('?') => {
+                let names = expression[1..].split(',');
+                if names.clone().any(|name| name == "query") {
+                    url.push(if url.contains('?') { '&' } else { '?' });
+                    url.push_str("query=");
+                    url.push_str(&encoded);
+                }
+            }
+            _ => {}
+        }
+        rest = &rest[end + 1..];
+    }
+    url.push_str(rest);
+    return url;
+}
+
+/// Resolves `href` against the absolute URL `base`, as browsers do for links.
+fn resolve_url(base: &str, href: &str) -> String {
+    let href = href.trim();
+    let Some((scheme, rest)) = base.split_once("://") else {
+        return href.to_string();
+    };
+    if href.contains("://") || href.starts_with("mailto:") || href.starts_with("data:") {
+        return href.to_string();
+    }
+    if let Some(network_path) = href.strip_prefix("//") {
+        return format!("{}://{}", scheme, network_path);
+    }
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let origin = &base[..scheme.len() + 3 + authority_end];
+    let base_path = rest[authority_end..]
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default();
+    if href.is_empty() || href.starts_with('#') {
+        return base.to_string();
+    }
+    if href.starts_with('?') {
+        return format!("{}{}{}", origin, base_path, href);
+    }
+    let (path, suffix) = match href.find(['?', '#']) {
+        Some(i) => href.split_at(i),
+        None => (href, ""),
+    };
+    let joined = match path.starts_with('/') {
+        true => path.to_string(),
+        false => {
+            let dir = base_path
+                .rfind('/')
+                .map(|i| &base_path[..=i])
+                .unwrap_or("/");
+            format!("{}{}", dir, path)
+        }
+    };
+    // Remove the dot segments, staying at the root when climbing above it.
+    let mut segments: Vec<&str> = Vec::new();
+    let parts: Vec<&str> = joined.split('/').skip(1).collect();
+    for (i, segment) in parts.iter().enumerate() {
+        match *segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+        // A trailing dot segment still names a directory.
+        if i == parts.len() - 1 && (*segment == "." || *segment == "..") {
+            segments.push("");
+        }
+    }
+    return format!("{}/{}{}", origin, segments.join("/"), suffix);
+}
+
+fn content_type(response: &Response<Body>) -> String {
+    return response
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+}
+
+/// File name from a `Content-Disposition: attachment; filename="..."` header.
+fn attachment_name(response: &Response<Body>) -> Option<String> {
+    let disposition = response
+        .headers()
+        .get("Content-Disposition")?
+        .to_str()
+        .ok()?;
+    for parameter in disposition.split(';').map(str::trim) {
+        // RFC 5987 `filename*=UTF-8''...` takes precedence over a plain `filename`.
+        if let Some(encoded) = parameter.strip_prefix("filename*=") {
+            let value = encoded.split_once("''").map_or(encoded, |(_, value)| value);
+            return Some(percent_decode_str(value).decode_utf8_lossy().into_owned());
+        }
+    }
+    return disposition
+        .split(';')
+        .map(str::trim)
+        .find_map(|parameter| parameter.strip_prefix("filename="))
+        .map(|value| value.trim_matches('"').to_string());
+}
+
+/// Keeps only the last component of `name` and replaces characters that are invalid in file
+/// names on any platform.
+fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let name = name
+        .trim()
+        .trim_start_matches('.')
+        .trim_end_matches(['.', ' ']);
+    return (!name.is_empty()).then(|| name.to_string());
+}
+
+/// `dir/name`, or `dir/name (2)`, `dir/name (3)` ... if it is taken.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    let mut n = 2;
+    loop {
+        let path = dir.join(format!("{} ({}){}", stem, n, extension));
+        if !path.exists() {
+            return path;
+        }
+        n += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use tiny_http::{Header, Response, Server};
+
+    use super::*;
+
+    const ATOM_FEED: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>root</id>
+  <title>Test &amp; Catalog</title>
+  <link rel="self" href="root.xml" type="application/atom+xml;profile=opds-catalog"/>
+  <link rel="next" href="root.xml?page=2" type="application/atom+xml;profile=opds-catalog"/>
+  <link rel="search" href="/opensearch.xml" type="application/opensearchdescription+xml"/>
+  <entry>
+    <title>By author</title>
+    <id>authors</id>
+    <link rel="subsection" href="authors.xml"
+          type="application/atom+xml;profile=opds-catalog;kind=navigation"/>
+  </entry>
+  <entry>
+    <title>Dune</title>
+    <id>urn:uuid:dune</id>
+    <author><name>Frank Herbert</name></author>
+    <summary type="html">&lt;p&gt;Spice &amp;amp; sand&lt;/p&gt;</summary>
+    <link rel="http://opds-spec.org/acquisition" href="../books/dune.epub"
+          type="application/epub+zip"/>
+    <link rel="http://opds-spec.org/image/thumbnail" href="covers/dune.jpg" type="image/jpeg"/>
+  </entry>
+</feed>"#;
+
+    const ATOM_PAGE_2: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Page 2</title>
+  <link rel="previous" href="root.xml" type="application/atom+xml;profile=opds-catalog"/>
+</feed>"#;
+
+    const OPENSEARCH: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+  <Url type="text/html" template="/web/search?q={searchTerms}"/>
+  <Url type="application/atom+xml" template="/catalog/search?q={searchTerms}&amp;page={startPage?}"/>
+</OpenSearchDescription>"#;
+
+    const JSON_FEED: &str = r#"{
+  "metadata": {"title": "JSON catalog"},
+  "links": [
+    {"rel": "self", "href": "root.json", "type": "application/opds+json"},
+    {"rel": ["next"], "href": "root.json?page=2", "type": "application/opds+json"},
+    {"rel": "search", "href": "search{?query}", "type": "application/opds+json", "templated": true}
+  ],
+  "navigation": [{"href": "new.json", "title": "New", "type": "application/opds+json"}],
+  "groups": [{
+    "metadata": {"title": "Featured"},
+    "publications": [{
+      "metadata": {
+        "identifier": "urn:isbn:9780553283686",
+        "title": "Hyperion",
+        "author": [{"name": "Dan Simmons"}, "A. N. Other"],
+        "description": "<b>The Shrike</b> waits"
+      },
+      "links": [{
+        "rel": "http://opds-spec.org/acquisition/open-access",
+        "href": "/files/hyperion.epub",
+        "type": "application/epub+zip"
+      }],
+      "images": [{"href": "covers/hyperion.jpg", "type": "image/jpeg"}]
+    }]
+  }]
+}"#;
+
+    /// Serves `routes`, `(path, content type, body)`, on a free local port until the
+    /// returned server is unblocked. Returns the server and its base URL.
+    fn serve(routes: Vec<(&'static str, &'static str, &'static str)>) -> (Arc<Server>, String) {
+        let server = match Server::http("127.0.0.1:0") {
+            std::result::Result::Ok(server) => Arc::new(server),
+            Err(e) => panic!("Failed to start the stub server: {}", e),
+        };
+        let base = format!("http://{}", server.server_addr());
+        let listener = server.clone();
+        thread::spawn(move || {
+            for request in listener.incoming_requests() {
+                let path = request
+                    .url()
+                    .split('?')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                let response = match routes.iter().find(|(route, _, _)| *route == path) {
+                    Some((_, kind, body)) => {
+                        let mut response = Response::from_string(*body);
+                        if let std::result::Result::Ok(header) =
+                            Header::from_bytes("Content-Type", *kind)
+                        {
+                            response = response.with_header(header);
+                        }
+                        response
+                    }
+                    None => Response::from_string("Not found").with_status_code(404),
+                };
+                let _ = request.respond(response);
+            }
+        });
+        return (server, base);
+    }
+
+    #[test]
+    fn reads_atom_navigation_pagination_and_acquisitions() -> anyhow::Result<()> {
+        let (server, base) = serve(vec![
+            ("/catalog/root.xml", "application/atom+xml", ATOM_FEED),
+            (
+                "/opensearch.xml",
+                "application/opensearchdescription+xml",
+                OPENSEARCH,
+            ),
+            ("/catalog/search", "application/atom+xml", ATOM_PAGE_2),
+        ]);
+        let client = OpdsClient::new(None);
+        let page = client.browse(&format!("{}/catalog/root.xml", base))?;
+
+        assert_eq!(page.title, "Test & Catalog");
+        assert_eq!(
+            page.next_url,
+            Some(format!("{}/catalog/root.xml?page=2", base))
+        );
+        assert_eq!(page.previous_url, None);
+        assert_eq!(page.search_url, Some(format!("{}/opensearch.xml", base)));
+        assert_eq!(page.entries.len(), 2);
+
+        let navigation = &page.entries[0];
+        assert_eq!(navigation.title, "By author");
+        assert_eq!(
+            navigation.navigation_url,
+            Some(format!("{}/catalog/authors.xml", base))
+        );
+        assert!(navigation.acquisitions.is_empty());
+
+        let book = &page.entries[1];
+        assert_eq!(book.id, "urn:uuid:dune");
+        assert_eq!(book.authors, vec!["Frank Herbert"]);
+        assert_eq!(book.summary.as_deref(), Some("Spice & sand"));
+        assert_eq!(
+            book.cover_url,
+            Some(format!("{}/catalog/covers/dune.jpg", base))
+        );
+        assert_eq!(book.acquisitions.len(), 1);
+        assert_eq!(
+            book.acquisitions[0].url,
+            format!("{}/books/dune.epub", base)
+        );
+        assert_eq!(book.acquisitions[0].mime_type, "application/epub+zip");
+
+        let results = client.search(&format!("{}/opensearch.xml", base), "spice")?;
+        assert_eq!(results.title, "Page 2");
+        assert_eq!(
+            results.previous_url,
+            Some(format!("{}/catalog/root.xml", base))
+        );
+        server.unblock();
+        return Ok(());
+    }
+
+    #[test]
+    fn reads_json_navigation_pagination_and_acquisitions() -> anyhow::Result<()> {
+        let (server, base) = serve(vec![
+            ("/opds2/root.json", "application/opds+json", JSON_FEED),
+            (
+                "/opds2/search",
+                "application/opds+json",
+                r#"{"metadata": {"title": "Results"}}"#,
+            ),
+        ]);
+        let client = OpdsClient::new(None);
+        let page = client.browse(&format!("{}/opds2/root.json", base))?;
+
+        assert_eq!(page.title, "JSON catalog");
+        assert_eq!(
+            page.next_url,
+            Some(format!("{}/opds2/root.json?page=2", base))
+        );
+        assert_eq!(
+            page.search_url,
+            Some(format!("{}/opds2/search{{?query}}", base))
+        );
+        assert_eq!(page.entries.len(), 2);
+
+        let navigation = &page.entries[0];
+        assert_eq!(navigation.title, "New");
+        assert_eq!(
+            navigation.navigation_url,
+            Some(format!("{}/opds2/new.json", base))
+        );
+
+        let book = &page.entries[1];
+        assert_eq!(book.id, "urn:isbn:9780553283686");
+        assert_eq!(book.title, "Hyperion");
+        assert_eq!(book.authors, vec!["Dan Simmons", "A. N. Other"]);
+        assert_eq!(book.summary.as_deref(), Some("The Shrike waits"));
+        assert_eq!(
+            book.cover_url,
+            Some(format!("{}/opds2/covers/hyperion.jpg", base))
+        );
+        assert_eq!(book.acquisitions.len(), 1);
+        assert_eq!(
+            book.acquisitions[0].url,
+            format!("{}/files/hyperion.epub", base)
+        );
+
+        let results = client.search(&format!("{}/opds2/search{{?query}}", base), "shrike")?;
+        assert_eq!(results.title, "Results");
+        server.unblock();
+        return Ok(());
+    }
+
+    #[test]
+    fn downloads_without_overwriting() -> anyhow::Result<()> {
+        let (server, base) = serve(vec![("/books/dune.epub", "application/epub+zip", "EPUB")]);
+        let dir = std::env::temp_dir().join(format!("opds-client-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let client = OpdsClient::new(None);
+        let url = format!("{}/books/dune.epub", base);
+
+        let first = client.download(&url, &dir, None, |_, _| {})?;
+        let second = client.download(&url, &dir, Some("dune.epub"), |_, _| {})?;
+        assert_eq!(first, dir.join("dune.epub"));
+        assert_eq!(second, dir.join("dune (2).epub"));
+        assert_eq!(fs::read_to_string(&second)?, "EPUB");
+        fs::remove_dir_all(&dir)?;
+        server.unblock();
+        return Ok(());
+    }
+}
@@ -0,0 +1,830 @@
+use std::{collections::HashSet, fmt::Write as _};
+
+use anyhow::{Ok, anyhow};
+use quick_xml::{
+    Reader, Writer,
+    escape::{escape, resolve_xml_entity},
+    events::{BytesStart, Event},
+};
+
+use crate::utility::metadata::BookMetadata;
+
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+const OPF_NAMESPACE: &str = "http://www.idpf.org/2007/opf";
+/// Prefix of the ids given to the elements written by [`write_metadata`].
+const ID_PREFIX: &str = "spectecle";
+/// Package document [`sidecar`] fills with the metadata of a book.
+const SIDECAR: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+  </metadata>
+</package>
+"#;
+
+/// Title and metadata read from an OPF package document.
+#[derive(Debug, Default)]
+pub struct OpfMetadata {
+    pub title: Option<String>,
+    pub metadata: BookMetadata,
+    pub description: Option<String>,
+}
+
+/// A manifest item of an OPF.
+#[derive(Debug, Clone)]
+pub struct ManifestItem {
+    pub id: String,
+    /// Relative to the OPF.
+    pub href: String,
+    pub media_type: String,
+}
+
+/// What [`write_metadata`] puts in an OPF. Every field replaces what the OPF had, except the
+/// identifier the package names as its unique identifier, which is kept as is.
+#[derive(Debug)]
+pub struct OpfEdit<'a> {
+    pub title: &'a str,
+    /// Authors, author sort (written as the `file-as` of the first author), series, subjects
+    /// and identifiers. The rating is not part of the OPF and is ignored.
+    pub metadata: &'a BookMetadata,
+    pub description: Option<&'a str>,
+    /// Image to add to the manifest as the cover of the book.
+    pub new_cover: Option<&'a ManifestItem>,
+    /// RFC 3339 time of the edit, the new `dcterms:modified` of EPUB 3 packages.
+    pub modified: &'a str,
+}
+
+/// A Dublin Core or refining `<meta>` element whose text is being read.
+struct DcElement {
+    name: Vec<u8>,
+    id: Option<String>,
+    scheme: Option<String>,
+    role: Option<String>,
+    file_as: Option<String>,
+    property: Option<String>,
+    refines: Option<String>,
+}
+
+/// An element EPUB 3 `<meta refines>` elements may add to: a creator or a collection.
+#[derive(Default)]
+struct Refinable {
+    id: Option<String>,
+    value: String,
+    role: Option<String>,
+    file_as: Option<String>,
+    collection_type: Option<String>,
+    group_position: Option<String>,
+}
+
+/// Creators and collections of an OPF, resolved once every refinement has been read.
+#[derive(Default)]
+struct Refinables {
+    creators: Vec<Refinable>,
+    collections: Vec<Refinable>,
+    /// Target id, property and value of each `<meta refines>`.
+    refinements: Vec<(String, String, String)>,
+}
+
+const DC_ELEMENTS: [&[u8]; 5] = [
+    b"title",
+    b"creator",
+    b"subject",
+    b"identifier",
+    b"description",
+];
+
+/// Reads the `<metadata>` of an OPF, including the `calibre:` extensions Calibre writes
+/// to its `metadata.opf` sidecars and the EPUB 3 refinements of creators and collections.
+pub fn read_metadata(xml: &str) -> anyhow::Result<OpfMetadata> {
+    let mut reader = Reader::from_str(xml);
+    let mut opf = OpfMetadata::default();
+    let mut refinables = Refinables::default();
+    let mut current: Option<DcElement> = None;
+    let mut text = String::new();
+    loop {
+        match reader.read_event()? {
+            Event::Start(e)
+                if DC_ELEMENTS.contains(&e.local_name().as_ref())
+                    || (e.local_name().as_ref() == b"meta"
+                        && attribute(&e, b"property")?.is_some()) =>
+            {
+                current = Some(DcElement {
+                    name: e.local_name().as_ref().to_vec(),
+                    id: attribute(&e, b"id")?,
+                    scheme: attribute(&e, b"scheme")?,
+                    role: attribute(&e, b"role")?,
+                    file_as: attribute(&e, b"file-as")?,
+                    property: attribute(&e, b"property")?,
+                    refines: attribute(&e, b"refines")?,
+                });
+                text.clear();
+            }
+            Event::Start(e) | Event::Empty(e) if e.local_name().as_ref() == b"meta" => {
+                read_meta(&e, &mut opf.metadata)?;
+            }
+            Event::Text(t) if current.is_some() => text.push_str(&t.decode()?),
+            Event::CData(t) if current.is_some() => text.push_str(&t.decode()?),
+            Event::GeneralRef(r) if current.is_some() => {
+                if let Some(c) = r.resolve_char_ref()? {
+                    text.push(c);
+                } else if let Some(entity) = resolve_xml_entity(&r.decode()?) {
+                    text.push_str(entity);
+                }
+            }
+            Event::End(_) => {
+                if let Some(element) = current.take() {
+                    apply_dc(&mut opf, &mut refinables, element, text.trim());
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    apply_refinements(&mut opf, refinables);
+    return Ok(opf);
+}
+
+fn apply_dc(opf: &mut OpfMetadata, refinables: &mut Refinables, element: DcElement, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match element.name.as_slice() {
+        b"title" if opf.title.is_none() => opf.title = Some(text.to_string()),
+        b"creator" => refinables.creators.push(Refinable {
+            id: element.id,
+            value: text.to_string(),
+            role: element.role,
+            file_as: element.file_as,
+            ..Default::default()
+        }),
+        b"subject" => opf.metadata.tags.push(text.to_string()),
+        b"description" if opf.description.is_none() => opf.description = Some(text.to_string()),
+        b"identifier" => {
+            // Either `opf:scheme="ISBN"` or an URN such as `urn:isbn:978...`.
+            let (scheme, value) = match element.scheme {
+                Some(scheme) => (scheme.to_ascii_lowercase(), text),
+                None => match text
+                    .strip_prefix("urn:")
+                    .and_then(|urn| urn.split_once(':'))
+                {
+                    Some((scheme, value)) => (scheme.to_ascii_lowercase(), value),
+                    None => return,
+                },
+            };
+            // Calibre's own database id means nothing outside of that database.
+            if scheme != "calibre" {
+                opf.metadata.identifiers.insert(scheme, value.to_string());
+            }
+        }
+        b"meta" => match (element.property.as_deref(), element.refines) {
+            (Some("belongs-to-collection"), None) => refinables.collections.push(Refinable {
+                id: element.id,
+                value: text.to_string(),
+                ..Default::default()
+            }),
+            (Some(property), Some(refines)) => refinables.refinements.push((
+                refines.trim_start_matches('#').to_string(),
+                property.to_string(),
+                text.to_string(),
+            )),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+/// Applies the `<meta refines>` elements to the creators and collections they refine, then
+/// keeps the authors and, unless Calibre already named one, the series.
+fn apply_refinements(opf: &mut OpfMetadata, mut refinables: Refinables) {
+    for (target, property, value) in refinables.refinements {
+        let Some(refinable) = refinables
+            .creators
+            .iter_mut()
+            .chain(refinables.collections.iter_mut())
+            .find(|r| r.id.as_deref() == Some(target.as_str()))
+        else {
+            continue;
+        };
+        match property.as_str() {
+            "role" => refinable.role = Some(value),
+            "file-as" => refinable.file_as = Some(value),
+            "collection-type" => refinable.collection_type = Some(value),
+            "group-position" => refinable.group_position = Some(value),
+            _ => {}
+        }
+    }
+    let authors: Vec<Refinable> = refinables
+        .creators
+        .into_iter()
+        .filter(|creator| creator.role.as_deref().is_none_or(|r| r == "aut"))
+        .collect();
+    if opf.metadata.author_sort.is_none() {
+        opf.metadata.author_sort = authors.first().and_then(|a| a.file_as.clone());
+    }
+    opf.metadata
+        .authors
+        .extend(authors.into_iter().map(|author| author.value));
+    if opf.metadata.series.is_none()
+        && let Some(series) = refinables
+            .collections
+            .into_iter()
+            .find(|c| c.collection_type.as_deref().is_none_or(|t| t == "series"))
+    {
+        opf.metadata.series_index = series.group_position.and_then(|p| p.parse().ok());
+        opf.metadata.series = Some(series.value);
+    }
+}
+
+fn read_meta(e: &BytesStart, metadata: &mut BookMetadata) -> anyhow::Result<()> {
+    let (Some(name), Some(content)) = (attribute(e, b"name")?, attribute(e, b"content")?) else {
+        return Ok(());
+    };
+    match name.as_str() {
+        "calibre:series" => metadata.series = Some(content),
+        "calibre:series_index" => metadata.series_index = content.parse().ok(),
+        "calibre:author_sort" => metadata.author_sort = Some(content),
+        "calibre:rating" => {
+            metadata.rating = content
+                .parse::<f64>()
+                .ok()
+                .map(|rating| rating.round().clamp(0.0, 10.0) as u8)
+                .filter(|rating| *rating > 0);
+        }
+        _ => {}
+    }
+    return Ok(());
+}
+
+/// Value of the attribute with the given local name, whatever its namespace prefix.
+pub fn attribute(e: &BytesStart, key: &[u8]) -> anyhow::Result<Option<String>> {
+    for attr in e.attributes().flatten() {
+        if attr.key.local_name().as_ref() == key {
+            return Ok(Some(attr.unescape_value()?.into_owned()));
+        }
+    }
+    return Ok(None);
+}
+
+/// Value of the identifier the package names as its unique identifier, if it has one.
+pub fn unique_identifier(xml: &str) -> anyhow::Result<Option<String>> {
+    let value = read_package(xml)?.unique_identifier_value;
+    return Ok(value.filter(|value| !value.is_empty()));
+}
+
+/// The manifest item of the cover image, from its EPUB 3 `cover-image` property or the
+/// EPUB 2 `<meta name="cover">`.
+pub fn cover_item(xml: &str) -> anyhow::Result<Option<ManifestItem>> {
+    let mut reader = Reader::from_str(xml);
+    let mut items = Vec::new();
+    let mut cover_id = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) => match e.local_name().as_ref() {
+                b"item" => {
+                    let (Some(id), Some(href)) = (attribute(&e, b"id")?, attribute(&e, b"href")?)
+                    else {
+                        continue;
+                    };
+                    let item = ManifestItem {
+                        id,
+                        href,
+                        media_type: attribute(&e, b"media-type")?.unwrap_or_default(),
+                    };
+                    let properties = attribute(&e, b"properties")?.unwrap_or_default();
+                    if properties.split_whitespace().any(|p| p == "cover-image") {
+                        return Ok(Some(item));
+                    }
+                    items.push(item);
+                }
+                b"meta" if attribute(&e, b"name")?.as_deref() == Some("cover") => {
+                    cover_id = attribute(&e, b"content")?;
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    return Ok(cover_id.and_then(|id| items.into_iter().find(|item| item.id == id)));
+}
+
+/// A direct child of `<metadata>`, as far as deciding whether it is replaced goes.
+#[derive(Default)]
+struct MetadataChild {
+    name: Vec<u8>,
+    id: Option<String>,
+    role: Option<String>,
+    meta_name: Option<String>,
+    property: Option<String>,
+    refines: Option<String>,
+    text: String,
+}
+
+/// Namespaces and identifiers of a package, needed to write its metadata.
+#[derive(Default)]
+struct Package {
+    epub3: bool,
+    unique_identifier: Option<String>,
+    unique_identifier_value: Option<String>,
+    dc_prefix: Option<String>,
+    opf_prefix: Option<String>,
+    /// Whitespace before each child of `<metadata>` on its line.
+    child_indent: String,
+    children: Vec<MetadataChild>,
+}
+
+/// Rewrites the `<metadata>` of an OPF with `edit`, keeping every element it does not
+/// replace and the rest of the document byte for byte. EPUB 3 packages get refinements
+/// where EPUB 2 ones get `opf:` attributes.
+pub fn write_metadata(xml: &str, edit: &OpfEdit) -> anyhow::Result<String> {
+    let package = read_package(xml)?;
+    let dropped = dropped_children(&package, edit.new_cover.is_some());
+
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+    let mut in_metadata = false;
+    let mut depth = 0usize;
+    let mut child = 0usize;
+    let mut skip_depth = 0usize;
+    // Whitespace before a child of `<metadata>`, dropped along with the child.
+    let mut pending_space: Option<Event> = None;
+    loop {
+        let event = reader.read_event()?;
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                Event::Eof => return Err(anyhow!("The OPF ends inside <metadata>.")),
+                _ => {}
+            }
+            continue;
+        }
+        match event {
+            Event::Start(e) if !in_metadata && e.local_name().as_ref() == b"metadata" => {
+                in_metadata = true;
+                let mut start = e.into_owned();
+                if package.dc_prefix.is_none() {
+                    start.push_attribute(("xmlns:dc", DC_NAMESPACE));
+                }
+                if !package.epub3 && package.opf_prefix.is_none() {
+                    start.push_attribute(("xmlns:opf", OPF_NAMESPACE));
+                }
+                writer.write_event(Event::Start(start))?;
+            }
+            Event::Text(t)
+                if in_metadata && depth == 0 && t.iter().all(u8::is_ascii_whitespace) =>
+            {
+                pending_space = Some(Event::Text(t.into_owned()));
+            }
+            Event::Start(e) if in_metadata && depth == 0 => {
+                let drop = dropped.contains(&child);
+                child += 1;
+                if drop {
+                    pending_space = None;
+                    skip_depth = 1;
+                    continue;
+                }
+                if let Some(space) = pending_space.take() {
+                    writer.write_event(space)?;
+                }
+                depth += 1;
+                writer.write_event(Event::Start(e))?;
+            }
+            Event::Empty(e) if in_metadata && depth == 0 => {
+                let drop = dropped.contains(&child);
+                child += 1;
+                if drop {
+                    pending_space = None;
+                    continue;
+                }
+                if let Some(space) = pending_space.take() {
+                    writer.write_event(space)?;
+                }
+                writer.write_event(Event::Empty(e))?;
+            }
+            Event::End(e) if in_metadata && depth == 0 => {
+                let elements = new_metadata(&package, edit, &package.child_indent)?;
+                writer.get_mut().extend(elements.as_bytes());
+                if let Some(space) = pending_space.take() {
+                    writer.write_event(space)?;
+                }
+                in_metadata = false;
+                writer.write_event(Event::End(e))?;
+            }
+            Event::End(e) if in_metadata => {
+                depth -= 1;
+                writer.write_event(Event::End(e))?;
+            }
+            Event::End(e) if e.local_name().as_ref() == b"manifest" => {
+                if let Some(cover) = edit.new_cover {
+                    let properties = match package.epub3 {
+                        true => " properties=\"cover-image\"",
+                        false => "",
+                    };
+                    let item = format!(
+                        "  <item id=\"{}\" href=\"{}\" media-type=\"{}\"{}/>\n  ",
+                        escape(cover.id.as_str()),
+                        escape(cover.href.as_str()),
+                        escape(cover.media_type.as_str()),
+                        properties
+                    );
+                    writer.get_mut().extend(item.as_bytes());
+                }
+                writer.write_event(Event::End(e))?;
+            }
+            Event::Eof => break,
+            event => {
+                if let Some(space) = pending_space.take() {
+                    writer.write_event(space)?;
+                }
+                writer.write_event(event)?;
+            }
+        }
+    }
+    return Ok(String::from_utf8(writer.into_inner())?);
+}
+
+/// A package document holding nothing but the title and metadata of a book, like the
+/// `metadata.opf` sidecars Calibre keeps next to its books.
+pub fn sidecar(title: &str, metadata: &BookMetadata) -> anyhow::Result<String> {
+    let edit = OpfEdit {
+        title,
+        metadata,
+        description: None,
+        new_cover: None,
+        modified: "",
+    };
+    return write_metadata(SIDECAR, &edit);
+}
+
+/// Reads what [`write_metadata`] needs to know before rewriting a package.
+fn read_package(xml: &str) -> anyhow::Result<Package> {
+    let mut reader = Reader::from_str(xml);
+    let mut package = Package::default();
+    let mut in_metadata = false;
+    let mut depth = 0usize;
+    let namespace_prefix = |e: &BytesStart, namespace: &str| -> Option<String> {
+        e.attributes().flatten().find_map(|attr| {
+            let prefix = attr.key.as_ref().strip_prefix(b"xmlns:")?;
+            (attr.value.as_ref() == namespace.as_bytes())
+                .then(|| String::from_utf8_lossy(prefix).into_owned())
+        })
+    };
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if !in_metadata && e.local_name().as_ref() == b"package" => {
+                package.epub3 = attribute(&e, b"version")?.is_some_and(|v| v.starts_with('3'));
+                package.unique_identifier = attribute(&e, b"unique-identifier")?;
+                package.dc_prefix = namespace_prefix(&e, DC_NAMESPACE);
+                package.opf_prefix = namespace_prefix(&e, OPF_NAMESPACE);
+            }
+            Event::Start(e) if !in_metadata && e.local_name().as_ref() == b"metadata" => {
+                in_metadata = true;
+                package.dc_prefix = package
+                    .dc_prefix
+                    .or_else(|| namespace_prefix(&e, DC_NAMESPACE));
+                package.opf_prefix = package
+                    .opf_prefix
+                    .or_else(|| namespace_prefix(&e, OPF_NAMESPACE));
+            }
+            Event::Start(e) if in_metadata && depth == 0 => {
+                package.children.push(metadata_child(&e)?);
+                depth = 1;
+            }
+            Event::Empty(e) if in_metadata && depth == 0 => {
+                package.children.push(metadata_child(&e)?);
+            }
+            Event::Start(_) if in_metadata => depth += 1,
+            Event::Text(t) if in_metadata && depth == 0 && package.children.is_empty() => {
+                let space = t.decode()?;
+                if let Some((_, indent)) = space.rsplit_once('\n') {
+                    package.child_indent = indent.to_string();
+                }
+            }
+            Event::Text(t) if in_metadata && depth == 1 => {
+                if let Some(child) = package.children.last_mut() {
+                    child.text.push_str(&t.decode()?);
+                }
+            }
+            Event::GeneralRef(r) if in_metadata && depth == 1 => {
+                if let Some(child) = package.children.last_mut() {
+                    if let Some(c) = r.resolve_char_ref()? {
+                        child.text.push(c);
+                    } else if let Some(entity) = resolve_xml_entity(&r.decode()?) {
+                        child.text.push_str(entity);
+                    }
+                }
+            }
+            Event::End(_) if in_metadata => match depth {
+                0 => break,
+                _ => depth -= 1,
+            },
+            Event::Eof => return Err(anyhow!("The OPF has no <metadata>.")),
+            _ => {}
+        }
+    }
+    // The only whitespace of an empty `<metadata>` is the indent of its end tag.
+    if package.children.is_empty() {
+        package.child_indent.push_str("  ");
+    }
+    let unique = package.unique_identifier.as_deref();
+    package.unique_identifier_value = package
+        .children
+        .iter()
+        .find(|child| child.name == b"identifier" && child.id.as_deref() == unique)
+        .map(|child| child.text.trim().to_string());
+    return Ok(package);
+}
+
+fn metadata_child(e: &BytesStart) -> anyhow::Result<MetadataChild> {
+    return Ok(MetadataChild {
+        name: e.local_name().as_ref().to_vec(),
+        id: attribute(e, b"id")?,
+        role: attribute(e, b"role")?,
+        meta_name: attribute(e, b"name")?,
+        property: attribute(e, b"property")?,
+        refines: attribute(e, b"refines")?.map(|r| r.trim_start_matches('#').to_string()),
+        text: String::new(),
+    });
+}
+
+/// Indices of the children of `<metadata>` that [`write_metadata`] replaces, along with the
+/// refinements of those children.
+fn dropped_children(package: &Package, new_cover: bool) -> HashSet<usize> {
+    let children = &package.children;
+    let refined_role = |id: &str| {
+        children
+            .iter()
+            .find(|c| c.refines.as_deref() == Some(id) && c.property.as_deref() == Some("role"))
+            .map(|c| c.text.trim())
+    };
+    let mut dropped: HashSet<usize> = HashSet::new();
+    for (i, child) in children.iter().enumerate() {
+        let drop = match child.name.as_slice() {
+            b"title" | b"subject" | b"description" => true,
+            b"creator" => {
+                let role = child
+                    .role
+                    .as_deref()
+                    .or_else(|| child.id.as_deref().and_then(refined_role));
+                role.is_none_or(|r| r == "aut")
+            }
+            b"identifier" => child.id.is_none() || child.id != package.unique_identifier,
+            b"meta" => match (child.meta_name.as_deref(), child.property.as_deref()) {
+                (Some("calibre:series" | "calibre:series_index" | "calibre:author_sort"), _) => {
+                    true
+                }
+                (Some("cover"), _) => new_cover,
+                (_, Some("belongs-to-collection" | "dcterms:modified")) => child.refines.is_none(),
+                _ => false,
+            },
+            _ => false,
+        };
+        if drop {
+            dropped.insert(i);
+        }
+    }
+    // Refinements of dropped elements go too, and so do refinements of those.
+    loop {
+        let ids: HashSet<&str> = dropped
+            .iter()
+            .filter_map(|i| children[*i].id.as_deref())
+            .collect();
+        let refining: Vec<usize> = children
+            .iter()
+            .enumerate()
+            .filter(|(i, c)| {
+                !dropped.contains(i) && c.refines.as_deref().is_some_and(|r| ids.contains(r))
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if refining.is_empty() {
+            return dropped;
+        }
+        dropped.extend(refining);
+    }
+}
+
+/// The elements [`write_metadata`] adds to `<metadata>`, each on its own line.
+fn new_metadata(package: &Package, edit: &OpfEdit, indent: &str) -> anyhow::Result<String> {
+    let dc = package.dc_prefix.as_deref().unwrap_or("dc");
+    let opf = package.opf_prefix.as_deref().unwrap_or("opf");
+    let metadata = edit.metadata;
+    let mut out = String::new();
+    let mut line = |element: String| {
+        out.push('\n');
+        out.push_str(indent);
+        out.push_str(&element);
+    };
+    line(format!("<{dc}:title>{}</{dc}:title>", escape(edit.title)));
+    for (i, author) in metadata.authors.iter().enumerate() {
+        let file_as = metadata.author_sort.as_deref().filter(|_| i == 0);
+        if package.epub3 {
+            let id = format!("{}-creator-{}", ID_PREFIX, i + 1);
+            line(format!(
+                "<{dc}:creator id=\"{id}\">{}</{dc}:creator>",
+                escape(author)
+            ));
+            line(format!(
+                "<meta refines=\"#{id}\" property=\"role\" scheme=\"marc:relators\">aut</meta>"
+            ));
+            if let Some(file_as) = file_as {
+                line(format!(
+                    "<meta refines=\"#{id}\" property=\"file-as\">{}</meta>",
+                    escape(file_as)
+                ));
+            }
+        } else {
+            let mut attributes = format!(" {opf}:role=\"aut\"");
+            if let Some(file_as) = file_as {
+                write!(attributes, " {opf}:file-as=\"{}\"", escape(file_as))?;
+            }
+            line(format!(
+                "<{dc}:creator{attributes}>{}</{dc}:creator>",
+                escape(author)
+            ));
+        }
+    }
+    if let Some(description) = edit.description.map(str::trim).filter(|d| !d.is_empty()) {
+        line(format!(
+            "<{dc}:description>{}</{dc}:description>",
+            escape(description)
+        ));
+    }
+    for subject in &metadata.tags {
+        line(format!("<{dc}:subject>{}</{dc}:subject>", escape(subject)));
+    }
+    for (scheme, value) in &metadata.identifiers {
+        let value = value.trim();
+        // The unique identifier is kept as it was.
+        if value.is_empty()
+            || package
+                .unique_identifier_value
+                .as_deref()
+                .is_some_and(|unique| unique.ends_with(value))
+        {
+            continue;
+        }
+        match package.epub3 {
+            true => line(format!(
+                "<{dc}:identifier>urn:{}:{}</{dc}:identifier>",
+                escape(scheme.as_str()),
+                escape(value)
+            )),
+            false => line(format!(
+                "<{dc}:identifier {opf}:scheme=\"{}\">{}</{dc}:identifier>",
+                escape(scheme.as_str()),
+                escape(value)
+            )),
+        }
+    }
+    if let Some(series) = &metadata.series {
+        line(format!(
+            "<meta name=\"calibre:series\" content=\"{}\"/>",
+            escape(series.as_str())
+        ));
+        if let Some(index) = metadata.series_index {
+            line(format!(
+                "<meta name=\"calibre:series_index\" content=\"{}\"/>",
+                index
+            ));
+        }
+        if package.epub3 {
+            let id = format!("{}-series", ID_PREFIX);
+            line(format!(
+                "<meta property=\"belongs-to-collection\" id=\"{id}\">{}</meta>",
+                escape(series.as_str())
+            ));
+            line(format!(
+                "<meta refines=\"#{id}\" property=\"collection-type\">series</meta>"
+            ));
+            if let Some(index) = metadata.series_index {
+                line(format!(
+                    "<meta refines=\"#{id}\" property=\"group-position\">{}</meta>",
+                    index
+                ));
+            }
+        }
+    }
+    if package.epub3 {
+        line(format!(
+            "<meta property=\"dcterms:modified\">{}</meta>",
+            escape(edit.modified)
+        ));
+    }
+    if let Some(cover) = edit.new_cover {
+        line(format!(
+            "<meta name=\"cover\" content=\"{}\"/>",
+            escape(cover.id.as_str())
+        ));
+    }
+    return Ok(out);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    const EPUB2: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:title>Dune</dc:title>
+    <dc:creator opf:role="aut" opf:file-as="Herbert, F.">F. Herbert</dc:creator>
+    <dc:creator opf:role="edt">Ed Itor</dc:creator>
+    <dc:identifier id="uid">urn:uuid:0b3c5e1a</dc:identifier>
+    <dc:identifier opf:scheme="ISBN">0000000000</dc:identifier>
+    <dc:subject>Old</dc:subject>
+    <meta name="calibre:series" content="Old series"/>
+    <meta name="cover" content="cover"/>
+  </metadata>
+  <manifest>
+    <item id="cover" href="cover.png" media-type="image/png"/>
+  </manifest>
+</package>
+"#;
+
+    const EPUB3: &str = r##"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>Dune</dc:title>
+    <dc:creator id="c1">F. Herbert</dc:creator>
+    <meta refines="#c1" property="file-as">Herbert, F.</meta>
+    <dc:identifier id="uid">urn:uuid:0b3c5e1a</dc:identifier>
+    <meta property="dcterms:modified">2020-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="cover" href="cover.png" media-type="image/png" properties="cover-image"/>
+  </manifest>
+</package>
+"##;
+
+    fn metadata() -> BookMetadata {
+        return BookMetadata {
+            authors: vec!["Frank Herbert".to_string(), "Brian Herbert".to_string()],
+            author_sort: Some("Herbert, Frank".to_string()),
+            series: Some("Dune & Co".to_string()),
+            series_index: Some(2.5),
+            tags: vec!["Science fiction".to_string()],
+            rating: None,
+            identifiers: BTreeMap::from([
+                ("isbn".to_string(), "9780441172696".to_string()),
+                ("uuid".to_string(), "0b3c5e1a".to_string()),
+            ]),
+        };
+    }
+
+    fn edit(metadata: &BookMetadata) -> OpfEdit<'_> {
+        return OpfEdit {
+            title: "Dune Messiah",
+            metadata,
+            description: Some("The sequel."),
+            new_cover: None,
+            modified: "2026-10-18T12:00:00Z",
+        };
+    }
+
+    #[test]
+    fn writes_metadata_that_reads_back() -> anyhow::Result<()> {
+        let metadata = metadata();
+        for xml in [EPUB2, EPUB3] {
+            let written = write_metadata(xml, &edit(&metadata))?;
+            let read = read_metadata(&written)?;
+            assert_eq!(read.title.as_deref(), Some("Dune Messiah"));
+            assert_eq!(read.description.as_deref(), Some("The sequel."));
+            assert_eq!(read.metadata.authors, metadata.authors);
+            assert_eq!(read.metadata.author_sort, metadata.author_sort);
+            assert_eq!(read.metadata.series, metadata.series);
+            assert_eq!(read.metadata.series_index, metadata.series_index);
+            assert_eq!(read.metadata.tags, metadata.tags);
+            assert_eq!(read.metadata.identifiers, metadata.identifiers);
+            // The unique identifier stays as it was, the cover too.
+            assert_eq!(
+                unique_identifier(&written)?.as_deref(),
+                Some("urn:uuid:0b3c5e1a")
+            );
+            assert_eq!(
+                cover_item(&written)?.map(|item| item.id).as_deref(),
+                Some("cover")
+            );
+        }
+        return Ok(());
+    }
+
+    #[test]
+    fn keeps_what_it_does_not_replace() -> anyhow::Result<()> {
+        let metadata = metadata();
+        let epub2 = write_metadata(EPUB2, &edit(&metadata))?;
+        assert!(epub2.contains(r#"<dc:creator opf:role="edt">Ed Itor</dc:creator>"#));
+        assert!(epub2.contains(r#"opf:file-as="Herbert, Frank""#));
+        assert!(!epub2.contains("Old") && !epub2.contains("0000000000"));
+        assert!(epub2.ends_with("  </manifest>\n</package>\n"));
+
+        let epub3 = write_metadata(EPUB3, &edit(&metadata))?;
+        assert!(epub3.contains(r#"property="file-as">Herbert, Frank</meta>"#));
+        assert!(!epub3.contains("Herbert, F.<") && !epub3.contains("2020-01-01"));
+        assert_eq!(epub3.matches("dcterms:modified").count(), 1);
+        assert!(epub3.contains("<dc:title>Dune Messiah</dc:title>"));
+        return Ok(());
+    }
+}
@@ -0,0 +1,766 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::{Ok, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::utility::{
+    archive,
+    cache::{Cache, CacheItem},
+    epub_edit,
+    library::DATA_DIR,
+    opds, sync,
+};
+
+/// Log of the runs of the organizer in the library data dir, see [`UndoLog`].
+const UNDO_LOG: &str = "organize.jsonl";
+/// Longest file or folder name written, in bytes. Most file systems allow 255; the rest is
+/// left for the ` (2)` of a collision and the `.sdr` folder of KOReader.
+const MAX_NAME_BYTES: usize = 240;
+/// Names Windows refuses for files and folders, whatever their extension.
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// A book field a template can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Title,
+    /// The first author.
+    Author,
+    /// Every author, separated with `, `.
+    Authors,
+    /// The author sort of the book, or the first author.
+    AuthorSort,
+    Series,
+    /// Without decimals for whole numbers, e.g. `2` or `2.5`.
+    SeriesIndex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+    /// `[...]`, left out entirely if any field in it is empty.
+    Optional(Vec<Part>),
+}
+
+/// Where books go, such as `{author_sort}/{series}/{series_index} - {title}.epub`: text and
+/// fields, with `/` separating folders. Text and fields inside `[...]` are left out when one
+/// of those fields is empty, e.g. `{author_sort}/[{series}/]{title}`; a book missing a field
+/// used outside of them cannot be placed. Books keep their extension, so `.kepub.epub` books
+/// stay Kobo books.
+#[derive(Debug, Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+/// The file system rules names are made to follow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// Also used on Android, whose shared storage and SD cards follow FAT rules.
+    Windows,
+    Apple,
+    Unix,
+}
+
+/// A book to move, by path relative to the library root with `/` separators.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookMove {
+    pub key: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// A book the organizer leaves where it is, and why.
+#[derive(Debug, Clone)]
+pub struct SkippedBook {
+    pub key: String,
+    pub path: String,
+    pub reason: String,
+}
+
+/// What organizing a library with a template does, see [`plan`].
+#[derive(Debug, Default)]
+pub struct OrganizePlan {
+    pub moves: Vec<BookMove>,
+    /// Books already where the template puts them.
+    pub unchanged: usize,
+    pub skipped: Vec<SkippedBook>,
+}
+
+/// One run of the organizer, as read from the undo log.
+#[derive(Debug)]
+pub struct OrganizeRun {
+    /// Moves that were made and not undone yet, in order.
+    pub moves: Vec<BookMove>,
+}
+
+/// A line of the undo log.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum UndoEntry {
+    /// A run starts; the moves that follow belong to it.
+    Run {
+        time: u64,
+        template: String,
+    },
+    Moved(BookMove),
+    /// The move from `from` to `to` was put back.
+    Undone {
+        from: String,
+        to: String,
+    },
+}
+
+/// The undo log of a library, `.spectecle/organize.jsonl`. Each move is appended as soon as
+/// it is made and each undone one as soon as it is put back, so the log matches the files
+/// even when a run is interrupted.
+#[derive(Debug)]
+pub struct UndoLog {
+    file: File,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        let source = source.trim();
+        if source.starts_with('/') || source.starts_with('\\') {
+            return Err(anyhow!("A template is relative to the library folder."));
+        }
+        if source.split('/').any(|component| component.trim() == "..") {
+            return Err(anyhow!("A template cannot leave the library folder."));
+        }
+        if source.is_empty() || source.ends_with('/') {
+            return Err(anyhow!("A template must end with a file name."));
+        }
+        return Ok(Self {
+            parts: Self::parse_parts(source)?,
+        });
+    }
+
+    fn parse_parts(source: &str) -> anyhow::Result<Vec<Part>> {
+        let mut parts = Vec::new();
+        // Parts of the `[...]` being read, if any.
+        let mut optional: Option<Vec<Part>> = None;
+        let mut text = String::new();
+        let mut chars = source.chars();
+        while let Some(c) = chars.next() {
+            let current = optional.as_mut().unwrap_or(&mut parts);
+            match c {
+                '{' => {
+                    let name: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                    if !text.is_empty() {
+                        current.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    current.push(Part::Field(Self::field(&name)?));
+                }
+                '[' if optional.is_none() => {
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    optional = Some(Vec::new());
+                }
+                ']' => {
+                    let Some(mut group) = optional.take() else {
+                        return Err(anyhow!("Unexpected ] in {}", source));
+                    };
+                    if !text.is_empty() {
+                        group.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Optional(group));
+                }
+                '[' => return Err(anyhow!("Optional parts cannot be nested: {}", source)),
+                c => text.push(c),
+            }
+        }
+        if optional.is_some() {
+            return Err(anyhow!("Missing ] in {}", source));
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        return Ok(parts);
+    }
+
+    fn field(name: &str) -> anyhow::Result<Field> {
+        return match name.trim() {
+            "title" => Ok(Field::Title),
+            "author" => Ok(Field::Author),
+            "authors" => Ok(Field::Authors),
+            "author_sort" => Ok(Field::AuthorSort),
+            "series" => Ok(Field::Series),
+            "series_index" => Ok(Field::SeriesIndex),
+            name => Err(anyhow!("Unknown field {{{}}}", name)),
+        };
+    }
+
+    /// Path the template gives `item`, relative to the library root with `/` separators.
+    /// Fails if a field outside of `[...]` is empty, rather than leave out its folder.
+    pub fn render(&self, item: &CacheItem, platform: Platform) -> anyhow::Result<String> {
+        let extension = book_extension(item.relative_path());
+        // Fields have their `/` replaced, so only those of the template separate folders.
+        let rendered = render_parts(&self.parts, item, platform)?;
+        let components: Vec<&str> = rendered.split('/').collect();
+        let last = components.len() - 1;
+        let mut names = Vec::new();
+        for (i, name) in components.into_iter().enumerate() {
+            if i == last {
+                let mut name = name.to_string();
+                for suffix in [".epub", ".kepub"] {
+                    if ends_with_ignore_case(&name, suffix) {
+                        name.truncate(name.len() - suffix.len());
+                    }
+                }
+                let stem = sanitize(&name, platform, MAX_NAME_BYTES - extension.len());
+                if stem.is_empty() {
+                    return Err(anyhow!("The template gives {} no file name", item.title()));
+                }
+                names.push(format!("{}{}", stem, extension));
+            } else {
+                let name = sanitize(name, platform, MAX_NAME_BYTES);
+                if !name.is_empty() {
+                    names.push(name);
+                }
+            }
+        }
+        return Ok(names.join("/"));
+    }
+}
+
+impl Field {
+    /// The name a template uses for the field, see [`Template::parse`].
+    fn name(self) -> &'static str {
+        return match self {
+            Field::Title => "title",
+            Field::Author => "author",
+            Field::Authors => "authors",
+            Field::AuthorSort => "author_sort",
+            Field::Series => "series",
+            Field::SeriesIndex => "series_index",
+        };
+    }
+}
+
+impl Platform {
+    pub fn current() -> Self {
+        if cfg!(any(windows, target_os = "android")) {
+            return Self::Windows;
+        }
+        if cfg!(any(target_os = "macos", target_os = "ios")) {
+            return Self::Apple;
+        }
+        return Self::Unix;
+    }
+
+    /// Whether two names that differ in case name the same file, as they do on the default
+    /// file systems of Windows and Apple platforms.
+    fn ignores_case(self) -> bool {
+        return self != Self::Unix;
+    }
+
+    /// `path` in a form equal for every path naming the same file.
+    fn comparable(self, path: &str) -> String {
+        return match self.ignores_case() {
+            true => path.to_lowercase(),
+            false => path.to_string(),
+        };
+    }
+}
+
+fn render_parts(parts: &[Part], item: &CacheItem, platform: Platform) -> anyhow::Result<String> {
+    let mut out = String::new();
+    for part in parts {
+        match part {
+            Part::Text(text) => out.push_str(text),
+            Part::Field(field) => {
+                let value = field_value(*field, item, platform);
+                if value.is_empty() {
+                    return Err(anyhow!(
+                        "The book has no {{{}}}, put it in [...] to place books without one.",
+                        field.name()
+                    ));
+                }
+                out.push_str(&value);
+            }
+            Part::Optional(group) => {
+                let has_every_field = group.iter().all(|part| match part {
+                    Part::Field(field) => !field_value(*field, item, platform).is_empty(),
+                    _ => true,
+                });
+                if has_every_field {
+                    out.push_str(&render_parts(group, item, platform)?);
+                }
+            }
+        }
+    }
+    return Ok(out);
+}
+
+/// Value of a field, with the characters that separate folders replaced.
+fn field_value(field: Field, item: &CacheItem, platform: Platform) -> String {
+    let metadata = item.metadata();
+    let value = match field {
+        Field::Title => item.title().to_string(),
+        Field::Author => metadata.authors.first().cloned().unwrap_or_default(),
+        Field::Authors => metadata.authors.join(", "),
+        Field::AuthorSort => metadata
+            .author_sort
+            .clone()
+            .or_else(|| metadata.authors.first().cloned())
+            .unwrap_or_default(),
+        Field::Series => metadata.series.clone().unwrap_or_default(),
+        Field::SeriesIndex => match (&metadata.series, metadata.series_index) {
+            (Some(_), Some(index)) if index.fract() == 0.0 => format!("{:.0}", index),
+            (Some(_), Some(index)) => index.to_string(),
+            _ => String::new(),
+        },
+    };
+    return sanitize(&value, platform, usize::MAX);
+}
+
+/// `.epub` or, for Kobo books, `.kepub.epub`, in the case the book uses.
+fn book_extension(path: &str) -> String {
+    let length = match ends_with_ignore_case(path, ".kepub.epub") {
+        true => ".kepub.epub".len(),
+        false => ".epub".len(),
+    };
+    return match path.is_char_boundary(path.len().saturating_sub(length)) {
+        true => path[path.len().saturating_sub(length)..].to_string(),
+        false => ".epub".to_string(),
+    };
+}
+
+/// `suffix` must be ASCII.
+fn ends_with_ignore_case(text: &str, suffix: &str) -> bool {
+    return text.len() >= suffix.len()
+        && text.as_bytes()[text.len() - suffix.len()..].eq_ignore_ascii_case(suffix.as_bytes());
+}
+
+/// Makes `name` a valid file or folder name on `platform`, at most `max_bytes` long:
+/// separators and characters the platform refuses become `_`, control characters and
+/// leading dots (which hide files) are dropped, whitespace is collapsed, and dashes or
+/// commas left dangling by empty fields are trimmed.
+pub fn sanitize(name: &str, platform: Platform, max_bytes: usize) -> String {
+    let mut out = String::with_capacity(name.len());
+    for c in name.chars() {
+        let replaced = match c {
+            '/' | '\\' => '_',
+            ':' if platform != Platform::Unix => '_',
+            '<' | '>' | '"' | '|' | '?' | '*' if platform == Platform::Windows => '_',
+            c if c.is_whitespace() => ' ',
+            c if c.is_control() => continue,
+            c => c,
+        };
+        if replaced == ' ' && (out.is_empty() || out.ends_with(' ')) {
+            continue;
+        }
+        out.push(replaced);
+    }
+    let trim = |c: char| c == ' ' || c == '-' || c == ',';
+    let mut out = out.trim_matches(trim).trim_start_matches('.').to_string();
+    if out.len() > max_bytes {
+        let mut end = max_bytes;
+        while !out.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.truncate(end);
+    }
+    if platform == Platform::Windows {
+        // Windows drops trailing dots and spaces, so two names could end up the same.
+        out = out.trim_end_matches(['.', ' ']).to_string();
+        let stem = out.split('.').next().unwrap_or_default();
+        if WINDOWS_RESERVED
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        {
+            out.insert(0, '_');
+        }
+    }
+    return out.trim_end_matches(trim).to_string();
+}
+
+/// Where `template` puts each book of `cache`. A book whose place is taken, on disk or by
+/// another book, gets ` (2)`, ` (3)` ... added to its name. Books inside archives cannot be
+/// moved and are skipped.
+pub fn plan(root: &Path, cache: &Cache, template: &Template, platform: Platform) -> OrganizePlan {
+    let mut plan = OrganizePlan::default();
+    let comparable = |path: &str| platform.comparable(path);
+    let mut taken = HashSet::new();
+    for item in cache.items() {
+        let from = sync::book_path(item.relative_path());
+        let skip = |reason: String| SkippedBook {
+            key: item.key().to_string(),
+            path: from.clone(),
+            reason,
+        };
+        if archive::split(&root.join(item.relative_path())).is_some() {
+            plan.skipped
+                .push(skip("Books inside archives cannot be moved.".to_string()));
+            continue;
+        }
+        let target = match template.render(item, platform) {
+            anyhow::Result::Ok(target) => target,
+            Err(e) => {
+                plan.skipped.push(skip(format!("{:#}", e)));
+                continue;
+            }
+        };
+        if target == from {
+            taken.insert(comparable(&target));
+            plan.unchanged += 1;
+            continue;
+        }
+        let is_free = |to: &str| {
+            // Only the case changes, the book is its own target.
+            let itself = comparable(to) == comparable(&from);
+            !taken.contains(&comparable(to)) && (itself || !root.join(to).exists())
+        };
+        let mut to = target.clone();
+        let mut copy = 1;
+        while !is_free(&to) {
+            copy += 1;
+            to = with_copy_number(&target, copy);
+        }
+        taken.insert(comparable(&to));
+        plan.moves.push(BookMove {
+            key: item.key().to_string(),
+            from,
+            to,
+        });
+    }
+    return plan;
+}
+
+/// `a/Dune.epub` as `a/Dune (2).epub`, keeping compound extensions whole.
+fn with_copy_number(path: &str, copy: usize) -> String {
+    let extension = book_extension(path);
+    let stem = &path[..path.len() - extension.len()];
+    return format!("{} ({}){}", stem, copy, extension);
+}
+
+/// Moves a book within the library at `root`, along with its KOReader sidecar folder and
+/// the original kept if it was edited, then removes the folders it leaves empty. On
+/// `platform`s that ignore case, a book may be renamed to its own name in another case.
+pub fn move_book(root: &Path, book: &BookMove, platform: Platform) -> anyhow::Result<()> {
+    let from = root.join(&book.from);
+    let to = root.join(&book.to);
+    if to.exists() && platform.comparable(&book.to) != platform.comparable(&book.from) {
+        return Err(anyhow!("{} already exists", book.to));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(&from, &to)?;
+    // The book is where it should be, its companions follow as far as they can.
+    let sidecar = from.with_extension("sdr");
+    let new_sidecar = to.with_extension("sdr");
+    if sidecar.is_dir() && !new_sidecar.exists() {
+        let _ = fs::rename(&sidecar, &new_sidecar);
+    }
+    let original = epub_edit::original_path(root, &book.from);
+    let new_original = epub_edit::original_path(root, &book.to);
+    if original.is_file() && !new_original.exists() {
+        if let Some(parent) = new_original.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let _ = fs::rename(&original, &new_original);
+        remove_empty_dirs(&root.join(DATA_DIR), &original);
+    }
+    remove_empty_dirs(root, &from);
+    return Ok(());
+}
+
+/// Removes the folders above `path` that are empty, up to `root`.
+fn remove_empty_dirs(root: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir
+        && current != root
+        && current.starts_with(root)
+    {
+        // Fails, and so stops, at the first folder that still holds something.
+        if fs::remove_dir(current).is_err() {
+            return;
+        }
+        dir = current.parent();
+    }
+}
+
+impl UndoLog {
+    /// Starts a run of the organizer with `template` in the undo log of the library at
+    /// `root`, before any book is moved.
+    pub fn start(root: &Path, template: &str) -> anyhow::Result<Self> {
+        let mut log = Self::open(root)?;
+        log.append(&UndoEntry::Run {
+            time: opds::now_millis() as u64,
+            template: template.to_string(),
+        })?;
+        return Ok(log);
+    }
+
+    /// Opens the undo log of the library at `root` to record the moves undone.
+    pub fn open(root: &Path) -> anyhow::Result<Self> {
+        let dir = root.join(DATA_DIR);
+        fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(UNDO_LOG))?;
+        return Ok(Self { file });
+    }
+
+    /// Records a move of the run started last, once it was made.
+    pub fn moved(&mut self, book: &BookMove) -> anyhow::Result<()> {
+        return self.append(&UndoEntry::Moved(book.clone()));
+    }
+
+    /// Records that the move from `from` to `to` was put back.
+    pub fn undone(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        return self.append(&UndoEntry::Undone {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+
+    fn append(&mut self, entry: &UndoEntry) -> anyhow::Result<()> {
+        writeln!(self.file, "{}", serde_json::to_string(entry)?)?;
+        return Ok(());
+    }
+}
+
+/// The last run of the organizer in the library at `root` with moves left to undo, if any.
+pub fn last_run(root: &Path) -> anyhow::Result<Option<OrganizeRun>> {
+    return Ok(read_runs(root)?.pop());
+}
+
+/// The runs of the undo log with moves left to undo, oldest first.
+fn read_runs(root: &Path) -> anyhow::Result<Vec<OrganizeRun>> {
+    let log = root.join(DATA_DIR).join(UNDO_LOG);
+    if !log.is_file() {
+        return Ok(vec![]);
+    }
+    let mut runs: Vec<OrganizeRun> = Vec::new();
+    for entry in fs::read_to_string(log)?
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+    {
+        match entry {
+            UndoEntry::Run { .. } => runs.push(OrganizeRun { moves: vec![] }),
+            UndoEntry::Moved(book) => {
+                if let Some(run) = runs.last_mut() {
+                    run.moves.push(book);
+                }
+            }
+            UndoEntry::Undone { from, to } => {
+                let undone = |book: &BookMove| book.from == from && book.to == to;
+                if let Some(run) = runs
+                    .iter_mut()
+                    .rev()
+                    .find(|run| run.moves.iter().any(undone))
+                {
+                    run.moves.retain(|book| !undone(book));
+                }
+            }
+        }
+    }
+    runs.retain(|run| !run.moves.is_empty());
+    return Ok(runs);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    const TEMPLATE: &str = "{author_sort}/[{series}/][{series_index} - ]{title}";
+
+    fn item(path: &str, title: &str, metadata: Value) -> anyhow::Result<CacheItem> {
+        return Ok(serde_json::from_value(json!({
+            "key": Cache::hash_relative_path(Path::new(path)),
+            "relative_path": path,
+            "last_modified": 0,
+            "title": title,
+            "has_cover": false,
+            "metadata": metadata,
+        }))?);
+    }
+
+    #[test]
+    fn parses_templates() {
+        assert!(Template::parse(TEMPLATE).is_ok());
+        for invalid in [
+            "/{title}",
+            "../{title}",
+            "{author}/",
+            "",
+            "{publisher}/{title}",
+            "[{series}",
+            "{series}]",
+            "[[{series}]]",
+        ] {
+            assert!(Template::parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn renders_fields_and_optional_parts() -> anyhow::Result<()> {
+        let template = Template::parse(TEMPLATE)?;
+        let dune = item(
+            "books/dune.kepub.EPUB",
+            "Dune",
+            json!({
+                "authors": ["Frank Herbert"],
+                "author_sort": "Herbert, Frank",
+                "series": "Dune",
+                "series_index": 1.0,
+            }),
+        )?;
+        assert_eq!(
+            template.render(&dune, Platform::Unix)?,
+            "Herbert, Frank/Dune/1 - Dune.kepub.EPUB"
+        );
+        // Without a series the optional parts go, but a missing author leaves no place for it.
+        let emma = item(
+            "emma.epub",
+            "Emma: A/B",
+            json!({ "authors": ["Jane Austen"] }),
+        )?;
+        assert_eq!(
+            template.render(&emma, Platform::Unix)?,
+            "Jane Austen/Emma: A_B.epub"
+        );
+        assert_eq!(
+            template.render(&emma, Platform::Windows)?,
+            "Jane Austen/Emma_ A_B.epub"
+        );
+        let anonymous = item("beowulf.epub", "Beowulf", json!({}))?;
+        assert!(template.render(&anonymous, Platform::Unix).is_err());
+        let optional = Template::parse("[{author_sort}/]{title}")?;
+        assert_eq!(optional.render(&anonymous, Platform::Unix)?, "Beowulf.epub");
+        let untitled = item("x.epub", " ", json!({}))?;
+        assert!(template.render(&untitled, Platform::Unix).is_err());
+        return Ok(());
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("CON", Platform::Windows, 240), "_CON");
+        assert_eq!(sanitize("lpt1.txt", Platform::Windows, 240), "_lpt1.txt");
+        assert_eq!(sanitize("CON", Platform::Unix, 240), "CON");
+        assert_eq!(sanitize("Vol. 2...", Platform::Windows, 240), "Vol. 2");
+        assert_eq!(sanitize("Vol. 2...", Platform::Unix, 240), "Vol. 2...");
+        assert_eq!(sanitize("..hidden", Platform::Unix, 240), "hidden");
+        assert_eq!(sanitize(" a \t b - ", Platform::Unix, 240), "a b");
+        assert_eq!(sanitize("a?b*", Platform::Windows, 240), "a_b_");
+        // Truncated to whole characters: `é` takes two bytes.
+        assert_eq!(sanitize("ééé", Platform::Unix, 5), "éé");
+        assert_eq!(sanitize("abc. ", Platform::Windows, 4), "abc");
+    }
+
+    #[test]
+    fn numbers_colliding_books() -> anyhow::Result<()> {
+        let root = temp_root("plan")?;
+        let cache_dir = root.join(DATA_DIR).join("cache");
+        fs::create_dir_all(&cache_dir)?;
+        fs::write(root.join("Emma.epub"), "taken")?;
+        let mut items = serde_json::Map::new();
+        for item in [
+            item("a.epub", "Dune", json!({}))?,
+            item("b.epub", "dune", json!({}))?,
+            item("c.epub", "Emma", json!({}))?,
+            item("d.kepub.epub", "Persuasion", json!({}))?,
+            item("e.kepub.epub", "Persuasion", json!({}))?,
+            item("Sanditon.epub", "Sanditon", json!({}))?,
+        ] {
+            items.insert(item.key().to_string(), serde_json::to_value(&item)?);
+        }
+        fs::write(
+            cache_dir.join("cache.json"),
+            serde_json::to_string(&json!({ "items": items }))?,
+        )?;
+        let cache = Cache::open_read_only(cache_dir)?;
+        let template = Template::parse("{title}")?;
+
+        // `Dune` and `dune` are one name on Windows, and `Emma.epub` is taken on disk.
+        let plan = plan(&root, &cache, &template, Platform::Windows);
+        let mut targets: Vec<&str> = plan.moves.iter().map(|book| book.to.as_str()).collect();
+        targets.sort();
+        assert_eq!(
+            targets,
+            [
+                "Dune.epub",
+                "Emma (2).epub",
+                "Persuasion (2).kepub.epub",
+                "Persuasion.kepub.epub",
+                "dune (2).epub",
+            ]
+        );
+        assert_eq!(plan.unchanged, 1);
+
+        let plan = super::plan(&root, &cache, &template, Platform::Unix);
+        assert!(plan.moves.iter().any(|book| book.to == "dune.epub"));
+
+        // Books without an author stay where they are rather than pile up at the root.
+        let by_author = Template::parse("{author}/{title}")?;
+        let plan = super::plan(&root, &cache, &by_author, Platform::Unix);
+        assert!(plan.moves.is_empty());
+        assert_eq!(plan.skipped.len(), 6);
+        assert_eq!(
+            with_copy_number("a/Dune.kepub.epub", 3),
+            "a/Dune (3).kepub.epub"
+        );
+        fs::remove_dir_all(root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn undo_log_keeps_the_moves_left_to_undo() -> anyhow::Result<()> {
+        let root = temp_root("undo")?;
+        let book = |from: &str, to: &str| BookMove {
+            key: String::new(),
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        let mut undo = UndoLog::start(&root, "{title}")?;
+        undo.moved(&book("a.epub", "A/a.epub"))?;
+        let mut undo = UndoLog::start(&root, "{author}/{title}")?;
+        undo.moved(&book("b.epub", "B/b.epub"))?;
+        undo.moved(&book("c.epub", "C/c.epub"))?;
+        // A run that was interrupted before its first move leaves nothing to undo.
+        UndoLog::start(&root, "{series}/{title}")?;
+        assert_eq!(
+            last_run(&root)?.map(|run| run.moves),
+            Some(vec![book("b.epub", "B/b.epub"), book("c.epub", "C/c.epub")])
+        );
+
+        let mut undo = UndoLog::open(&root)?;
+        undo.undone("c.epub", "C/c.epub")?;
+        undo.undone("b.epub", "B/b.epub")?;
+        assert_eq!(
+            last_run(&root)?.map(|run| run.moves),
+            Some(vec![book("a.epub", "A/a.epub")])
+        );
+        undo.undone("a.epub", "A/a.epub")?;
+        assert!(last_run(&root)?.is_none());
+        fs::remove_dir_all(root)?;
+        return Ok(());
+    }
+
+    fn temp_root(name: &str) -> anyhow::Result<std::path::PathBuf> {
+        let root = std::env::temp_dir().join(format!(
+            "spectecle-organize-{}-{}",
+            name,
+            std::process::id()
+        ));
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+        fs::create_dir_all(&root)?;
+        return Ok(root);
+    }
+}
@@ -0,0 +1,198 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Ok;
+use serde::{Deserialize, Serialize};
+
+use crate::utility::{cache::Cache, metadata::BookMetadata, opf};
+
+/// Corrections to the metadata of a book, kept in the library settings instead of the book
+/// file, so books on read-only shares can be fixed too. Each field set replaces the value
+/// read from the book; an empty series or author sort hides the one of the book.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetadataOverlay {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub authors: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author_sort: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series_index: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifiers: Option<BTreeMap<String, String>>,
+}
+
+impl MetadataOverlay {
+    pub fn is_empty(&self) -> bool {
+        return *self == Self::default();
+    }
+
+    /// The title and metadata of a book with the overlay on top.
+    pub fn apply(&self, title: &str, metadata: &BookMetadata) -> (String, BookMetadata) {
+        let text = |value: &Option<String>, extracted: &Option<String>| match value {
+            Some(value) if value.is_empty() => None,
+            Some(value) => Some(value.clone()),
+            None => extracted.clone(),
+        };
+        let series = text(&self.series, &metadata.series);
+        let metadata = BookMetadata {
+            authors: self.authors.as_ref().unwrap_or(&metadata.authors).clone(),
+            author_sort: text(&self.author_sort, &metadata.author_sort),
+            series: series.clone(),
+            series_index: series.and(self.series_index.or(metadata.series_index)),
+            tags: self.tags.as_ref().unwrap_or(&metadata.tags).clone(),
+            rating: metadata.rating,
+            identifiers: self
+                .identifiers
+                .as_ref()
+                .unwrap_or(&metadata.identifiers)
+                .clone(),
+        };
+        return (self.title.as_deref().unwrap_or(title).to_string(), metadata);
+    }
+}
+
+/// Writes an OPF sidecar with the corrected metadata of every book of `cache` that has an
+/// overlay, at the path of the book under `destination` with an `.opf` extension, so the
+/// corrections can be applied with other tools. Returns the number of sidecars written.
+pub fn export_sidecars(cache: &Cache, destination: &Path) -> anyhow::Result<usize> {
+    let mut written = 0;
+    for item in cache.items().filter(|item| item.has_overlay()) {
+        let sidecar = destination.join(item.relative_path()).with_extension("opf");
+        if let Some(parent) = sidecar.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(sidecar, opf::sidecar(item.title(), item.metadata())?)?;
+        written += 1;
+    }
+    return Ok(written);
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::utility::{archive, fixtures, library, scan::ScanRules};
+
+    fn extracted() -> BookMetadata {
+        return BookMetadata {
+            authors: vec!["Frank Herbert".to_string()],
+            author_sort: Some("Herbert, Frank".to_string()),
+            series: Some("Dune".to_string()),
+            series_index: Some(2.0),
+            tags: vec!["Science Fiction".to_string()],
+            rating: Some(8),
+            identifiers: BTreeMap::from([("isbn".to_string(), "9780441172696".to_string())]),
+        };
+    }
+
+    #[test]
+    fn replaces_the_fields_it_sets() {
+        let (title, metadata) = MetadataOverlay::default().apply("Dune Messiah", &extracted());
+        assert_eq!(title, "Dune Messiah");
+        assert_eq!(metadata, extracted());
+
+        let overlay = MetadataOverlay {
+            title: Some("Children of Dune".to_string()),
+            authors: Some(vec!["F. Herbert".to_string()]),
+            series_index: Some(3.0),
+            tags: Some(vec![]),
+            ..Default::default()
+        };
+        let (title, metadata) = overlay.apply("Dune Messiah", &extracted());
+        assert_eq!(title, "Children of Dune");
+        assert_eq!(metadata.authors, ["F. Herbert"]);
+        assert_eq!(metadata.series_index, Some(3.0));
+        assert!(metadata.tags.is_empty());
+        // Fields the overlay leaves unset keep what was read from the book.
+        assert_eq!(metadata.author_sort.as_deref(), Some("Herbert, Frank"));
+        assert_eq!(metadata.series.as_deref(), Some("Dune"));
+        assert_eq!(metadata.rating, Some(8));
+        assert_eq!(metadata.identifiers, extracted().identifiers);
+    }
+
+    #[test]
+    fn empty_values_hide_the_extracted_ones() {
+        let overlay = MetadataOverlay {
+            author_sort: Some(String::new()),
+            series: Some(String::new()),
+            ..Default::default()
+        };
+        let (_, metadata) = overlay.apply("Dune Messiah", &extracted());
+        assert_eq!(metadata.author_sort, None);
+        assert_eq!(metadata.series, None);
+        // A position in no series means nothing, whichever side it comes from.
+        assert_eq!(metadata.series_index, None);
+
+        let overlay = MetadataOverlay {
+            series: Some(String::new()),
+            series_index: Some(3.0),
+            ..Default::default()
+        };
+        assert_eq!(overlay.apply("", &extracted()).1.series_index, None);
+        let overlay = MetadataOverlay {
+            series_index: Some(3.0),
+            ..Default::default()
+        };
+        let standalone = BookMetadata {
+            series: None,
+            ..extracted()
+        };
+        assert_eq!(overlay.apply("", &standalone).1.series_index, None);
+    }
+
+    #[test]
+    fn exports_sidecars_of_archived_books() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("spectecle-overlay-export-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        let root = dir.join("Books");
+        fs::create_dir_all(root.join("sf"))?;
+        library::mark_root(&root, "library")?;
+        fixtures::write_book(
+            &root.join("loose.epub"),
+            &fixtures::package("Loose", "", ""),
+        )?;
+        let book = dir.join("messiah.epub");
+        fixtures::write_book(&book, &fixtures::messiah())?;
+        fixtures::write_zip(
+            &root.join("sf/bundle.zip"),
+            &[("dune/messiah.epub", &fs::read(&book)?)],
+        )?;
+
+        let mut cache = Cache::open(dir.join("cache"))?;
+        cache.refresh(root.clone(), "library", 1, &ScanRules::default())?;
+        let archived = archive::virtual_path(&root.join("sf/bundle.zip"), "dune/messiah.epub")
+            .ok_or_else(|| anyhow!("No virtual path"))?;
+        let relative_path = archived.strip_prefix(&root)?.to_string_lossy().into_owned();
+        let key = cache
+            .items()
+            .find(|item| item.relative_path() == relative_path)
+            .map(|item| item.key().to_string())
+            .ok_or_else(|| anyhow!("The archived book was not indexed"))?;
+        let overlay = MetadataOverlay {
+            title: Some("Children of Dune".to_string()),
+            ..Default::default()
+        };
+        cache.set_overlay(&key, overlay)?;
+
+        // Only books with an overlay get a sidecar.
+        let destination = dir.join("sidecars");
+        assert_eq!(export_sidecars(&cache, &destination)?, 1);
+        let sidecar = destination.join(&relative_path).with_extension("opf");
+        let opf = opf::read_metadata(&fs::read_to_string(&sidecar)?)?;
+        assert_eq!(opf.title.as_deref(), Some("Children of Dune"));
+        assert_eq!(opf.metadata.series.as_deref(), Some("Dune"));
+        assert!(!destination.join("loose.opf").exists());
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+}
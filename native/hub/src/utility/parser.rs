@@ -0,0 +1,406 @@
+use std::{
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Ok, anyhow};
+use epub::doc::EpubDoc;
+use rbook::{
+    Ebook, Epub,
+    ebook::{
+        manifest::{Manifest, ManifestEntry},
+        metadata::{MetaEntry, Metadata},
+    },
+};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use zip::ZipArchive;
+
+use crate::utility::{archive, cover, epub_edit, href, metadata::BookMetadata};
+
+/// The EPUB parsing library that managed to read a book.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParserBackend {
+    #[default]
+    Epub,
+    Rbook,
+    EpubParser,
+}
+
+/// Metadata and cover extracted from a book, along with the backend that read it.
+#[derive(Debug)]
+pub struct ParsedBook {
+    pub title: Option<String>,
+    /// Read from the package document whichever backend opened the book, empty if it cannot
+    /// be read.
+    pub metadata: BookMetadata,
+    pub cover: Option<Vec<u8>>,
+    pub backend: ParserBackend,
+}
+
+impl ParserBackend {
+    /// Backends in the order they are tried. `epub` gives the best cover detection,
+    /// `rbook` is lenient about malformed OPFs and `epub-parser` only needs a readable zip.
+    const FALLBACK_CHAIN: [ParserBackend; 3] = [
+        ParserBackend::Epub,
+        ParserBackend::Rbook,
+        ParserBackend::EpubParser,
+    ];
+
+    fn parse(&self, source: &BookSource) -> anyhow::Result<ParsedBook> {
+        let (title, cover) = match self {
+            ParserBackend::Epub => parse_epub(source)?,
+            ParserBackend::Rbook => parse_rbook(source)?,
+            ParserBackend::EpubParser => parse_epub_parser(source)?,
+        };
+        let title = title.filter(|t| !t.trim().is_empty());
+        return Ok(ParsedBook {
+            title,
+            metadata: BookMetadata::default(),
+            cover: cover.map(|cover| resolve_svg_cover(source, cover)),
+            backend: *self,
+        });
+    }
+}
+
+/// Where the bytes of a book are read from.
+enum BookSource<'a> {
+    File(&'a Path),
+    /// A book read out of an archive, see [`archive::read_book`]. Shared so that backends
+    /// which need to own their reader do not copy it.
+    Memory(Arc<[u8]>),
+}
+
+/// A cover image as found by a backend.
+struct FoundCover {
+    /// Where the image is in the container, used to resolve the references of SVG covers.
+    path: Option<CoverPath>,
+    data: Vec<u8>,
+}
+
+impl FoundCover {
+    fn new(path: String, data: Vec<u8>) -> Self {
+        return FoundCover {
+            path: Some(CoverPath::Container(path)),
+            data,
+        };
+    }
+}
+
+enum CoverPath {
+    /// A container path.
+    Container(String),
+    /// An href relative to the package document, as reported by `epub-parser`.
+    Package(String),
+}
+
+/// Reads the metadata and cover of a book, falling back through every backend
+/// until one of them is able to open it. Books inside archives are read in memory.
+pub fn parse(file_path: &Path) -> anyhow::Result<ParsedBook> {
+    let source = match archive::split(file_path) {
+        Some(_) => BookSource::Memory(archive::read_book(file_path)?.into()),
+        None => BookSource::File(file_path),
+    };
+    let mut errors = Vec::new();
+    for backend in ParserBackend::FALLBACK_CHAIN {
+        match backend.parse(&source) {
+            anyhow::Result::Ok(mut book) => {
+                match package_metadata(&source) {
+                    anyhow::Result::Ok(metadata) => book.metadata = metadata,
+                    Err(e) => println!(
+                        "Failed to read the metadata of {}: {:#}",
+                        file_path.display(),
+                        e
+                    ),
+                }
+                return Ok(book);
+            }
+            Err(e) => errors.push(format!("{:?}: {:#}", backend, e)),
+        }
+    }
+    return Err(anyhow!(
+        "No parser could open {}: {}",
+        file_path.display(),
+        errors.join("; ")
+    ));
+}
+
+/// Authors, series, tags and identifiers from the package document, which the backends
+/// do not all expose.
+fn package_metadata(source: &BookSource) -> anyhow::Result<BookMetadata> {
+    let opf = match source {
+        BookSource::File(file_path) => epub_edit::package_metadata(&mut ZipArchive::new(
+            BufReader::new(File::open(file_path)?),
+        )?)?,
+        BookSource::Memory(data) => {
+            epub_edit::package_metadata(&mut ZipArchive::new(Cursor::new(&data[..]))?)?
+        }
+    };
+    return Ok(opf.metadata);
+}
+
+fn parse_epub(source: &BookSource) -> anyhow::Result<(Option<String>, Option<FoundCover>)> {
+    return match source {
+        BookSource::File(file_path) => read_epub(EpubDoc::new(file_path)?),
+        BookSource::Memory(data) => read_epub(EpubDoc::from_reader(Cursor::new(&data[..]))?),
+    };
+}
+
+fn read_epub<R: Read + Seek>(
+    mut book: EpubDoc<R>,
+) -> anyhow::Result<(Option<String>, Option<FoundCover>)> {
+    let cover = find_cover(&mut book)?;
+    return Ok((book.get_title(), cover));
+}
+
+fn parse_rbook(source: &BookSource) -> anyhow::Result<(Option<String>, Option<FoundCover>)> {
+    let options = Epub::options().strict(false).skip_toc(true);
+    let book = match source {
+        BookSource::File(file_path) => options.open(file_path)?,
+        BookSource::Memory(data) => options.read(Cursor::new(Arc::clone(data)))?,
+    };
+    let title = book.metadata().title().map(|t| t.value().to_string());
+    // Resolved hrefs are URL-encoded and absolute, starting at the container root.
+    let cover = book.manifest().cover_image().and_then(|entry| {
+        return Some(FoundCover {
+            path: href::resolve("", entry.href().as_str()).map(CoverPath::Container),
+            data: entry.read_bytes().ok()?,
+        });
+    });
+    return Ok((title, cover));
+}
+
+fn parse_epub_parser(source: &BookSource) -> anyhow::Result<(Option<String>, Option<FoundCover>)> {
+    let book = match source {
+        BookSource::File(file_path) => epub_parser::Epub::parse(file_path)?,
+        BookSource::Memory(data) => epub_parser::Epub::parse_from_buffer(data)?,
+    };
+    // Prefer an image named like a cover, otherwise the first image is the best guess.
+    let cover = book
+        .images
+        .iter()
+        .filter(|image| {
+            let id = image.id.to_ascii_lowercase();
+            let href = image.href.to_ascii_lowercase();
+            (id.contains("cover") || href.contains("cover")) && !href.contains("back")
+        })
+        .chain(book.images.iter())
+        .next()
+        .map(|image| FoundCover {
+            path: Some(CoverPath::Package(image.href.clone())),
+            data: image.content.clone(),
+        });
+    return Ok((book.metadata.title, cover));
+}
+
+/// Looks for the cover image of a book, trying in order: the manifest cover, the image
+/// on the cover page of the spine, image resources named like a cover and finally the
+/// `cover` metadata entry.
+fn find_cover<R: Read + Seek>(book: &mut EpubDoc<R>) -> anyhow::Result<Option<FoundCover>> {
+    if let Some(cover_path) = book.get_cover_id().and_then(|id| resource_path(book, &id))
+        && let Some(cover_data) = book.get_resource_by_path(&cover_path)
+    {
+        return Ok(Some(FoundCover::new(cover_path, cover_data)));
+    }
+
+    let mut cover_spine = None;
+    for spine in &book.spine {
+        if spine.idref.contains("cover") && !spine.idref.contains("back") {
+            cover_spine = Some(spine.idref.clone());
+        }
+    }
+    if let Some(cover_page_path) = cover_spine.and_then(|id| resource_path(book, &id))
+        && let Some(cover_page_data) = book.get_resource_by_path(&cover_page_path)
+    {
+        let cover_page = String::from_utf8_lossy(&cover_page_data).to_string();
+        let re = Regex::new(r#"(?i)<(?:img|image)[^>]+(?:src|href)\s*=\s*["']([^"']+)["']"#)?;
+        if let Some(img_href) = re.captures(&cover_page).and_then(|c| c.get(1))
+            && let Some(img_path) = href::resolve(&cover_page_path, img_href.as_str())
+            && let Some(cover_data) = book.get_resource_by_path(&img_path)
+        {
+            return Ok(Some(FoundCover::new(img_path, cover_data)));
+        }
+    }
+
+    let mut cover_id = None;
+    for (key, item) in &book.resources {
+        if key.to_ascii_lowercase().contains("cover")
+            && !key.to_ascii_lowercase().contains("back")
+            && item.mime.starts_with("image/")
+            && !item.mime.contains("html")
+        {
+            cover_id = Some(key.clone());
+        }
+    }
+    if let Some(cover_path) = cover_id.and_then(|id| resource_path(book, &id))
+        && let Some(cover_data) = book.get_resource_by_path(&cover_path)
+    {
+        return Ok(Some(FoundCover::new(cover_path, cover_data)));
+    }
+
+    let mut cover = None;
+    for md in &book.metadata {
+        if md.property == "cover" || md.property == "cover-image" {
+            cover = Some(md.value.clone());
+        }
+    }
+    if let Some(cover) = cover {
+        // EPUB2 `<meta name="cover">` holds a manifest id, but some books put an href there.
+        let cover_path = match resource_path(book, &cover) {
+            Some(cover_path) => Some(cover_path),
+            None => href::container_path(&book.root_file)
+                .and_then(|opf_path| href::resolve(&opf_path, &cover)),
+        };
+        if let Some(cover_path) = cover_path
+            && let Some(cover_data) = book.get_resource_by_path(&cover_path)
+        {
+            return Ok(Some(FoundCover::new(cover_path, cover_data)));
+        }
+    }
+    return Ok(None);
+}
+
+/// Container path of the manifest item with the given id.
+fn resource_path<R: Read + Seek>(book: &EpubDoc<R>, id: &str) -> Option<String> {
+    let item = book.resources.get(id)?;
+    return href::container_path(&item.path);
+}
+
+/// EPUB3 covers are often an SVG page wrapping a single `<image>`, which the backends return
+/// as is. The raster it references is read relative to the SVG; the SVG itself is kept when
+/// that fails.
+fn resolve_svg_cover(source: &BookSource, cover: FoundCover) -> Vec<u8> {
+    if !cover::is_svg(&cover.data) {
+        return cover.data;
+    }
+    let Some(svg_path) = cover.path else {
+        return cover.data;
+    };
+    let raster = match source {
+        BookSource::File(file_path) => File::open(file_path)
+            .ok()
+            .and_then(|file| ZipArchive::new(BufReader::new(file)).ok())
+            .and_then(|zip| svg_raster(zip, svg_path, &cover.data)),
+        BookSource::Memory(data) => ZipArchive::new(Cursor::new(&data[..]))
+            .ok()
+            .and_then(|zip| svg_raster(zip, svg_path, &cover.data)),
+    };
+    return raster.unwrap_or(cover.data);
+}
+
+/// The image referenced by `svg`, a resource of the container `zip` at `svg_path`.
+fn svg_raster<R: Read + Seek>(
+    mut zip: ZipArchive<R>,
+    svg_path: CoverPath,
+    svg: &[u8],
+) -> Option<Vec<u8>> {
+    let img_href = cover::svg_image_href(svg)?;
+    let svg_path = match svg_path {
+        CoverPath::Container(path) => path,
+        CoverPath::Package(svg_href) => {
+            href::resolve(&epub_edit::package_path(&mut zip).ok()?, &svg_href)?
+        }
+    };
+    let img_path = href::resolve(&svg_path, &img_href)?;
+    let mut image = Vec::new();
+    zip.by_name(&img_path).ok()?.read_to_end(&mut image).ok()?;
+    return Some(image);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+    use crate::utility::fixtures::{self, CONTAINER, TEXT};
+
+    fn temp_dir(name: &str) -> anyhow::Result<PathBuf> {
+        let dir =
+            std::env::temp_dir().join(format!("spectecle-parser-{}-{}", name, std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        return Ok(dir);
+    }
+
+    #[test]
+    fn reads_well_formed_books_with_epub() -> anyhow::Result<()> {
+        let dir = temp_dir("epub")?;
+        let path = dir.join("messiah.epub");
+        fixtures::write_book(&path, &fixtures::messiah())?;
+
+        let book = parse(&path)?;
+        assert_eq!(book.backend, ParserBackend::Epub);
+        assert_eq!(book.title.as_deref(), Some("Dune Messiah"));
+        assert_eq!(book.metadata.series.as_deref(), Some("Dune"));
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn falls_back_to_rbook_for_malformed_packages() -> anyhow::Result<()> {
+        let dir = temp_dir("rbook")?;
+        let path = dir.join("malformed.epub");
+        // `epub` rejects package documents without a spine, `rbook` only does when strict.
+        let opf = fixtures::package("Children of Dune", "", "")
+            .replace(r#"<spine><itemref idref="text"/></spine>"#, "");
+        fixtures::write_book(&path, &opf)?;
+        assert!(ParserBackend::Epub.parse(&BookSource::File(&path)).is_err());
+
+        let book = parse(&path)?;
+        assert_eq!(book.backend, ParserBackend::Rbook);
+        assert_eq!(book.title.as_deref(), Some("Children of Dune"));
+
+        // Books inside archives are read in memory.
+        let bundle = dir.join("bundle.zip");
+        fixtures::write_zip(&bundle, &[("dune/malformed.epub", &fs::read(&path)?)])?;
+        let virtual_path = archive::virtual_path(&bundle, "dune/malformed.epub")
+            .ok_or(anyhow!("No virtual path"))?;
+        let book = parse(&virtual_path)?;
+        assert_eq!(book.backend, ParserBackend::Rbook);
+        assert_eq!(book.title.as_deref(), Some("Children of Dune"));
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn every_backend_reads_the_raster_of_svg_covers() -> anyhow::Result<()> {
+        let dir = temp_dir("svg")?;
+        let path = dir.join("svg.epub");
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><image xlink:href="../raster/cover.png"/></svg>"#;
+        let png = b"\x89PNG\r\n\x1a\n not really a png";
+        let opf = fixtures::package(
+            "Dune",
+            r#"<meta name="cover" content="cover-image"/>"#,
+            r#"<item id="cover-image" href="images/cover.svg" media-type="image/svg+xml" properties="cover-image"/>
+    <item id="raster" href="raster/cover.png" media-type="image/png"/>"#,
+        );
+        fixtures::write_zip(
+            &path,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER.as_bytes()),
+                ("OEBPS/content.opf", opf.as_bytes()),
+                ("OEBPS/text.xhtml", TEXT.as_bytes()),
+                // The same SVG elsewhere, whose reference leads nowhere.
+                ("cover.svg", svg),
+                ("OEBPS/images/cover.svg", svg),
+                ("OEBPS/raster/cover.png", png),
+            ],
+        )?;
+
+        let source = BookSource::Memory(fs::read(&path)?.into());
+        for backend in ParserBackend::FALLBACK_CHAIN {
+            let book = backend.parse(&source)?;
+            assert_eq!(book.cover.as_deref(), Some(&png[..]), "{:?}", backend);
+        }
+
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,305 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Ok;
+use ignore::{
+    DirEntry, WalkBuilder,
+    overrides::{Override, OverrideBuilder},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::utility::{archive, library};
+
+/// Name of the ignore files honored in any folder of a library, with `.gitignore` syntax.
+pub const IGNORE_FILE_NAME: &str = ".spectecleignore";
+
+/// Folders never scanned, whatever the rules: the app's own data and the bookkeeping
+/// folders operating systems leave on removable drives.
+const SYSTEM_FOLDERS: [&str; 4] = [
+    library::DATA_DIR,
+    "$RECYCLE.BIN",
+    "System Volume Information",
+    "lost+found",
+];
+
+/// Which files under a library root are indexed as books.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ScanRules {
+    /// Globs, relative to the root, a book must match. Empty matches every book. Books inside
+    /// archives are matched by their virtual path, e.g. `bundle.zip!/book.epub`.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Globs, relative to the root, of files and folders to skip, inside archives as well.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// How many folders deep to look, `None` for no limit. Books at the root are at depth 1.
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Follow symbolic links to folders. Links looping back to one of their parents are skipped.
+    #[serde(default)]
+    pub follow_links: bool,
+    /// Also scan hidden files and folders (names starting with a `.`).
+    #[serde(default)]
+    pub include_hidden: bool,
+    /// Do not look for books inside `.zip` archives.
+    #[serde(default)]
+    pub skip_archives: bool,
+}
+
+impl ScanRules {
+    /// Checks that every glob of the rules is valid.
+    pub fn validate(&self, root: &Path) -> anyhow::Result<()> {
+        self.overrides(root, true)?;
+        return Ok(());
+    }
+
+    /// The globs of the rules, without the include ones unless `include`.
+    fn overrides(&self, root: &Path, include: bool) -> anyhow::Result<Override> {
+        let mut overrides = OverrideBuilder::new(root);
+        if include {
+            for glob in &self.include {
+                overrides.add(glob)?;
+            }
+        }
+        for glob in &self.exclude {
+            overrides.add(&format!("!{}", glob))?;
+        }
+        return Ok(overrides.build()?);
+    }
+}
+
+/// Lists the books under `root` following `rules`, with books inside archives as virtual
+/// paths (see [`archive::virtual_path`]). Entries that cannot be read, including symbolic
+/// link loops and broken archives, are skipped.
+pub fn find_books(root: &Path, rules: &ScanRules) -> anyhow::Result<Vec<PathBuf>> {
+    // Include globs name books, so rather than pruning the walk, where they would drop the
+    // archives, they are matched against each book found, along with the exclude ones.
+    let globs = rules.overrides(root, true)?;
+    let books = WalkBuilder::new(root)
+        .standard_filters(false)
+        .hidden(!rules.include_hidden)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .max_depth(rules.max_depth)
+        .follow_links(rules.follow_links)
+        .overrides(rules.overrides(root, false)?)
+        .filter_entry(|entry| !is_system_folder(entry))
+        .build()
+        .filter_map(|e| e.ok())
+        .map(DirEntry::into_path)
+        .filter(|path| path.is_file())
+        .flat_map(|path| {
+            if is_book(&path) {
+                return vec![path];
+            }
+            if !rules.skip_archives && archive::is_archive(&path) {
+                return archive::list_books(&path).unwrap_or_default();
+            }
+            return vec![];
+        })
+        .filter(|book| is_matched(&globs, root, book))
+        .collect();
+    return Ok(books);
+}
+
+/// Whether `book` and the folders it is in, inside its archive too, pass `globs` the way
+/// the walk applies them.
+fn is_matched(globs: &Override, root: &Path, book: &Path) -> bool {
+    let folders = book
+        .ancestors()
+        .skip(1)
+        .take_while(|folder| *folder != root && folder.starts_with(root));
+    for folder in folders {
+        if globs.matched(folder, true).is_ignore() {
+            return false;
+        }
+    }
+    return !globs.matched(book, false).is_ignore();
+}
+
+/// Matches `.epub` in any case, which also covers `.kepub.epub`.
+pub fn is_book(path: &Path) -> bool {
+    return path
+        .extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("epub"));
+}
+
+fn is_system_folder(entry: &DirEntry) -> bool {
+    let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+    let name = entry.file_name().to_string_lossy();
+    return is_dir && SYSTEM_FOLDERS.iter().any(|f| name.eq_ignore_ascii_case(f));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::utility::fixtures;
+
+    /// A library with books on disk and in an archive, returning its root.
+    fn library(name: &str) -> anyhow::Result<PathBuf> {
+        let root =
+            std::env::temp_dir().join(format!("spectecle-scan-{}-{}", name, std::process::id()));
+        if root.exists() {
+            fs::remove_dir_all(&root)?;
+        }
+        for book in [
+            "dune.epub",
+            "drafts/messiah.epub",
+            "sf/children.EPUB",
+            "sf/notes.txt",
+            ".hidden/heretics.epub",
+            ".spectecle/extracted/emperor.epub",
+        ] {
+            let path = root.join(book);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, "")?;
+        }
+        fixtures::write_zip(
+            &root.join("sf/bundle.zip"),
+            &[
+                ("chapterhouse.epub", b""),
+                ("drafts/hunters.epub", b""),
+                ("cover.jpg", b""),
+            ],
+        )?;
+        return Ok(root);
+    }
+
+    /// The books found, relative to the root with `/` separators, sorted.
+    fn found(root: &Path, rules: &ScanRules) -> anyhow::Result<Vec<String>> {
+        let mut books: Vec<String> = find_books(root, rules)?
+            .iter()
+            .filter_map(|book| book.strip_prefix(root).ok())
+            .map(|book| book.to_string_lossy().replace('\\', "/"))
+            .collect();
+        books.sort();
+        return Ok(books);
+    }
+
+    #[test]
+    fn finds_books_on_disk_and_in_archives() -> anyhow::Result<()> {
+        let root = library("all")?;
+        let all = [
+            "drafts/messiah.epub",
+            "dune.epub",
+            "sf/bundle.zip!/chapterhouse.epub",
+            "sf/bundle.zip!/drafts/hunters.epub",
+            "sf/children.EPUB",
+        ];
+        assert_eq!(found(&root, &ScanRules::default())?, all);
+
+        let rules = ScanRules {
+            include_hidden: true,
+            ..Default::default()
+        };
+        assert!(found(&root, &rules)?.contains(&".hidden/heretics.epub".to_string()));
+        assert!(
+            !found(&root, &rules)?
+                .iter()
+                .any(|b| b.starts_with(".spectecle"))
+        );
+
+        let rules = ScanRules {
+            skip_archives: true,
+            max_depth: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(found(&root, &rules)?, ["dune.epub"]);
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn includes_books_in_archives() -> anyhow::Result<()> {
+        let root = library("include")?;
+        let rules = ScanRules {
+            include: vec!["**/*.epub".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            found(&root, &rules)?,
+            [
+                "drafts/messiah.epub",
+                "dune.epub",
+                "sf/bundle.zip!/chapterhouse.epub",
+                "sf/bundle.zip!/drafts/hunters.epub",
+            ]
+        );
+
+        let rules = ScanRules {
+            include: vec!["sf/**".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            found(&root, &rules)?,
+            [
+                "sf/bundle.zip!/chapterhouse.epub",
+                "sf/bundle.zip!/drafts/hunters.epub",
+                "sf/children.EPUB",
+            ]
+        );
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn excludes_books_in_archives() -> anyhow::Result<()> {
+        let root = library("exclude")?;
+        let rules = ScanRules {
+            exclude: vec!["drafts".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            found(&root, &rules)?,
+            [
+                "dune.epub",
+                "sf/bundle.zip!/chapterhouse.epub",
+                "sf/children.EPUB",
+            ]
+        );
+
+        let rules = ScanRules {
+            include: vec!["*.epub".to_string()],
+            exclude: vec!["*.zip".to_string(), "dune.epub".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(found(&root, &rules)?, ["drafts/messiah.epub"]);
+
+        let rules = ScanRules {
+            include: vec!["[".to_string()],
+            ..Default::default()
+        };
+        assert!(rules.validate(&root).is_err());
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn honors_ignore_files() -> anyhow::Result<()> {
+        let root = library("ignore")?;
+        fs::write(root.join(IGNORE_FILE_NAME), "drafts/\n*.zip\n")?;
+        fs::write(root.join("sf").join(IGNORE_FILE_NAME), "children.*\n")?;
+        assert_eq!(found(&root, &ScanRules::default())?, ["dune.epub"]);
+
+        // Ignore files are read in every folder and can be overridden further down.
+        fs::write(root.join("sf").join(IGNORE_FILE_NAME), "!*.zip\n")?;
+        assert_eq!(
+            found(&root, &ScanRules::default())?,
+            [
+                "dune.epub",
+                "sf/bundle.zip!/chapterhouse.epub",
+                "sf/bundle.zip!/drafts/hunters.epub",
+                "sf/children.EPUB",
+            ]
+        );
+
+        fs::remove_dir_all(&root)?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,503 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+use anyhow::{Ok, anyhow};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use percent_encoding::percent_decode_str;
+use tiny_http::{Header, Method, Request, Response, ResponseBox, Server, StatusCode};
+
+use crate::utility::{
+    archive,
+    cache::Cache,
+    opds::{self, Feed, Selection},
+    overlay::MetadataOverlay,
+    state::{CatalogSource, State},
+};
+
+/// Threads answering requests. Downloads are streamed, so a few are enough on a home network.
+const WORKER_THREADS: usize = 4;
+
+/// Username and password clients must send with HTTP Basic authentication.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Credentials from the optional username and password of a request to start a server:
+    /// none if both are missing or empty, an error if only one of them is set, so that the
+    /// catalog is never served openly by mistake.
+    pub fn from_parts(
+        username: Option<String>,
+        password: Option<String>,
+    ) -> anyhow::Result<Option<Self>> {
+        let username = username.filter(|username| !username.is_empty());
+        let password = password.filter(|password| !password.is_empty());
+        return match (username, password) {
+            (Some(username), Some(password)) => Ok(Some(Self { username, password })),
+            (None, None) => Ok(None),
+            _ => Err(anyhow!("Set both a username and a password, or neither.")),
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Port to listen on, `0` picks a free one.
+    pub port: u16,
+    pub credentials: Option<Credentials>,
+    /// Publish every registered library instead of only the open one.
+    pub all_libraries: bool,
+}
+
+/// An OPDS catalog of the libraries served over HTTP on the local network:
+///
+/// - `/opds/...` serves OPDS 1.2 (Atom) feeds and `/opds2/...` the same feeds as OPDS 2.0,
+///   browsable by author, series, recently added and search.
+/// - `/covers/<library>/<book>` serves covers from the cache and `/books/<library>/<book>`
+///   the EPUB files, streamed from disk or read out of their archive.
+///
+/// The server runs on its own threads until it is dropped.
+pub struct CatalogServer {
+    server: Arc<Server>,
+    workers: Vec<JoinHandle<()>>,
+    port: u16,
+}
+
+impl CatalogServer {
+    pub fn start(config: ServerConfig) -> anyhow::Result<Self> {
+        let server = Server::http(("0.0.0.0", config.port))
+            .map_err(|e| anyhow!("Failed to listen on port {}: {}", config.port, e))?;
+        let port = server
+            .server_addr()
+            .to_ip()
+            .map(|addr| addr.port())
+            .unwrap_or(config.port);
+        let server = Arc::new(server);
+        let config = Arc::new(config);
+        let caches = Arc::new(LoadedCaches::default());
+        let workers = (0..WORKER_THREADS)
+            .map(|_| {
+                let server = server.clone();
+                let config = config.clone();
+                let caches = caches.clone();
+                thread::spawn(move || {
+                    for request in server.incoming_requests() {
+                        // A request that panics is answered with a 500 when it is dropped, and
+                        // must not take the worker down with it.
+                        let url = request.url().to_string();
+                        let handled = panic::catch_unwind(AssertUnwindSafe(|| {
+                            handle(request, &config, &caches);
+                        }));
+                        if handled.is_err() {
+                            println!("Panicked answering {}", url);
+                        }
+                    }
+                })
+            })
+            .collect();
+        return Ok(Self {
+            server,
+            workers,
+            port,
+        });
+    }
+
+    pub fn port(&self) -> u16 {
+        return self.port;
+    }
+}
+
+impl Drop for CatalogServer {
+    fn drop(&mut self) {
+        // Each unblock wakes a single worker.
+        for _ in &self.workers {
+            self.server.unblock();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Caches of the published libraries other than the open one, read once and kept until the
+/// cache is saved again or the overlays of the library change.
+#[derive(Default)]
+struct LoadedCaches {
+    caches: Mutex<HashMap<String, LoadedCache>>,
+}
+
+struct LoadedCache {
+    cache_dir: PathBuf,
+    saved_at: Option<SystemTime>,
+    overlays: BTreeMap<String, MetadataOverlay>,
+    cache: Arc<Cache>,
+}
+
+impl LoadedCaches {
+    /// Runs `f` with the root and cache of the library with the given id. The state is only
+    /// held to look the library up, or while `f` reads the cache of the open library, so
+    /// loading another cache never blocks it.
+    fn with_cache<T>(&self, id: &str, f: impl FnOnce(&Path, &Cache) -> T) -> anyhow::Result<T> {
+        let (root, cache_dir, overlays) = {
+            let state = State::get()?.blocking_read();
+            match state.catalog_source(id)? {
+                CatalogSource::Open(root, cache) => return Ok(f(&root, cache)),
+                CatalogSource::Closed {
+                    root,
+                    cache_dir,
+                    overlays,
+                } => (root, cache_dir, overlays),
+            }
+        };
+        let saved_at = Cache::saved_at(&cache_dir);
+        let loaded = self
+            .lock()?
+            .get(id)
+            .filter(|loaded| {
+                loaded.cache_dir == cache_dir
+                    && loaded.saved_at == saved_at
+                    && loaded.overlays == overlays
+            })
+            .map(|loaded| loaded.cache.clone());
+        let cache = match loaded {
+            Some(cache) => cache,
+            None => {
+                let mut cache = Cache::open_read_only(cache_dir.clone())?;
+                cache.set_overlays(overlays.clone());
+                let cache = Arc::new(cache);
+                self.lock()?.insert(
+                    id.to_string(),
+                    LoadedCache {
+                        cache_dir,
+                        saved_at,
+                        overlays,
+                        cache: cache.clone(),
+                    },
+                );
+                cache
+            }
+        };
+        return Ok(f(&root, &cache));
+    }
+
+    fn lock(&self) -> anyhow::Result<MutexGuard<'_, HashMap<String, LoadedCache>>> {
+        return self
+            .caches
+            .lock()
+            .map_err(|_| anyhow!("The loaded caches are poisoned"));
+    }
+}
+
+fn handle(request: Request, config: &ServerConfig, caches: &LoadedCaches) {
+    let response = if !is_authorized(&request, config) {
+        header(
+            "WWW-Authenticate",
+            "Basic realm=\"Spectecle\", charset=\"UTF-8\"",
+        )
+        .map(|h| text(401, "Authentication required").with_header(h))
+    } else if *request.method() != Method::Get && *request.method() != Method::Head {
+        Ok(text(405, "Method not allowed"))
+    } else {
+        route(request.url(), config, caches)
+    };
+    // The error names paths on this device, which clients on the network have no use for.
+    let response = response.unwrap_or_else(|e| {
+        println!("Failed to answer {}: {:#}", request.url(), e);
+        text(500, "Internal server error")
+    });
+    let _ = request.respond(response);
+}
+
+fn is_authorized(request: &Request, config: &ServerConfig) -> bool {
+    let Some(credentials) = &config.credentials else {
+        return true;
+    };
+    let expected = format!("{}:{}", credentials.username, credentials.password);
+    return request
+        .headers()
+        .iter()
+        .filter(|h| h.field.equiv("Authorization"))
+        .filter_map(|h| h.value.as_str().strip_prefix("Basic "))
+        .filter_map(|encoded| STANDARD.decode(encoded.trim()).ok())
+        .any(|decoded| decoded == expected.as_bytes());
+}
+
+fn route(url: &str, config: &ServerConfig, caches: &LoadedCaches) -> anyhow::Result<ResponseBox> {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let segments: Vec<String> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| percent_decode_str(s).decode_utf8_lossy().into_owned())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let params = parse_query(query);
+    let libraries = visible_libraries(&State::get()?.blocking_read(), config);
+
+    let (json, rest) = match segments.as_slice() {
+        ["covers", id, key] if libraries.contains_key(*id) => {
+            let cover = caches.with_cache(id, |_, cache| {
+                cache.item(key).and_then(|item| cache.cover_file(item))
+            })?;
+            return Ok(match cover.map(File::open) {
+                Some(anyhow::Result::Ok(file)) => Response::from_file(file)
+                    .with_header(header("Content-Type", "image/jpeg")?)
+                    .boxed(),
+                _ => text(404, "No cover"),
+            });
+        }
+        ["books", id, key] if libraries.contains_key(*id) => {
+            let book = caches.with_cache(id, |root, cache| {
+                cache.item(key).map(|item| root.join(item.relative_path()))
+            })?;
+            return match book {
+                Some(book) => download(&book),
+                None => Ok(text(404, "No such book")),
+            };
+        }
+        [] => (false, &[][..]),
+        ["opds", rest @ ..] => (false, rest),
+        ["opds2", rest @ ..] => (true, rest),
+        _ => return Ok(text(404, "Not found")),
+    };
+
+    let page = params
+        .get("page")
+        .and_then(|p| p.parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let feed: Feed = match rest {
+        [] if libraries.len() == 1 => {
+            let (id, (name, _)) = libraries
+                .iter()
+                .next()
+                .ok_or_else(|| anyhow!("No library"))?;
+            opds::library_feed(id, name)
+        }
+        [] => {
+            let mut entries: Vec<(String, String)> = libraries
+                .iter()
+                .map(|(id, (name, _))| (id.clone(), name.clone()))
+                .collect();
+            entries.sort_by(|a, b| a.1.cmp(&b.1));
+            opds::root_feed(&entries)
+        }
+        ["libraries", id, rest @ ..] if libraries.contains_key(*id) => {
+            let id = *id;
+            let (name, _) = &libraries[id];
+            match rest {
+                [] => opds::library_feed(id, name),
+                ["opensearch.xml"] => {
+                    let search = format!("libraries/{}", id);
+                    return Ok(Response::from_string(opds::opensearch(name, &search))
+                        .with_header(header("Content-Type", opds::OPENSEARCH)?)
+                        .boxed());
+                }
+                ["authors"] => caches.with_cache(id, |_, cache| {
+                    return opds::grouped_feed(id, cache, false);
+                })?,
+                ["series"] => caches.with_cache(id, |_, cache| {
+                    return opds::grouped_feed(id, cache, true);
+                })?,
+                other => {
+                    let selection = match other {
+                        ["all"] => Selection::All,
+                        ["recent"] => Selection::Recent,
+                        ["authors", author] => Selection::Author(author.to_string()),
+                        ["series", series] => Selection::Series(series.to_string()),
+                        // OPDS 1.2 clients fill `q` from the OpenSearch template, 2.0 ones `query`.
+                        ["search"] => Selection::Search(
+                            params
+                                .get("q")
+                                .or_else(|| params.get("query"))
+                                .cloned()
+                                .unwrap_or_default(),
+                        ),
+                        _ => return Ok(text(404, "Not found")),
+                    };
+                    caches.with_cache(id, |_, cache| {
+                        return opds::books_feed(id, cache, &selection, page);
+                    })?
+                }
+            }
+        }
+        _ => return Ok(text(404, "Not found")),
+    };
+    return feed_response(feed, json);
+}
+
+/// Libraries published by the server, by id, with their name and root.
+fn visible_libraries(state: &State, config: &ServerConfig) -> HashMap<String, (String, PathBuf)> {
+    let count = if config.all_libraries { usize::MAX } else { 1 };
+    return state
+        .get_library_ids()
+        .into_iter()
+        .take(count)
+        .map(|(id, root)| {
+            let name = root
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| root.to_string_lossy().into_owned());
+            (id, (name, root))
+        })
+        .collect();
+}
+
+fn download(book: &Path) -> anyhow::Result<ResponseBox> {
+    let file_name: String = book
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .filter(|c| *c != '"')
+        .collect();
+    let response = match archive::split(book) {
+        Some(_) => Response::from_data(archive::read_book(book)?).boxed(),
+        None => Response::from_file(File::open(book)?).boxed(),
+    };
+    return Ok(response
+        .with_header(header("Content-Type", "application/epub+zip")?)
+        .with_header(header(
+            "Content-Disposition",
+            &format!("attachment; filename=\"{}\"", file_name),
+        )?));
+}
+
+fn feed_response(feed: Feed, json: bool) -> anyhow::Result<ResponseBox> {
+    let (body, kind) = match json {
+        true => (feed.to_json(), opds::OPDS2),
+        false => (feed.to_atom(), "application/atom+xml;profile=opds-catalog"),
+    };
+    return Ok(Response::from_string(body)
+        .with_header(header("Content-Type", kind)?)
+        .boxed());
+}
+
+fn text(status: u16, body: &str) -> ResponseBox {
+    return Response::from_string(body)
+        .with_status_code(StatusCode(status))
+        .boxed();
+}
+
+pub fn header(field: &str, value: &str) -> anyhow::Result<Header> {
+    return Header::from_bytes(field.as_bytes(), value.as_bytes())
+        .map_err(|_| anyhow!("Invalid header {}: {}", field, value));
+}
+
+/// Parses `a=1&b=two+words` into a map, decoding `+` and percent escapes.
+fn parse_query(query: &str) -> HashMap<String, String> {
+    return query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| {
+            let decode = |s: &str| {
+                percent_decode_str(&s.replace('+', " "))
+                    .decode_utf8_lossy()
+                    .into_owned()
+            };
+            (decode(key), decode(value))
+        })
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use ureq::Agent;
+
+    #[test]
+    fn serves_any_page_and_refuses_bad_credentials() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("spectecle-server-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        let root = dir.join("Books");
+        let support_dir = dir.join("support");
+        fs::create_dir_all(&root)?;
+        fs::create_dir_all(&support_dir)?;
+        State::initialize(support_dir.to_string_lossy().into_owned())?;
+        let id = {
+            let mut state = State::get()?.blocking_write();
+            state.import_lib(root.clone())?;
+            state.refresh_cache(false)?;
+            state
+                .get_library_ids()
+                .into_iter()
+                .next()
+                .map(|(id, _)| id)
+                .ok_or_else(|| anyhow!("No library"))?
+        };
+
+        let config = ServerConfig {
+            port: 0,
+            credentials: Some(Credentials {
+                username: "reader".to_string(),
+                password: "secret".to_string(),
+            }),
+            all_libraries: false,
+        };
+        let caches = LoadedCaches::default();
+        for page in [
+            "0",
+            "1",
+            "18446744073709551615",
+            "99999999999999999999999",
+            "-1",
+            "x",
+        ] {
+            let url = format!("/opds/libraries/{}/all?page={}", id, page);
+            let status = route(&url, &config, &caches)?.status_code();
+            assert_eq!(status, StatusCode(200), "page {}", page);
+        }
+
+        let server = CatalogServer::start(config)?;
+        let agent: Agent = Agent::config_builder()
+            .http_status_as_error(false)
+            .build()
+            .into();
+        let status = |authorization: Option<&str>, page: &str| -> anyhow::Result<u16> {
+            let url = format!(
+                "http://127.0.0.1:{}/opds/libraries/{}/all?page={}",
+                server.port(),
+                id,
+                page
+            );
+            let mut request = agent.get(&url);
+            if let Some(authorization) = authorization {
+                request = request.header("Authorization", authorization);
+            }
+            return Ok(request.call()?.status().as_u16());
+        };
+        let authorized = format!("Basic {}", STANDARD.encode("reader:secret"));
+        assert_eq!(status(None, "1")?, 401);
+        let wrong = format!("Basic {}", STANDARD.encode("reader:guess"));
+        assert_eq!(status(Some(&wrong), "1")?, 401);
+        assert_eq!(status(Some("Basic not-base64!"), "1")?, 401);
+        assert_eq!(status(Some("Bearer secret"), "1")?, 401);
+        // More requests than workers: none of them may take a worker down.
+        for _ in 0..=WORKER_THREADS {
+            assert_eq!(status(Some(&authorized), "0")?, 200);
+            assert_eq!(status(Some(&authorized), "18446744073709551615")?, 200);
+        }
+        drop(server);
+        fs::remove_dir_all(&dir)?;
+        return Ok(());
+    }
+}
@@ -1,12 +1,34 @@
-use anyhow::Ok;
-use std::path::PathBuf;
+use anyhow::{Ok, anyhow};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::OnceLock;
 use tokio::sync::RwLock;
 
-use crate::signals::library_signals::BookData;
-use crate::utility::cache::Cache;
-use crate::utility::library::Library;
+use crate::signals::collection_signals::CollectionPage;
+use crate::signals::library_signals::{LibraryDelta, LibraryPage, LibrarySummary};
+use crate::utility::archive;
+use crate::utility::backup::{self, BackupManifest, RestoreMode};
+use crate::utility::cache::{Cache, CacheChanges, CacheItem, ChangedSidecar};
+use crate::utility::calibre;
+use crate::utility::collection::{Collection, CollectionContent};
+use crate::utility::epub_edit::{self, MetadataEdit};
+use crate::utility::export::{self, ExportFormat};
+use crate::utility::koreader;
+use crate::utility::kosync::KosyncAccount;
+use crate::utility::library::{self, CacheLocation, Library};
+use crate::utility::metadata::BookMetadata;
+use crate::utility::opds;
+use crate::utility::opf::OpfMetadata;
+use crate::utility::organize::{
+    self, BookMove, OrganizePlan, Platform, SkippedBook, Template, UndoLog,
+};
+use crate::utility::overlay::{self, MetadataOverlay};
+use crate::utility::scan::ScanRules;
+use crate::utility::sync::{self, SyncLog};
+use crate::utility::user_data::{
+    Annotation, BookFilter, BookUserData, Change, Progress, ReadingStatus,
+};
 
 pub static STATE: OnceLock<RwLock<State>> = OnceLock::new();
 
@@ -15,6 +37,27 @@ pub struct State {
     support_dir: PathBuf,
     library: Library,
     cache: Option<Cache>,
+    /// `true` while the root of the open library is unreachable, e.g. an unmounted SD card.
+    /// The last known catalog is served read-only until it comes back.
+    offline: bool,
+    /// Names this device in the sync logs.
+    device_id: String,
+    /// Sync log of the open library, opened on first use.
+    sync: Option<SyncLog>,
+    /// Account on a kosync server progress is synced with, for KOReader devices.
+    kosync: Option<KosyncAccount>,
+}
+
+/// Where the catalog server reads a library from, see [`State::catalog_source`].
+pub enum CatalogSource<'a> {
+    /// The open library, with its root and the cache in memory.
+    Open(PathBuf, &'a Cache),
+    /// Another library, whose cache is read from `cache_dir` with `overlays` on top.
+    Closed {
+        root: PathBuf,
+        cache_dir: PathBuf,
+        overlays: BTreeMap<String, MetadataOverlay>,
+    },
 }
 
 impl State {
@@ -23,21 +66,44 @@ impl State {
     pub fn initialize(support_dir: String) -> anyhow::Result<()> {
         let support_dir = PathBuf::from_str(&support_dir)?;
         let library = Library::open(&support_dir)?;
+        let mut offline = false;
         let cache = match library.get_open_lib() {
-            Some(open) => Some(Cache::open(open)?),
+            Some(open) => {
+                let cache_dir = library.get_settings(&open).cache_dir(&open, &support_dir);
+                let cache = Cache::open_read_only(cache_dir.clone())?;
+                offline = !cache.is_reachable(&open, library.get_settings(&open).root_marker());
+                match offline {
+                    true => Some(cache),
+                    false => Some(Cache::open(cache_dir)?),
+                }
+            }
             None => None,
         };
-        let state = RwLock::new(Self {
+        let device_id = sync::device_id(&support_dir)?;
+        let kosync = KosyncAccount::read(&support_dir)?;
+        let mut state = Self {
             support_dir,
             library,
             cache,
-        });
+            offline,
+            device_id,
+            sync: None,
+            kosync,
+        };
+        state.apply_overlays();
         STATE
-            .set(state)
-            .expect("Failed to set LIBRARY during initialization.");
+            .set(RwLock::new(state))
+            .map_err(|_| anyhow!("Failed to set STATE during initialization."))?;
         Ok(())
     }
 
+    /// Returns the global state, failing if it has not been initialized yet.
+    pub fn get() -> anyhow::Result<&'static RwLock<State>> {
+        return STATE
+            .get()
+            .ok_or_else(|| anyhow!("STATE accessed before initialization."));
+    }
+
     pub fn has_lib(&self) -> bool {
         return self.library.has_lib();
     }
@@ -45,34 +111,1031 @@ impl State {
     /// Adds the provided library path as one one of the library options
     /// Does NOT alter the cache.
     pub fn import_lib(&mut self, lib_path: PathBuf) -> anyhow::Result<()> {
-        self.library.add_lib_and_switch(lib_path);
+        let is_new = !self.library.has_lib_path(&lib_path);
+        self.library.add_lib_and_switch(lib_path.clone());
+        let settings = self.library.settings_mut(lib_path.clone());
+        // Read-only or shared folders keep their cache in the app support directory instead.
+        if is_new && !Cache::is_writable(&settings.cache_dir(&lib_path, &self.support_dir)) {
+            settings.cache_location = CacheLocation::AppSupport;
+        }
+        let cache_dir = settings.cache_dir(&lib_path, &self.support_dir);
+        if let anyhow::Result::Ok(marker) = library::mark_root(&lib_path, &settings.id) {
+            settings.set_root_marker(marker);
+        }
         self.library.write(&self.support_dir)?;
-        self.cache = Some(Cache::open(self.library.get_open_lib().unwrap())?);
+        self.cache = Some(Cache::open(cache_dir)?);
+        self.offline = false;
+        self.apply_overlays();
         return anyhow::Ok(());
     }
 
-    pub fn refresh_cache(&mut self, rebuild: bool) -> anyhow::Result<()> {
-        let lib = self.library.get_open_lib().unwrap();
+    /// Imports a Calibre library: it is added like any other library, then its cache is filled
+    /// from what Calibre knows so the books are not parsed again. Calibre manages its folder
+    /// itself, so new Calibre libraries keep their cache in the app support directory. Calibre
+    /// ratings become the rating of books the reader has not rated yet.
+    pub fn import_calibre(&mut self, lib_path: PathBuf) -> anyhow::Result<()> {
+        let books = calibre::read_library(&lib_path)?;
+        let stars: HashMap<String, u8> = books
+            .iter()
+            .filter_map(|book| {
+                let path = book.relative_path.to_string_lossy().into_owned();
+                return book.stars().map(|stars| (path, stars));
+            })
+            .collect();
+        if !self.library.has_lib_path(&lib_path) {
+            self.library.settings_mut(lib_path.clone()).cache_location = CacheLocation::AppSupport;
+        }
+        self.import_lib(lib_path.clone())?;
+        let concurrency = self
+            .library
+            .get_settings(&lib_path)
+            .index_concurrency
+            .unwrap_or(0);
+        let cache = self
+            .cache
+            .as_mut()
+            .ok_or_else(|| anyhow!("No cache is open."))?;
+        cache.import_calibre(&lib_path, books, concurrency)?;
+        let rated: Vec<(String, String, u8)> = cache
+            .items()
+            .filter(|item| !item.identity().is_empty())
+            .filter_map(|item| {
+                let rating = *stars.get(item.relative_path())?;
+                return Some((
+                    item.identity().to_string(),
+                    item.relative_path().to_string(),
+                    rating,
+                ));
+            })
+            .collect();
+        let sync_log = self.sync_log()?;
+        for (book, path, rating) in rated {
+            if sync_log
+                .book(&book, &path)
+                .is_none_or(|data| data.rating.is_none())
+            {
+                sync_log.record(
+                    &book,
+                    &path,
+                    Change::Rating {
+                        rating: Some(rating),
+                    },
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Sets which files of the open library are indexed. Takes effect on the next refresh.
+    pub fn set_scan_rules(&mut self, rules: ScanRules) -> anyhow::Result<()> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        rules.validate(&lib)?;
+        self.library.settings_mut(lib).scan = rules;
+        self.library.write(&self.support_dir)?;
+        return Ok(());
+    }
+
+    /// Moves a library to a new root, e.g. after its folder was renamed or copied to another
+    /// drive. The new root is checked against a sample of the cached books, then the cache is
+    /// moved along if it lives inside the library, once everything else succeeded. Other per
+    /// library data is keyed by the library id and follows as is. Returns `true` if the
+    /// relocated library is the open one.
+    pub fn relocate_lib(&mut self, old: PathBuf, new: PathBuf) -> anyhow::Result<bool> {
+        if !self.library.has_lib_path(&old) {
+            return Err(anyhow!("{} is not a library", old.display()));
+        }
+        if self.library.has_lib_path(&new) {
+            return Err(anyhow!("{} is already a library", new.display()));
+        }
+        if !new.is_dir() {
+            return Err(anyhow!("{} is not a folder", new.display()));
+        }
+        let settings = self.library.get_settings(&old);
+        let old_dir = settings.cache_dir(&old, &self.support_dir);
+        let new_dir = settings.cache_dir(&new, &self.support_dir);
+        // A copied folder brings its own cache along, otherwise the one at the old root moves.
+        let has_own_cache = new_dir.join("cache.json").exists();
+        let mut cache = match has_own_cache {
+            true => Cache::open_read_only(new_dir.clone())?,
+            false => Cache::open_read_only(old_dir.clone())?,
+        };
+        cache.verify_root(&new)?;
+
+        let is_open = self.library.get_open_lib().as_deref() == Some(old.as_path());
+        self.library.relocate(&old, new.clone())?;
+        if let Err(e) = self.library.write(&self.support_dir) {
+            self.library.relocate(&new, old)?;
+            return Err(e);
+        }
+        if !has_own_cache
+            && old_dir.exists()
+            && let Err(e) = cache.migrate(new_dir.clone())
+        {
+            self.library.relocate(&new, old)?;
+            self.library.write(&self.support_dir)?;
+            return Err(e);
+        }
+        if is_open {
+            self.cache = Some(Cache::open(new_dir)?);
+            self.offline = false;
+            self.apply_overlays();
+        }
+        return Ok(is_open);
+    }
+
+    /// Moves the cache of the open library to `location`.
+    pub fn set_cache_location(&mut self, location: CacheLocation) -> anyhow::Result<()> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        if self.offline {
+            return Err(anyhow!("Cannot move the cache of an offline library."));
+        }
+        let settings = self.library.settings_mut(lib.clone());
+        let mut moved = settings.clone();
+        moved.cache_location = location.clone();
+        let new_dir = moved.cache_dir(&lib, &self.support_dir);
+        if !Cache::is_writable(&new_dir) {
+            return Err(anyhow!("Cannot write a cache to {}", new_dir.display()));
+        }
         match &mut self.cache {
+            Some(cache) => cache.migrate(new_dir)?,
+            None => self.cache = Some(Cache::open(new_dir)?),
+        }
+        settings.cache_location = location;
+        self.library.write(&self.support_dir)?;
+        self.apply_overlays();
+        return Ok(());
+    }
+
+    /// Backs up every library to `destination`, see [`backup::create_backup`].
+    pub fn create_backup(
+        &self,
+        destination: &Path,
+        include_cache: bool,
+    ) -> anyhow::Result<BackupManifest> {
+        return backup::create_backup(&self.support_dir, &self.library, destination, include_cache);
+    }
+
+    /// Restores a backup, see [`backup::restore_backup`], then reopens the cache of the open
+    /// library. Returns the number of libraries restored.
+    pub fn restore_backup(
+        &mut self,
+        path: &Path,
+        remaps: &HashMap<PathBuf, PathBuf>,
+        mode: RestoreMode,
+    ) -> anyhow::Result<usize> {
+        let result =
+            backup::restore_backup(&self.support_dir, &mut self.library, path, remaps, mode);
+        if result.is_err() {
+            // Drop whatever was registered before the restore failed.
+            self.library = Library::open(&self.support_dir)?;
+        }
+        let restored = result?;
+        // The sync logs may have changed under the one that is open.
+        self.sync = None;
+        self.cache = match self.library.get_open_lib() {
+            Some(open) => {
+                let cache_dir = self
+                    .library
+                    .get_settings(&open)
+                    .cache_dir(&open, &self.support_dir);
+                Some(Cache::open(cache_dir)?)
+            }
+            None => None,
+        };
+        self.offline = false;
+        self.apply_overlays();
+        return Ok(restored.len());
+    }
+
+    /// Refreshes or rebuilds the cache of the open library, importing the KOReader sidecars
+    /// the scan found changed. If the library root is unreachable the library is marked
+    /// offline and the cache is left untouched. Returns the changes and the keys of the books
+    /// whose reading data changed.
+    pub fn refresh_cache(&mut self, rebuild: bool) -> anyhow::Result<(CacheChanges, Vec<String>)> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let mut settings = self.library.get_settings(&lib);
+        let reachable = match &self.cache {
+            Some(cache) => cache.is_reachable(&lib, settings.root_marker()),
+            None => return Err(anyhow!("No cache is open.")),
+        };
+        if !reachable {
+            self.offline = true;
+            return Ok((CacheChanges::default(), vec![]));
+        }
+        // Read-only roots cannot be marked, they keep being recognised by their books.
+        if let anyhow::Result::Ok(marker) = library::mark_root(&lib, &settings.id)
+            && marker != settings.root_marker()
+        {
+            // Another device sharing the folder marked it first.
+            settings.set_root_marker(marker.clone());
+            self.library
+                .settings_mut(lib.clone())
+                .set_root_marker(marker);
+            self.library.write(&self.support_dir)?;
+        }
+        if self.offline {
+            // The cache was opened read-only and may live on the device that just came back.
+            self.cache = Some(Cache::open(settings.cache_dir(&lib, &self.support_dir))?);
+            self.offline = false;
+            self.apply_overlays();
+        }
+        let concurrency = settings.index_concurrency.unwrap_or(0);
+        let mut changes = match &mut self.cache {
             Some(cache) => {
                 if rebuild {
-                    cache.rebuild(lib)
+                    cache.rebuild(lib, settings.root_marker(), concurrency, &settings.scan)?
                 } else {
-                    cache.refresh(lib)
+                    cache.refresh(lib, settings.root_marker(), concurrency, &settings.scan)?
+                }
+            }
+            None => return Err(anyhow!("No cache is open.")),
+        };
+        // The cache is up to date already, sidecars that failed are tried again next time.
+        let synced = match self.import_sidecars(std::mem::take(&mut changes.sidecars)) {
+            anyhow::Result::Ok(synced) => synced,
+            Err(e) => {
+                println!("Failed to import KOReader sidecars: {:#}", e);
+                vec![]
+            }
+        };
+        return Ok((changes, synced));
+    }
+
+    /// Adds a single book, e.g. one just downloaded, to the cache of the library at
+    /// `lib_path`. Returns the changes if that library is the open one.
+    pub fn index_book(
+        &mut self,
+        lib_path: &Path,
+        book: PathBuf,
+    ) -> anyhow::Result<Option<CacheChanges>> {
+        if !self.library.has_lib_path(lib_path) {
+            return Err(anyhow!("{} is not a library", lib_path.display()));
+        }
+        if self.library.get_open_lib().as_deref() == Some(lib_path) {
+            if self.offline {
+                return Err(anyhow!("Cannot index a book of an offline library."));
+            }
+            let cache = self
+                .cache
+                .as_mut()
+                .ok_or_else(|| anyhow!("No cache is open."))?;
+            return Ok(Some(cache.index_book(lib_path, book)?));
+        }
+        let cache_dir = self
+            .library
+            .get_settings(lib_path)
+            .cache_dir(lib_path, &self.support_dir);
+        Cache::open(cache_dir)?.index_book(lib_path, book)?;
+        return Ok(None);
+    }
+
+    /// Metadata stored in the EPUB of a book of the open library.
+    pub fn read_book_metadata(&self, key: &str) -> anyhow::Result<OpfMetadata> {
+        return epub_edit::read_metadata(&self.book_file(key)?);
+    }
+
+    /// Writes metadata into the EPUB of a book of the open library, see
+    /// [`epub_edit::write_metadata`], then indexes the book again. The original file is kept
+    /// in the library data folder the first time a book is edited.
+    pub fn edit_book_metadata(
+        &mut self,
+        key: &str,
+        mut edit: MetadataEdit,
+    ) -> anyhow::Result<CacheChanges> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        if self.offline {
+            return Err(anyhow!("Cannot edit a book of an offline library."));
+        }
+        let cache = self
+            .cache
+            .as_mut()
+            .ok_or_else(|| anyhow!("No cache is open."))?;
+        let item = cache
+            .item(key)
+            .ok_or_else(|| anyhow!("No book with key {}", key))?;
+        edit.metadata.rating = item.metadata().rating;
+        let rel_path = item.relative_path().to_string();
+        let identity = item.identity().to_string();
+        let file = lib.join(&rel_path);
+        epub_edit::write_metadata(&file, &edit, &epub_edit::original_path(&lib, &rel_path))?;
+        cache.set_metadata(key, edit.metadata)?;
+        let changes = cache.index_book(&lib, file)?;
+        // Books without a unique identifier are known by their content, which just changed.
+        let edited = cache.item(key).map(|item| item.identity().to_string());
+        if let Some(edited) = edited
+            && !identity.is_empty()
+            && edited != identity
+        {
+            self.sync_log()?.copy_book(&identity, &edited, &rel_path)?;
+            let overlays = &mut self.library.settings_mut(lib).overlays;
+            if let Some(overlay) = overlays.remove(&identity) {
+                overlays.insert(edited, overlay);
+                self.library.write(&self.support_dir)?;
+                self.apply_overlays();
+            }
+        }
+        return Ok(changes);
+    }
+
+    /// Overlay of a book of the open library, with its title and metadata as read from the
+    /// book.
+    pub fn get_metadata_overlay(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<(MetadataOverlay, String, BookMetadata)> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let item = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.item(key))
+            .ok_or_else(|| anyhow!("No book with key {}", key))?;
+        let overlay = self
+            .library
+            .settings(&lib)
+            .and_then(|settings| settings.overlays.get(item.identity()))
+            .cloned()
+            .unwrap_or_default();
+        let (title, metadata) = item.extracted();
+        return Ok((overlay, title.to_string(), metadata.clone()));
+    }
+
+    /// Replaces the overlay of a book of the open library, removing it if `overlay` is
+    /// empty. The book file is left alone, so this works for offline libraries too.
+    pub fn set_metadata_overlay(
+        &mut self,
+        key: &str,
+        overlay: MetadataOverlay,
+    ) -> anyhow::Result<CacheChanges> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let item = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.item(key))
+            .ok_or_else(|| anyhow!("No book with key {}", key))?;
+        if item.identity().is_empty() {
+            return Err(anyhow!(
+                "{} is not identified yet, refresh the library once it is online.",
+                item.relative_path()
+            ));
+        }
+        let book = item.identity().to_string();
+        let overlays = &mut self.library.settings_mut(lib).overlays;
+        match overlay.is_empty() {
+            true => overlays.remove(&book),
+            false => overlays.insert(book, overlay.clone()),
+        };
+        self.library.write(&self.support_dir)?;
+        let cache = self
+            .cache
+            .as_mut()
+            .ok_or_else(|| anyhow!("No cache is open."))?;
+        return cache.set_overlay(key, overlay);
+    }
+
+    /// Writes the corrected metadata of the books of the open library that have an overlay
+    /// as OPF sidecars under `destination`, see [`overlay::export_sidecars`].
+    pub fn export_overlay_sidecars(&self, destination: &Path) -> anyhow::Result<usize> {
+        let cache = self
+            .cache
+            .as_ref()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        return overlay::export_sidecars(cache, destination);
+    }
+
+    /// Where `template` would put the books of the open library, see [`organize::plan`].
+    pub fn preview_organize(&self, template: &str) -> anyhow::Result<OrganizePlan> {
+        let template = Template::parse(template)?;
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let cache = self
+            .cache
+            .as_ref()
+            .ok_or_else(|| anyhow!("No cache is open."))?;
+        return Ok(organize::plan(&lib, cache, &template, Platform::current()));
+    }
+
+    /// Moves the books of the open library where `template` puts them. Their cache items,
+    /// covers, user data, overlays and places in collections follow, and each move is logged
+    /// as it is made so that [`State::undo_organize`] can put the books back. Returns the
+    /// changes and the books that were left where they are. Should the undo log fail, the
+    /// books moved so far are still followed before the error is returned.
+    pub fn organize_library(
+        &mut self,
+        template: &str,
+    ) -> anyhow::Result<(CacheChanges, Vec<SkippedBook>)> {
+        let plan = self.preview_organize(template)?;
+        let lib = self.writable_lib()?;
+        let mut undo = UndoLog::start(&lib, template.trim())?;
+        let (done, mut skipped, logged) =
+            Self::move_files(&lib, plan.moves, |book| undo.moved(book));
+        let changes = self.follow_moves(&lib, &done)?;
+        logged?;
+        skipped.extend(plan.skipped);
+        return Ok((changes, skipped));
+    }
+
+    /// Puts back the books moved by the last run of the organizer in the open library.
+    /// Books that cannot go back stay in the undo log, so undoing again retries them.
+    pub fn undo_organize(&mut self) -> anyhow::Result<(CacheChanges, Vec<SkippedBook>)> {
+        let lib = self.writable_lib()?;
+        let run =
+            organize::last_run(&lib)?.ok_or_else(|| anyhow!("The library was not organized."))?;
+        let moves = run
+            .moves
+            .iter()
+            .rev()
+            .map(|book| BookMove {
+                key: Cache::hash_relative_path(&book.to.split('/').collect::<PathBuf>()),
+                from: book.to.clone(),
+                to: book.from.clone(),
+            })
+            .collect();
+        let mut undo = UndoLog::open(&lib)?;
+        let (done, failed, logged) =
+            Self::move_files(&lib, moves, |book| undo.undone(&book.to, &book.from));
+        let changes = self.follow_moves(&lib, &done)?;
+        logged?;
+        return Ok((changes, failed));
+    }
+
+    /// Root of the open library, which must be online to have its files changed.
+    fn writable_lib(&self) -> anyhow::Result<PathBuf> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        if self.offline {
+            return Err(anyhow!("Cannot move the books of an offline library."));
+        }
+        return Ok(lib);
+    }
+
+    /// Moves book files, calling `logged` after each move. Returns the moves made, the
+    /// books that could not be moved and the error of `logged`, if it failed; no book is
+    /// moved once it did, so that the log never misses a move.
+    fn move_files(
+        lib: &Path,
+        moves: Vec<BookMove>,
+        mut logged: impl FnMut(&BookMove) -> anyhow::Result<()>,
+    ) -> (Vec<BookMove>, Vec<SkippedBook>, anyhow::Result<()>) {
+        let platform = Platform::current();
+        let mut done = Vec::new();
+        let mut failed = Vec::new();
+        for book in moves {
+            match organize::move_book(lib, &book, platform) {
+                anyhow::Result::Ok(()) => {
+                    let result = logged(&book);
+                    done.push(book);
+                    if result.is_err() {
+                        return (done, failed, result);
+                    }
                 }
+                Err(e) => failed.push(SkippedBook {
+                    key: book.key,
+                    path: book.from,
+                    reason: format!("{:#}", e),
+                }),
             }
-            None => todo!(),
         }
+        return (done, failed, Ok(()));
     }
 
-    pub fn get_book_data(&self) -> Vec<BookData> {
-        if !self.has_lib() || self.cache.is_none() {
-            return vec![];
+    /// Re-keys what the library keeps about moved books: cache items and manual collections.
+    /// User data and overlays are keyed by book identity and follow the books as is.
+    fn follow_moves(&mut self, lib: &Path, moves: &[BookMove]) -> anyhow::Result<CacheChanges> {
+        if moves.is_empty() {
+            return Ok(CacheChanges::default());
+        }
+        let settings = self.library.settings_mut(lib.to_path_buf());
+        let cache = self
+            .cache
+            .as_mut()
+            .ok_or_else(|| anyhow!("No cache is open."))?;
+        let (changes, keys) = cache.move_books(moves)?;
+        for collection in &mut settings.collections {
+            collection.rekey(&keys);
         }
+        self.library.write(&self.support_dir)?;
+        return Ok(changes);
+    }
+
+    /// Saves the reading progress of a book of the open library.
+    pub fn set_progress(&mut self, key: &str, progress: Progress) -> anyhow::Result<()> {
+        let (book, path) = self.sync_book(key)?;
         return self
+            .sync_log()?
+            .record(&book, &path, Change::Progress(progress));
+    }
+
+    /// Adds or replaces an annotation of a book of the open library, giving new ones an id.
+    /// Returns the id.
+    pub fn save_annotation(
+        &mut self,
+        key: &str,
+        mut annotation: Annotation,
+    ) -> anyhow::Result<String> {
+        let (book, path) = self.sync_book(key)?;
+        let log = self.sync_log()?;
+        if annotation.id.is_empty() {
+            annotation.id = log.new_id();
+        }
+        let id = annotation.id.clone();
+        log.record(&book, &path, Change::Annotation(annotation))?;
+        return Ok(id);
+    }
+
+    pub fn delete_annotation(&mut self, key: &str, id: String) -> anyhow::Result<()> {
+        let (book, path) = self.sync_book(key)?;
+        return self
+            .sync_log()?
+            .record(&book, &path, Change::AnnotationDeleted { id });
+    }
+
+    /// Sets the reading status of a book of the open library. Finishing a book also dates
+    /// it, unless it already has a date.
+    pub fn set_status(&mut self, key: &str, status: Option<ReadingStatus>) -> anyhow::Result<()> {
+        let (book, path) = self.sync_book(key)?;
+        let log = self.sync_log()?;
+        log.record(&book, &path, Change::Status { status })?;
+        if status == Some(ReadingStatus::Finished)
+            && log
+                .book(&book, &path)
+                .and_then(BookUserData::finished)
+                .is_none()
+        {
+            let date = Some(opds::now_millis() as u64);
+            log.record(&book, &path, Change::Finished { date })?;
+        }
+        return Ok(());
+    }
+
+    /// Rates a book of the open library from 1 to 5, or clears its rating.
+    pub fn set_rating(&mut self, key: &str, rating: Option<u8>) -> anyhow::Result<()> {
+        if let Some(rating) = rating
+            && !(1..=5).contains(&rating)
+        {
+            return Err(anyhow!("Ratings go from 1 to 5, not {}", rating));
+        }
+        let (book, path) = self.sync_book(key)?;
+        return self
+            .sync_log()?
+            .record(&book, &path, Change::Rating { rating });
+    }
+
+    /// Replaces the tags of a book of the open library. Blank and repeated tags are dropped.
+    pub fn set_tags(&mut self, key: &str, tags: Vec<String>) -> anyhow::Result<()> {
+        let mut unique: Vec<String> = Vec::new();
+        for tag in tags {
+            let tag = tag.trim();
+            if !tag.is_empty() && !unique.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                unique.push(tag.to_string());
+            }
+        }
+        let (book, path) = self.sync_book(key)?;
+        return self
+            .sync_log()?
+            .record(&book, &path, Change::Tags { tags: unique });
+    }
+
+    /// Sets when a book of the open library was finished, in milliseconds since the Unix
+    /// epoch.
+    pub fn set_date_finished(&mut self, key: &str, date: Option<u64>) -> anyhow::Result<()> {
+        let (book, path) = self.sync_book(key)?;
+        return self
+            .sync_log()?
+            .record(&book, &path, Change::Finished { date });
+    }
+
+    /// Progress, annotations, status, rating and tags of a book of the open library, from
+    /// every device.
+    pub fn get_reading_data(&mut self, key: &str) -> anyhow::Result<BookUserData> {
+        let (book, path) = self.sync_book(key)?;
+        return Ok(self
+            .sync_log()?
+            .book(&book, &path)
+            .cloned()
+            .unwrap_or_default());
+    }
+
+    /// Merges the sync logs other devices wrote to the open library since the last merge,
+    /// or the snapshot of them while it is offline. Returns the keys of the books whose
+    /// reading data changed.
+    pub fn merge_sync(&mut self) -> anyhow::Result<Vec<String>> {
+        if self.library.get_open_lib().is_none() {
+            return Ok(vec![]);
+        }
+        let changed: HashSet<String> = self.sync_log()?.merge()?.into_iter().collect();
+        return Ok(self.keys_of(&changed));
+    }
+
+    /// Imports the progress, status and annotations KOReader keeps next to the books of the
+    /// open library, see [`SyncLog::import_sidecar`]. Sidecars that cannot be read, e.g.
+    /// while KOReader writes them, are tried again on the next refresh. Returns the keys of
+    /// the books whose reading data changed.
+    fn import_sidecars(&mut self, sidecars: Vec<ChangedSidecar>) -> anyhow::Result<Vec<String>> {
+        let mut changed = Vec::new();
+        let mut imported = Vec::new();
+        for found in sidecars {
+            let Some(item) = self.cache.as_ref().and_then(|cache| cache.item(&found.key)) else {
+                continue;
+            };
+            let (identity, path) = (
+                item.identity().to_string(),
+                item.relative_path().to_string(),
+            );
+            let anyhow::Result::Ok(sidecar) = koreader::read_sidecar(&found.path) else {
+                continue;
+            };
+            if self.sync_log()?.import_sidecar(&identity, &path, sidecar)? {
+                changed.push(found.key.clone());
+            }
+            imported.push(found);
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.sidecars_imported(&imported)?;
+        }
+        return Ok(changed);
+    }
+
+    pub fn support_dir(&self) -> &Path {
+        return &self.support_dir;
+    }
+
+    pub fn device_id(&self) -> &str {
+        return &self.device_id;
+    }
+
+    pub fn kosync_account(&self) -> Option<&KosyncAccount> {
+        return self.kosync.as_ref();
+    }
+
+    /// Saves the kosync account to sync with, or stops syncing for `None`.
+    pub fn set_kosync_account(&mut self, account: Option<KosyncAccount>) -> anyhow::Result<()> {
+        KosyncAccount::write(account.as_ref(), &self.support_dir)?;
+        self.kosync = account;
+        return Ok(());
+    }
+
+    /// Points a kosync account on the kosync server of this device at the port the server now
+    /// listens on, which changes whenever it picks a free one. Returns `true` if it moved.
+    pub fn follow_kosync_server(&mut self, port: u16) -> anyhow::Result<bool> {
+        let Some(account) = &self.kosync else {
+            return Ok(false);
+        };
+        if account.local_port().is_none_or(|local| local == port) {
+            return Ok(false);
+        }
+        self.set_kosync_account(Some(account.with_local_port(port)))?;
+        return Ok(true);
+    }
+
+    /// Path of a book of the open library.
+    pub fn book_file(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let item = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.item(key))
+            .ok_or_else(|| anyhow!("No book with key {}", key))?;
+        return Ok(lib.join(item.relative_path()));
+    }
+
+    /// A file the reader can open for a book of the open library. Books inside archives are
+    /// extracted into the cache of the library first.
+    pub fn open_book(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let file = self.book_file(key)?;
+        let cache = self
             .cache
             .as_ref()
-            .unwrap()
-            .get_book_data(self.library.get_open_lib().unwrap());
+            .ok_or_else(|| anyhow!("No cache is open."))?;
+        return archive::extract_book(&file, &cache.extracted_path(key));
+    }
+
+    /// User data of the library at `open_lib`, if its sync log is open. The log is opened by
+    /// the refresh that follows opening a library.
+    fn user_data(&self, open_lib: &Path) -> Option<&SyncLog> {
+        return self.sync.as_ref().filter(|log| log.root() == open_lib);
+    }
+
+    /// Identity and relative path of a book of the open library, as written to the sync
+    /// logs. Books indexed before identities existed are looked up in the logs by path until
+    /// a refresh identifies them.
+    fn sync_book(&mut self, key: &str) -> anyhow::Result<(String, String)> {
+        let (identity, path) = self
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.item(key))
+            .map(|item| {
+                (
+                    item.identity().to_string(),
+                    item.relative_path().to_string(),
+                )
+            })
+            .ok_or_else(|| anyhow!("No book with key {}", key))?;
+        if !identity.is_empty() {
+            return Ok((identity, path));
+        }
+        let identity = self.sync_log()?.book_at(&path).ok_or_else(|| {
+            anyhow!(
+                "{} is not identified yet, refresh the library once it is online.",
+                path
+            )
+        })?;
+        return Ok((identity.to_string(), path));
+    }
+
+    /// Keys of the books of the open library with one of the given identities.
+    fn keys_of(&self, identities: &HashSet<String>) -> Vec<String> {
+        return self
+            .cache
+            .iter()
+            .flat_map(|cache| cache.items())
+            .filter(|item| identities.contains(item.identity()))
+            .map(|item| item.key().to_string())
+            .collect();
+    }
+
+    /// Hands the overlays of the open library to its cache, after it was opened.
+    fn apply_overlays(&mut self) {
+        if let (Some(lib), Some(cache)) = (self.library.get_open_lib(), self.cache.as_mut()) {
+            cache.set_overlays(self.library.get_settings(&lib).overlays);
+        }
+    }
+
+    /// Sync log of the open library, reopened when another library was opened or the open
+    /// one went offline or came back.
+    fn sync_log(&mut self) -> anyhow::Result<&mut SyncLog> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let online = !self.offline;
+        if self
+            .sync
+            .as_ref()
+            .is_none_or(|log| log.root() != lib || log.is_online() != online)
+        {
+            let local_dir = self.library.get_settings(&lib).sync_dir(&self.support_dir);
+            self.sync = Some(SyncLog::open(&lib, &local_dir, &self.device_id, online)?);
+        }
+        return self
+            .sync
+            .as_mut()
+            .ok_or_else(|| anyhow!("No sync log is open."));
+    }
+
+    /// Sets how many books of the open library may be parsed at once while indexing.
+    pub fn set_index_concurrency(&mut self, limit: Option<usize>) -> anyhow::Result<()> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        self.library.settings_mut(lib).index_concurrency = limit;
+        self.library.write(&self.support_dir)?;
+        return Ok(());
+    }
+
+    /// Returns `true` if the open library is offline but its root can be read again.
+    pub fn library_reappeared(&self) -> bool {
+        return match (&self.cache, self.library.get_open_lib()) {
+            (Some(cache), Some(open_lib)) => {
+                let settings = self.library.get_settings(&open_lib);
+                self.offline && cache.is_reachable(&open_lib, settings.root_marker())
+            }
+            _ => false,
+        };
+    }
+
+    /// Registered libraries as `(id, root)` pairs, the open one first.
+    pub fn get_library_ids(&self) -> Vec<(String, PathBuf)> {
+        let open_lib = self.library.get_open_lib();
+        let mut libraries: Vec<(String, PathBuf)> = self
+            .library
+            .get_libraries()
+            .iter()
+            .map(|lib| (self.library.get_settings(lib).id, lib.clone()))
+            .collect();
+        libraries.sort_by_key(|(_, lib)| Some(lib) != open_lib.as_ref());
+        return libraries;
+    }
+
+    /// Where the catalog server reads the library with the given id from: the cache of the
+    /// open library, or the cache folder and overlays of another one, to read without holding
+    /// the state.
+    pub fn catalog_source(&self, id: &str) -> anyhow::Result<CatalogSource<'_>> {
+        let (_, lib) = self
+            .get_library_ids()
+            .into_iter()
+            .find(|(lib_id, _)| lib_id == id)
+            .ok_or_else(|| anyhow!("No library with id {}", id))?;
+        if self.library.get_open_lib().as_ref() == Some(&lib)
+            && let Some(cache) = &self.cache
+        {
+            return Ok(CatalogSource::Open(lib, cache));
+        }
+        let settings = self.library.get_settings(&lib);
+        return Ok(CatalogSource::Closed {
+            cache_dir: settings.cache_dir(&lib, &self.support_dir),
+            overlays: settings.overlays,
+            root: lib,
+        });
+    }
+
+    /// Collections of the open library, with the number of their books in the library.
+    pub fn get_collections(&self) -> anyhow::Result<Vec<(Collection, usize)>> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let collections = self.library.get_settings(&lib).collections;
+        return Ok(collections
+            .into_iter()
+            .map(|collection| {
+                let total = match &self.cache {
+                    Some(cache) => collection.books(cache, self.user_data(&lib)).len(),
+                    None => 0,
+                };
+                (collection, total)
+            })
+            .collect());
+    }
+
+    /// Adds a collection to the open library.
+    pub fn create_collection(
+        &mut self,
+        name: &str,
+        content: CollectionContent,
+    ) -> anyhow::Result<()> {
+        if let CollectionContent::Smart(rules) = &content {
+            rules.validate()?;
+        }
+        let collection = Collection::new(name, content)?;
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        self.library.settings_mut(lib).collections.push(collection);
+        self.library.write(&self.support_dir)?;
+        return Ok(());
+    }
+
+    /// Changes a collection of the open library with `edit`, then saves it.
+    pub fn edit_collection(
+        &mut self,
+        id: &str,
+        edit: impl FnOnce(&mut Collection) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let collection = self
+            .library
+            .settings_mut(lib)
+            .collections
+            .iter_mut()
+            .find(|collection| collection.id == id)
+            .ok_or_else(|| anyhow!("No collection with id {}", id))?;
+        edit(collection)?;
+        self.library.write(&self.support_dir)?;
+        return Ok(());
+    }
+
+    pub fn delete_collection(&mut self, id: &str) -> anyhow::Result<()> {
+        let lib = self
+            .library
+            .get_open_lib()
+            .ok_or_else(|| anyhow!("No library is open."))?;
+        let collections = &mut self.library.settings_mut(lib).collections;
+        let count = collections.len();
+        collections.retain(|collection| collection.id != id);
+        if collections.len() == count {
+            return Err(anyhow!("No collection with id {}", id));
+        }
+        self.library.write(&self.support_dir)?;
+        return Ok(());
+    }
+
+    /// Up to `limit` books of a collection of the open library, starting at `offset`, in
+    /// collection order. Smart collections are evaluated against the cache on every call.
+    pub fn get_collection_page(
+        &self,
+        id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> anyhow::Result<CollectionPage> {
+        let (Some(cache), Some(lib)) = (&self.cache, self.library.get_open_lib()) else {
+            return Err(anyhow!("No library is open."));
+        };
+        let collection = self
+            .library
+            .get_settings(&lib)
+            .collections
+            .into_iter()
+            .find(|collection| collection.id == id)
+            .ok_or_else(|| anyhow!("No collection with id {}", id))?;
+        let items = collection.books(cache, self.user_data(&lib));
+        let books = items
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|item| cache.book_data(&lib, item))
+            .collect();
+        return Ok(CollectionPage {
+            collection_id: collection.id,
+            offset: offset as u32,
+            total: items.len() as u32,
+            books,
+        });
+    }
+
+    /// Writes the catalog of the open library to `destination`, see [`export::export_catalog`].
+    pub fn export_catalog(
+        &self,
+        format: ExportFormat,
+        destination: &Path,
+    ) -> anyhow::Result<usize> {
+        match (&self.cache, self.library.get_open_lib()) {
+            (Some(cache), Some(open_lib)) => {
+                let user_data = self.user_data(&open_lib);
+                export::export_catalog(&open_lib, cache, user_data, format, destination)
+            }
+            _ => Err(anyhow!("No library is open.")),
+        }
+    }
+
+    pub fn get_library_summary(&self) -> LibrarySummary {
+        let mut summary = match &self.cache {
+            Some(cache) => cache.get_summary(),
+            None => LibrarySummary {
+                total: 0,
+                ordering_token: String::new(),
+                version: 0,
+                offline: false,
+            },
+        };
+        summary.offline = self.offline;
+        return summary;
+    }
+
+    pub fn get_library_delta(&self, changes: CacheChanges) -> Option<LibraryDelta> {
+        match (&self.cache, self.library.get_open_lib()) {
+            (Some(cache), Some(open_lib)) => Some(cache.get_delta(open_lib, changes)),
+            _ => None,
+        }
+    }
+
+    /// A page of the books of the open library, see [`Cache::get_book_page`], keeping only
+    /// those whose user data matches `filter`.
+    pub fn get_library_page(
+        &self,
+        offset: usize,
+        limit: usize,
+        query: Option<&str>,
+        filter: Option<&BookFilter>,
+    ) -> LibraryPage {
+        match (&self.cache, self.library.get_open_lib()) {
+            (Some(cache), Some(open_lib)) => {
+                let log = self.user_data(&open_lib);
+                let keep = |item: &CacheItem| {
+                    filter.is_none_or(|filter| {
+                        filter.matches(
+                            log.and_then(|log| log.book(item.identity(), item.relative_path())),
+                        )
+                    })
+                };
+                cache.get_book_page(open_lib, offset, limit, query, keep)
+            }
+            _ => LibraryPage {
+                offset: offset as u32,
+                total: 0,
+                ordering_token: String::new(),
+                query: query.map(str::to_string),
+                books: vec![],
+            },
+        }
     }
 }
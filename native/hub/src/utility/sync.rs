@@ -0,0 +1,643 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    hash::{DefaultHasher, Hash, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Ok;
+
+use crate::utility::{
+    cache::Cache,
+    epub_edit,
+    koreader::Sidecar,
+    kosync,
+    library::DATA_DIR,
+    opds,
+    user_data::{BookUserData, Change, ChangeEntry, LocatorKind, Progress, UserData},
+};
+
+/// Folder of the sync logs, inside the data folder of the library.
+pub const SYNC_DIR: &str = "sync";
+const LOG_EXTENSION: &str = "jsonl";
+/// The merged logs of the library, kept in its local folder for when it is offline.
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+/// File in the support directory holding the id of this device.
+const DEVICE_FILE: &str = "device_id";
+/// Entries of the own log past which it is compacted.
+const COMPACT_THRESHOLD: usize = 5000;
+
+/// Returns the id of this device, created on first use.
+pub fn device_id(support_dir: &Path) -> anyhow::Result<String> {
+    let file = support_dir.join(DEVICE_FILE);
+    if let anyhow::Result::Ok(id) = fs::read_to_string(&file)
+        && !id.trim().is_empty()
+    {
+        return Ok(id.trim().to_string());
+    }
+    let mut hasher = DefaultHasher::new();
+    support_dir.hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    process::id().hash(&mut hasher);
+    let id = format!("{:x}", hasher.finish());
+    fs::write(&file, &id)?;
+    return Ok(id);
+}
+
+/// Identity of a book in the logs: the unique identifier of its package, or the KOReader
+/// [`kosync::partial_md5`] of the file for EPUBs that do not name one. Unlike the path, it
+/// survives moves and renames, and every device finds the same one.
+pub fn book_identity(path: &Path) -> anyhow::Result<String> {
+    if let anyhow::Result::Ok(Some(identifier)) = epub_edit::unique_identifier(path) {
+        return Ok(format!("uid:{}", identifier));
+    }
+    return Ok(format!("md5:{}", kosync::partial_md5(path)?));
+}
+
+/// Path of a book as written to the logs: relative to the library root with `/` separators,
+/// so every device names it the same way.
+pub fn book_path(relative_path: &str) -> String {
+    return relative_path.replace('\\', "/");
+}
+
+/// Reading progress and annotations of a library, synced between devices through the library
+/// folder itself (Syncthing, Nextcloud ...) without any server. Books are known by their
+/// [`book_identity`], so their data follows them when they move.
+///
+/// Each device appends its changes to its own log, `.spectecle/sync/<device>.jsonl`, and
+/// never writes to the logs of other devices, so the sync tool never sees concurrent edits
+/// of a file. The user data is the merge of every log, see [`UserData::apply`]: the latest
+/// progress wins and annotations are the union of every device's, minus the deleted ones.
+///
+/// Libraries that cannot be written, being read-only or offline, still take changes: they
+/// wait in a local folder of the library until its own log can be written.
+#[derive(Debug)]
+pub struct SyncLog {
+    root: PathBuf,
+    /// `.spectecle/sync` of the library.
+    shared_dir: PathBuf,
+    /// Folder of the library in the support directory, see [`SyncLog::open`].
+    local_dir: PathBuf,
+    device: String,
+    /// The library root can be read; its folder is left alone otherwise.
+    online: bool,
+    /// The own log is in `shared_dir`, rather than waiting in `local_dir`.
+    shared: bool,
+    data: UserData,
+    /// Entries in the own log, to know when to compact it.
+    own_entries: usize,
+}
+
+impl SyncLog {
+    /// Opens the sync logs of the library at `root` and merges them. `local_dir` keeps what
+    /// the library folder cannot: the changes of this device while the library is read-only
+    /// or not `online`, moved to the library once it can be written, and a snapshot of the
+    /// merged logs that stands in for the library while it is offline.
+    pub fn open(root: &Path, local_dir: &Path, device: &str, online: bool) -> anyhow::Result<Self> {
+        let shared_dir = root.join(DATA_DIR).join(SYNC_DIR);
+        let mut log = Self {
+            root: root.to_path_buf(),
+            shared: online && Cache::is_writable(&shared_dir),
+            shared_dir,
+            local_dir: local_dir.to_path_buf(),
+            device: device.to_string(),
+            online,
+            data: UserData::default(),
+            own_entries: 0,
+        };
+        log.merge()?;
+        return Ok(log);
+    }
+
+    pub fn root(&self) -> &Path {
+        return &self.root;
+    }
+
+    /// The library root could be read when the log was opened.
+    pub fn is_online(&self) -> bool {
+        return self.online;
+    }
+
+    /// User data of the book with `identity`. A book whose identity is not known yet, e.g.
+    /// indexed by an older version and offline since, is looked up by its relative path.
+    pub fn book(&self, identity: &str, path: &str) -> Option<&BookUserData> {
+        return match identity.is_empty() {
+            true => self.data.book(self.book_at(path)?),
+            false => self.data.book(identity),
+        };
+    }
+
+    /// Identity of the book last seen at the relative `path`, as far as the logs know.
+    pub fn book_at(&self, path: &str) -> Option<&str> {
+        return self.data.book_at(&book_path(path));
+    }
+
+    /// A new id for an annotation, unique across devices.
+    pub fn new_id(&self) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        return format!("{}-{:x}", self.device, nanos);
+    }
+
+    /// Appends a change made on this device to the book with `identity`, now at the
+    /// relative `path`, to its log and applies it.
+    pub fn record(&mut self, identity: &str, path: &str, change: Change) -> anyhow::Result<()> {
+        return self.append(ChangeEntry {
+            time: opds::now_millis() as u64,
+            device: self.device.clone(),
+            book: identity.to_string(),
+            path: Some(book_path(path)),
+            change,
+        });
+    }
+
+    /// Gives the book with identity `to`, at the relative `path`, the user data of the book
+    /// with identity `from`, e.g. after an edit changed the content a book without a unique
+    /// identifier is known by. Each change keeps the time and device it had, so the copy
+    /// merges with whatever other devices did to the book in the meantime; the entries of
+    /// `from` stay for devices that have not seen the edit yet.
+    pub fn copy_book(&mut self, from: &str, to: &str, path: &str) -> anyhow::Result<()> {
+        let Some(data) = self.data.book(from).cloned() else {
+            return Ok(());
+        };
+        let mut changes: Vec<(u64, String, Change)> = Vec::new();
+        if let Some(p) = data.progress {
+            changes.push((p.time, p.device, Change::Progress(p.value)));
+        }
+        for (_, a) in data.annotations {
+            changes.push((a.time, a.device, Change::Annotation(a.value)));
+        }
+        if let Some(s) = data.status {
+            changes.push((s.time, s.device, Change::Status { status: s.value }));
+        }
+        if let Some(r) = data.rating {
+            changes.push((r.time, r.device, Change::Rating { rating: r.value }));
+        }
+        if let Some(t) = data.tags {
+            changes.push((t.time, t.device, Change::Tags { tags: t.value }));
+        }
+        if let Some(f) = data.finished {
+            changes.push((f.time, f.device, Change::Finished { date: f.value }));
+        }
+        let now = opds::now_millis() as u64;
+        for id in data.deleted {
+            changes.push((now, self.device.clone(), Change::AnnotationDeleted { id }));
+        }
+        for (time, device, change) in changes {
+            self.append(ChangeEntry {
+                time,
+                device,
+                book: to.to_string(),
+                path: Some(book_path(path)),
+                change,
+            })?;
+        }
+        return Ok(());
+    }
+
+    fn append(&mut self, entry: ChangeEntry) -> anyhow::Result<()> {
+        let own_log = self.own_log();
+        if let Some(dir) = own_log.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(own_log)?;
+        let line = serde_json::to_string(&entry)?;
+        writeln!(file, "{}", line)?;
+        // The own log is out of reach once the library goes offline, the snapshot is not.
+        if self.shared {
+            fs::create_dir_all(&self.local_dir)?;
+            let mut snapshot = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.local_dir.join(SNAPSHOT_FILE))?;
+            writeln!(snapshot, "{}", line)?;
+        }
+        self.data.apply(entry);
+        self.own_entries += 1;
+        if self.own_entries > COMPACT_THRESHOLD {
+            self.compact()?;
+        }
+        return Ok(());
+    }
+
+    /// Records what a KOReader sidecar knows about the book with `identity`, at the relative
+    /// `path`. Whatever was changed in KOReader after our last change wins and annotations
+    /// deleted here stay deleted. Nothing already known is recorded again, so importing the
+    /// same sidecar twice changes nothing. Returns `true` if anything was recorded.
+    pub fn import_sidecar(
+        &mut self,
+        identity: &str,
+        path: &str,
+        sidecar: Sidecar,
+    ) -> anyhow::Result<bool> {
+        let current = self.data.book(identity).cloned().unwrap_or_default();
+        let is_newer = |time: u64| time < sidecar.modified;
+        let mut changes = Vec::new();
+        if let (Some(locator), Some(percent)) = (sidecar.last_xpointer, sidecar.percent_finished)
+            && current
+                .progress
+                .as_ref()
+                .is_none_or(|p| is_newer(p.time) && p.value.locator != locator)
+        {
+            changes.push(Change::Progress(Progress {
+                locator,
+                locator_kind: LocatorKind::XPointer,
+                percent: percent.clamp(0.0, 1.0),
+            }));
+        }
+        if let Some(status) = sidecar.status
+            && current
+                .status
+                .as_ref()
+                .is_none_or(|s| is_newer(s.time) && s.value != Some(status))
+        {
+            changes.push(Change::Status {
+                status: Some(status),
+            });
+        }
+        if let Some(rating) = sidecar.rating
+            && current
+                .rating
+                .as_ref()
+                .is_none_or(|r| is_newer(r.time) && r.value != Some(rating))
+        {
+            changes.push(Change::Rating {
+                rating: Some(rating),
+            });
+        }
+        for annotation in sidecar.annotations {
+            if current.deleted.contains(&annotation.id) {
+                continue;
+            }
+            let known = current.annotations.get(&annotation.id);
+            if known.is_none_or(|a| is_newer(a.time) && a.value != annotation) {
+                changes.push(Change::Annotation(annotation));
+            }
+        }
+        let changed = !changes.is_empty();
+        for change in changes {
+            self.record(identity, path, change)?;
+        }
+        return Ok(changed);
+    }
+
+    /// Merges the logs of every device again, returning the identities of the books whose
+    /// data changed. While the library is offline, the snapshot of its logs taken by the last
+    /// merge stands in for them.
+    pub fn merge(&mut self) -> anyhow::Result<Vec<String>> {
+        if self.shared {
+            self.flush_pending()?;
+        }
+        let snapshot_file = self.local_dir.join(SNAPSHOT_FILE);
+        let mut logs = match self.online {
+            true => read_logs(&self.shared_dir)?,
+            false => vec![(snapshot_file.clone(), read_log(&snapshot_file))],
+        };
+        let snapshot = match self.online {
+            true => Some(compacted(
+                logs.iter().flat_map(|(_, log)| log.iter().cloned()),
+            )),
+            false => None,
+        };
+        if !self.shared {
+            let pending = self.pending_log();
+            logs.push((pending.clone(), read_log(&pending)));
+        }
+        let own_log = self.own_log();
+        let mut data = UserData::default();
+        self.own_entries = 0;
+        // Conflict copies made by the sync tool are logs too; applying an entry twice is harmless.
+        for (path, entries) in logs {
+            if path == own_log {
+                self.own_entries = entries.len();
+            }
+            for entry in entries {
+                data.apply(entry);
+            }
+        }
+        let changed = data.changed_books(&self.data);
+        self.data = data;
+        if let Some(snapshot) = snapshot {
+            write_log(&snapshot_file, &snapshot)?;
+        }
+        return Ok(changed);
+    }
+
+    fn own_log(&self) -> PathBuf {
+        return match self.shared {
+            true => self
+                .shared_dir
+                .join(format!("{}.{}", self.device, LOG_EXTENSION)),
+            false => self.pending_log(),
+        };
+    }
+
+    /// Log of the changes waiting for the library to be writable.
+    fn pending_log(&self) -> PathBuf {
+        return self
+            .local_dir
+            .join(format!("{}.{}", self.device, LOG_EXTENSION));
+    }
+
+    /// Moves the changes that waited in the local folder to the own log in the library.
+    fn flush_pending(&mut self) -> anyhow::Result<()> {
+        let pending = self.pending_log();
+        let anyhow::Result::Ok(content) = fs::read_to_string(&pending) else {
+            return Ok(());
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.own_log())?;
+        file.write_all(content.as_bytes())?;
+        fs::remove_file(pending)?;
+        return Ok(());
+    }
+
+    /// Rewrites the own log keeping only the entries that still matter, see [`compacted`].
+    fn compact(&mut self) -> anyhow::Result<()> {
+        let own_log = self.own_log();
+        let entries = compacted(read_log(&own_log));
+        write_log(&own_log, &entries)?;
+        self.own_entries = entries.len();
+        return Ok(());
+    }
+}
+
+/// Every log in `dir`, with its path.
+fn read_logs(dir: &Path) -> anyhow::Result<Vec<(PathBuf, Vec<ChangeEntry>)>> {
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut logs = Vec::new();
+    for entry in fs::read_dir(dir)?.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !is_log(&path) {
+            continue;
+        }
+        let entries = read_log(&path);
+        logs.push((path, entries));
+    }
+    return Ok(logs);
+}
+
+/// Whether `path` names a sync log.
+pub fn is_log(path: &Path) -> bool {
+    return path.extension().and_then(|e| e.to_str()) == Some(LOG_EXTENSION);
+}
+
+/// Adds the entries of the log `other` missing from the log at `path`, e.g. one restored
+/// from a backup. Changes commute, so the merged log holds the user data of both.
+pub fn merge_log(path: &Path, other: &str) -> anyhow::Result<()> {
+    let mut entries = read_log(path);
+    let mut known = HashSet::new();
+    for entry in &entries {
+        known.insert(serde_json::to_string(entry)?);
+    }
+    for entry in other
+        .lines()
+        .filter_map(|line| serde_json::from_str::<ChangeEntry>(line).ok())
+    {
+        if known.insert(serde_json::to_string(&entry)?) {
+            entries.push(entry);
+        }
+    }
+    // Stable, so changes a device made within the same millisecond keep their order.
+    entries.sort_by_key(|e| e.time);
+    return write_log(path, &entries);
+}
+
+/// Entries of a log, none if it cannot be read.
+fn read_log(path: &Path) -> Vec<ChangeEntry> {
+    let anyhow::Result::Ok(content) = fs::read_to_string(path) else {
+        return vec![];
+    };
+    // The last line may still be on its way from another device.
+    return content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect();
+}
+
+/// Writes a log aside then renames it, so the sync tool never uploads half a log.
+fn write_log(path: &Path, entries: &[ChangeEntry]) -> anyhow::Result<()> {
+    let mut content = String::new();
+    for entry in entries {
+        content.push_str(&serde_json::to_string(entry)?);
+        content.push('\n');
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = dir.join(format!(".{}.part", name));
+    fs::write(&partial, content)?;
+    fs::rename(&partial, path)?;
+    return Ok(());
+}
+
+/// The entries that still matter: the latest progress, status, rating, tags and finish date
+/// of each book, the latest version of each annotation and the deletions.
+fn compacted(entries: impl IntoIterator<Item = ChangeEntry>) -> Vec<ChangeEntry> {
+    let mut latest: HashMap<(String, String), ChangeEntry> = HashMap::new();
+    for entry in entries {
+        let target = match &entry.change {
+            Change::Progress(_) => "progress".to_string(),
+            Change::Status { .. } => "status".to_string(),
+            Change::Rating { .. } => "rating".to_string(),
+            Change::Tags { .. } => "tags".to_string(),
+            Change::Finished { .. } => "finished".to_string(),
+            Change::Annotation(annotation) => annotation.id.clone(),
+            Change::AnnotationDeleted { id } => id.clone(),
+        };
+        let key = (entry.book.clone(), target);
+        // A deletion is final, whatever came after it.
+        if latest
+            .get(&key)
+            .is_some_and(|e| matches!(e.change, Change::AnnotationDeleted { .. }))
+        {
+            continue;
+        }
+        // Ties between devices go the way [`UserData::apply`] sends them.
+        if latest
+            .get(&key)
+            .is_none_or(|e| (e.time, &e.device) <= (entry.time, &entry.device))
+            || matches!(entry.change, Change::AnnotationDeleted { .. })
+        {
+            latest.insert(key, entry);
+        }
+    }
+    let mut entries: Vec<ChangeEntry> = latest.into_values().collect();
+    entries.sort_by_key(|e| e.time);
+    return entries;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::utility::{
+        koreader,
+        user_data::{AnnotationKind, ReadingStatus},
+    };
+
+    const IDENTITY: &str = "uid:urn:uuid:dune";
+
+    fn temp_dir(name: &str) -> anyhow::Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("spectecle-sync-{}-{}", name, process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        return Ok(dir);
+    }
+
+    #[test]
+    fn keeps_changes_locally_until_the_library_is_back() -> anyhow::Result<()> {
+        let dir = temp_dir("offline")?;
+        let (root, local) = (dir.join("library"), dir.join("local"));
+        fs::create_dir_all(&root)?;
+        let status = |log: &SyncLog| log.book(IDENTITY, "").and_then(BookUserData::status);
+
+        let mut offline = SyncLog::open(&root, &local, "a", false)?;
+        offline.record(
+            IDENTITY,
+            "Herbert\\Dune.epub",
+            Change::Rating { rating: Some(4) },
+        )?;
+        assert!(!root.join(DATA_DIR).exists());
+        // Books not identified yet are found by the path they were last seen at.
+        assert_eq!(offline.book_at("Herbert/Dune.epub"), Some(IDENTITY));
+
+        let mut online = SyncLog::open(&root, &local, "a", true)?;
+        assert!(!local.join("a.jsonl").exists());
+        assert_eq!(
+            read_log(&root.join(DATA_DIR).join(SYNC_DIR).join("a.jsonl")).len(),
+            1
+        );
+        let status_change = Change::Status {
+            status: Some(ReadingStatus::Reading),
+        };
+        online.record(IDENTITY, "Dune.epub", status_change)?;
+
+        // Offline again, the snapshot of the last merge stands in for the library.
+        fs::remove_dir_all(&root)?;
+        let offline = SyncLog::open(&root, &local, "a", false)?;
+        assert_eq!(status(&offline), Some(ReadingStatus::Reading));
+        assert_eq!(
+            offline.book(IDENTITY, "").and_then(BookUserData::rating),
+            Some(4)
+        );
+        assert_eq!(
+            offline.book("", "Dune.epub").and_then(BookUserData::rating),
+            Some(4)
+        );
+        fs::remove_dir_all(dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn compaction_keeps_the_winner_of_each_target() -> anyhow::Result<()> {
+        let entry = |time: u64, device: &str, rating: u8| ChangeEntry {
+            time,
+            device: device.to_string(),
+            book: IDENTITY.to_string(),
+            path: None,
+            change: Change::Rating {
+                rating: Some(rating),
+            },
+        };
+        let entries = compacted(vec![entry(2, "b", 2), entry(1, "a", 1), entry(2, "a", 3)]);
+        let mut data = UserData::default();
+        for entry in entries {
+            data.apply(entry);
+        }
+        assert_eq!(data.book(IDENTITY).and_then(BookUserData::rating), Some(2));
+        return Ok(());
+    }
+
+    #[test]
+    fn imports_a_koreader_sidecar_once() -> anyhow::Result<()> {
+        let dir = temp_dir("sidecar")?;
+        let root = dir.join("library");
+        let sidecar = root.join("Dune.sdr").join("metadata.epub.lua");
+        fs::create_dir_all(root.join("Dune.sdr"))?;
+        fs::write(root.join("Dune.epub"), "")?;
+        fs::write(
+            &sidecar,
+            r#"-- we can read Lua syntax here!
+return {
+    ["last_xpointer"] = "/body/DocFragment[3]/body/p[2]/text().0",
+    ["percent_finished"] = 0.25,
+    ["summary"] = {
+        ["status"] = "reading",
+    },
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2026-01-02 10:00:00",
+            ["pos0"] = "/body/DocFragment[3]/body/p[1]/text().0",
+            ["pos1"] = "/body/DocFragment[3]/body/p[1]/text().11",
+            ["text"] = "A beginning",
+            ["note"] = "Is a very delicate time.",
+        },
+    },
+}
+"#,
+        )?;
+        assert_eq!(
+            koreader::find_sidecar(&root.join("Dune.epub")),
+            Some(sidecar.clone())
+        );
+        let mut log = SyncLog::open(&root, &dir.join("local"), "a", true)?;
+        assert!(log.import_sidecar(IDENTITY, "Dune.epub", koreader::read_sidecar(&sidecar)?)?);
+        assert!(!log.import_sidecar(IDENTITY, "Dune.epub", koreader::read_sidecar(&sidecar)?)?);
+
+        let data = log.book(IDENTITY, "").cloned().unwrap_or_default();
+        assert_eq!(data.status(), Some(ReadingStatus::Reading));
+        let progress = data.progress.map(|p| p.value);
+        assert_eq!(
+            progress.map(|p| (p.locator, p.locator_kind, p.percent)),
+            Some((
+                "/body/DocFragment[3]/body/p[2]/text().0".to_string(),
+                LocatorKind::XPointer,
+                0.25
+            ))
+        );
+        let annotations: Vec<_> = data.annotations.into_values().map(|a| a.value).collect();
+        assert_eq!(annotations.len(), 1);
+        assert!(annotations.iter().all(|a| a.kind == AnnotationKind::Note
+            && a.locator_kind == LocatorKind::XPointer
+            && a.note.as_deref() == Some("Is a very delicate time.")));
+        fs::remove_dir_all(dir)?;
+        return Ok(());
+    }
+
+    #[test]
+    fn merges_logs_entry_by_entry() -> anyhow::Result<()> {
+        let dir = temp_dir("merge")?;
+        let log = dir.join("a.jsonl");
+        let entry = |time: u64, rating: u8| ChangeEntry {
+            time,
+            device: "a".to_string(),
+            book: IDENTITY.to_string(),
+            path: None,
+            change: Change::Rating {
+                rating: Some(rating),
+            },
+        };
+        write_log(&log, &[entry(1, 1), entry(3, 3)])?;
+        let backup: Vec<String> = [entry(1, 1), entry(2, 2)]
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<_, _>>()?;
+        merge_log(&log, &backup.join("\n"))?;
+        let times: Vec<u64> = read_log(&log).iter().map(|e| e.time).collect();
+        assert_eq!(times, [1, 2, 3]);
+        fs::remove_dir_all(dir)?;
+        return Ok(());
+    }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+/// How a locator points into a book.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LocatorKind {
+    /// An EPUB CFI, as the reader of the app writes them. Logs written before locators had a
+    /// kind only hold those.
+    #[default]
+    Cfi,
+    /// A CREngine XPointer, such as `/body/DocFragment[12]/body/p[3]/text().0`, as KOReader
+    /// writes them in its sidecars and to kosync servers.
+    XPointer,
+}
+
+/// Where a reader stopped in a book.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    pub locator: String,
+    #[serde(default)]
+    pub locator_kind: LocatorKind,
+    /// From 0 to 1.
+    pub percent: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnnotationKind {
+    Highlight,
+    Note,
+    Bookmark,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    /// Unique across devices, see [`crate::utility::sync::SyncLog::new_id`].
+    pub id: String,
+    pub kind: AnnotationKind,
+    pub locator: String,
+    #[serde(default)]
+    pub locator_kind: LocatorKind,
+    /// The highlighted text.
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub created: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReadingStatus {
+    ToRead,
+    Reading,
+    Finished,
+    Abandoned,
+}
+
+impl ReadingStatus {
+    /// Name of the status in exports.
+    pub fn name(self) -> &'static str {
+        return match self {
+            Self::ToRead => "to_read",
+            Self::Reading => "reading",
+            Self::Finished => "finished",
+            Self::Abandoned => "abandoned",
+        };
+    }
+}
+
+/// A change to the user data of a book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Change {
+    Progress(Progress),
+    /// Adds an annotation or replaces the one with the same id.
+    Annotation(Annotation),
+    AnnotationDeleted {
+        id: String,
+    },
+    /// `None` clears the status.
+    Status {
+        status: Option<ReadingStatus>,
+    },
+    /// From 1 to 5, `None` clears the rating.
+    Rating {
+        rating: Option<u8>,
+    },
+    /// Replaces every tag of the book.
+    Tags {
+        tags: Vec<String>,
+    },
+    /// Milliseconds since the Unix epoch, `None` clears the date.
+    Finished {
+        date: Option<u64>,
+    },
+}
+
+/// A change made on one device, one line of its sync log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEntry {
+    /// Milliseconds since the Unix epoch, by the clock of the device that made the change.
+    pub time: u64,
+    pub device: String,
+    /// Identity of the book, see [`crate::utility::sync::book_identity`].
+    pub book: String,
+    /// Path of the book relative to the library root when the change was made, with `/`
+    /// separators. Only a hint to find books whose identity is not known yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub change: Change,
+}
+
+/// A value with the time and device of the change that set it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stamped<T> {
+    pub value: T,
+    pub time: u64,
+    pub device: String,
+}
+
+impl<T> Stamped<T> {
+    /// Orders concurrent changes: the later one wins, ties go to the greater device id so
+    /// that every device picks the same winner. Changes a device made within the same
+    /// millisecond come from the same log and are applied in the order they were made.
+    fn loses_to(&self, time: u64, device: &str) -> bool {
+        return (self.time, self.device.as_str()) <= (time, device);
+    }
+
+    /// Sets `slot` to `value` unless it holds a later change.
+    fn set_latest(slot: &mut Option<Self>, value: T, time: u64, device: String) {
+        if slot.as_ref().is_none_or(|s| s.loses_to(time, &device)) {
+            *slot = Some(Stamped {
+                value,
+                time,
+                device,
+            });
+        }
+    }
+}
+
+/// What the reader keeps about a book, merged from the changes of every device.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BookUserData {
+    pub progress: Option<Stamped<Progress>>,
+    pub annotations: BTreeMap<String, Stamped<Annotation>>,
+    /// Ids of deleted annotations. A deletion wins over any edit of the same annotation,
+    /// so an annotation deleted on one device never comes back from another.
+    pub deleted: BTreeSet<String>,
+    pub status: Option<Stamped<Option<ReadingStatus>>>,
+    pub rating: Option<Stamped<Option<u8>>>,
+    pub tags: Option<Stamped<Vec<String>>>,
+    pub finished: Option<Stamped<Option<u64>>>,
+}
+
+impl BookUserData {
+    pub fn status(&self) -> Option<ReadingStatus> {
+        return self.status.as_ref().and_then(|s| s.value);
+    }
+
+    pub fn rating(&self) -> Option<u8> {
+        return self.rating.as_ref().and_then(|r| r.value);
+    }
+
+    pub fn tags(&self) -> &[String] {
+        return self.tags.as_ref().map_or(&[], |t| &t.value);
+    }
+
+    /// When the book was finished, in milliseconds since the Unix epoch.
+    pub fn finished(&self) -> Option<u64> {
+        return self.finished.as_ref().and_then(|f| f.value);
+    }
+}
+
+/// Keeps the books whose user data matches every criterion that is set.
+#[derive(Debug, Default, Clone)]
+pub struct BookFilter {
+    pub status: Option<ReadingStatus>,
+    pub min_rating: Option<u8>,
+    /// A tag of the book, in any case.
+    pub tag: Option<String>,
+}
+
+impl BookFilter {
+    pub fn matches(&self, data: Option<&BookUserData>) -> bool {
+        let status = data.and_then(BookUserData::status);
+        let rating = data.and_then(BookUserData::rating);
+        let tags = data.map_or(&[][..], BookUserData::tags);
+        return self.status.is_none_or(|s| status == Some(s))
+            && self
+                .min_rating
+                .is_none_or(|min| rating.is_some_and(|r| r >= min))
+            && self
+                .tag
+                .as_ref()
+                .is_none_or(|tag| tags.iter().any(|t| t.eq_ignore_ascii_case(tag)));
+    }
+}
+
+/// User data of every book of a library, by book identity.
+#[derive(Debug, Default)]
+pub struct UserData {
+    books: HashMap<String, BookUserData>,
+    /// Identity of the book last seen at each path.
+    paths: HashMap<String, Stamped<String>>,
+}
+
+impl UserData {
+    /// Applies a change. Changes commute, so logs can be applied in any order and more than
+    /// once with the same result.
+    pub fn apply(&mut self, entry: ChangeEntry) {
+        if let Some(path) = entry.path {
+            let mut seen = self.paths.remove(&path);
+            let device = entry.device.clone();
+            Stamped::set_latest(&mut seen, entry.book.clone(), entry.time, device);
+            if let Some(seen) = seen {
+                self.paths.insert(path, seen);
+            }
+        }
+        let book = self.books.entry(entry.book).or_default();
+        let (time, device) = (entry.time, entry.device);
+        match entry.change {
+            Change::Progress(progress) => {
+                Stamped::set_latest(&mut book.progress, progress, time, device);
+            }
+            Change::Annotation(annotation) => {
+                if book.deleted.contains(&annotation.id) {
+                    return;
+                }
+                let mut current = book.annotations.remove(&annotation.id);
+                let id = annotation.id.clone();
+                Stamped::set_latest(&mut current, annotation, time, device);
+                if let Some(current) = current {
+                    book.annotations.insert(id, current);
+                }
+            }
+            Change::AnnotationDeleted { id } => {
+                book.annotations.remove(&id);
+                book.deleted.insert(id);
+            }
+            Change::Status { status } => {
+                Stamped::set_latest(&mut book.status, status, time, device)
+            }
+            Change::Rating { rating } => {
+                Stamped::set_latest(&mut book.rating, rating, time, device)
+            }
+            Change::Tags { tags } => Stamped::set_latest(&mut book.tags, tags, time, device),
+            Change::Finished { date } => {
+                Stamped::set_latest(&mut book.finished, date, time, device);
+            }
+        }
+    }
+
+    pub fn book(&self, book: &str) -> Option<&BookUserData> {
+        return self.books.get(book);
+    }
+
+    /// Identity of the book last seen at `path`.
+    pub fn book_at(&self, path: &str) -> Option<&str> {
+        return self.paths.get(path).map(|seen| seen.value.as_str());
+    }
+
+    /// Books whose data differs between `self` and `other`.
+    pub fn changed_books(&self, other: &UserData) -> Vec<String> {
+        let empty = BookUserData::default();
+        let mut books: BTreeSet<&String> = self.books.keys().collect();
+        books.extend(other.books.keys());
+        return books
+            .into_iter()
+            .filter(|book| {
+                self.books.get(*book).unwrap_or(&empty) != other.books.get(*book).unwrap_or(&empty)
+            })
+            .cloned()
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(change: Change) -> ChangeEntry {
+        return ChangeEntry {
+            time: 1,
+            device: "device".to_string(),
+            book: "book".to_string(),
+            path: None,
+            change,
+        };
+    }
+
+    #[test]
+    fn filters_on_every_criterion_set() {
+        let mut user_data = UserData::default();
+        user_data.apply(entry(Change::Status {
+            status: Some(ReadingStatus::Reading),
+        }));
+        user_data.apply(entry(Change::Rating { rating: Some(3) }));
+        user_data.apply(entry(Change::Tags {
+            tags: vec!["Favourite".to_string()],
+        }));
+        let data = user_data.book("book");
+        let filter = |status, min_rating, tag: Option<&str>| BookFilter {
+            status,
+            min_rating,
+            tag: tag.map(str::to_string),
+        };
+
+        assert!(filter(None, None, None).matches(data));
+        assert!(filter(None, None, None).matches(None));
+        assert!(filter(Some(ReadingStatus::Reading), Some(3), Some("FAVOURITE")).matches(data));
+        assert!(!filter(Some(ReadingStatus::Finished), None, None).matches(data));
+        assert!(!filter(None, Some(4), None).matches(data));
+        assert!(!filter(Some(ReadingStatus::Reading), None, Some("Classic")).matches(data));
+        // Books without user data only pass filters that set nothing.
+        assert!(!filter(None, Some(1), None).matches(None));
+        assert!(!filter(None, None, Some("Favourite")).matches(None));
+    }
+}